@@ -0,0 +1,1067 @@
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(not(feature = "schema"))]
+#[allow(unreachable_pub)]
+mod schema;
+
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    fmt,
+    hash::Hasher,
+    io, mem,
+};
+
+use anyhow::{bail, Context as _, Result};
+use regex::Regex;
+use serde::{
+    de::{self, DeserializeSeed, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+use self::schema::{CoverageCounts, DataSeed, Export, Function, Summary};
+
+// https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/tools/llvm-cov/CoverageExporterJson.cpp#L13-L47
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlvmCovJsonExport {
+    /// List of one or more export objects
+    pub(crate) data: Vec<Export>,
+    // llvm.coverage.json.export
+    #[serde(rename = "type")]
+    pub(crate) type_: String,
+    pub(crate) version: String,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Files -> list of uncovered lines.
+pub type UncoveredLines = BTreeMap<String, Vec<u64>>;
+
+/// Files -> set of lines excluded from coverage.
+pub type ExcludedLines = BTreeMap<String, BTreeSet<u64>>;
+
+/// Region coverage of a function, merged over all of its instantiations.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    /// Demangled name of the function.
+    pub name: String,
+    pub filename: String,
+    pub line: u64,
+    /// The last line of the function.
+    pub end_line: u64,
+    /// Number of times the function was executed, summed over all of its instantiations.
+    pub execution_count: u64,
+    /// Number of code regions.
+    pub count: u64,
+    /// Number of code regions executed at least once.
+    pub covered: u64,
+}
+
+/// A region of a function instantiation.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RegionCoverage {
+    /// Demangled name of the function.
+    pub function: String,
+    pub filename: String,
+    pub line_start: u64,
+    pub column_start: u64,
+    pub line_end: u64,
+    pub column_end: u64,
+    pub execution_count: u64,
+    /// Kind of the region: `code`, `expansion`, `skipped`, `gap`, or `branch`.
+    pub kind: &'static str,
+}
+
+/// Deserializes [`LlvmCovJsonExport`], skipping functions in files that match
+/// `ignore_filename_regex` (see [`LlvmCovJsonExport::from_reader`]).
+struct LlvmCovJsonExportSeed<'a>(DataSeed<'a>);
+
+impl<'de> DeserializeSeed<'de> for LlvmCovJsonExportSeed<'_> {
+    type Value = LlvmCovJsonExport;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for LlvmCovJsonExportSeed<'_> {
+    type Value = LlvmCovJsonExport;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the output of llvm-cov export")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut data = None;
+        let mut type_ = None;
+        let mut version = None;
+        let mut extra = serde_json::Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "data" if data.is_none() => data = Some(map.next_value_seed(self.0)?),
+                "type" if type_.is_none() => type_ = Some(map.next_value()?),
+                "version" if version.is_none() => version = Some(map.next_value()?),
+                "data" => return Err(de::Error::duplicate_field("data")),
+                "type" => return Err(de::Error::duplicate_field("type")),
+                "version" => return Err(de::Error::duplicate_field("version")),
+                _ => {
+                    extra.insert(key, map.next_value()?);
+                }
+            }
+        }
+        Ok(LlvmCovJsonExport {
+            data: data.ok_or_else(|| de::Error::missing_field("data"))?,
+            type_: type_.ok_or_else(|| de::Error::missing_field("type"))?,
+            version: version.ok_or_else(|| de::Error::missing_field("version"))?,
+            extra,
+        })
+    }
+}
+
+impl LlvmCovJsonExport {
+    /// Returns the export objects in the coverage data.
+    #[cfg(feature = "schema")]
+    #[must_use]
+    pub fn data(&self) -> &[Export] {
+        &self.data
+    }
+
+    /// Returns the version of the export format (e.g., `2.0.1`).
+    #[cfg(feature = "schema")]
+    #[must_use]
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Parses the output of `llvm-cov export -format=text` from the reader.
+    ///
+    /// `llvm-cov export -ignore-filename-regex` removes ignored files from the
+    /// output, but not functions in them. So, functions in files that match
+    /// `ignore_filename_regex` are skipped here while parsing, instead of being
+    /// filtered after the whole output is parsed.
+    ///
+    /// The output is parsed while it is read, so it is never buffered as text,
+    /// but the parsed data is kept in memory as a whole: functions follow all
+    /// files in the output, and the consumers need all of it (e.g., to
+    /// recompute the totals after excluding lines, to sort the report, or to
+    /// compare the files with the previous html report). So memory usage is
+    /// still proportional to the size of the coverage data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `ignore_filename_regex` is not a valid regex, or the
+    /// input is not a valid output of `llvm-cov export`.
+    pub fn from_reader(reader: impl io::Read, ignore_filename_regex: Option<&str>) -> Result<Self> {
+        let re = ignore_filename_regex.map(Regex::new).transpose()?;
+        let seed = LlvmCovJsonExportSeed(DataSeed { ignore_filename_regex: re.as_ref() });
+        let mut de = serde_json::Deserializer::from_reader(reader);
+        let json = seed.deserialize(&mut de)?;
+        de.end()?;
+        Ok(json)
+    }
+
+    pub fn demangle(&mut self) {
+        for data in &mut self.data {
+            if let Some(functions) = &mut data.functions {
+                for func in functions {
+                    func.name = format!("{:#}", rustc_demangle::demangle(&func.name));
+                }
+            }
+        }
+    }
+
+    /// Gets the list of files in the coverage data.
+    pub fn filenames(&self) -> impl Iterator<Item = &str> {
+        self.data.iter().flat_map(|data| data.files.iter().map(|file| file.filename.as_str()))
+    }
+
+    /// Computes the hash of the coverage data of each file.
+    ///
+    /// The hash covers everything that affects the report of the file: the
+    /// file's segments, branches, expansions, and summary, and the functions
+    /// defined in the file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coverage data cannot be serialized, which does not happen
+    /// for data parsed from the output of `llvm-cov export`.
+    #[must_use]
+    pub fn file_hashes(&self) -> BTreeMap<String, u64> {
+        let mut res = BTreeMap::new();
+        for data in &self.data {
+            for file in &data.files {
+                let mut hasher = DefaultHasher::new();
+                hasher.write(serde_json::to_string(file).unwrap().as_bytes());
+                for func in data.functions.iter().flatten() {
+                    if func.filenames.contains(&file.filename) {
+                        hasher.write(serde_json::to_string(func).unwrap().as_bytes());
+                    }
+                }
+                res.insert(file.filename.clone(), hasher.finish());
+            }
+        }
+        res
+    }
+
+    /// Sorts files by filename, and functions by filename and position, so
+    /// that the output does not depend on the order of the object files.
+    ///
+    /// Returns `true` if the order was changed.
+    pub fn sort(&mut self) -> bool {
+        let mut changed = false;
+        for data in &mut self.data {
+            changed |= sort_by(&mut data.files, |a, b| a.filename.cmp(&b.filename));
+            if let Some(functions) = &mut data.functions {
+                changed |= sort_by(functions, |a, b| {
+                    let start = |f: &Function| f.regions.first().map(|r| (r.0, r.1));
+                    a.filenames
+                        .cmp(&b.filenames)
+                        .then_with(|| start(a).cmp(&start(b)))
+                        .then_with(|| a.name.cmp(&b.name))
+                });
+            }
+        }
+        changed
+    }
+
+    /// Removes the given lines from the coverage data and updates summaries accordingly.
+    ///
+    /// Regions and functions are removed only if all of their lines are excluded.
+    /// Branches are removed if they start on an excluded line.
+    /// This has no effect on the summary-only data.
+    #[allow(clippy::missing_panics_doc)] // `keep` has the same length as `functions`.
+    pub fn exclude_lines(&mut self, excluded: &ExcludedLines) {
+        if excluded.is_empty() {
+            return;
+        }
+        for data in &mut self.data {
+            let functions = match &mut data.functions {
+                Some(functions) => functions,
+                None => continue,
+            };
+
+            let mut removed: BTreeMap<String, Removed> = BTreeMap::new();
+            let mut keep = Vec::with_capacity(functions.len());
+            for function in functions.iter_mut() {
+                let function_covered = function.count > 0;
+                let filenames = &function.filenames;
+                function.branches.retain(|branch| {
+                    let file = branch.get(6).and_then(serde_json::Value::as_u64);
+                    let file = file.and_then(|i| filenames.get(usize::try_from(i).ok()?));
+                    !matches!(
+                        (file.and_then(|file| excluded.get(file)), branch_line(branch)),
+                        (Some(lines), Some(line)) if lines.contains(&line)
+                    )
+                });
+                let mut first = true;
+                let mut entry_removed = false;
+                function.regions.retain(|region| {
+                    let is_entry = mem::replace(&mut first, false);
+                    let filename =
+                        match usize::try_from(region.5).ok().and_then(|i| filenames.get(i)) {
+                            Some(filename) => filename,
+                            None => return true,
+                        };
+                    let lines = match excluded.get(filename) {
+                        Some(lines) => lines,
+                        None => return true,
+                    };
+                    // LineStart, ColumnStart, LineEnd, ColumnEnd, ExecutionCount, FileID, ExpandedFileID, Kind
+                    let covered = region.4 > 0;
+                    let removed = removed.entry(filename.clone()).or_default();
+                    for line in region.0..=region.2 {
+                        if lines.contains(&line) {
+                            *removed.lines.entry(line).or_insert(false) |= covered;
+                        }
+                    }
+                    if !(region.0..=region.2).all(|line| lines.contains(&line)) {
+                        return true;
+                    }
+                    // CodeRegion
+                    if region.7 == 0 {
+                        let location = (region.0, region.1, region.2, region.3);
+                        *removed.regions.entry(location).or_insert(false) |= covered;
+                    }
+                    if is_entry {
+                        entry_removed = true;
+                        let location = (region.0, region.1);
+                        *removed.functions.entry(location).or_insert(false) |= function_covered;
+                        removed.instantiations.0 += 1;
+                        removed.instantiations.1 += u64::from(function_covered);
+                    }
+                    false
+                });
+                keep.push(!entry_removed);
+            }
+            let mut keep = keep.into_iter();
+            functions.retain(|_| keep.next().unwrap());
+
+            if removed.is_empty() {
+                continue;
+            }
+            for file in &mut data.files {
+                if let (Some(lines), Some(segments)) =
+                    (excluded.get(&file.filename), &mut file.segments)
+                {
+                    segments.retain(|segment| !lines.contains(&segment.0));
+                }
+                if let (Some(lines), Some(branches)) =
+                    (excluded.get(&file.filename), &mut file.branches)
+                {
+                    let (mut count, mut covered) = (0, 0);
+                    branches.retain(|branch| {
+                        if !branch_line(branch).map_or(false, |line| lines.contains(&line)) {
+                            return true;
+                        }
+                        // LineStart, ColumnStart, LineEnd, ColumnEnd, ExecutionCount, FalseExecutionCount, ...
+                        count += 2;
+                        for i in [4, 5] {
+                            covered += u64::from(branch[i].as_u64().map_or(false, |n| n > 0));
+                        }
+                        false
+                    });
+                    file.summary.branches.exclude(count, covered);
+                }
+                if let Some(removed) = removed.get(&file.filename) {
+                    let Removed { lines, regions, functions, instantiations } = removed;
+                    let summary = &mut file.summary;
+                    summary.lines.exclude(lines.len() as u64, count_covered(lines));
+                    summary.regions.exclude(regions.len() as u64, count_covered(regions));
+                    summary.functions.exclude(functions.len() as u64, count_covered(functions));
+                    summary.instantiations.exclude(instantiations.0, instantiations.1);
+                }
+            }
+            data.update_totals();
+        }
+    }
+
+    /// Removes everything except summaries, like `llvm-cov export -summary-only` does.
+    pub fn summary_only(&mut self) {
+        for data in &mut self.data {
+            data.functions = None;
+            for file in &mut data.files {
+                file.branches = None;
+                file.expansions = None;
+                file.segments = None;
+                // e.g., `mcdc_records`
+                file.extra.clear();
+            }
+        }
+    }
+
+    /// Gets the minimal lines coverage of all files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals are missing in the coverage data.
+    pub fn get_lines_percent(&self) -> Result<f64> {
+        let mut count = 0_f64;
+        let mut covered = 0_f64;
+        for data in &self.data {
+            let totals = &data.totals.as_object().context("totals is not an object")?;
+            let lines = &totals["lines"].as_object().context("no lines")?;
+            count += lines["count"].as_f64().context("no count")?;
+            covered += lines["covered"].as_f64().context("no covered")?;
+        }
+
+        if count == 0_f64 {
+            return Ok(0_f64);
+        }
+
+        Ok(covered * 100_f64 / count)
+    }
+
+    /// Gets the number of items and covered items of the given kind
+    /// (`"lines"`, `"functions"`, or `"regions"`) of all files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals of the kind are missing in the coverage data.
+    pub fn get_totals(&self, kind: &str) -> Result<(u64, u64)> {
+        let mut count = 0_u64;
+        let mut covered = 0_u64;
+        for data in &self.data {
+            let totals = &data.totals.as_object().context("totals is not an object")?;
+            let totals = totals
+                .get(kind)
+                .and_then(serde_json::Value::as_object)
+                .with_context(|| format!("no {}", kind))?;
+            count += totals["count"].as_u64().context("no count")?;
+            covered += totals["covered"].as_u64().context("no covered")?;
+        }
+        Ok((count, covered))
+    }
+
+    /// Gets the number of lines and covered lines of each file.
+    pub fn get_lines_by_file(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        self.data.iter().flat_map(|data| {
+            data.files.iter().map(|file| {
+                (file.filename.as_str(), file.summary.lines.count, file.summary.lines.covered)
+            })
+        })
+    }
+
+    /// Gets the number of items and covered items of the given kind
+    /// (`"lines"`, `"functions"`, `"regions"`, or `"branches"`) of each file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kind is unknown.
+    pub fn get_totals_by_file(&self, kind: &str) -> Result<Vec<(&str, u64, u64)>> {
+        let counts: fn(&Summary) -> &CoverageCounts = match kind {
+            "lines" => |summary| &summary.lines,
+            "functions" => |summary| &summary.functions,
+            "regions" => |summary| &summary.regions,
+            "branches" => |summary| &summary.branches,
+            _ => bail!("unknown kind of coverage: {}", kind),
+        };
+        Ok(self
+            .data
+            .iter()
+            .flat_map(|data| {
+                data.files.iter().map(|file| {
+                    let counts = counts(&file.summary);
+                    (file.filename.as_str(), counts.count, counts.covered)
+                })
+            })
+            .collect())
+    }
+
+    /// Gets the region coverage of each function, sorted by the number of
+    /// uncovered regions (most first).
+    ///
+    /// Generic functions have an entry for each instantiation in the coverage
+    /// data. They are merged into one, and a region is considered covered if it
+    /// is covered in any instantiation.
+    #[must_use]
+    pub fn get_functions(&self) -> Vec<FunctionCoverage> {
+        // Kind of regions that are counted in the region coverage.
+        const CODE_REGION: u64 = 0;
+
+        // (filename, line, column) -> (name, end line, execution count, region location -> covered)
+        #[allow(clippy::type_complexity)]
+        let mut functions: BTreeMap<
+            (&str, u64, u64),
+            (&str, u64, u64, BTreeMap<(u64, u64, u64, u64), bool>),
+        > = BTreeMap::new();
+        for function in self.data.iter().flat_map(|data| data.functions.iter().flatten()) {
+            let (filename, first) = match (function.filenames.first(), function.regions.first()) {
+                (Some(filename), Some(first)) => (filename, first),
+                _ => continue,
+            };
+            let (_, end_line, execution_count, regions) = functions
+                .entry((filename, first.0, first.1))
+                .or_insert_with(|| (&function.name, 0, 0, BTreeMap::new()));
+            *execution_count += function.count;
+            for region in &function.regions {
+                if region.5 == 0 {
+                    *end_line = (*end_line).max(region.2);
+                }
+                if region.7 == CODE_REGION && region.5 == 0 {
+                    *regions.entry((region.0, region.1, region.2, region.3)).or_insert(false) |=
+                        region.4 > 0;
+                }
+            }
+        }
+
+        let mut functions: Vec<_> = functions
+            .into_iter()
+            .map(|((filename, line, _), (name, end_line, execution_count, regions))| {
+                FunctionCoverage {
+                    name: format!("{:#}", rustc_demangle::demangle(name)),
+                    filename: filename.to_owned(),
+                    line,
+                    end_line,
+                    execution_count,
+                    count: regions.len() as u64,
+                    covered: regions.values().filter(|&&covered| covered).count() as u64,
+                }
+            })
+            .collect();
+        // Stable sort, so functions with the same number of uncovered regions
+        // are in the order of their location.
+        functions.sort_by_key(|f| std::cmp::Reverse(f.count - f.covered));
+        functions
+    }
+
+    /// Gets the list of uncovered lines of all files.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ignore_filename_regex` is not a valid regex.
+    #[must_use]
+    pub fn get_uncovered_lines(&self, ignore_filename_regex: &Option<String>) -> UncoveredLines {
+        let mut uncovered_files: UncoveredLines = BTreeMap::new();
+        let mut covered_files: UncoveredLines = BTreeMap::new();
+        let mut re: Option<regex::Regex> = None;
+        if let Some(ref ignore_filename_regex) = *ignore_filename_regex {
+            re = Some(regex::Regex::new(ignore_filename_regex).unwrap());
+        }
+        for data in &self.data {
+            if let Some(ref functions) = data.functions {
+                // Iterate over all functions inside the coverage data.
+                for function in functions {
+                    if function.filenames.is_empty() {
+                        continue;
+                    }
+                    let file_name = &function.filenames[0];
+                    if let Some(ref re) = re {
+                        if re.is_match(file_name) {
+                            continue;
+                        }
+                    }
+                    let mut lines: BTreeMap<u64, u64> = BTreeMap::new();
+                    // Iterate over all possible regions inside a function:
+                    for region in &function.regions {
+                        // LineStart, ColumnStart, LineEnd, ColumnEnd, ExecutionCount, FileID, ExpandedFileID, Kind
+                        let line_start = region.0;
+                        let line_end = region.2;
+                        let exec_count = region.4;
+                        // Remember the execution count for each line of that region:
+                        for line in line_start..=line_end {
+                            *lines.entry(line).or_insert(0) += exec_count;
+                        }
+                    }
+
+                    let mut uncovered_lines: Vec<u64> = lines
+                        .iter()
+                        .filter(|(_line, exec_count)| **exec_count == 0)
+                        .map(|(line, _exec_count)| *line)
+                        .collect();
+                    let mut covered_lines: Vec<u64> = lines
+                        .iter()
+                        .filter(|(_line, exec_count)| **exec_count > 0)
+                        .map(|(line, _exec_count)| *line)
+                        .collect();
+                    if !uncovered_lines.is_empty() {
+                        uncovered_files
+                            .entry(file_name.clone())
+                            .or_insert_with(Vec::new)
+                            .append(&mut uncovered_lines);
+                    }
+                    if !covered_lines.is_empty() {
+                        covered_files
+                            .entry(file_name.clone())
+                            .or_insert_with(Vec::new)
+                            .append(&mut covered_lines);
+                    }
+                }
+            }
+        }
+
+        for uncovered_file in &mut uncovered_files {
+            // Check if a line is both covered and non-covered. It's covered in this case.
+            let file_name = uncovered_file.0;
+            let uncovered_lines = uncovered_file.1;
+            if let Some(covered_lines) = covered_files.get(file_name) {
+                uncovered_lines.retain(|&x| !covered_lines.contains(&x));
+            }
+
+            // Remove duplicates.
+            uncovered_lines.sort_unstable();
+            uncovered_lines.dedup();
+        }
+
+        // Remove empty keys.
+        uncovered_files.retain(|_, v| !v.is_empty());
+
+        uncovered_files
+    }
+
+    /// Gets the regions of each function instantiation, sorted by location.
+    #[must_use]
+    pub fn get_regions(&self) -> Vec<RegionCoverage> {
+        let mut regions = vec![];
+        for function in self.data.iter().flat_map(|data| data.functions.iter().flatten()) {
+            let name = format!("{:#}", rustc_demangle::demangle(&function.name));
+            for region in &function.regions {
+                // LineStart, ColumnStart, LineEnd, ColumnEnd, ExecutionCount, FileID, ExpandedFileID, Kind
+                let filename =
+                    match usize::try_from(region.5).ok().and_then(|i| function.filenames.get(i)) {
+                        Some(filename) => filename,
+                        None => continue,
+                    };
+                // https://github.com/llvm/llvm-project/blob/llvmorg-17.0.0/llvm/include/llvm/ProfileData/Coverage/CoverageMapping.h#L232-L256
+                let kind = match region.7 {
+                    0 => "code",
+                    1 => "expansion",
+                    2 => "skipped",
+                    3 => "gap",
+                    4 => "branch",
+                    _ => "unknown",
+                };
+                regions.push(RegionCoverage {
+                    function: name.clone(),
+                    filename: filename.clone(),
+                    line_start: region.0,
+                    column_start: region.1,
+                    line_end: region.2,
+                    column_end: region.3,
+                    execution_count: region.4,
+                    kind,
+                });
+            }
+        }
+        regions.sort_by(|a, b| {
+            (&a.filename, a.line_start, a.column_start, &a.function).cmp(&(
+                &b.filename,
+                b.line_start,
+                b.column_start,
+                &b.function,
+            ))
+        });
+        regions
+    }
+
+    /// Gets whether each line of each file is covered.
+    ///
+    /// A line is covered if any region that contains it is executed.
+    #[must_use]
+    pub fn get_line_coverage(&self) -> BTreeMap<String, BTreeMap<u64, bool>> {
+        let mut files: BTreeMap<String, BTreeMap<u64, bool>> = BTreeMap::new();
+        for data in &self.data {
+            for function in data.functions.iter().flatten() {
+                let file_name = match function.filenames.first() {
+                    Some(file_name) => file_name,
+                    None => continue,
+                };
+                let lines = files.entry(file_name.clone()).or_default();
+                for region in &function.regions {
+                    // LineStart, ColumnStart, LineEnd, ColumnEnd, ExecutionCount, FileID, ExpandedFileID, Kind
+                    for line in region.0..=region.2 {
+                        *lines.entry(line).or_default() |= region.4 > 0;
+                    }
+                }
+            }
+        }
+        files
+    }
+
+    /// Gets the number of uncovered functions of all files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals are missing in the coverage data.
+    pub fn count_uncovered_functions(&self) -> Result<u64> {
+        let mut count = 0_u64;
+        let mut covered = 0_u64;
+        for data in &self.data {
+            let totals = &data.totals.as_object().context("totals is not an object")?;
+            let functions = &totals["functions"].as_object().context("no functions")?;
+            count += functions["count"].as_u64().context("no count")?;
+            covered += functions["covered"].as_u64().context("no covered")?;
+        }
+        Ok(count.saturating_sub(covered))
+    }
+
+    /// Gets the number of uncovered lines of all files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals are missing in the coverage data.
+    pub fn count_uncovered_lines(&self) -> Result<u64> {
+        let mut count = 0_u64;
+        let mut covered = 0_u64;
+        for data in &self.data {
+            let totals = &data.totals.as_object().context("totals is not an object")?;
+            let lines = &totals["lines"].as_object().context("no lines")?;
+            count += lines["count"].as_u64().context("no count")?;
+            covered += lines["covered"].as_u64().context("no covered")?;
+        }
+        Ok(count.saturating_sub(covered))
+    }
+
+    /// Gets the number of uncovered regions of all files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals are missing in the coverage data.
+    pub fn count_uncovered_regions(&self) -> Result<u64> {
+        let mut count = 0_u64;
+        let mut covered = 0_u64;
+        for data in &self.data {
+            let totals = &data.totals.as_object().context("totals is not an object")?;
+            let regions = &totals["regions"].as_object().context("no regions")?;
+            count += regions["count"].as_u64().context("no count")?;
+            covered += regions["covered"].as_u64().context("no covered")?;
+        }
+        Ok(count.saturating_sub(covered))
+    }
+}
+
+/// Lines, regions, and functions removed by [`LlvmCovJsonExport::exclude_lines`].
+#[derive(Default)]
+struct Removed {
+    /// Line -> whether the line was covered
+    lines: BTreeMap<u64, bool>,
+    /// Location of the region -> whether the region was covered
+    regions: BTreeMap<(u64, u64, u64, u64), bool>,
+    /// Location of the function -> whether the function was covered
+    functions: BTreeMap<(u64, u64), bool>,
+    /// (instantiations, covered instantiations)
+    instantiations: (u64, u64),
+}
+
+/// Returns the start line of the branch.
+fn branch_line(branch: &serde_json::Value) -> Option<u64> {
+    branch.get(0)?.as_u64()
+}
+
+fn count_covered<K>(map: &BTreeMap<K, bool>) -> u64 {
+    map.values().filter(|&&covered| covered).count() as u64
+}
+
+/// Sorts the slice if it is not sorted. Returns `true` if the order was changed.
+fn sort_by<T>(v: &mut [T], mut cmp: impl FnMut(&T, &T) -> Ordering) -> bool {
+    if v.windows(2).all(|w| cmp(&w[0], &w[1]) != Ordering::Greater) {
+        return false;
+    }
+    v.sort_by(cmp);
+    true
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn percent(count: u64, covered: u64) -> f64 {
+    if count == 0 {
+        0_f64
+    } else {
+        covered as f64 * 100_f64 / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use fs_err as fs;
+
+    use super::*;
+
+    #[test]
+    fn parse_llvm_cov_json() {
+        let files: Vec<_> = glob::glob(&format!(
+            "{}/tests/fixtures/coverage-reports/**/*.json",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+        assert!(!files.is_empty());
+
+        for file in files {
+            let s = fs::read_to_string(file).unwrap();
+            let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+            assert_eq!(json.type_, "llvm.coverage.json.export");
+            assert!(json.version.starts_with("2.0."));
+            // All fields of these exports are known.
+            assert!(json.extra.is_empty());
+            for data in &json.data {
+                assert!(data.extra.is_empty());
+                for file in &data.files {
+                    assert!(file.extra.is_empty() && file.summary.extra.is_empty());
+                }
+                for function in data.functions.iter().flatten() {
+                    assert!(function.extra.is_empty());
+                }
+            }
+            serde_json::to_string(&json).unwrap();
+        }
+    }
+
+    #[test]
+    fn round_trip_unknown_fields() {
+        // Export of LLVM 19 with MC/DC coverage, whose files and functions are not sorted.
+        let file = format!("{}/tests/fixtures/mcdc.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let input: serde_json::Value = serde_json::from_str(&s).unwrap();
+        let mut json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        assert!(json.sort());
+        let output = serde_json::to_value(&json).unwrap();
+        let (input, output) = (&input["data"][0], &output["data"][0]);
+        assert!(input["files"][0]["mcdc_records"].is_array());
+        assert!(input["functions"][0]["mcdc_records"].is_array());
+        assert!(input["totals"]["mcdc"].is_object());
+        assert_eq!(output["files"][0], input["files"][1]);
+        assert_eq!(output["files"][1], input["files"][0]);
+        assert_eq!(output["functions"][0], input["functions"][1]);
+        assert_eq!(output["functions"][1], input["functions"][0]);
+        assert_eq!(output["totals"], input["totals"]);
+
+        json.summary_only();
+        let output = serde_json::to_value(&json).unwrap();
+        assert_eq!(output["data"][0]["files"][0]["summary"], input["files"][1]["summary"]);
+        assert!(output["data"][0]["files"][0].get("mcdc_records").is_none());
+    }
+
+    #[test]
+    fn test_get_lines_percent() {
+        // There are 5 different percentages, make sure we pick the correct one.
+        let file = format!(
+            "{}/tests/fixtures/coverage-reports/no_coverage/no_coverage.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        let percent = json.get_lines_percent().unwrap();
+
+        let error_margin = f64::EPSILON;
+        assert!((percent - 69.565_217_391_304_34).abs() < error_margin);
+
+        let (count, covered) = json
+            .get_lines_by_file()
+            .fold((0, 0), |(count, covered), (_, c, v)| (count + c, covered + v));
+        assert!((super::percent(count, covered) - percent).abs() < error_margin);
+        assert_eq!(json.get_totals("lines").unwrap(), (count, covered));
+    }
+
+    #[test]
+    fn test_count_uncovered() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+        let cases = &[
+            // (path, uncovered_functions, uncovered_lines, uncovered_regions)
+            ("tests/fixtures/coverage-reports/no_coverage/no_coverage.json", 0, 7, 6),
+            ("tests/fixtures/coverage-reports/no_test/no_test.json", 1, 7, 6),
+        ];
+
+        for &(file, uncovered_functions, uncovered_lines, uncovered_regions) in cases {
+            let file = manifest_dir.join(file);
+            let s = fs::read_to_string(file).unwrap();
+            let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+            assert_eq!(json.count_uncovered_functions().unwrap(), uncovered_functions);
+            assert_eq!(json.count_uncovered_lines().unwrap(), uncovered_lines);
+            assert_eq!(json.count_uncovered_regions().unwrap(), uncovered_regions);
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        assert_eq!(json.version(), "2.0.1");
+        let data = &json.data()[0];
+        assert_eq!(data.files[0].filename, "src/lib.rs");
+        assert_eq!(data.files[0].summary.regions.count, 7);
+        let functions = data.functions.as_ref().unwrap();
+        assert_eq!(functions[0].regions[0].0, 7);
+    }
+
+    #[test]
+    fn test_get_functions() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        let functions = json.get_functions();
+        assert_eq!(functions[0], FunctionCoverage {
+            name: "t::bar".to_owned(),
+            filename: "src/lib.rs".to_owned(),
+            line: 7,
+            end_line: 9,
+            execution_count: 0,
+            count: 3,
+            covered: 0,
+        });
+        assert!(functions[1..].iter().all(|f| f.count == f.covered));
+        let (count, covered) = functions
+            .iter()
+            .fold((0, 0), |(count, covered), f| (count + f.count, covered + f.covered));
+        assert_eq!((count, covered), json.get_totals("regions").unwrap());
+    }
+
+    #[test]
+    fn test_get_regions() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        let regions = json.get_regions();
+        let bar: Vec<_> = regions.iter().filter(|r| r.function == "t::bar").collect();
+        assert_eq!(bar.len(), 3);
+        assert_eq!(bar[0], &RegionCoverage {
+            function: "t::bar".to_owned(),
+            filename: "src/lib.rs".to_owned(),
+            line_start: 7,
+            column_start: 1,
+            line_end: 8,
+            column_end: 13,
+            execution_count: 0,
+            kind: "code",
+        });
+        let code = regions.iter().filter(|r| r.kind == "code").count() as u64;
+        assert_eq!(code, json.get_totals("regions").unwrap().0);
+    }
+
+    #[test]
+    fn test_get_totals_by_file() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        for kind in ["lines", "functions", "regions"] {
+            let (count, covered) = json
+                .get_totals_by_file(kind)
+                .unwrap()
+                .iter()
+                .fold((0, 0), |(count, covered), f| (count + f.1, covered + f.2));
+            assert_eq!((count, covered), json.get_totals(kind).unwrap());
+        }
+        assert!(json.get_totals_by_file("unknown").is_err());
+        assert!(json.get_totals("unknown").is_err());
+    }
+
+    #[test]
+    fn test_get_uncovered_lines() {
+        // Given a coverage report which includes function regions:
+        // There are 5 different percentages, make sure we pick the correct one.
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        // When finding uncovered lines in that report:
+        let ignore_filename_regex = None;
+        let uncovered_lines = json.get_uncovered_lines(&ignore_filename_regex);
+
+        // Then make sure the file / line data matches the `llvm-cov report` output:
+        let expected: UncoveredLines =
+            vec![("src/lib.rs".to_string(), vec![7, 8, 9])].into_iter().collect();
+        assert_eq!(uncovered_lines, expected);
+    }
+
+    #[test]
+    fn test_from_reader_ignore_filename_regex() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+
+        let json = LlvmCovJsonExport::from_reader(s.as_bytes(), None).unwrap();
+        assert_eq!(json.data[0].functions.as_ref().unwrap().len(), 5);
+        let json = LlvmCovJsonExport::from_reader(s.as_bytes(), Some("main\\.rs$")).unwrap();
+        assert_eq!(json.data[0].functions.as_ref().unwrap().len(), 5);
+        let json = LlvmCovJsonExport::from_reader(s.as_bytes(), Some("lib\\.rs$")).unwrap();
+        assert_eq!(json.data[0].functions.as_ref().unwrap().len(), 0);
+        // The regex is only applied while parsing.
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        assert_eq!(json.data[0].functions.as_ref().unwrap().len(), 5);
+
+        // Fields not covered by the types are kept, as with `Deserialize`.
+        for file in ["show-missing-lines.json", "mcdc.json"] {
+            let file = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let s = fs::read_to_string(file).unwrap();
+            let json = LlvmCovJsonExport::from_reader(s.as_bytes(), None).unwrap();
+            assert_eq!(
+                serde_json::to_value(&json).unwrap(),
+                serde_json::to_value(serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap())
+                    .unwrap()
+            );
+        }
+        for invalid in [
+            "{}",
+            r#"{ "type": "", "version": "" }"#,
+            r#"{ "data": [], "data": [], "type": "", "version": "" }"#,
+            r#"{ "data": [{ "totals": {} }], "type": "", "version": "" }"#,
+            r#"{ "data": [], "type": "", "version": "" } {}"#,
+        ] {
+            assert!(
+                LlvmCovJsonExport::from_reader(invalid.as_bytes(), None).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_exclude_lines() {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        let s = fs::read_to_string(file).unwrap();
+
+        // Exclude the whole `bar` function.
+        let mut json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        let excluded: ExcludedLines =
+            vec![("src/lib.rs".to_string(), (7..=9).collect())].into_iter().collect();
+        json.exclude_lines(&excluded);
+        assert_eq!(json.count_uncovered_functions().unwrap(), 0);
+        assert_eq!(json.count_uncovered_lines().unwrap(), 0);
+        assert_eq!(json.count_uncovered_regions().unwrap(), 0);
+        assert_eq!(json.get_uncovered_lines(&None), UncoveredLines::new());
+        assert!((json.get_lines_percent().unwrap() - 100_f64).abs() < f64::EPSILON);
+
+        // Exclude only a part of the `bar` function.
+        let mut json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        let excluded: ExcludedLines =
+            vec![("src/lib.rs".to_string(), [8].iter().copied().collect())].into_iter().collect();
+        json.exclude_lines(&excluded);
+        assert_eq!(json.count_uncovered_functions().unwrap(), 1);
+        assert_eq!(json.count_uncovered_lines().unwrap(), 2);
+        assert_eq!(json.count_uncovered_regions().unwrap(), 2);
+
+        // Branches on the excluded lines.
+        let file = format!("{}/tests/fixtures/mcdc.json", env!("CARGO_MANIFEST_DIR"));
+        let mut json =
+            serde_json::from_str::<LlvmCovJsonExport>(&fs::read_to_string(file).unwrap()).unwrap();
+        assert_eq!(json.get_totals("branches").unwrap(), (2, 1));
+        let excluded: ExcludedLines = vec![
+            ("src/a.rs".to_string(), [2].iter().copied().collect()),
+            ("src/b.rs".to_string(), [2].iter().copied().collect()),
+        ]
+        .into_iter()
+        .collect();
+        json.exclude_lines(&excluded);
+        assert_eq!(json.get_totals("branches").unwrap(), (0, 0));
+        assert!(json.data[0].files.iter().all(|f| f.branches.as_ref().unwrap().is_empty()));
+        assert!(json.data[0].functions.iter().flatten().all(|f| f.branches.is_empty()));
+    }
+
+    #[test]
+    /// This was a case when counting line coverage based on the segments in files lead to
+    /// incorrect results but doing it based on regions inside functions (the way `llvm-cov
+    /// report`) leads to complete line coverage.
+    fn test_get_uncovered_lines_complete() {
+        let file = format!(
+            "{}/tests/fixtures/show-missing-lines-complete.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        let ignore_filename_regex = None;
+        let uncovered_lines = json.get_uncovered_lines(&ignore_filename_regex);
+
+        let expected: UncoveredLines = UncoveredLines::new();
+        assert_eq!(uncovered_lines, expected);
+    }
+
+    #[test]
+    fn test_get_uncovered_lines_multi_missing() {
+        // Given a coverage report which includes a line with multiple functions via macros + two
+        // other uncovered lines:
+        let file = format!(
+            "{}/tests/fixtures/show-missing-lines-multi-missing.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let s = fs::read_to_string(file).unwrap();
+        let json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+
+        // When finding uncovered lines in that report:
+        let ignore_filename_regex = None;
+        let uncovered_lines = json.get_uncovered_lines(&ignore_filename_regex);
+
+        // Then make sure the file / line data matches the `llvm-cov report` output:
+        let expected: UncoveredLines =
+            vec![("src/lib.rs".to_string(), vec![15, 17])].into_iter().collect();
+        // This was just '11', i.e. there were two problems:
+        // 1) line 11 has a serde macro which expands to multiple functions; some of those were
+        //    covered, which should be presented as a "covered" 11th line.
+        // 2) only the last function with missing lines were reported, so 15 and 17 was missing.
+        assert_eq!(uncovered_lines, expected);
+    }
+}
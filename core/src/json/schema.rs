@@ -0,0 +1,311 @@
+//! Types that represent the output of `llvm-cov export -format=text`.
+//!
+//! These are public only if the `schema` feature is enabled. Fields that are
+//! added in newer versions of LLVM may be added to these types, so they are
+//! marked as `#[non_exhaustive]`. Until then, such fields (e.g., MC/DC data
+//! added in LLVM 18) are kept in the `extra` field of each object, so that they
+//! are not lost when the data is serialized again.
+
+use std::fmt;
+
+use regex::Regex;
+use serde::{
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+/// Json representation of one `CoverageMapping`
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Export {
+    /// List of objects describing coverage for files
+    pub files: Vec<File>,
+    /// List of objects describing coverage for functions
+    ///
+    /// This is None if report is summary-only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub functions: Option<Vec<Function>>,
+    pub totals: serde_json::Value,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Deserializes the list of [`Export`]s, skipping functions in files that match
+/// `ignore_filename_regex`.
+#[derive(Clone, Copy)]
+pub(crate) struct DataSeed<'a> {
+    pub(crate) ignore_filename_regex: Option<&'a Regex>,
+}
+
+impl<'de> DeserializeSeed<'de> for DataSeed<'_> {
+    type Value = Vec<Export>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DataSeed<'_> {
+    type Value = Vec<Export>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of export objects")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut data = vec![];
+        while let Some(export) = seq.next_element_seed(ExportSeed(self))? {
+            data.push(export);
+        }
+        Ok(data)
+    }
+}
+
+struct ExportSeed<'a>(DataSeed<'a>);
+
+impl<'de> DeserializeSeed<'de> for ExportSeed<'_> {
+    type Value = Export;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ExportSeed<'_> {
+    type Value = Export;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an export object")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut files = None;
+        let mut functions = None;
+        let mut totals = None;
+        let mut extra = serde_json::Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "files" if files.is_none() => files = Some(map.next_value()?),
+                "functions" if functions.is_none() => {
+                    functions = Some(map.next_value_seed(FunctionsSeed(self.0))?);
+                }
+                "totals" if totals.is_none() => totals = Some(map.next_value()?),
+                "files" => return Err(de::Error::duplicate_field("files")),
+                "functions" => return Err(de::Error::duplicate_field("functions")),
+                "totals" => return Err(de::Error::duplicate_field("totals")),
+                _ => {
+                    extra.insert(key, map.next_value()?);
+                }
+            }
+        }
+        Ok(Export {
+            files: files.ok_or_else(|| de::Error::missing_field("files"))?,
+            functions,
+            totals: totals.ok_or_else(|| de::Error::missing_field("totals"))?,
+            extra,
+        })
+    }
+}
+
+struct FunctionsSeed<'a>(DataSeed<'a>);
+
+impl<'de> DeserializeSeed<'de> for FunctionsSeed<'_> {
+    type Value = Vec<Function>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for FunctionsSeed<'_> {
+    type Value = Vec<Function>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of functions")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let re = self.0.ignore_filename_regex;
+        let mut functions = vec![];
+        while let Some(function) = seq.next_element::<Function>()? {
+            let ignored =
+                re.map_or(false, |re| function.filenames.first().map_or(false, |f| re.is_match(f)));
+            if !ignored {
+                functions.push(function);
+            }
+        }
+        Ok(functions)
+    }
+}
+
+impl Export {
+    /// Recomputes the totals from the summaries of files.
+    pub(crate) fn update_totals(&mut self) {
+        fn set(totals: &mut serde_json::Value, count: u64, covered: u64) {
+            totals["count"] = count.into();
+            totals["covered"] = covered.into();
+            if totals.get("notcovered").is_some() {
+                totals["notcovered"] = count.saturating_sub(covered).into();
+            }
+            totals["percent"] = super::percent(count, covered).into();
+        }
+
+        let mut branches = (0, 0);
+        let mut functions = (0, 0);
+        let mut instantiations = (0, 0);
+        let mut lines = (0, 0);
+        let mut regions = (0, 0);
+        for file in &self.files {
+            let summary = &file.summary;
+            branches.0 += summary.branches.count;
+            branches.1 += summary.branches.covered;
+            functions.0 += summary.functions.count;
+            functions.1 += summary.functions.covered;
+            instantiations.0 += summary.instantiations.count;
+            instantiations.1 += summary.instantiations.covered;
+            lines.0 += summary.lines.count;
+            lines.1 += summary.lines.covered;
+            regions.0 += summary.regions.count;
+            regions.1 += summary.regions.covered;
+        }
+        set(&mut self.totals["branches"], branches.0, branches.1);
+        set(&mut self.totals["functions"], functions.0, functions.1);
+        set(&mut self.totals["instantiations"], instantiations.0, instantiations.1);
+        set(&mut self.totals["lines"], lines.0, lines.1);
+        set(&mut self.totals["regions"], regions.0, regions.1);
+    }
+}
+
+/// Coverage for a single file
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct File {
+    /// List of Branches in the file
+    ///
+    /// This is None if report is summary-only.
+    // https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/tools/llvm-cov/CoverageExporterJson.cpp#L93
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branches: Option<Vec<serde_json::Value>>,
+    /// List of expansion records
+    ///
+    /// This is None if report is summary-only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expansions: Option<Vec<serde_json::Value>>,
+    pub filename: String,
+    /// List of Segments contained in the file
+    ///
+    /// This is None if report is summary-only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<Segment>>,
+    /// Object summarizing the coverage for this file
+    pub summary: Summary,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Describes a segment of the file with a counter
+// https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/tools/llvm-cov/CoverageExporterJson.cpp#L80
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+#[non_exhaustive]
+pub struct Segment(
+    /* Line */ pub u64,
+    /* Col */ pub u64,
+    /* Count */ pub u64,
+    /* HasCount */ pub bool,
+    /* IsRegionEntry */ pub bool,
+    /* IsGapRegion */ pub bool,
+);
+
+// https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/tools/llvm-cov/CoverageExporterJson.cpp#L259
+/// Coverage info for a single function
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Function {
+    pub branches: Vec<serde_json::Value>,
+    pub count: u64,
+    /// List of filenames that the function relates to
+    pub filenames: Vec<String>,
+    pub name: String,
+    pub regions: Vec<Region>,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+#[non_exhaustive]
+pub struct Region(
+    /* LineStart */ pub u64,
+    /* ColumnStart */ pub u64,
+    /* LineEnd */ pub u64,
+    /* ColumnEnd */ pub u64,
+    /* ExecutionCount */ pub u64,
+    /* FileID */ pub u64,
+    /* ExpandedFileID */ pub u64,
+    /* Kind */ pub u64,
+);
+
+/// Object summarizing the coverage for this file
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Summary {
+    /// Object summarizing branch coverage
+    pub branches: CoverageCounts,
+    /// Object summarizing function coverage
+    pub functions: CoverageCounts,
+    pub instantiations: CoverageCounts,
+    /// Object summarizing line coverage
+    pub lines: CoverageCounts,
+    /// Object summarizing region coverage
+    pub regions: CoverageCounts,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CoverageCounts {
+    pub count: u64,
+    pub covered: u64,
+    // Currently only branches and regions has this field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notcovered: Option<u64>,
+    pub percent: f64,
+    /// Fields not covered by this type.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CoverageCounts {
+    pub(crate) fn exclude(&mut self, count: u64, covered: u64) {
+        self.count = self.count.saturating_sub(count);
+        self.covered = self.covered.saturating_sub(covered);
+        if let Some(notcovered) = &mut self.notcovered {
+            *notcovered = self.count.saturating_sub(self.covered);
+        }
+        self.percent = super::percent(self.count, self.covered);
+    }
+}
@@ -0,0 +1,44 @@
+//! Coverage data model of [cargo-llvm-cov].
+//!
+//! This crate parses the output of `llvm-cov export -format=text` (which is
+//! also written by `cargo llvm-cov --json`), and provides the summaries and
+//! threshold checks that cargo-llvm-cov uses, so that other tools (e.g., xtask
+//! scripts) can work with coverage data without parsing the human-readable
+//! output of cargo-llvm-cov.
+//!
+//! ```no_run
+//! use cargo_llvm_cov_core::{json::LlvmCovJsonExport, thresholds::Thresholds};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let file = std::fs::File::open("coverage.json")?;
+//! let json = LlvmCovJsonExport::from_reader(file, None)?;
+//! let (count, covered) = json.get_totals("lines")?;
+//! println!("{}/{} lines covered", covered, count);
+//!
+//! let thresholds = Thresholds { fail_under_lines: Some(80.0), ..Thresholds::default() };
+//! for gate in thresholds.evaluate(&json)? {
+//!     assert!(gate.passed(), "{} failed", gate.kind.flag());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `run` feature, [`run::run_subprocess`] runs `cargo llvm-cov` in a
+//! subprocess and returns the coverage totals and the results of the threshold
+//! checks, and [`run::show_env`] returns the environment variables that
+//! `cargo llvm-cov show-env` sets, along with the target directory and the
+//! names of the profile data files. Both need a compatible version of
+//! cargo-llvm-cov to be installed.
+//!
+//! [cargo-llvm-cov]: https://github.com/taiki-e/cargo-llvm-cov
+
+#![forbid(unsafe_code)]
+#![warn(rust_2018_idioms, single_use_lifetimes, unreachable_pub)]
+#![warn(clippy::pedantic)]
+#![allow(clippy::single_match_else)]
+
+pub mod json;
+pub mod policy;
+#[cfg(feature = "run")]
+pub mod run;
+pub mod thresholds;
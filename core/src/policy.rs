@@ -0,0 +1,506 @@
+//! Coverage policies: sets of threshold rules that can be applied to the whole
+//! coverage data, to each file, to each package, or to changed lines.
+//!
+//! Policies can be (de)serialized, and are usually written in JSON or TOML
+//! (see [`Policy::from_toml`]):
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     { "kind": "lines", "min-percent": 80 },
+//!     { "kind": "functions", "max-uncovered": 5 },
+//!     { "kind": "lines", "min-percent": 50, "scope": "file", "filter": "^/path/to/src/" },
+//!     { "kind": "regions", "min-percent": 70, "scope": "package" },
+//!     { "kind": "lines", "min-percent": 90, "scope": "diff" }
+//!   ]
+//! }
+//! ```
+//!
+//! This is used by the `--policy` flag of cargo-llvm-cov, and the
+//! `--fail-under-lines` and `--fail-uncovered-*` flags are evaluated as a
+//! policy with the rules of the `total` scope (see [`Thresholds`]).
+//!
+//! [`Thresholds`]: crate::thresholds::Thresholds
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::json::LlvmCovJsonExport;
+
+/// A set of threshold rules.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+/// A threshold rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Rule {
+    pub kind: CoverageKind,
+    /// Minimum coverage in percent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_percent: Option<f64>,
+    /// Maximum number of uncovered items.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uncovered: Option<u64>,
+    #[serde(default)]
+    pub scope: Scope,
+    /// Regex to select the files (`file` scope) or the packages (`package`
+    /// scope) to which the rule is applied. All are selected by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+/// Kind of coverage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverageKind {
+    Lines,
+    Functions,
+    Regions,
+    Branches,
+}
+
+impl CoverageKind {
+    /// Returns the name of this kind, as used in the output of `llvm-cov export`.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Lines => "lines",
+            Self::Functions => "functions",
+            Self::Regions => "regions",
+            Self::Branches => "branches",
+        }
+    }
+}
+
+/// What a rule is applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// The whole coverage data.
+    Total,
+    /// Each file.
+    File,
+    /// Each package given by [`Input::packages`].
+    Package,
+    /// The changed lines given by [`Input::changed_lines`]. Only line coverage
+    /// is supported.
+    Diff,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self::Total
+    }
+}
+
+/// The coverage data and additional information that policies are evaluated
+/// against.
+#[derive(Debug)]
+pub struct Input<'a> {
+    pub export: &'a LlvmCovJsonExport,
+    /// Name and directory of each package, for the rules of the `package`
+    /// scope. A file belongs to the package with the longest directory that
+    /// contains it.
+    pub packages: Vec<(String, PathBuf)>,
+    /// Changed line numbers of each file, for the rules of the `diff` scope.
+    /// `None` if the rules of the `diff` scope cannot be evaluated.
+    pub changed_lines: Option<BTreeMap<String, BTreeSet<u64>>>,
+}
+
+impl<'a> Input<'a> {
+    /// Creates an input without packages and changed lines.
+    #[must_use]
+    pub fn new(export: &'a LlvmCovJsonExport) -> Self {
+        Self { export, packages: vec![], changed_lines: None }
+    }
+}
+
+/// The subject that an [`Outcome`] was measured for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subject {
+    Total,
+    File(String),
+    Package(String),
+    Diff,
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Total => f.write_str("total"),
+            Self::File(name) => write!(f, "file {}", name),
+            Self::Package(name) => write!(f, "package {}", name),
+            Self::Diff => f.write_str("changed lines"),
+        }
+    }
+}
+
+/// Result of checking one threshold of a rule for one subject.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// Index of the rule in [`Policy::rules`].
+    pub rule: usize,
+    pub kind: CoverageKind,
+    pub subject: Subject,
+    /// `true` if this is the result of [`Rule::min_percent`], `false` if this
+    /// is the result of [`Rule::max_uncovered`].
+    pub percent: bool,
+    /// Coverage in percent if [`Outcome::percent`] is `true`, the number of
+    /// uncovered items otherwise.
+    pub measured: f64,
+    pub threshold: f64,
+}
+
+impl Outcome {
+    /// Returns `true` if the coverage meets the threshold.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.margin() >= 0.0
+    }
+
+    /// Returns the distance from the threshold; positive if passed.
+    #[must_use]
+    pub fn margin(&self) -> f64 {
+        if self.percent {
+            self.measured - self.threshold
+        } else {
+            self.threshold - self.measured
+        }
+    }
+}
+
+impl Policy {
+    /// Reads a policy in JSON format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input is not a valid policy.
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str::<Self>(s)?.validate()
+    }
+
+    /// Reads a policy in TOML format, with the same structure as JSON:
+    ///
+    /// ```toml
+    /// [[rules]]
+    /// kind = "lines"
+    /// min-percent = 80
+    /// scope = "package"
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input is not a valid policy.
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str::<Self>(s)?.validate()
+    }
+
+    fn validate(self) -> Result<Self> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate().with_context(|| format!("invalid rule #{}", i + 1))?;
+        }
+        Ok(self)
+    }
+
+    /// Checks the coverage against the rules, in the order of the rules.
+    /// Subjects other than the total without items of the kind of the rule
+    /// (e.g., files without functions) are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a rule is invalid, if the coverage data lacks the
+    /// kind of coverage of a rule, or if a rule of the `diff` scope is
+    /// evaluated without [`Input::changed_lines`].
+    pub fn evaluate(&self, input: &Input<'_>) -> Result<Vec<Outcome>> {
+        let mut line_coverage = None;
+        let mut outcomes = vec![];
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate().with_context(|| format!("invalid rule #{}", i + 1))?;
+            let counts = match rule.scope {
+                Scope::Total => {
+                    vec![(Subject::Total, input.export.get_totals(rule.kind.as_str())?)]
+                }
+                Scope::File => {
+                    let filter = rule.filter()?;
+                    input
+                        .export
+                        .get_totals_by_file(rule.kind.as_str())?
+                        .into_iter()
+                        .filter(|(file, ..)| filter.as_ref().map_or(true, |re| re.is_match(file)))
+                        .map(|(file, count, covered)| {
+                            (Subject::File(file.to_owned()), (count, covered))
+                        })
+                        .collect()
+                }
+                Scope::Package => {
+                    let filter = rule.filter()?;
+                    let mut packages: BTreeMap<&str, (u64, u64)> = input
+                        .packages
+                        .iter()
+                        .filter(|(name, _)| filter.as_ref().map_or(true, |re| re.is_match(name)))
+                        .map(|(name, _)| (name.as_str(), (0, 0)))
+                        .collect();
+                    for (file, count, covered) in
+                        input.export.get_totals_by_file(rule.kind.as_str())?
+                    {
+                        if let Some(name) = owner(&input.packages, Path::new(file)) {
+                            if let Some(counts) = packages.get_mut(name) {
+                                counts.0 += count;
+                                counts.1 += covered;
+                            }
+                        }
+                    }
+                    packages
+                        .into_iter()
+                        .map(|(name, counts)| (Subject::Package(name.to_owned()), counts))
+                        .collect()
+                }
+                Scope::Diff => {
+                    let changed_lines = match &input.changed_lines {
+                        Some(changed_lines) => changed_lines,
+                        None => {
+                            bail!("rule #{} of the diff scope requires the changed lines", i + 1)
+                        }
+                    };
+                    let line_coverage =
+                        line_coverage.get_or_insert_with(|| input.export.get_line_coverage());
+                    vec![(Subject::Diff, diff_counts(line_coverage, changed_lines))]
+                }
+            };
+
+            for (subject, (count, covered)) in counts {
+                if count == 0 && subject != Subject::Total {
+                    continue;
+                }
+                if let Some(threshold) = rule.min_percent {
+                    outcomes.push(Outcome {
+                        rule: i,
+                        kind: rule.kind,
+                        subject: subject.clone(),
+                        percent: true,
+                        measured: percent(count, covered),
+                        threshold,
+                    });
+                }
+                if let Some(threshold) = rule.max_uncovered {
+                    #[allow(clippy::cast_precision_loss)]
+                    outcomes.push(Outcome {
+                        rule: i,
+                        kind: rule.kind,
+                        subject,
+                        percent: false,
+                        measured: count.saturating_sub(covered) as f64,
+                        threshold: threshold as f64,
+                    });
+                }
+            }
+        }
+        Ok(outcomes)
+    }
+}
+
+impl Rule {
+    fn validate(&self) -> Result<()> {
+        if self.min_percent.is_none() && self.max_uncovered.is_none() {
+            bail!("either min-percent or max-uncovered is required");
+        }
+        if self.filter.is_some() && !matches!(self.scope, Scope::File | Scope::Package) {
+            bail!("filter can only be used in the file or package scope");
+        }
+        if self.scope == Scope::Diff && self.kind != CoverageKind::Lines {
+            bail!("only line coverage is supported in the diff scope");
+        }
+        self.filter()?;
+        Ok(())
+    }
+
+    fn filter(&self) -> Result<Option<Regex>> {
+        match &self.filter {
+            Some(filter) => Ok(Some(
+                Regex::new(filter).with_context(|| format!("invalid filter `{}`", filter))?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the package that contains the given file.
+fn owner<'a>(packages: &'a [(String, PathBuf)], file: &Path) -> Option<&'a str> {
+    // Use the longest match to handle nested packages.
+    packages
+        .iter()
+        .filter(|(_, dir)| file.starts_with(dir))
+        .max_by_key(|(_, dir)| dir.as_os_str().len())
+        .map(|(name, _)| name.as_str())
+}
+
+/// Counts the changed lines that have coverage data, and the covered ones.
+fn diff_counts(
+    line_coverage: &BTreeMap<String, BTreeMap<u64, bool>>,
+    changed_lines: &BTreeMap<String, BTreeSet<u64>>,
+) -> (u64, u64) {
+    let mut count = 0;
+    let mut covered = 0;
+    for (file, changed) in changed_lines {
+        let lines = match line_coverage.get(file) {
+            Some(lines) => lines,
+            None => continue,
+        };
+        for line in changed {
+            if let Some(&c) = lines.get(line) {
+                count += 1;
+                covered += u64::from(c);
+            }
+        }
+    }
+    (count, covered)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn percent(count: u64, covered: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        covered as f64 * 100.0 / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_err as fs;
+
+    use super::*;
+
+    fn export() -> LlvmCovJsonExport {
+        let file = format!("{}/tests/fixtures/show-missing-lines.json", env!("CARGO_MANIFEST_DIR"));
+        serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let policy = Policy::from_json(
+            r#"{ "rules": [
+                { "kind": "lines", "min-percent": 80 },
+                { "kind": "regions", "max-uncovered": 3, "scope": "file", "filter": "lib" }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.rules[0].scope, Scope::Total);
+        assert_eq!(policy.rules[1].kind, CoverageKind::Regions);
+        assert_eq!(policy.rules[1].filter.as_deref(), Some("lib"));
+
+        for invalid in [
+            r#"{ "rules": [{ "kind": "lines" }] }"#,
+            r#"{ "rules": [{ "kind": "regions", "min-percent": 1, "scope": "diff" }] }"#,
+            r#"{ "rules": [{ "kind": "lines", "min-percent": 1, "filter": "a" }] }"#,
+            r#"{ "rules": [{ "kind": "lines", "min-percent": 1, "scope": "file", "filter": "(" }] }"#,
+            r#"{ "rules": [{ "kind": "lines", "min-percent": 1, "unknown": 1 }] }"#,
+        ] {
+            assert!(Policy::from_json(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_toml() {
+        let toml = r#"
+            [[rules]]
+            kind = "lines"
+            min-percent = 80 # integers are accepted
+
+            [[rules]]
+            kind = "regions"
+            max-uncovered = 3
+            scope = "file"
+            filter = 'lib'
+        "#;
+        let json = r#"{ "rules": [
+            { "kind": "lines", "min-percent": 80.0 },
+            { "kind": "regions", "max-uncovered": 3, "scope": "file", "filter": "lib" }
+        ] }"#;
+        assert_eq!(Policy::from_toml(toml).unwrap(), Policy::from_json(json).unwrap());
+        let toml = r#"rules = [
+            { kind = "lines", min-percent = 80 },
+            { kind = "regions", max-uncovered = 3, scope = "file", filter = 'lib' },
+        ]"#;
+        assert_eq!(Policy::from_toml(toml).unwrap(), Policy::from_json(json).unwrap());
+        assert!(Policy::from_toml("[[rules]]\nkind = \"lines\"").is_err());
+        assert!(Policy::from_toml("[[rules]]\nkind = \"lines\"\nmin-percent = \"80\"").is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        let export = export();
+        let (count, covered) = export.get_totals("lines").unwrap();
+        let policy = Policy {
+            rules: vec![
+                Rule {
+                    kind: CoverageKind::Lines,
+                    min_percent: Some(100.0),
+                    max_uncovered: Some(count - covered),
+                    scope: Scope::Total,
+                    filter: None,
+                },
+                Rule {
+                    kind: CoverageKind::Lines,
+                    min_percent: None,
+                    max_uncovered: Some(0),
+                    scope: Scope::File,
+                    filter: None,
+                },
+                Rule {
+                    kind: CoverageKind::Lines,
+                    min_percent: Some(0.0),
+                    max_uncovered: None,
+                    scope: Scope::Package,
+                    filter: None,
+                },
+            ],
+        };
+        let mut input = Input::new(&export);
+        input.packages.push(("t".to_owned(), PathBuf::from("src")));
+        let outcomes = policy.evaluate(&input).unwrap();
+        assert_eq!(outcomes[0].subject, Subject::Total);
+        assert!(!outcomes[0].passed());
+        assert_eq!(outcomes[1].subject, Subject::Total);
+        assert!(outcomes[1].passed());
+        assert!(outcomes.iter().any(|o| o.subject == Subject::File("src/lib.rs".to_owned())));
+        assert_eq!(outcomes.last().unwrap().subject, Subject::Package("t".to_owned()));
+    }
+
+    #[test]
+    fn evaluate_diff() {
+        let export = export();
+        let lines = &export.get_line_coverage()["src/lib.rs"];
+        let uncovered = *lines.iter().find(|(_, &covered)| !covered).unwrap().0;
+        let policy = Policy {
+            rules: vec![Rule {
+                kind: CoverageKind::Lines,
+                min_percent: Some(100.0),
+                max_uncovered: None,
+                scope: Scope::Diff,
+                filter: None,
+            }],
+        };
+        let mut input = Input::new(&export);
+        assert!(policy.evaluate(&input).is_err());
+
+        let mut changed_lines = BTreeMap::new();
+        changed_lines.insert("src/lib.rs".to_owned(), [uncovered, 10_000].into_iter().collect());
+        input.changed_lines = Some(changed_lines);
+        let outcomes = policy.evaluate(&input).unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert!((outcomes[0].measured - 0.0).abs() < f64::EPSILON);
+        assert!(!outcomes[0].passed());
+    }
+}
@@ -0,0 +1,302 @@
+//! Running cargo-llvm-cov from build tools (e.g., xtask scripts or release
+//! tooling), and getting the results as structured data.
+//!
+//! This runs `cargo llvm-cov` as a subprocess, so a compatible version of
+//! cargo-llvm-cov (the same `0.x` series as this crate, and not older) needs to
+//! be installed. The output of tests and the status messages of cargo-llvm-cov
+//! are inherited from the current process.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde::Deserialize;
+
+use crate::{
+    json::LlvmCovJsonExport,
+    thresholds::{Gate, Thresholds},
+};
+
+/// Options of [`run_subprocess`].
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Path to cargo. Defaults to `$CARGO`, or `cargo` in `PATH`.
+    pub cargo: Option<PathBuf>,
+    /// Path to Cargo.toml. Defaults to the manifest in the current directory.
+    pub manifest_path: Option<PathBuf>,
+    /// Additional arguments of `cargo llvm-cov`, e.g., `["--workspace"]` or
+    /// `["nextest", "--all-features"]`.
+    ///
+    /// Report format flags (e.g., `--html`) and `--output-path` cannot be used
+    /// because the coverage data is exported in JSON format.
+    pub args: Vec<OsString>,
+    /// Thresholds to check the coverage against. The results are returned as
+    /// [`CoverageSummary::gates`] instead of failing the run.
+    pub thresholds: Thresholds,
+}
+
+/// The number of items and covered items of a kind of coverage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub count: u64,
+    pub covered: u64,
+}
+
+impl Counts {
+    /// Returns the coverage in percent, or 0 if there are no items.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn percent(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.covered as f64 * 100.0 / self.count as f64
+        }
+    }
+}
+
+/// Results of [`run_subprocess`].
+#[derive(Debug)]
+pub struct CoverageSummary {
+    pub lines: Counts,
+    pub functions: Counts,
+    pub regions: Counts,
+    /// Results of checking [`Config::thresholds`], in the order of the fields.
+    pub gates: Vec<Gate>,
+    /// The exported coverage data.
+    pub export: LlvmCovJsonExport,
+}
+
+impl CoverageSummary {
+    /// Returns `true` if the coverage meets all thresholds.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.gates.iter().all(Gate::passed)
+    }
+}
+
+/// Runs `cargo llvm-cov` in a subprocess with the given config, and returns the
+/// coverage it exported to a temporary file.
+///
+/// # Errors
+///
+/// Returns an error if the installed cargo-llvm-cov is not compatible with this
+/// crate, if `cargo llvm-cov` could not be executed or exited with a non-zero
+/// status (e.g., a test failed), or if its output could not be parsed.
+pub fn run_subprocess(config: &Config) -> Result<CoverageSummary> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    check_version(config)?;
+    let output_path = env::temp_dir().join(format!(
+        "cargo-llvm-cov-{}-{}.json",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let res = run_inner(config, &output_path);
+    let _ = fs_err::remove_file(&output_path);
+    res
+}
+
+fn run_inner(config: &Config, output_path: &Path) -> Result<CoverageSummary> {
+    let mut cmd = command(config, output_path);
+    let status = cmd.status().with_context(|| format!("could not execute process {:?}", cmd))?;
+    if !status.success() {
+        bail!("process didn't exit successfully: {:?} ({})", cmd, status);
+    }
+
+    let export = LlvmCovJsonExport::from_reader(fs_err::File::open(output_path)?, None)?;
+    let counts = |kind| -> Result<Counts> {
+        let (count, covered) = export.get_totals(kind)?;
+        Ok(Counts { count, covered })
+    };
+    Ok(CoverageSummary {
+        lines: counts("lines")?,
+        functions: counts("functions")?,
+        regions: counts("regions")?,
+        gates: config.thresholds.evaluate(&export)?,
+        export,
+    })
+}
+
+/// The environment that `cargo llvm-cov show-env` sets up. Returned by [`show_env`].
+#[derive(Debug, Clone, Deserialize)]
+#[non_exhaustive]
+pub struct Environment {
+    /// The environment variables. Variables mapped to `None` need to be
+    /// removed from the environment.
+    pub env: BTreeMap<String, Option<String>>,
+    /// The target directory of the instrumented builds.
+    pub target_dir: PathBuf,
+    /// The pattern of the names of the profile data files written by the
+    /// instrumented binaries (the value of `LLVM_PROFILE_FILE`).
+    pub profraw_pattern: String,
+}
+
+/// Returns the environment that `cargo llvm-cov show-env` sets up, for
+/// building and running instrumented binaries with other tools (see "Get
+/// coverage of external tests" section in README of cargo-llvm-cov).
+///
+/// [`Config::args`] can contain options that affect the environment (e.g.,
+/// `--target` or `--include-ffi`), but not subcommands.
+///
+/// # Errors
+///
+/// Returns an error if the installed cargo-llvm-cov is not compatible with this
+/// crate, if `cargo llvm-cov show-env` could not be executed or exited with a
+/// non-zero status, or if its output could not be parsed.
+pub fn show_env(config: &Config) -> Result<Environment> {
+    check_version(config)?;
+    let mut cmd = cargo_llvm_cov(config);
+    cmd.arg("show-env");
+    cmd.args(["--format", "json"]);
+    let output = cmd.output().with_context(|| format!("could not execute process {:?}", cmd))?;
+    if !output.status.success() {
+        bail!(
+            "process didn't exit successfully: {:?} ({})\n--- stderr\n{}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("failed to parse the output of {:?}", cmd))
+}
+
+/// Checks that the installed cargo-llvm-cov is in the same `0.x` series as
+/// this crate and not older, because this crate may use options added in its
+/// version.
+fn check_version(config: &Config) -> Result<()> {
+    let mut cmd = cargo(config);
+    cmd.arg("llvm-cov");
+    cmd.arg("--version");
+    let output = cmd.output().with_context(|| format!("could not execute process {:?}", cmd))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = match stdout.trim().strip_prefix("cargo-llvm-cov ") {
+        Some(version) if output.status.success() => version,
+        _ => bail!(
+            "cargo-llvm-cov is not installed or not working: {:?} ({})\n--- stderr\n{}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    };
+    let required = env!("CARGO_PKG_VERSION");
+    if !is_compatible(version, required) {
+        bail!(
+            "cargo-llvm-cov-core {0} requires cargo-llvm-cov {0} or later in the same 0.x series, \
+             but cargo-llvm-cov {1} is installed",
+            required,
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Returns `true` if `version` is semver-compatible with `required` and not older.
+fn is_compatible(version: &str, required: &str) -> bool {
+    fn parse(version: &str) -> Option<(u64, u64, u64)> {
+        let version = version.split(|c| c == '-' || c == '+').next()?;
+        let mut parts = version.split('.').map(str::parse);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                Some((major, minor, patch))
+            }
+            _ => None,
+        }
+    }
+    match (parse(version), parse(required)) {
+        (Some(version), Some(required)) => {
+            let series =
+                |(major, minor, _): (u64, u64, u64)| (major, if major == 0 { minor } else { 0 });
+            series(version) == series(required) && version >= required
+        }
+        _ => false,
+    }
+}
+
+fn cargo(config: &Config) -> Command {
+    let cargo = match &config.cargo {
+        Some(cargo) => cargo.clone().into_os_string(),
+        None => env::var_os("CARGO").unwrap_or_else(|| "cargo".into()),
+    };
+    Command::new(cargo)
+}
+
+fn cargo_llvm_cov(config: &Config) -> Command {
+    let mut cmd = cargo(config);
+    cmd.arg("llvm-cov");
+    cmd.args(&config.args);
+    if let Some(manifest_path) = &config.manifest_path {
+        cmd.arg("--manifest-path");
+        cmd.arg(manifest_path);
+    }
+    cmd
+}
+
+fn command(config: &Config, output_path: &Path) -> Command {
+    let mut cmd = cargo_llvm_cov(config);
+    cmd.arg("--json");
+    cmd.arg("--output-path");
+    cmd.arg(output_path);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use super::{command, is_compatible, Config, Counts};
+
+    #[test]
+    fn command_args() {
+        let config = Config {
+            cargo: Some("/bin/cargo".into()),
+            manifest_path: Some("a/Cargo.toml".into()),
+            args: vec!["nextest".into(), "--workspace".into()],
+            ..Config::default()
+        };
+        let cmd = command(&config, Path::new("out.json"));
+        assert_eq!(cmd.get_program(), "/bin/cargo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            [
+                "llvm-cov",
+                "nextest",
+                "--workspace",
+                "--manifest-path",
+                "a/Cargo.toml",
+                "--json",
+                "--output-path",
+                "out.json"
+            ]
+            .iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn compatible_version() {
+        assert!(is_compatible("0.4.11", "0.4.11"));
+        assert!(is_compatible("0.4.12", "0.4.11"));
+        assert!(is_compatible("0.4.12-alpha+build", "0.4.11"));
+        assert!(!is_compatible("0.4.10", "0.4.11"));
+        assert!(!is_compatible("0.5.0", "0.4.11"));
+        assert!(is_compatible("1.2.0", "1.1.0"));
+        assert!(!is_compatible("2.0.0", "1.1.0"));
+        assert!(!is_compatible("0.4", "0.4.11"));
+        assert!(!is_compatible("", "0.4.11"));
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn counts_percent() {
+        assert_eq!(Counts { count: 0, covered: 0 }.percent(), 0.0);
+        assert_eq!(Counts { count: 4, covered: 3 }.percent(), 75.0);
+    }
+}
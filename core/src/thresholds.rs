@@ -0,0 +1,154 @@
+//! Coverage thresholds, corresponding to the `--fail-under-lines` and
+//! `--fail-uncovered-*` flags of cargo-llvm-cov.
+
+use anyhow::Result;
+
+use crate::{
+    json::LlvmCovJsonExport,
+    policy::{CoverageKind, Input, Policy, Rule, Scope},
+};
+
+/// Thresholds to check the coverage data against. `None` means no threshold.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Thresholds {
+    /// Minimum line coverage in percent.
+    pub fail_under_lines: Option<f64>,
+    /// Maximum number of uncovered functions.
+    pub fail_uncovered_functions: Option<u64>,
+    /// Maximum number of uncovered lines.
+    pub fail_uncovered_lines: Option<u64>,
+    /// Maximum number of uncovered regions.
+    pub fail_uncovered_regions: Option<u64>,
+}
+
+/// Kind of threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Kind {
+    LinesPercent,
+    UncoveredFunctions,
+    UncoveredLines,
+    UncoveredRegions,
+}
+
+impl Kind {
+    /// Returns the flag of cargo-llvm-cov that sets this kind of threshold.
+    #[must_use]
+    pub const fn flag(self) -> &'static str {
+        match self {
+            Self::LinesPercent => "--fail-under-lines",
+            Self::UncoveredFunctions => "--fail-uncovered-functions",
+            Self::UncoveredLines => "--fail-uncovered-lines",
+            Self::UncoveredRegions => "--fail-uncovered-regions",
+        }
+    }
+}
+
+/// Result of checking one threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gate {
+    pub kind: Kind,
+    /// Line coverage in percent for [`Kind::LinesPercent`], the number of
+    /// uncovered items otherwise.
+    pub measured: f64,
+    pub threshold: f64,
+}
+
+impl Gate {
+    /// Returns `true` if the coverage meets the threshold.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.margin() >= 0.0
+    }
+
+    /// Returns the distance from the threshold; positive if passed.
+    #[must_use]
+    pub fn margin(&self) -> f64 {
+        match self.kind {
+            Kind::LinesPercent => self.measured - self.threshold,
+            _ => self.threshold - self.measured,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Returns the policy with the rules of the `total` scope equivalent to
+    /// the thresholds that are set, in the order of the fields.
+    #[must_use]
+    pub fn to_policy(&self) -> Policy {
+        let rule = |kind, min_percent, max_uncovered| Rule {
+            kind,
+            min_percent,
+            max_uncovered,
+            scope: Scope::Total,
+            filter: None,
+        };
+        let mut rules = vec![];
+        if let Some(threshold) = self.fail_under_lines {
+            rules.push(rule(CoverageKind::Lines, Some(threshold), None));
+        }
+        if let Some(threshold) = self.fail_uncovered_functions {
+            rules.push(rule(CoverageKind::Functions, None, Some(threshold)));
+        }
+        if let Some(threshold) = self.fail_uncovered_lines {
+            rules.push(rule(CoverageKind::Lines, None, Some(threshold)));
+        }
+        if let Some(threshold) = self.fail_uncovered_regions {
+            rules.push(rule(CoverageKind::Regions, None, Some(threshold)));
+        }
+        Policy { rules }
+    }
+
+    /// Checks the coverage data against the thresholds that are set, in the
+    /// order of the fields.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the totals are missing in the coverage data.
+    pub fn evaluate(&self, json: &LlvmCovJsonExport) -> Result<Vec<Gate>> {
+        let outcomes = self.to_policy().evaluate(&Input::new(json))?;
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| {
+                let kind = match (outcome.kind, outcome.percent) {
+                    (CoverageKind::Lines, true) => Kind::LinesPercent,
+                    (CoverageKind::Functions, false) => Kind::UncoveredFunctions,
+                    (CoverageKind::Lines, false) => Kind::UncoveredLines,
+                    (CoverageKind::Regions, false) => Kind::UncoveredRegions,
+                    _ => unreachable!(),
+                };
+                Gate { kind, measured: outcome.measured, threshold: outcome.threshold }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_err as fs;
+
+    use super::*;
+
+    #[test]
+    fn evaluate() {
+        let file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/coverage-reports/no_test/no_test.json"
+        );
+        let json =
+            serde_json::from_str::<LlvmCovJsonExport>(&fs::read_to_string(file).unwrap()).unwrap();
+        let thresholds = Thresholds {
+            fail_uncovered_functions: Some(1),
+            fail_uncovered_lines: Some(6),
+            ..Thresholds::default()
+        };
+        let gates = thresholds.evaluate(&json).unwrap();
+        assert_eq!(gates.len(), 2);
+        assert_eq!(gates[0].kind, Kind::UncoveredFunctions);
+        assert!(gates[0].passed());
+        assert!((gates[0].margin() - 0.0).abs() < f64::EPSILON);
+        assert_eq!(gates[1].kind, Kind::UncoveredLines);
+        assert!(!gates[1].passed());
+        assert!((gates[1].margin() + 1.0).abs() < f64::EPSILON);
+    }
+}
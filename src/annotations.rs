@@ -0,0 +1,47 @@
+// Annotations of uncovered changed lines for GitHub check runs (--annotations).
+// https://docs.github.com/en/rest/checks/runs#annotations-object
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use serde_json::Value;
+
+use crate::{changed, ci, context::Context};
+
+/// Handles --annotations.
+pub(crate) fn generate(cx: &Context, json: &LlvmCovJsonExport) -> Result<Vec<Value>> {
+    let rev = cx.cov.annotations_diff.as_deref().unwrap_or("HEAD");
+    let (_, uncovered) = changed::uncovered_changed_lines(cx, json, rev)?;
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let repo_root = Utf8PathBuf::from(ci::git(cx, &["rev-parse", "--show-toplevel"])?);
+
+    let mut annotations = vec![];
+    for (filename, lines) in &uncovered {
+        let mut path = Utf8PathBuf::from(filename);
+        if path.is_relative() {
+            // --remap-path-prefix strips the workspace root.
+            path = workspace_root.join(path);
+        }
+        let path = path.strip_prefix(&repo_root).unwrap_or(&path);
+        for (start, end) in changed::line_ranges(lines) {
+            annotations.push(annotation(path, start, end));
+        }
+    }
+    Ok(annotations)
+}
+
+fn annotation(path: &Utf8Path, start: u64, end: u64) -> Value {
+    let message = if start == end {
+        format!("Line {} is not covered by tests.", start)
+    } else {
+        format!("Lines {}-{} are not covered by tests.", start, end)
+    };
+    serde_json::json!({
+        "path": path.as_str().replace('\\', "/"),
+        "start_line": start,
+        "end_line": end,
+        "annotation_level": "warning",
+        "title": "Uncovered lines",
+        "message": message,
+    })
+}
@@ -0,0 +1,279 @@
+// Collecting the reports and the test results into one directory (--artifact-dir),
+// and the coverage data of runs with --no-report for `merge-and-report`.
+
+use std::{collections::BTreeSet, ffi::OsString, path::Path};
+
+use anyhow::{bail, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use regex::Regex;
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+use crate::{context::Context, fs, summary};
+
+/// Handles --artifact-dir.
+pub(crate) fn write(
+    cx: &Context,
+    dir: &Utf8Path,
+    reports: &[(&str, Utf8PathBuf)],
+    json: &LlvmCovJsonExport,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut manifest_reports = vec![];
+    for (format, path) in reports {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => continue,
+        };
+        copy(path, &dir.join(name))?;
+        manifest_reports.push(serde_json::json!({ "format": format, "path": name }));
+    }
+
+    let mut junit = vec![];
+    let mut tests = vec![];
+    for path in junit_files(cx) {
+        let name = format!("junit/{}", path.file_name().unwrap_or("junit.xml"));
+        copy(&path, &dir.join(&name))?;
+        for (id, result) in parse_junit(&fs::read_to_string(&path)?) {
+            tests.push(serde_json::json!({ "id": id, "result": result, "junit": name }));
+        }
+        junit.push(name);
+    }
+
+    let manifest = serde_json::json!({
+        "reports": manifest_reports,
+        "junit": junit,
+        "tests": tests,
+        "totals": summary::totals(json)?,
+    });
+    fs::write(dir.join("manifest.json"), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(())
+}
+
+const COVERAGE_DATA_MANIFEST: &str = "coverage-data.json";
+
+/// The list of the files exported by --no-report --artifact-dir, in
+/// `coverage-data.json`. Object files are listed because uploading artifacts
+/// usually loses the executable permission they are collected by.
+#[derive(Deserialize)]
+struct CoverageData {
+    workspace_root: Utf8PathBuf,
+    /// Missing in the data exported by older versions.
+    #[serde(default)]
+    target_dir: Option<Utf8PathBuf>,
+    profraw: Vec<Utf8PathBuf>,
+    objects: Vec<Utf8PathBuf>,
+}
+
+/// Handles --no-report --artifact-dir.
+pub(crate) fn export_coverage_data(
+    cx: &Context,
+    dir: &Utf8Path,
+    profraw_files: &[impl AsRef<Path>],
+    object_files: &[OsString],
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let manifest = serde_json::json!({
+        "workspace_root": cx.ws.metadata.workspace_root,
+        "target_dir": cx.ws.target_dir,
+        "profraw": copy_into(dir, "profraw", profraw_files)?,
+        "objects": copy_into(dir, "objects", object_files)?,
+    });
+    fs::write(dir.join(COVERAGE_DATA_MANIFEST), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(())
+}
+
+/// Copies the files to `dir/sub` and returns their paths relative to `dir`.
+fn copy_into(dir: &Utf8Path, sub: &str, files: &[impl AsRef<Path>]) -> Result<Vec<String>> {
+    fs::create_dir_all(dir.join(sub))?;
+    let mut names = BTreeSet::new();
+    for file in files {
+        let file = file.as_ref();
+        let file_name = match file.file_name() {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => continue,
+        };
+        let mut name = format!("{}/{}", sub, file_name);
+        let mut i = 1;
+        while names.contains(&name) {
+            name = format!("{}/{}-{}", sub, file_name, i);
+            i += 1;
+        }
+        fs::copy(file, dir.join(&name))?;
+        names.insert(name);
+    }
+    Ok(names.into_iter().collect())
+}
+
+/// Returns the profile data files and the object files exported to the
+/// directories given to `merge-and-report` (or by multiple --manifest-path).
+pub(crate) fn import_coverage_data(cx: &Context) -> Result<(Vec<Utf8PathBuf>, Vec<OsString>)> {
+    let mut profraw_files = vec![];
+    let mut object_files = vec![];
+    for (dir, data) in read_coverage_data(cx)? {
+        if data.workspace_root != cx.ws.metadata.workspace_root && !data.workspace_root.exists() {
+            warn!(
+                "coverage data in {} was collected in a workspace at {}, which does not exist; \
+                 source files may not be found",
+                dir, data.workspace_root
+            );
+        }
+        profraw_files.extend(data.profraw.iter().map(|p| dir.join(p)));
+        object_files.extend(data.objects.iter().map(|p| dir.join(p).into_std_path_buf().into()));
+    }
+    Ok((profraw_files, object_files))
+}
+
+/// Returns the workspace roots and the target directories of the imported
+/// coverage data other than the current ones, for the default
+/// --ignore-filename-regex.
+pub(crate) fn imported_workspaces(cx: &Context) -> (Vec<Utf8PathBuf>, Vec<Utf8PathBuf>) {
+    let mut workspace_roots = BTreeSet::new();
+    let mut target_dirs = BTreeSet::new();
+    // Errors are reported when the coverage data is imported.
+    for (_, data) in read_coverage_data(cx).unwrap_or_default() {
+        if data.workspace_root != cx.ws.metadata.workspace_root {
+            workspace_roots.insert(data.workspace_root);
+        }
+        if let Some(target_dir) = data.target_dir {
+            if target_dir != cx.ws.target_dir {
+                target_dirs.insert(target_dir);
+            }
+        }
+    }
+    (workspace_roots.into_iter().collect(), target_dirs.into_iter().collect())
+}
+
+fn read_coverage_data(cx: &Context) -> Result<Vec<(&Utf8Path, CoverageData)>> {
+    let mut out = vec![];
+    for dir in &cx.import_dirs {
+        let path = dir.join(COVERAGE_DATA_MANIFEST);
+        if !path.exists() {
+            bail!(
+                "{} does not contain coverage data exported by `--no-report --artifact-dir`",
+                dir
+            );
+        }
+        let data: CoverageData = serde_json::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("failed to parse {}", path))?;
+        out.push((dir.as_path(), data));
+    }
+    Ok(out)
+}
+
+/// Returns the JUnit XML files in the store directory of cargo-nextest. Without
+/// the nextest profile (e.g., with --no-run), the files of all profiles are
+/// returned.
+// https://nexte.st/book/junit
+fn junit_files(cx: &Context) -> Vec<Utf8PathBuf> {
+    let store = cx.ws.target_dir.join("nextest");
+    let dir = match &cx.build.nextest_profile {
+        Some(profile) => store.join(profile),
+        None => store,
+    };
+    let mut files = vec![];
+    for entry in WalkDir::new(&dir).sort_by_file_name().into_iter().filter_map(Result::ok) {
+        let path = match Utf8Path::from_path(entry.path()) {
+            Some(path) => path,
+            None => continue,
+        };
+        if entry.file_type().is_file() && path.extension() == Some("xml") {
+            files.push(path.to_owned());
+        }
+    }
+    files
+}
+
+fn copy(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    // The report may have been saved to the artifact directory.
+    if to.exists() && fs::canonicalize(from)? == fs::canonicalize(to)? {
+        return Ok(());
+    }
+    if !from.is_dir() {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+    for entry in WalkDir::new(from).into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let dst = to.as_std_path().join(entry.path().strip_prefix(from)?);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(entry.path(), dst)?;
+    }
+    Ok(())
+}
+
+/// Returns the IDs and results (`passed`, `failed`, or `skipped`) of the test
+/// cases in JUnit XML written by cargo-nextest.
+fn parse_junit(xml: &str) -> Vec<(String, &'static str)> {
+    let testcase = Regex::new(r"(?s)<testcase[ \t\r\n]([^>]*?)(/>|>(.*?)</testcase>)").unwrap();
+    let attr = |attrs: &str, name: &str| {
+        Regex::new(&format!(r#"(?:^|[ \t\r\n]){}="([^"]*)""#, name))
+            .unwrap()
+            .captures(attrs)
+            .map(|c| unescape(&c[1]))
+    };
+    let mut tests = vec![];
+    for c in testcase.captures_iter(xml) {
+        let (classname, name) = match (attr(&c[1], "classname"), attr(&c[1], "name")) {
+            (Some(classname), Some(name)) => (classname, name),
+            _ => continue,
+        };
+        let body = c.get(3).map_or("", |m| m.as_str());
+        // Flaky tests that passed on retry have `<flakyFailure>`, which is not a failure.
+        let result = if body.contains("<failure") || body.contains("<error") {
+            "failed"
+        } else if body.contains("<skipped") {
+            "skipped"
+        } else {
+            "passed"
+        };
+        tests.push((format!("{} {}", classname, name), result));
+    }
+    tests
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_junit;
+
+    #[test]
+    fn junit() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="nextest-run" tests="4" failures="1" errors="0">
+    <testsuite name="a::tests" tests="4">
+        <testcase name="ok" classname="a::tests" timestamp="2022-01-01T00:00:00Z" time="0.001">
+        </testcase>
+        <testcase name="fail&lt;T&gt;" classname="a::tests" time="0.001">
+            <failure type="test failure">assertion failed</failure>
+        </testcase>
+        <testcase name="flaky" classname="a::tests" time="0.001">
+            <flakyFailure type="test failure"/>
+        </testcase>
+        <testcase name="empty" classname="a::tests"/>
+    </testsuite>
+</testsuites>
+"#;
+        assert_eq!(parse_junit(xml), [
+            ("a::tests ok".to_owned(), "passed"),
+            ("a::tests fail<T>".to_owned(), "failed"),
+            ("a::tests flaky".to_owned(), "passed"),
+            ("a::tests empty".to_owned(), "passed"),
+        ]);
+    }
+}
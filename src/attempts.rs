@@ -0,0 +1,147 @@
+// Merging only some attempts of tests retried by cargo-nextest (--merge-attempts),
+// and separating the profile data of each test (--per-test).
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::{OsStr, OsString},
+    hash::{Hash, Hasher},
+    iter,
+    process::{self, Command, ExitStatus},
+};
+
+use anyhow::{bail, Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{cli::MergeAttempts, context::Context, env, fs, process::ProcessBuilder};
+
+const ATTEMPTS_DIR_ENV: &str = "CARGO_LLVM_COV_ATTEMPTS_DIR";
+const MERGE_ATTEMPTS_ENV: &str = "CARGO_LLVM_COV_MERGE_ATTEMPTS";
+
+/// Sets cargo-llvm-cov as the target runner of cargo-nextest. Handles
+/// --merge-attempts and --per-test.
+///
+/// The runner writes the profile data of each attempt to its own files, and
+/// removes the files of the previous attempts of a retried test (`last`) or
+/// of a failed attempt (`passed`). Processes that are not test attempts
+/// (e.g., listing tests) write profile data to the usual location.
+pub(crate) fn set_runner(cx: &Context, cmd: &mut ProcessBuilder) -> Result<()> {
+    let which = match cx.build.merge_attempts {
+        Some(which) if which != MergeAttempts::All => which,
+        _ if cx.cov.per_test.is_some() => MergeAttempts::All,
+        _ => return Ok(()),
+    };
+    // https://nexte.st/book/target-runners
+    let target = cx.build.target.as_ref().unwrap_or(&cx.ws.host_triple);
+    let key = format!(
+        "CARGO_TARGET_{}_RUNNER",
+        target.to_uppercase().replace(|c| c == '-' || c == '.', "_")
+    );
+    if env::var_os(&key).is_some() {
+        bail!(
+            "--merge-attempts and --per-test may not be used together with a target runner \
+             ({} is set)",
+            key
+        );
+    }
+    // cargo-nextest splits the runner by whitespace.
+    let exe = match cx.current_exe.to_str() {
+        Some(exe) if !exe.contains(char::is_whitespace) => exe,
+        _ => bail!(
+            "--merge-attempts and --per-test are not supported when the path of cargo-llvm-cov contains \
+             whitespace or is not valid UTF-8: {}",
+            cx.current_exe.display()
+        ),
+    };
+    cmd.env(key, format!("{} llvm-cov nextest-runner", exe));
+    cmd.env(ATTEMPTS_DIR_ENV, cx.ws.attempts_dir(cx.build.partition).as_str());
+    cmd.env(MERGE_ATTEMPTS_ENV, which.name());
+    Ok(())
+}
+
+/// Returns the patterns of the profile data files of the attempts to merge,
+/// one for each directory. Without --partition, this also returns the
+/// patterns for the directories of all partitions.
+pub(crate) fn profraw_patterns(cx: &Context) -> Result<Vec<String>> {
+    let dirs = match cx.build.partition {
+        Some(partition) => vec![cx.ws.attempts_dir(Some(partition)).into_std_path_buf()],
+        None => glob::glob(cx.ws.target_dir.join(format!("{}-attempts*", cx.ws.name)).as_str())?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_dir())
+            .collect(),
+    };
+    Ok(dirs.into_iter().map(|dir| dir.join("*.profraw").to_string_lossy().into_owned()).collect())
+}
+
+/// Runs a test binary as the target runner of cargo-nextest. Handles `cargo
+/// llvm-cov nextest-runner`.
+pub(crate) fn run(args: &[OsString]) -> Result<()> {
+    let (program, args) = args.split_first().context("no test binary was given")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    // cargo-nextest sets the attempt number (starting at 1) of each test.
+    let attempt = match env::var("NEXTEST_ATTEMPT")? {
+        Some(attempt) => Some(attempt),
+        None => env::var("__NEXTEST_ATTEMPT")?,
+    };
+    let (attempt, dir, which) =
+        match (attempt, env::var(ATTEMPTS_DIR_ENV)?, env::var(MERGE_ATTEMPTS_ENV)?) {
+            (Some(attempt), Some(dir), Some(which)) => (attempt, dir, which),
+            _ => exit(cmd.status().with_context(|| format!("failed to run {:?}", program))?),
+        };
+    let attempt: u32 =
+        attempt.parse().with_context(|| format!("invalid attempt number `{}`", attempt))?;
+    let dir = Utf8Path::new(&dir);
+    let id = test_id(program, args);
+    fs::create_dir_all(dir)?;
+    let test: Vec<_> = iter::once(program).chain(args).map(|arg| arg.to_string_lossy()).collect();
+    fs::write(dir.join(format!("{}.args.json", id)), serde_json::to_string(&test)?)?;
+
+    if which == MergeAttempts::Last.name() && attempt > 1 {
+        remove(dir, &format!("{}-*.profraw", id))?;
+    }
+    cmd.env("LLVM_PROFILE_FILE", dir.join(format!("{}-{}-%p-%m.profraw", id, attempt)).as_str());
+    let status = cmd.status().with_context(|| format!("failed to run {:?}", program))?;
+    if which == MergeAttempts::Passed.name() && !status.success() {
+        remove(dir, &format!("{}-{}-*.profraw", id, attempt))?;
+    }
+    exit(status)
+}
+
+/// Exits with the exit code of the test. A test killed by a signal is
+/// reported with the exit code of a panicked test.
+fn exit(status: ExitStatus) -> ! {
+    process::exit(status.code().unwrap_or(101))
+}
+
+/// Returns an identifier of the test, which is the same for all attempts of
+/// the test, since cargo-nextest runs them with the same arguments.
+fn test_id(program: &OsStr, args: &[OsString]) -> String {
+    let mut hasher = DefaultHasher::new();
+    program.hash(&mut hasher);
+    args.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn remove(dir: &Utf8Path, pattern: &str) -> Result<()> {
+    for path in glob::glob(dir.join(pattern).as_str())?.filter_map(Result::ok) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_test() {
+        let args = |name: &str| vec![OsString::from("--exact"), name.into(), "--nocapture".into()];
+        let program = OsStr::new("/target/debug/deps/a-0123");
+        assert_eq!(test_id(program, &args("t1")), test_id(program, &args("t1")));
+        assert_ne!(test_id(program, &args("t1")), test_id(program, &args("t2")));
+        assert_ne!(
+            test_id(program, &args("t1")),
+            test_id(OsStr::new("/target/debug/deps/b-0123"), &args("t1"))
+        );
+    }
+}
@@ -1,19 +1,28 @@
 use std::{
     ffi::OsStr,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use anyhow::{bail, format_err, Context as _, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 
 use crate::{
-    cli::{Args, ManifestOptions, RunOptions},
+    cli::{Args, ManifestOptions, Partition, RunOptions},
     config::Config,
     context::Context,
-    env,
+    env, fs, metadata_cache,
     process::ProcessBuilder,
 };
 
+/// Whether cargo-llvm-cov has been run directly as `cargo-llvm-cov <ARGS>...`,
+/// not by cargo (see `cli::normalize_args`).
+static RUN_DIRECTLY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_run_directly() {
+    RUN_DIRECTLY.store(true, Ordering::Relaxed);
+}
+
 pub(crate) struct Workspace {
     pub(crate) name: String,
     pub(crate) config: Config,
@@ -24,13 +33,23 @@ pub(crate) struct Workspace {
     pub(crate) output_dir: Utf8PathBuf,
     pub(crate) doctests_dir: Utf8PathBuf,
     pub(crate) profdata_file: Utf8PathBuf,
+    /// Key of the profraw files used to generate `profdata_file`.
+    pub(crate) profdata_hash_file: Utf8PathBuf,
 
     cargo: PathBuf,
+    cargo_version: String,
     rustc: ProcessBuilder,
     pub(crate) host_triple: String,
+    /// The rustup toolchain in use, specified by `--toolchain` flag or
+    /// toolchain override shorthand (`+toolchain`).
+    pub(crate) toolchain: Option<String>,
     pub(crate) nightly: bool,
+    /// LLVM version used by rustc.
+    pub(crate) llvm_version: Option<String>,
     /// Whether `-C instrument-coverage` is available.
     pub(crate) stable_coverage: bool,
+    /// Whether `--check-cfg` is available.
+    pub(crate) check_cfg: bool,
 }
 
 impl Workspace {
@@ -40,13 +59,40 @@ impl Workspace {
         doctests: bool,
         show_env: bool,
     ) -> Result<Self> {
-        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
-        let host_triple = host_triple(&cargo)?;
+        // When toolchain override shorthand (`+toolchain`) is used, rustup
+        // sets RUSTUP_TOOLCHAIN environment variable.
+        let toolchain = match &options.toolchain {
+            Some(toolchain) => Some(toolchain.clone()),
+            None => env::var("RUSTUP_TOOLCHAIN")?,
+        };
+        let cargo = match &options.toolchain {
+            // `CARGO` environment variable points to the cargo of the
+            // toolchain cargo-llvm-cov was invoked with, so ask rustup.
+            Some(toolchain) => cmd!("rustup", "which", "cargo", "--toolchain", toolchain)
+                .read()
+                .with_context(|| format!("failed to find cargo for toolchain {}", toolchain))?
+                .into(),
+            // When cargo runs a subcommand, it sets `CARGO` to the cargo of
+            // the toolchain in use. When cargo-llvm-cov is run directly, `CARGO`
+            // may be inherited from an outer cargo process (e.g., of an xtask)
+            // of another toolchain, so use the cargo in PATH, which respects
+            // the toolchain selected for the workspace (e.g., by rust-toolchain.toml).
+            None if RUN_DIRECTLY.load(Ordering::Relaxed) => "cargo".into(),
+            None => env::var_os("CARGO").unwrap_or_else(|| "cargo".into()),
+        };
+        let cargo_version = cargo_version(&cargo)?;
+        let host_triple = host_triple(&cargo_version)?;
 
         // Metadata and config
-        let current_manifest = package_root(&cargo, options.manifest_path.as_deref())?;
-        let metadata = metadata(&cargo, &current_manifest, options)?;
-        let config = Config::new(&cargo, target, Some(&host_triple))?;
+        if options.manifest_path.len() > 1 {
+            bail!(
+                "multiple --manifest-path are only supported by `cargo llvm-cov` without subcommands"
+            );
+        }
+        let current_manifest =
+            package_root(&cargo, options.manifest_path.first().map(Utf8PathBuf::as_path))?;
+        let metadata = metadata(&cargo, &cargo_version, &current_manifest, options)?;
+        let mut config = Config::new(&cargo, target)?;
 
         // The following priorities are not documented, but at as of cargo
         // 1.63.0-nightly (2022-05-31), `RUSTC_WRAPPER` is preferred over `RUSTC_WORKSPACE_WRAPPER`.
@@ -61,10 +107,16 @@ impl Workspace {
             Some(wrapper) => cmd!(wrapper, rustc),
             None => cmd!(rustc),
         };
-        let nightly = rustc_version(&rustc)?;
+        config.resolve_flags(&rustc, &host_triple)?;
+        let (minor, nightly, llvm_version) = rustc_version(&rustc)?;
+        // --check-cfg has been stabilized in Rust 1.80.
+        let check_cfg = minor.map_or(true, |minor| minor >= 80);
 
         if doctests && !nightly {
-            bail!("--doctests flag requires nightly toolchain; consider using `cargo +nightly llvm-cov`")
+            bail!(
+                "--doctests flag requires nightly toolchain; consider using `cargo +nightly llvm-cov` \
+                 or `cargo llvm-cov --toolchain nightly`"
+            )
         }
         let stable_coverage =
             rustc.clone().args(&["-C", "help"]).read()?.contains("instrument-coverage");
@@ -75,8 +127,8 @@ impl Workspace {
             );
         }
 
-        let target_dir = if let Some(path) = env::var("CARGO_LLVM_COV_TARGET_DIR")? {
-            path.into()
+        let target_dir = if let Some(path) = target_dir_from_env()? {
+            path
         } else if show_env {
             metadata.target_directory.clone()
         } else {
@@ -89,6 +141,7 @@ impl Workspace {
 
         let name = metadata.workspace_root.file_name().unwrap().to_owned();
         let profdata_file = target_dir.join(format!("{}.profdata", name));
+        let profdata_hash_file = target_dir.join(format!("{}.profdata.hash", name));
 
         Ok(Self {
             name,
@@ -99,16 +152,72 @@ impl Workspace {
             output_dir,
             doctests_dir,
             profdata_file,
+            profdata_hash_file,
             cargo: cargo.into(),
+            cargo_version,
             rustc,
             host_triple,
+            toolchain,
             nightly,
+            llvm_version,
             stable_coverage,
+            check_cfg,
         })
     }
 
+    /// Uses profile data files specific to the given partition, so that
+    /// partitions sharing the target directory do not overwrite each other's
+    /// files.
+    pub(crate) fn set_partition(&mut self, partition: Partition) {
+        let name = format!("{}-{}", self.name, partition.file_suffix());
+        self.profdata_file = self.target_dir.join(format!("{}.profdata", name));
+        self.profdata_hash_file = self.target_dir.join(format!("{}.profdata.hash", name));
+    }
+
+    /// Returns the pattern of the names of the profile data files generated
+    /// by the tests (`LLVM_PROFILE_FILE`), with `%m` as the placeholder.
+    pub(crate) fn profraw_pattern(&self, partition: Option<Partition>) -> Utf8PathBuf {
+        // With --partition, include the partition in the file name so that
+        // profile data from different partitions of the same test binary do
+        // not conflict when combined into one directory.
+        match partition {
+            Some(partition) => self.target_dir.join(format!(
+                "{}-{}-%m.profraw",
+                self.name,
+                partition.file_suffix()
+            )),
+            None => self.target_dir.join(format!("{}-%m.profraw", self.name)),
+        }
+    }
+
+    /// Returns the directory for the intermediate results of merging a large
+    /// number of profile data files.
+    pub(crate) fn profdata_chunk_dir(&self, partition: Option<Partition>) -> Utf8PathBuf {
+        match partition {
+            Some(partition) => {
+                self.target_dir.join(format!("profdata-chunks-{}", partition.file_suffix()))
+            }
+            None => self.target_dir.join("profdata-chunks"),
+        }
+    }
+
+    /// Returns the directory for the profile data files of the attempts of
+    /// tests retried by cargo-nextest (see `attempts` module).
+    pub(crate) fn attempts_dir(&self, partition: Option<Partition>) -> Utf8PathBuf {
+        match partition {
+            Some(partition) => {
+                self.target_dir.join(format!("{}-attempts-{}", self.name, partition.file_suffix()))
+            }
+            None => self.target_dir.join(format!("{}-attempts", self.name)),
+        }
+    }
+
     pub(crate) fn cargo(&self, verbose: u8) -> ProcessBuilder {
         let mut cmd = cmd!(&self.cargo);
+        if let Some(toolchain) = &self.toolchain {
+            // Make sure rustc and rustdoc invoked by cargo are of the same toolchain.
+            cmd.env("RUSTUP_TOOLCHAIN", toolchain);
+        }
         // cargo displays env vars only with -vv.
         if verbose > 1 {
             cmd.display_env_vars();
@@ -116,6 +225,22 @@ impl Workspace {
         cmd
     }
 
+    /// Runs `cargo metadata --no-deps` for a package outside of the workspace.
+    pub(crate) fn metadata_no_deps(
+        &self,
+        manifest_path: &Utf8Path,
+    ) -> Result<cargo_metadata::Metadata> {
+        let cmd = cmd!(
+            &self.cargo,
+            "metadata",
+            "--format-version=1",
+            "--no-deps",
+            "--manifest-path",
+            manifest_path
+        );
+        metadata_cache::metadata(cmd, &self.cargo_version, manifest_path)
+    }
+
     pub(crate) fn rustc(&self) -> ProcessBuilder {
         self.rustc.clone()
     }
@@ -147,7 +272,10 @@ fn rustc_path(cargo: impl AsRef<Path>) -> PathBuf {
     }
 }
 
-fn rustc_version(rustc: &ProcessBuilder) -> Result<bool> {
+/// Returns whether rustc is nightly and the LLVM version used by rustc.
+/// Returns the minor version (`None` if it is a development build), whether
+/// it is a nightly compiler, and the LLVM version of rustc.
+fn rustc_version(rustc: &ProcessBuilder) -> Result<(Option<u32>, bool, Option<String>)> {
     let mut cmd = rustc.clone();
     cmd.args(&["--version", "--verbose"]);
     let verbose_version = cmd.read()?;
@@ -157,28 +285,73 @@ fn rustc_version(rustc: &ProcessBuilder) -> Result<bool> {
         )?;
     let (_version, channel) = version.split_once('-').unwrap_or_default();
     let nightly = channel == "nightly" || version == "dev";
-    Ok(nightly)
+    let minor = version.split('.').nth(1).and_then(|minor| minor.parse().ok());
+    Ok((minor, nightly, llvm_version(&verbose_version).map(str::to_owned)))
 }
 
-fn host_triple(cargo: &OsStr) -> Result<String> {
-    let mut cmd = cmd!(cargo, "--version", "--verbose");
-    let verbose_version = cmd.read()?;
+/// Parses the LLVM version from the output of `rustc -vV` or `llvm-* --version`.
+pub(crate) fn llvm_version(verbose_version: &str) -> Option<&str> {
+    // Versions of Apple's LLVM do not correspond to the upstream LLVM versions.
+    if verbose_version.contains("Apple") {
+        return None;
+    }
+    let (_, version) = verbose_version.split_once("LLVM version")?;
+    version.trim_start_matches(':').split_whitespace().next()
+}
+
+/// Returns the major version of the given LLVM version.
+pub(crate) fn llvm_major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.parse().ok()
+}
+
+fn cargo_version(cargo: &OsStr) -> Result<String> {
+    cmd!(cargo, "--version", "--verbose").read()
+}
+
+fn host_triple(verbose_version: &str) -> Result<String> {
     let host = verbose_version
         .lines()
         .find_map(|line| line.strip_prefix("host: "))
         .ok_or_else(|| {
-            format_err!("unexpected version output from `{}`: {}", cmd, verbose_version)
+            format_err!(
+                "unexpected version output from `cargo --version --verbose`: {}",
+                verbose_version
+            )
         })?
         .to_owned();
     Ok(host)
 }
 
+/// Returns the target directory set by `CARGO_LLVM_COV_TARGET_DIR`.
+///
+/// A relative path is resolved against the current directory here, because
+/// the test binaries run in the directories of their packages and would
+/// otherwise write the profile data relative to them.
+pub(crate) fn target_dir_from_env() -> Result<Option<Utf8PathBuf>> {
+    match env::var("CARGO_LLVM_COV_TARGET_DIR")? {
+        Some(path) => {
+            let path = Utf8PathBuf::from(path);
+            if path.is_absolute() {
+                return Ok(Some(path));
+            }
+            let current_dir = Utf8PathBuf::try_from(env::current_dir()?)?;
+            Ok(Some(current_dir.join(path)))
+        }
+        None => Ok(None),
+    }
+}
+
 fn package_root(cargo: &OsStr, manifest_path: Option<&Utf8Path>) -> Result<Utf8PathBuf> {
     let package_root = if let Some(manifest_path) = manifest_path {
         manifest_path.to_owned()
     } else {
         locate_project(cargo)?.into()
     };
+    // Resolve symlinks so that paths from cargo metadata, paths passed to
+    // rustc, and the current directory agree on the spelling of the
+    // workspace root. (On Windows, canonicalize returns verbatim paths.)
+    #[cfg(not(windows))]
+    let package_root = Utf8PathBuf::try_from(fs::canonicalize(&package_root)?)?;
     Ok(package_root)
 }
 
@@ -190,17 +363,27 @@ fn locate_project(cargo: &OsStr) -> Result<String> {
 // https://doc.rust-lang.org/nightly/cargo/commands/cargo-metadata.html
 fn metadata(
     cargo: &OsStr,
+    cargo_version: &str,
     manifest_path: &Utf8Path,
     options: &ManifestOptions,
 ) -> Result<cargo_metadata::Metadata> {
     let mut cmd = cmd!(cargo, "metadata", "--format-version=1", "--manifest-path", manifest_path);
     options.cargo_args(&mut cmd);
-    serde_json::from_str(&cmd.read()?)
-        .with_context(|| format!("failed to parse output from {}", cmd))
+    metadata_cache::metadata(cmd, cargo_version, manifest_path)
 }
 
 // https://doc.rust-lang.org/nightly/cargo/commands/cargo-test.html
 pub(crate) fn test_args(cx: &Context, args: &Args, cmd: &mut ProcessBuilder) {
+    test_or_list_args(cx, args, cmd, false);
+}
+
+/// Returns the arguments of `cargo nextest list` that lists the tests run by
+/// `cargo nextest run` with [`test_args`].
+pub(crate) fn nextest_list_args(cx: &Context, args: &Args, cmd: &mut ProcessBuilder) {
+    test_or_list_args(cx, args, cmd, true);
+}
+
+fn test_or_list_args(cx: &Context, args: &Args, cmd: &mut ProcessBuilder, list: bool) {
     let mut has_target_selection_options = false;
     if args.lib {
         has_target_selection_options = true;
@@ -255,10 +438,11 @@ pub(crate) fn test_args(cx: &Context, args: &Args, cmd: &mut ProcessBuilder) {
         cmd.arg("--tests");
     }
 
-    if args.quiet {
+    // These options only affect running tests.
+    if args.quiet && !list {
         cmd.arg("--quiet");
     }
-    if args.no_fail_fast {
+    if args.no_fail_fast && !list {
         cmd.arg("--no-fail-fast");
     }
     for package in &args.package {
@@ -291,6 +475,22 @@ pub(crate) fn test_args(cx: &Context, args: &Args, cmd: &mut ProcessBuilder) {
         cmd.arg(unstable_flag);
     }
 
+    // Only set on nextest; see Args::set_nextest.
+    if let Some(profile) = &cx.build.nextest_profile {
+        cmd.arg("--profile");
+        cmd.arg(profile);
+    }
+    // Only supported by nextest; `cargo test` with --partition or --filter-expr is
+    // rejected in run_test.
+    for expr in &cx.build.filter_expr {
+        cmd.arg("--filter-expr");
+        cmd.arg(expr);
+    }
+    if let Some(partition) = cx.build.partition {
+        cmd.arg("--partition");
+        cmd.arg(partition.nextest_arg());
+    }
+
     if !args.args.is_empty() {
         cmd.arg("--");
         cmd.args(&args.args);
@@ -367,3 +567,23 @@ pub(crate) fn clean_args(cx: &Context, cmd: &mut ProcessBuilder) {
         cmd.arg(format!("-{}", "v".repeat(cx.build.verbose as usize - 1)));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_llvm_version() {
+        let rustc = "rustc 1.62.0 (a8314ef7d 2022-06-27)\nbinary: rustc\ncommit-hash: a8314ef7d0ec7b75c336af2c9857bfaf43002bfc\ncommit-date: 2022-06-27\nhost: x86_64-unknown-linux-gnu\nrelease: 1.62.0\nLLVM version: 14.0.5\n";
+        assert_eq!(llvm_version(rustc), Some("14.0.5"));
+        let llvm_cov = "LLVM (http://llvm.org/):\n  LLVM version 14.0.5-rust-1.62.0-stable\n  Optimized build.\n";
+        assert_eq!(llvm_version(llvm_cov), Some("14.0.5-rust-1.62.0-stable"));
+        let apple = "Apple LLVM version 13.1.6 (clang-1316.0.21.2.5)\n  Optimized build.\n";
+        assert_eq!(llvm_version(apple), None);
+        assert_eq!(llvm_version("rustc 1.62.0\nrelease: 1.62.0\n"), None);
+
+        assert_eq!(llvm_major_version("14.0.5"), Some(14));
+        assert_eq!(llvm_major_version("14.0.5-rust-1.62.0-stable"), Some(14));
+        assert_eq!(llvm_major_version("dev"), None);
+    }
+}
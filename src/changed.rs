@@ -0,0 +1,254 @@
+// Determine workspace members affected by changes since the given revision.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use cargo_metadata::PackageId;
+
+use crate::{context::Context, fs};
+
+/// Files that affect all packages in the workspace.
+const WORKSPACE_FILES: &[&str] =
+    &["Cargo.lock", "Cargo.toml", "rust-toolchain", "rust-toolchain.toml", ".cargo/"];
+
+pub(crate) fn affected_packages(cx: &Context, rev: &str) -> Result<Vec<PackageId>> {
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let mut changed = String::new();
+    // Changed tracked files, relative to the workspace root.
+    let mut cmd = cmd!("git", "diff", "--name-only", "--relative", rev, "--");
+    cmd.dir(workspace_root);
+    changed.push_str(&cmd.read().with_context(|| format!("failed to get changes since {}", rev))?);
+    changed.push('\n');
+    // Untracked files.
+    let mut cmd = cmd!("git", "ls-files", "--others", "--exclude-standard");
+    cmd.dir(workspace_root);
+    changed.push_str(&cmd.read()?);
+
+    let members: Vec<_> = cx
+        .ws
+        .metadata
+        .workspace_members
+        .iter()
+        .map(|id| {
+            let dir = cx.ws.metadata[id].manifest_path.parent().unwrap();
+            (dir.strip_prefix(workspace_root).unwrap_or(dir), id)
+        })
+        .collect();
+    let mut affected = BTreeSet::new();
+    for file in changed.lines().map(str::trim).filter(|f| !f.is_empty()) {
+        if WORKSPACE_FILES.iter().any(|f| file == *f || f.ends_with('/') && file.starts_with(f)) {
+            return Ok(cx.ws.metadata.workspace_members.clone());
+        }
+        if let Some(id) = owner(&members, Utf8Path::new(file)) {
+            affected.insert(id);
+        }
+    }
+
+    // Workspace member -> workspace members that depend on it.
+    let mut dependents: BTreeMap<&PackageId, Vec<&PackageId>> = BTreeMap::new();
+    if let Some(resolve) = &cx.ws.metadata.resolve {
+        for node in &resolve.nodes {
+            if !cx.ws.metadata.workspace_members.contains(&node.id) {
+                continue;
+            }
+            for dep in &node.deps {
+                dependents.entry(&dep.pkg).or_default().push(&node.id);
+            }
+        }
+    }
+    Ok(with_dependents(affected, &dependents).into_iter().cloned().collect())
+}
+
+/// Returns the lines added or modified since the given revision in each file,
+/// including untracked files. The keys are the paths of the files in the
+/// coverage data.
+pub(crate) fn changed_lines(cx: &Context, rev: &str) -> Result<BTreeMap<String, BTreeSet<u64>>> {
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let path = |file: &str| {
+        if cx.build.remap_path_prefix {
+            file.to_owned()
+        } else {
+            workspace_root.join(file).into_string()
+        }
+    };
+    let mut cmd =
+        cmd!("git", "diff", "--unified=0", "--no-color", "--no-ext-diff", "--relative", rev);
+    cmd.dir(workspace_root);
+    let diff = cmd.read().with_context(|| format!("failed to get changes since {}", rev))?;
+    let mut changed: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    for (file, lines) in parse_diff(&diff) {
+        changed.entry(path(file)).or_default().extend(lines);
+    }
+    // Untracked files.
+    let mut cmd = cmd!("git", "ls-files", "--others", "--exclude-standard");
+    cmd.dir(workspace_root);
+    for file in cmd.read()?.lines().map(str::trim).filter(|f| !f.is_empty()) {
+        let lines = match fs::read_to_string(workspace_root.join(file)) {
+            Ok(s) => s.lines().count() as u64,
+            Err(_) => continue,
+        };
+        changed.entry(path(file)).or_default().extend(1..=lines);
+    }
+    Ok(changed)
+}
+
+/// Returns the number of lines changed since the given revision that have
+/// coverage data, and the uncovered ones in each file.
+pub(crate) fn uncovered_changed_lines(
+    cx: &Context,
+    json: &LlvmCovJsonExport,
+    rev: &str,
+) -> Result<(usize, BTreeMap<String, BTreeSet<u64>>)> {
+    let changed = changed_lines(cx, rev)?;
+    let mut count = 0;
+    let mut uncovered: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    for (filename, lines) in json.get_line_coverage() {
+        let changed = match changed.get(&filename) {
+            Some(changed) => changed,
+            None => continue,
+        };
+        for (line, covered) in lines {
+            if !changed.contains(&line) {
+                continue;
+            }
+            count += 1;
+            if !covered {
+                uncovered.entry(filename.clone()).or_default().insert(line);
+            }
+        }
+    }
+    Ok((count, uncovered))
+}
+
+/// Groups the line numbers into ranges of consecutive lines.
+pub(crate) fn line_ranges(lines: &BTreeSet<u64>) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+}
+
+/// Parses the output of `git diff --unified=0` into the added or modified
+/// lines of each file.
+fn parse_diff(diff: &str) -> Vec<(&str, Vec<u64>)> {
+    let mut files: Vec<(&str, Vec<u64>)> = vec![];
+    for line in diff.lines() {
+        if let Some(file) = line.strip_prefix("+++ ") {
+            // `/dev/null` if the file was deleted.
+            if let Some(file) = file.strip_prefix("b/") {
+                files.push((file, vec![]));
+            }
+        } else if let Some(hunk) = line.strip_prefix("@@ ") {
+            // @@ -<start>[,<count>] +<start>[,<count>] @@
+            let new = match hunk.split(' ').find_map(|s| s.strip_prefix('+')) {
+                Some(new) => new,
+                None => continue,
+            };
+            let (start, count) = match new.split_once(',') {
+                Some((start, count)) => (start.parse::<u64>(), count.parse::<u64>()),
+                None => (new.parse::<u64>(), Ok(1)),
+            };
+            if let (Some((_, lines)), Ok(start), Ok(count)) = (files.last_mut(), start, count) {
+                lines.extend(start..start + count);
+            }
+        }
+    }
+    files
+}
+
+/// Returns the package that contains the given file.
+fn owner<'a, T>(members: &[(&Utf8Path, &'a T)], file: &Utf8Path) -> Option<&'a T> {
+    // Use the longest match to handle nested packages.
+    members
+        .iter()
+        .filter(|(dir, _)| file.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.as_str().len())
+        .map(|&(_, id)| id)
+}
+
+fn with_dependents<'a, T: Ord>(
+    mut affected: BTreeSet<&'a T>,
+    dependents: &BTreeMap<&'a T, Vec<&'a T>>,
+) -> BTreeSet<&'a T> {
+    let mut queue: Vec<_> = affected.iter().copied().collect();
+    while let Some(id) = queue.pop() {
+        for &dependent in dependents.get(id).into_iter().flatten() {
+            if affected.insert(dependent) {
+                queue.push(dependent);
+            }
+        }
+    }
+    affected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_package() {
+        let members = [
+            (Utf8Path::new(""), &"root"),
+            (Utf8Path::new("crates/a"), &"a"),
+            (Utf8Path::new("crates/a/b"), &"b"),
+        ];
+        assert_eq!(owner(&members, Utf8Path::new("src/lib.rs")), Some(&"root"));
+        assert_eq!(owner(&members, Utf8Path::new("crates/a/src/lib.rs")), Some(&"a"));
+        assert_eq!(owner(&members, Utf8Path::new("crates/a/b/src/lib.rs")), Some(&"b"));
+        // `crates/ab` is not in `crates/a`.
+        assert_eq!(owner(&members, Utf8Path::new("crates/ab/src/lib.rs")), Some(&"root"));
+        assert_eq!(owner(&members[1..], Utf8Path::new("README.md")), None);
+    }
+
+    #[test]
+    fn parse_git_diff() {
+        let diff = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -3 +3 @@ fn a() {
+-    1
++    2
+@@ -10,0 +11,3 @@ fn b() {
++x
++y
++z
+@@ -20,2 +23,0 @@ fn c() {
+-p
+-q
+diff --git a/old.rs b/old.rs
+deleted file mode 100644
+--- a/old.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-a
+";
+        assert_eq!(parse_diff(diff), [("src/lib.rs", vec![3, 11, 12, 13])]);
+    }
+
+    #[test]
+    fn ranges() {
+        let lines: BTreeSet<u64> = [1, 2, 3, 5, 7, 8].into_iter().collect();
+        assert_eq!(line_ranges(&lines), [(1, 3), (5, 5), (7, 8)]);
+        assert_eq!(line_ranges(&BTreeSet::new()), []);
+    }
+
+    #[test]
+    fn dependents() {
+        let (a, b, c, d) = ("a", "b", "c", "d");
+        // b and c depend on a, d depends on c
+        let dependents: BTreeMap<_, _> =
+            vec![(&a, vec![&b, &c]), (&c, vec![&d])].into_iter().collect();
+        let affected = with_dependents(vec![&c].into_iter().collect(), &dependents);
+        assert_eq!(affected.into_iter().collect::<Vec<_>>(), [&c, &d]);
+        let affected = with_dependents(vec![&a].into_iter().collect(), &dependents);
+        assert_eq!(affected.into_iter().collect::<Vec<_>>(), [&a, &b, &c, &d]);
+    }
+}
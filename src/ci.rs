@@ -0,0 +1,195 @@
+// Detection of CI environments, and integrations with CI services.
+
+use std::{collections::BTreeMap, fmt::Write as _, io::Write as _};
+
+use anyhow::{bail, Result};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+use crate::{
+    cli::BuildOptions,
+    context::Context,
+    env, fs, summary,
+    term::{self, Coloring, LogFormat},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ci {
+    GitHubActions,
+    GitLab,
+    Buildkite,
+}
+
+impl Ci {
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Self::GitHubActions => "GitHub Actions",
+            Self::GitLab => "GitLab CI",
+            Self::Buildkite => "Buildkite",
+        }
+    }
+}
+
+/// Returns the CI environment in which cargo-llvm-cov is running, if any.
+pub(crate) fn detect() -> Option<Ci> {
+    // https://docs.github.com/en/actions/learn-github-actions/environment-variables#default-environment-variables
+    // https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+    // https://buildkite.com/docs/pipelines/environment-variables
+    [("GITHUB_ACTIONS", Ci::GitHubActions), ("GITLAB_CI", Ci::GitLab), ("BUILDKITE", Ci::Buildkite)]
+        .iter()
+        .find(|(key, _)| env::var_os(key).map_or(false, |v| v == "true"))
+        .map(|&(_, ci)| ci)
+}
+
+/// Metadata of a CI build.
+pub(crate) struct Build {
+    /// The name of the service (e.g., `github-actions`).
+    pub(crate) service: &'static str,
+    pub(crate) id: Option<String>,
+    pub(crate) url: Option<String>,
+    pub(crate) job: Option<String>,
+    /// The branch being built.
+    pub(crate) branch: Option<String>,
+}
+
+impl Build {
+    /// Returns the metadata of the build in the given CI environment.
+    pub(crate) fn get(ci: Ci) -> Result<Self> {
+        // https://docs.github.com/en/actions/learn-github-actions/environment-variables#default-environment-variables
+        // https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+        // https://buildkite.com/docs/pipelines/environment-variables
+        Ok(match ci {
+            Ci::GitHubActions => {
+                let server = env::var("GITHUB_SERVER_URL")?
+                    .unwrap_or_else(|| "https://github.com".to_owned());
+                let id = env::var("GITHUB_RUN_ID")?;
+                let url = match (env::var("GITHUB_REPOSITORY")?, &id) {
+                    (Some(repo), Some(id)) => {
+                        Some(format!("{}/{}/actions/runs/{}", server, repo, id))
+                    }
+                    _ => None,
+                };
+                let branch = match env::var("GITHUB_HEAD_REF")? {
+                    Some(branch) => Some(branch),
+                    None => env::var("GITHUB_REF_NAME")?,
+                };
+                Self { service: "github-actions", id, url, job: env::var("GITHUB_JOB")?, branch }
+            }
+            Ci::GitLab => Self {
+                service: "gitlab-ci",
+                id: env::var("CI_PIPELINE_ID")?,
+                url: env::var("CI_JOB_URL")?,
+                job: env::var("CI_JOB_NAME")?,
+                branch: env::var("CI_COMMIT_REF_NAME")?,
+            },
+            Ci::Buildkite => Self {
+                service: "buildkite",
+                id: env::var("BUILDKITE_BUILD_ID")?,
+                url: env::var("BUILDKITE_BUILD_URL")?,
+                job: env::var("BUILDKITE_LABEL")?,
+                branch: env::var("BUILDKITE_BRANCH")?,
+            },
+        })
+    }
+}
+
+/// Runs git in the workspace root and returns its output.
+pub(crate) fn git(cx: &Context, args: &[&str]) -> Result<String> {
+    let mut cmd = cmd!("git");
+    cmd.args(args);
+    cmd.dir(&cx.ws.metadata.workspace_root);
+    Ok(cmd.read()?.trim().to_owned())
+}
+
+/// Applies the defaults for the detected CI environment to the options that
+/// were not specified explicitly, and returns the detected CI environment.
+pub(crate) fn apply_defaults(build: &mut BuildOptions) -> Option<Ci> {
+    let ci = detect()?;
+    term::ci::set(true);
+    if build.color.is_none() && build.log_format != Some(LogFormat::Json) {
+        build.color = Some(Coloring::Always);
+    }
+    Some(ci)
+}
+
+/// Handles --github-output.
+// https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions#setting-an-output-parameter
+pub(crate) fn write_github_output(json: &LlvmCovJsonExport) -> Result<()> {
+    let path = match env::var("GITHUB_OUTPUT")? {
+        Some(path) => path,
+        None => bail!("--github-output requires GITHUB_OUTPUT environment variable, which is set on GitHub Actions"),
+    };
+    let mut out = String::new();
+    for kind in ["lines", "functions", "regions"] {
+        let (count, covered) = json.get_totals(kind)?;
+        let _ = writeln!(out, "{}_percent={:.2}", kind, summary::percent(count, covered));
+        let _ = writeln!(out, "{}_count={}", kind, count);
+        let _ = writeln!(out, "{}_covered={}", kind, covered);
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(out.as_bytes())?;
+    Ok(())
+}
+
+/// Handles `show-env --github-env`.
+// https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions#setting-an-environment-variable
+pub(crate) fn write_github_env(env: &BTreeMap<String, Option<String>>) -> Result<()> {
+    let path = match env::var("GITHUB_ENV")? {
+        Some(path) => path,
+        None => bail!(
+            "--github-env requires GITHUB_ENV environment variable, which is set on GitHub Actions"
+        ),
+    };
+    let mut out = String::new();
+    for (key, value) in env {
+        match value {
+            Some(value) => push_github_env(&mut out, key, value),
+            // GITHUB_ENV can only set variables; setting an empty value is not
+            // equivalent to unsetting, e.g., for CARGO_ENCODED_RUSTFLAGS.
+            None => warn!(
+                "{} cannot be unset via GITHUB_ENV; make sure it is not set in the subsequent steps",
+                key
+            ),
+        }
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(out.as_bytes())?;
+    Ok(())
+}
+
+/// Appends `key=value`, or the multiline syntax if the value contains newlines.
+fn push_github_env(out: &mut String, key: &str, value: &str) {
+    if !value.contains(|c| c == '\n' || c == '\r') {
+        let _ = writeln!(out, "{}={}", key, value);
+        return;
+    }
+    // The delimiter must not appear in the value.
+    let mut delimiter = "EOF".to_owned();
+    while value.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+    let _ = writeln!(out, "{}<<{}", key, delimiter);
+    let _ = writeln!(out, "{}", value);
+    let _ = writeln!(out, "{}", delimiter);
+}
+
+/// Prints the total line coverage for the `coverage` keyword of GitLab CI
+/// (--gitlab).
+// https://docs.gitlab.com/ee/ci/yaml/#coverage
+pub(crate) fn print_gitlab_coverage(json: &LlvmCovJsonExport) -> Result<()> {
+    let (count, covered) = json.get_totals("lines")?;
+    println!("Coverage: {:.1}%", summary::percent(count, covered));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::push_github_env;
+
+    #[test]
+    fn github_env() {
+        let mut out = String::new();
+        push_github_env(&mut out, "A", "a b");
+        push_github_env(&mut out, "B", "x\nEOF\ny");
+        assert_eq!(out, "A=a b\nB<<EOF_\nx\nEOF\ny\nEOF_\n");
+    }
+}
@@ -2,18 +2,23 @@
 // - https://doc.rust-lang.org/nightly/cargo/commands/cargo-clean.html
 // - https://github.com/rust-lang/cargo/blob/0.62.0/src/cargo/ops/cargo_clean.rs
 
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata::PackageId;
 use regex::Regex;
 use walkdir::WalkDir;
 
 use crate::{
     cargo::{self, Workspace},
-    cli::{CleanOptions, ManifestOptions},
+    cli::{CleanOptions, ManifestOptions, Partition},
     context::Context,
-    fs, term,
+    fs, object_list, term,
 };
 
 pub(crate) fn run(mut options: CleanOptions) -> Result<()> {
@@ -21,15 +26,32 @@ pub(crate) fn run(mut options: CleanOptions) -> Result<()> {
     ws.config.merge_to_args(&mut None, &mut options.verbose, &mut options.color);
     term::set_coloring(&mut options.color);
 
-    if !options.workspace {
-        for dir in &[&ws.target_dir, &ws.output_dir] {
-            rm_rf(dir, options.verbose != 0)?;
+    let gc_mode = options.older_than.is_some() || options.max_size.is_some();
+    let summary = options.summary || options.verbose != 0 || options.dry_run || gc_mode;
+    let rm = &mut Remover::new(&ws, options.verbose != 0, options.dry_run, summary);
+
+    if gc_mode {
+        gc(&ws, &options, rm)?;
+    } else if !options.workspace && options.package.is_empty() {
+        rm.rm_rf(&ws.target_dir)?;
+        if !options.keep_reports {
+            rm.rm_rf(&ws.output_dir)?;
+        }
+    } else {
+        if !options.keep_reports {
+            clean_reports(&ws, false, rm)?;
+        }
+        if options.package.is_empty() {
+            clean_ws(&ws, &ws.metadata.workspace_members, &options.manifest, options.verbose, rm)?;
+        } else {
+            let pkg_ids = package_ids(&ws, &options.package)?;
+            clean_packages(&ws, &pkg_ids, &options.manifest, options.verbose, rm)?;
         }
-        return Ok(());
     }
 
-    clean_ws(&ws, &ws.metadata.workspace_members, &options.manifest, options.verbose)?;
-
+    if summary {
+        rm.print_summary();
+    }
     Ok(())
 }
 
@@ -39,13 +61,32 @@ pub(crate) fn run(mut options: CleanOptions) -> Result<()> {
 // - profdata
 // - profraw
 // - doctest bins
-// - old reports
-pub(crate) fn clean_partial(cx: &Context) -> Result<()> {
-    if cx.no_run || cx.cov.no_report {
+// - old reports (except for html report, which is regenerated incrementally)
+//
+// Build artifacts are only removed if the build configuration (`fingerprint`)
+// changed since the last run. Otherwise, cargo overwrites the artifacts of the
+// previous run, so there is no need to rebuild everything.
+//
+// With --partition, only the profdata and profraw of the partition are removed.
+pub(crate) fn clean_partial(cx: &Context, fingerprint: &str) -> Result<()> {
+    if cx.no_run || cx.cov.no_report || cx.build.dry_run {
         return Ok(());
     }
 
-    clean_ws_inner(&cx.ws, &cx.workspace_members.included, cx.build.verbose > 1)?;
+    if let Some(partition) = cx.build.partition {
+        // Other partitions may be running with the same target directory, so
+        // only remove the files of this partition, and keep build artifacts.
+        return clean_partition(cx, partition);
+    }
+    let rm = &mut Remover::new(&cx.ws, cx.build.verbose > 1, false, false);
+    clean_reports(&cx.ws, true, rm)?;
+    clean_ws_inner(&cx.ws, &cx.workspace_members.included, rm)?;
+
+    let fingerprint_file = fingerprint_file(&cx.ws);
+    if fs::read_to_string(&fingerprint_file).ok().as_deref() == Some(fingerprint) {
+        return Ok(());
+    }
+    fs::remove_file(&fingerprint_file)?;
 
     let package_args: Vec<_> = cx
         .workspace_members
@@ -58,18 +99,85 @@ pub(crate) fn clean_partial(cx: &Context) -> Result<()> {
     cargo::clean_args(cx, &mut cmd);
     if let Err(e) = if cx.build.verbose > 1 { cmd.run() } else { cmd.run_with_output() } {
         warn!("{:#}", e);
+        return Ok(());
     }
 
+    fs::create_dir_all(&cx.ws.target_dir)?;
+    fs::write(fingerprint_file, fingerprint)?;
+    Ok(())
+}
+
+fn clean_partition(cx: &Context, partition: Partition) -> Result<()> {
+    let ws = &cx.ws;
+    let rm = &mut Remover::new(ws, cx.build.verbose > 1, false, false);
+    let profraw = ws.profraw_pattern(Some(partition)).as_str().replace("%m", "*");
+    for path in glob::glob(&profraw)?.filter_map(Result::ok) {
+        rm.rm_rf(path)?;
+    }
+    rm.rm_rf(&ws.profdata_file)?;
+    rm.rm_rf(&ws.profdata_hash_file)?;
+    rm.rm_rf(object_list::path(ws, Some(partition)))?;
+    rm.rm_rf(ws.profdata_chunk_dir(Some(partition)))?;
+    rm.rm_rf(ws.attempts_dir(Some(partition)))?;
     Ok(())
 }
 
+fn fingerprint_file(ws: &Workspace) -> Utf8PathBuf {
+    ws.target_dir.join(format!("{}.fingerprint", ws.name))
+}
+
 fn clean_ws(
     ws: &Workspace,
     pkg_ids: &[PackageId],
     manifest: &ManifestOptions,
     verbose: u8,
+    rm: &mut Remover,
+) -> Result<()> {
+    rm.rm_rf(fingerprint_file(ws))?;
+    for path in glob::glob(ws.target_dir.join("metadata-*.json").as_str())?.filter_map(Result::ok) {
+        rm.rm_rf(path)?;
+    }
+    clean_packages(ws, pkg_ids, manifest, verbose, rm)
+}
+
+/// Returns the IDs of the workspace members that match the package specs (`name`
+/// or `name@version`).
+fn package_ids(ws: &Workspace, specs: &[String]) -> Result<Vec<PackageId>> {
+    let mut ids = vec![];
+    for spec in specs {
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec.as_str(), None),
+        };
+        let matched: Vec<_> = ws
+            .metadata
+            .workspace_members
+            .iter()
+            .filter(|id| {
+                let pkg = &ws.metadata[id];
+                pkg.name == name && version.map_or(true, |v| pkg.version.to_string() == v)
+            })
+            .collect();
+        if matched.is_empty() {
+            bail!("package `{}` is not a member of the workspace", spec);
+        }
+        ids.extend(matched.into_iter().cloned());
+    }
+    Ok(ids)
+}
+
+/// Removes the build artifacts of the packages and the profile data.
+///
+/// Unlike `clean_ws`, the fingerprint is kept, so the next run does not
+/// remove the build artifacts of other packages.
+fn clean_packages(
+    ws: &Workspace,
+    pkg_ids: &[PackageId],
+    manifest: &ManifestOptions,
+    verbose: u8,
+    rm: &mut Remover,
 ) -> Result<()> {
-    clean_ws_inner(ws, pkg_ids, verbose != 0)?;
+    clean_ws_inner(ws, pkg_ids, rm)?;
 
     let package_args: Vec<_> =
         pkg_ids.iter().flat_map(|id| ["--package", &ws.metadata[id].name]).collect();
@@ -92,29 +200,166 @@ fn clean_ws(
         }
         manifest.cargo_args(&mut cmd);
         cmd.dir(&ws.metadata.workspace_root);
-        if let Err(e) = if verbose > 0 { cmd.run() } else { cmd.run_with_output() } {
+        if rm.dry_run {
+            status!("Would run", "{}", cmd);
+            continue;
+        }
+        // With the summary, show the files removed by cargo as well.
+        if let Err(e) = if verbose > 0 || rm.summary { cmd.run() } else { cmd.run_with_output() } {
             warn!("{:#}", e);
         }
     }
     Ok(())
 }
 
-fn clean_ws_inner(ws: &Workspace, pkg_ids: &[PackageId], verbose: bool) -> Result<()> {
-    for format in &["html", "text"] {
-        rm_rf(ws.output_dir.join(format), verbose)?;
+/// Removes the reports in the output directory, other than the exported files.
+fn clean_reports(ws: &Workspace, keep_html: bool, rm: &mut Remover) -> Result<()> {
+    for format in &["html", "html.partial", "text"] {
+        // The html report is regenerated incrementally, so keep it if requested.
+        if keep_html && *format == "html" {
+            continue;
+        }
+        rm.rm_rf(ws.output_dir.join(format))?;
     }
+    Ok(())
+}
 
+fn clean_ws_inner(ws: &Workspace, pkg_ids: &[PackageId], rm: &mut Remover) -> Result<()> {
     for path in glob::glob(ws.target_dir.join("*.profraw").as_str())?.filter_map(Result::ok) {
-        rm_rf(path, verbose)?;
+        rm.rm_rf(path)?;
+    }
+    for path in glob::glob(ws.target_dir.join("*.rsp").as_str())?.filter_map(Result::ok) {
+        rm.rm_rf(path)?;
     }
 
-    rm_rf(&ws.doctests_dir, verbose)?;
-    rm_rf(&ws.profdata_file, verbose)?;
+    rm.rm_rf(&ws.doctests_dir)?;
+    rm.rm_rf(&ws.profdata_file)?;
+    rm.rm_rf(&ws.profdata_hash_file)?;
+    rm.rm_rf(object_list::path(ws, None))?;
+    let partitions = ws.target_dir.join(format!("{}.objects-*.json", ws.name));
+    for path in glob::glob(partitions.as_str())?.filter_map(Result::ok) {
+        rm.rm_rf(path)?;
+    }
+    rm.rm_rf(ws.profdata_chunk_dir(None))?;
+    // Files of partitions (see `Workspace::set_partition`) and attempts of
+    // retried tests (see `attempts` module).
+    for pattern in [
+        "profdata-chunks-*",
+        &format!("{}-*.profdata*", ws.name),
+        &format!("{}-attempts*", ws.name),
+    ] {
+        for path in glob::glob(ws.target_dir.join(pattern).as_str())?.filter_map(Result::ok) {
+            rm.rm_rf(path)?;
+        }
+    }
+
+    clean_trybuild_artifacts(ws, pkg_ids, rm)?;
+    Ok(())
+}
 
-    clean_trybuild_artifacts(ws, pkg_ids, verbose)?;
+struct Artifact {
+    path: PathBuf,
+    size: u64,
+    mtime: SystemTime,
+}
+
+/// Removes the coverage artifacts last modified more than `older_than` ago,
+/// and then the least recently modified ones until the total size is at most
+/// `max_size` (--older-than and --max-size).
+fn gc(ws: &Workspace, options: &CleanOptions, rm: &mut Remover) -> Result<()> {
+    let (older_than, max_size) = (options.older_than, options.max_size);
+    let mut artifacts = coverage_artifacts(ws, options.keep_reports)?;
+    artifacts.sort_by_key(|a| a.mtime);
+    let now = SystemTime::now();
+    let mut total: u64 = artifacts.iter().map(|a| a.size).sum();
+    // Oldest first, so the remaining artifacts satisfy both constraints once
+    // an artifact that does not need to be removed is found.
+    for artifact in &artifacts {
+        let expired = older_than.map_or(false, |older_than| {
+            now.duration_since(artifact.mtime).map_or(false, |age| age > older_than)
+        });
+        if !expired && max_size.map_or(true, |max_size| total <= max_size) {
+            break;
+        }
+        rm.rm_rf(&artifact.path)?;
+        total -= artifact.size;
+    }
     Ok(())
 }
 
+/// Returns the coverage artifacts in the target and output directories. Build
+/// artifacts are not included because removing some of them may break
+/// subsequent builds.
+fn coverage_artifacts(ws: &Workspace, keep_reports: bool) -> Result<Vec<Artifact>> {
+    let attempts = format!("{}-attempts", ws.name);
+    let objects = format!("{}.objects", ws.name);
+    let is_coverage_artifact = |path: &Path| {
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        let ext = path.extension().and_then(|e| e.to_str());
+        ext == Some("profraw")
+            || ext == Some("rsp")
+            || name.contains(".profdata")
+            || name.starts_with("profdata-chunks")
+            || name.starts_with(&attempts)
+            || name.starts_with(&objects)
+            || path == ws.doctests_dir
+    };
+    let mut paths = vec![];
+    // Profile data of `show-env` is written to the default target directory.
+    for dir in [&ws.target_dir, &ws.metadata.target_directory] {
+        if dir.is_dir() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if is_coverage_artifact(&path) && !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+    }
+    if !keep_reports && ws.output_dir.is_dir() {
+        for entry in fs::read_dir(&ws.output_dir)? {
+            paths.push(entry?.path());
+        }
+    }
+
+    let mut artifacts = vec![];
+    for path in paths {
+        let (mut size, mut mtime) = (0, SystemTime::UNIX_EPOCH);
+        for entry in WalkDir::new(&path).into_iter().filter_map(Result::ok) {
+            let m = match entry.metadata() {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if m.is_file() {
+                size += m.len();
+            }
+            if let Ok(modified) = m.modified() {
+                mtime = mtime.max(modified);
+            }
+        }
+        artifacts.push(Artifact { path, size, mtime });
+    }
+    Ok(artifacts)
+}
+
+#[allow(clippy::cast_precision_loss)] // only used for display
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    for unit in ["KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{:.1}{}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}TiB", size)
+}
+
 fn pkg_hash_re(ws: &Workspace, pkg_ids: &[PackageId]) -> Regex {
     let mut re = String::from("^(lib)?(");
     let mut first = true;
@@ -132,7 +377,7 @@ fn pkg_hash_re(ws: &Workspace, pkg_ids: &[PackageId]) -> Regex {
     Regex::new(&re).unwrap()
 }
 
-fn clean_trybuild_artifacts(ws: &Workspace, pkg_ids: &[PackageId], verbose: bool) -> Result<()> {
+fn clean_trybuild_artifacts(ws: &Workspace, pkg_ids: &[PackageId], rm: &mut Remover) -> Result<()> {
     let trybuild_dir = &ws.metadata.target_directory.join("tests");
     let trybuild_target = &trybuild_dir.join("target");
     let re = pkg_hash_re(ws, pkg_ids);
@@ -141,34 +386,145 @@ fn clean_trybuild_artifacts(ws: &Workspace, pkg_ids: &[PackageId], verbose: bool
         let path = e.path();
         if let Some(file_stem) = fs::file_stem_recursive(path).unwrap().to_str() {
             if re.is_match(file_stem) {
-                rm_rf(path, verbose)?;
+                rm.rm_rf(path)?;
             }
         }
     }
     Ok(())
 }
 
-fn rm_rf(path: impl AsRef<Path>, verbose: bool) -> Result<()> {
-    let path = path.as_ref();
-    let m = fs::symlink_metadata(path);
-    if m.as_ref().map(fs::Metadata::is_dir).unwrap_or(false) {
-        if verbose {
-            status!("Removing", "{}", path.display());
+/// Removes files and directories, and records the number of files and the
+/// total size of what was removed for the summary.
+struct Remover {
+    verbose: bool,
+    dry_run: bool,
+    summary: bool,
+    output_dir: Utf8PathBuf,
+    removed: BTreeMap<Category, (u64, u64)>,
+    /// Directories that would have been removed, to avoid counting the files
+    /// in them twice with --dry-run.
+    dry_run_dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Category {
+    Profraw,
+    Profdata,
+    Build,
+    Reports,
+}
+
+impl Category {
+    fn of(path: &Path, output_dir: &Utf8Path) -> Self {
+        if path.starts_with(output_dir) {
+            return Self::Reports;
+        }
+        if path.extension() == Some("profraw".as_ref()) {
+            return Self::Profraw;
         }
-        fs::remove_dir_all(path)?;
-    } else if m.is_ok() {
-        if verbose {
-            status!("Removing", "{}", path.display());
+        let profdata = path.components().any(|c| {
+            c.as_os_str()
+                .to_str()
+                .map_or(false, |c| c.contains(".profdata") || c.starts_with("profdata-chunks"))
+        });
+        if profdata {
+            Self::Profdata
+        } else {
+            Self::Build
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Profraw => "profraw",
+            Self::Profdata => "profdata",
+            Self::Build => "build artifacts",
+            Self::Reports => "reports",
+        }
+    }
+}
+
+impl Remover {
+    fn new(ws: &Workspace, verbose: bool, dry_run: bool, summary: bool) -> Self {
+        Self {
+            verbose,
+            dry_run,
+            summary,
+            output_dir: ws.output_dir.clone(),
+            removed: BTreeMap::new(),
+            dry_run_dirs: vec![],
+        }
+    }
+
+    fn rm_rf(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if self.dry_run_dirs.iter().any(|dir| path.starts_with(dir)) {
+            return Ok(());
+        }
+        let m = match fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(_) => return Ok(()),
+        };
+        if self.verbose {
+            status!(if self.dry_run { "Would remove" } else { "Removing" }, "{}", path.display());
+        }
+        if self.summary {
+            for entry in WalkDir::new(path).into_iter().filter_map(Result::ok) {
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                let size = entry.metadata().map_or(0, |m| m.len());
+                let category = Category::of(entry.path(), &self.output_dir);
+                let removed = self.removed.entry(category).or_default();
+                removed.0 += 1;
+                removed.1 += size;
+            }
+        }
+        if self.dry_run {
+            if m.is_dir() {
+                self.dry_run_dirs.push(path.to_owned());
+            }
+        } else if m.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn print_summary(&self) {
+        let verb = if self.dry_run { "Would remove" } else { "Removed" };
+        if self.removed.is_empty() {
+            status!(verb, "no coverage artifacts");
+        }
+        for (category, (count, size)) in &self.removed {
+            let files = if *count == 1 { "file" } else { "files" };
+            status!(verb, "{}: {} {}, {} total", category.name(), count, files, format_size(*size));
         }
-        fs::remove_file(path)?;
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
+    use camino::Utf8Path;
     use regex::Regex;
 
+    use super::Category;
+
+    #[test]
+    fn category() {
+        let output_dir = Utf8Path::new("/t/llvm-cov");
+        let of = |path: &str| Category::of(Path::new(path), output_dir);
+        assert_eq!(of("/t/llvm-cov/html/index.html"), Category::Reports);
+        assert_eq!(of("/t/llvm-cov-target/a-123.profraw"), Category::Profraw);
+        assert_eq!(of("/t/llvm-cov-target/a-attempts/1-1-2-3.profraw"), Category::Profraw);
+        assert_eq!(of("/t/llvm-cov-target/a.profdata"), Category::Profdata);
+        assert_eq!(of("/t/llvm-cov-target/profdata-chunks/0.profdata"), Category::Profdata);
+        assert_eq!(of("/t/llvm-cov-target/debug/deps/a-123"), Category::Build);
+    }
+
     fn pkg_hash_re(pkg_names: &[String]) -> Result<Regex, regex::Error> {
         let mut re = String::from("^(lib)?(");
         let mut first = true;
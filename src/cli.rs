@@ -1,9 +1,12 @@
-use std::mem;
+use std::{ffi::OsString, mem, path::PathBuf, time::Duration};
 
 use camino::Utf8PathBuf;
-use clap::{AppSettings, Parser};
+use clap::{AppSettings, CommandFactory, Parser};
 
-use crate::{process::ProcessBuilder, term::Coloring};
+use crate::{
+    process::ProcessBuilder,
+    term::{Coloring, Failure, LogFormat},
+};
 
 const ABOUT: &str =
     "Cargo subcommand to easily use LLVM source-based code coverage (-C instrument-coverage).
@@ -24,6 +27,19 @@ pub(crate) enum Opts {
     LlvmCov(Args),
 }
 
+/// Returns the arguments in the form in which cargo passes them to the
+/// subcommand (`cargo-llvm-cov llvm-cov <ARGS>...`), and whether
+/// cargo-llvm-cov has been invoked by cargo (as `cargo llvm-cov` or through a
+/// cargo alias) rather than directly as `cargo-llvm-cov <ARGS>...`.
+pub(crate) fn normalize_args(args: impl IntoIterator<Item = OsString>) -> (Vec<OsString>, bool) {
+    let mut args: Vec<_> = args.into_iter().collect();
+    let by_cargo = args.get(1).map_or(false, |arg| arg == "llvm-cov");
+    if !by_cargo && !args.is_empty() {
+        args.insert(1, "llvm-cov".into());
+    }
+    (args, by_cargo)
+}
+
 #[derive(Debug, Parser)]
 #[clap(
     bin_name = "cargo llvm-cov",
@@ -151,6 +167,21 @@ pub(crate) struct Args {
     /// Exclude packages from the report (but not from the test)
     #[clap(long, multiple_occurrences = true, value_name = "SPEC")]
     pub(crate) exclude_from_report: Vec<String>,
+    /// Test only packages affected by changes since the given git revision
+    ///
+    /// Packages that contain changed files and packages that depend on them are tested.
+    /// Changes to workspace-wide files such as Cargo.lock affect all packages.
+    #[clap(
+        long,
+        value_name = "REV",
+        conflicts_with = "package",
+        conflicts_with = "workspace",
+        conflicts_with = "no-run"
+    )]
+    pub(crate) changed_since: Option<String>,
+    /// Report coverage only for packages affected by changes (requires --changed-since)
+    #[clap(long, requires = "changed-since")]
+    pub(crate) report_affected_only: bool,
 
     #[clap(flatten)]
     build: BuildOptions,
@@ -163,8 +194,8 @@ pub(crate) struct Args {
     pub(crate) unstable_flags: Vec<String>,
 
     /// Arguments for the test binary
-    #[clap(last = true)]
-    pub(crate) args: Vec<String>,
+    #[clap(last = true, parse(from_os_str))]
+    pub(crate) args: Vec<OsString>,
 }
 
 impl Args {
@@ -179,6 +210,20 @@ impl Args {
     pub(crate) fn manifest(&mut self) -> ManifestOptions {
         mem::take(&mut self.manifest)
     }
+
+    pub(crate) fn manifest_paths(&self) -> &[Utf8PathBuf] {
+        &self.manifest.manifest_path
+    }
+
+    /// Interprets the arguments as those of `cargo llvm-cov nextest`, in which
+    /// --profile is the nextest profile and --cargo-profile is the profile to
+    /// build artifacts with.
+    pub(crate) fn set_nextest(&mut self) {
+        let build = &mut self.build;
+        build.nextest = true;
+        build.nextest_profile = build.profile.take();
+        build.profile = build.cargo_profile.take();
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -199,6 +244,14 @@ pub(crate) enum Subcommand {
     )]
     ShowEnv(ShowEnvOptions),
 
+    /// Generate coverage report from the coverage data exported by `--no-report --artifact-dir`
+    #[clap(
+        bin_name = "cargo llvm-cov merge-and-report",
+        max_term_width(MAX_TERM_WIDTH),
+        setting(AppSettings::DeriveDisplayOrder)
+    )]
+    MergeAndReport(Box<MergeAndReportOptions>),
+
     /// Remove artifacts that cargo-llvm-cov has generated in the past
     #[clap(
         bin_name = "cargo llvm-cov clean",
@@ -216,8 +269,8 @@ pub(crate) enum Subcommand {
         allow_hyphen_values = true
     )]
     Nextest {
-        #[clap(multiple_values = true)]
-        passthrough_options: Vec<String>,
+        #[clap(multiple_values = true, parse(from_os_str))]
+        passthrough_options: Vec<OsString>,
     },
 
     // internal (unstable)
@@ -228,9 +281,22 @@ pub(crate) enum Subcommand {
         setting(AppSettings::DeriveDisplayOrder)
     )]
     Demangle,
+
+    // internal (unstable)
+    #[clap(
+        bin_name = "cargo llvm-cov nextest-runner",
+        max_term_width(MAX_TERM_WIDTH),
+        hide = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    NextestRunner {
+        #[clap(multiple_values = true, parse(from_os_str))]
+        args: Vec<OsString>,
+    },
 }
 
-#[derive(Debug, Default, Parser)]
+#[derive(Debug, Default, Clone, Parser)]
 pub(crate) struct LlvmCovOptions {
     /// Export coverage data in "json" format
     ///
@@ -240,6 +306,15 @@ pub(crate) struct LlvmCovOptions {
     /// See <https://llvm.org/docs/CommandGuide/llvm-cov.html#llvm-cov-export> for more.
     #[clap(long)]
     pub(crate) json: bool,
+    /// Export a compact record of each function instead of the coverage data of llvm-cov
+    ///
+    /// Each record has the demangled name, the file, the first and last lines, the execution
+    /// count, and the region coverage of a function. The instantiations of a generic function
+    /// are merged into one record. Lines excluded by comment markers are not counted.
+    ///
+    /// This flag can only be used together with --json.
+    #[clap(long, requires = "json", conflicts_with = "summary-only")]
+    pub(crate) functions: bool,
     /// Export coverage data in "lcov" format
     ///
     /// If --output-path is not specified, the report will be printed to stdout.
@@ -248,6 +323,25 @@ pub(crate) struct LlvmCovOptions {
     /// See <https://llvm.org/docs/CommandGuide/llvm-cov.html#llvm-cov-export> for more.
     #[clap(long, conflicts_with = "json")]
     pub(crate) lcov: bool,
+    /// Export the regions of each function in CSV format
+    ///
+    /// If --output-path is not specified, the report will be printed to stdout.
+    ///
+    /// Each row has the filename, the demangled name of the function, the start and end of
+    /// the region, its execution count, and its kind (`code`, `expansion`, `skipped`, `gap`,
+    /// or `branch`). Generic functions have rows for each instantiation.
+    #[clap(
+        long,
+        conflicts_with = "json",
+        conflicts_with = "lcov",
+        conflicts_with = "text",
+        conflicts_with = "html",
+        conflicts_with = "open",
+        conflicts_with = "template",
+        conflicts_with = "summary-only",
+        conflicts_with = "output-dir"
+    )]
+    pub(crate) csv: bool,
 
     /// Generate coverage report in “text” format
     ///
@@ -267,9 +361,41 @@ pub(crate) struct LlvmCovOptions {
     pub(crate) html: bool,
     /// Generate coverage reports in "html" format and open them in a browser after the operation.
     ///
+    /// If a source file is given (e.g., `--open src/parser.rs`), the page of that file is opened
+    /// instead of the index. A module path (e.g., `--open parser` or `--open parser::lexer`) is
+    /// also accepted if it identifies a single file.
+    ///
     /// See --html for more.
-    #[clap(long, conflicts_with = "json", conflicts_with = "lcov", conflicts_with = "text")]
-    pub(crate) open: bool,
+    #[clap(
+        long,
+        value_name = "FILE",
+        min_values = 0,
+        max_values = 1,
+        conflicts_with = "json",
+        conflicts_with = "lcov",
+        conflicts_with = "text"
+    )]
+    #[allow(clippy::option_option)] // --open [FILE]
+    pub(crate) open: Option<Option<String>>,
+    /// Generate coverage report by rendering the given template
+    ///
+    /// If --output-path is not specified, the report will be printed to stdout.
+    ///
+    /// The template uses the Handlebars syntax, and receives the coverage totals
+    /// (`totals.lines.percent`, etc.) and the coverage of each file (`files`). Values are
+    /// HTML-escaped unless inserted with `{{{value}}}`. See the "Report templates" section in
+    /// README for more.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with = "json",
+        conflicts_with = "lcov",
+        conflicts_with = "text",
+        conflicts_with = "html",
+        conflicts_with = "open",
+        conflicts_with = "summary-bars"
+    )]
+    pub(crate) template: Option<Utf8PathBuf>,
 
     /// Export only summary information for each file in the coverage data
     ///
@@ -279,7 +405,7 @@ pub(crate) struct LlvmCovOptions {
     pub(crate) summary_only: bool,
     /// Specify a file to write coverage data into.
     ///
-    /// This flag can only be used together with --json, --lcov, or --text.
+    /// This flag can only be used together with --json, --lcov, --csv, --text, or --template.
     /// See --output-dir for --html and --open.
     #[clap(
         long,
@@ -303,27 +429,151 @@ pub(crate) struct LlvmCovOptions {
         forbid_empty_values = true
     )]
     pub(crate) output_dir: Option<Utf8PathBuf>,
+    /// Show only functions whose names match the regex in text and html reports
+    ///
+    /// This is passed to `llvm-cov show -name-regex`. Names are matched in
+    /// mangled form, so a path component (e.g., `parser` or `Parser`) is usually
+    /// more reliable than a full path with `::`.
+    ///
+    /// This flag can only be used together with --text, --html, or --open.
+    // If the format flag is not specified, this flag is no-op.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        conflicts_with = "json",
+        conflicts_with = "lcov",
+        forbid_empty_values = true
+    )]
+    pub(crate) function_regex: Option<String>,
+    /// Show only the uncovered lines in the text report, with some lines of context around them
+    ///
+    /// Instead of whole annotated source files, only the hunks of uncovered lines are printed,
+    /// which is easier to review in a terminal or to paste into a pull request. Lines excluded
+    /// by comment markers are not shown as uncovered.
+    ///
+    /// This flag can only be used together with --text.
+    #[clap(
+        long,
+        requires = "text",
+        conflicts_with = "output-dir",
+        conflicts_with = "function-regex"
+    )]
+    pub(crate) uncovered_only: bool,
+    /// Number of lines of context shown around uncovered lines (default to 3)
+    #[clap(long, value_name = "N", requires = "uncovered-only")]
+    pub(crate) context: Option<usize>,
 
     /// Fail if `any` or `all` profiles cannot be merged (default to `any`)
     #[clap(long, value_name = "any|all", possible_values(&["any", "all"]), hide_possible_values = true)]
     pub(crate) failure_mode: Option<String>,
+    /// Do not use sparse mode when merging profiles
+    ///
+    /// By default, profiles are merged in sparse mode, which omits records of
+    /// functions that were never executed. This makes the merged profile smaller
+    /// and merging faster, and does not affect the coverage report. Use this flag
+    /// if you want to use the merged profile for other purposes that need all records.
+    #[clap(long)]
+    pub(crate) no_sparse: bool,
     /// Skip source code files with file paths that match the given regular expression.
     #[clap(long, value_name = "PATTERN", forbid_empty_values = true)]
     pub(crate) ignore_filename_regex: Option<String>,
+    /// Include dependencies whose package names match the given glob pattern in the report
+    ///
+    /// By default, the sources of dependencies from registries and git
+    /// repositories (including forks used via `[patch]`) are excluded from the
+    /// report, as are dependencies in the home directory when
+    /// --remap-path-prefix is used. This flag includes the sources of the
+    /// matching dependencies, such as vendored or patched crates.
+    ///
+    /// This flag can be used multiple times.
+    #[clap(
+        long,
+        value_name = "GLOB",
+        multiple_occurrences = true,
+        parse(try_from_str = glob::Pattern::new)
+    )]
+    pub(crate) include_vendored: Vec<glob::Pattern>,
     // For debugging (unstable)
     #[clap(long, hide = true)]
     pub(crate) disable_default_ignore_filename_regex: bool,
     /// Hide instantiations from report
     #[clap(long)]
     pub(crate) hide_instantiations: bool,
+    /// Exclude the code generated by derive macros from coverage
+    ///
+    /// rustc attributes the code generated by derive macros to the lines of `#[derive(...)]`
+    /// attributes, so derived impls that are never called make these lines uncovered. With this
+    /// flag, these lines are excluded as if they were marked with `// llvm-cov: ignore-line`.
+    /// The code generated by function-like macros cannot be told apart from the code around
+    /// the macro call, so use comment markers for them if needed.
+    #[clap(long)]
+    pub(crate) exclude_derives: bool,
+    /// Exclude lines that only panic from coverage, and list them
+    ///
+    /// Lines that consist solely of `unreachable!(..)` or `panic!(..)` (optionally as a match
+    /// arm, e.g., `_ => unreachable!(),`) and the bodies of `#[cold]` functions are excluded as
+    /// if they were marked with `// llvm-cov: ignore-line`. The excluded lines are listed after
+    /// the report for audit.
+    #[clap(long)]
+    pub(crate) exclude_unreachable: bool,
     /// Unset cfg(coverage), which is enabled when code is built using cargo-llvm-cov.
     #[clap(long)]
     pub(crate) no_cfg_coverage: bool,
+    /// Set cfg(NAME) instead of cfg(coverage) when code is built using cargo-llvm-cov
+    ///
+    /// This is useful if the project already uses `coverage` as a cfg or a feature for other
+    /// purposes. NAME is also declared as an expected cfg with `--check-cfg` (see --no-check-cfg).
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with = "no-cfg-coverage",
+        parse(try_from_str = parse_cfg_name)
+    )]
+    pub(crate) cfg_coverage_name: Option<String>,
     /// Unset cfg(coverage_nightly), which is enabled when code is built using cargo-llvm-cov and nightly compiler.
     #[clap(long)]
     pub(crate) no_cfg_coverage_nightly: bool,
-    /// Run tests, but don't generate coverage report
+    /// Do not pass `--check-cfg` for `cfg(coverage)` and `cfg(coverage_nightly)`.
+    ///
+    /// By default, these cfgs are declared as expected on Rust 1.80+ so that the
+    /// `unexpected_cfgs` lint does not warn about them.
     #[clap(long)]
+    pub(crate) no_check_cfg: bool,
+    /// Run tests, but don't generate coverage report
+    #[clap(
+        long,
+        conflicts_with = "json",
+        conflicts_with = "lcov",
+        conflicts_with = "text",
+        conflicts_with = "html",
+        conflicts_with = "open",
+        conflicts_with = "summary-only",
+        conflicts_with = "output-path",
+        conflicts_with = "output-dir",
+        conflicts_with = "fail-under-lines",
+        conflicts_with = "fail-uncovered-lines",
+        conflicts_with = "fail-uncovered-regions",
+        conflicts_with = "fail-uncovered-functions",
+        conflicts_with = "policy",
+        conflicts_with = "show-missing-lines",
+        conflicts_with = "show-functions",
+        conflicts_with = "unsafe-coverage",
+        conflicts_with = "summary-bars",
+        conflicts_with = "gutters",
+        conflicts_with = "github-output",
+        conflicts_with = "post-process",
+        conflicts_with = "codecov",
+        conflicts_with = "pr-comment",
+        conflicts_with = "annotations",
+        conflicts_with = "gitlab",
+        conflicts_with = "azure",
+        conflicts_with = "buildkite-annotation",
+        conflicts_with = "notify-url",
+        conflicts_with = "per-test",
+        conflicts_with = "uncovered-spans",
+        conflicts_with = "template",
+        conflicts_with = "csv"
+    )]
     pub(crate) no_report: bool,
     /// Exit with a status of 1 if the total line coverage is less than MIN percent.
     #[clap(long, value_name = "MIN")]
@@ -337,12 +587,256 @@ pub(crate) struct LlvmCovOptions {
     /// Exit with a status of 1 if the uncovered functions are greater than MAX.
     #[clap(long, value_name = "MAX")]
     pub(crate) fail_uncovered_functions: Option<u64>,
+    /// Exit with a status of 1 if the coverage does not satisfy the rules in the given policy file
+    ///
+    /// The policy is a JSON or TOML (if the extension is `.toml`) file with threshold
+    /// rules for the total, each file, each package, or the changed lines, e.g.,
+    /// `{"rules": [{"kind": "lines", "min-percent": 80, "scope": "package"}]}`.
+    /// See the documentation of `cargo_llvm_cov_core::policy` for the format.
+    ///
+    /// If this flag is not passed, the policy file is read from `CARGO_LLVM_COV_POLICY`
+    /// environment variable, so that CI environments can apply a policy shared across
+    /// repositories without changing the commands.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) policy: Option<Utf8PathBuf>,
+    /// Git revision to compare with for the rules of the `diff` scope in --policy (default to HEAD)
+    #[clap(long, value_name = "REV")]
+    pub(crate) policy_base: Option<String>,
+    /// Exit with a status of CODE on the given kind of failure (can be specified multiple times).
+    ///
+    /// KIND is one of `test` (tests failed), `threshold` (the coverage does not satisfy
+    /// --fail-under-lines or --fail-uncovered-*), or `report` (other errors, e.g., failed to
+    /// generate the report). The default exit status for all kinds is 1.
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        value_name = "KIND=CODE",
+        parse(try_from_str = parse_failure_exit_code)
+    )]
+    pub(crate) failure_exit_code: Vec<(Failure, u8)>,
     /// Show lines with no coverage.
     #[clap(long)]
     pub(crate) show_missing_lines: bool,
+    /// Show a compact summary with a bar of line coverage per package, instead of the table of llvm-cov
+    ///
+    /// This flag can be used together with --html, but not with other report formats.
+    /// See also --group-by.
+    #[clap(long, conflicts_with = "json", conflicts_with = "lcov", conflicts_with = "text")]
+    pub(crate) summary_bars: bool,
+    /// Group the coverage in --summary-bars by package (default) or by module path
+    ///
+    /// With `module`, each file in the targets of the workspace members is shown as a
+    /// module path (e.g., `foo::parser::lexer` for `src/parser/lexer.rs` of the library
+    /// `foo`), which is derived from the path of the file relative to the target's root.
+    /// Files that are included with `#[path]` attribute or `include!` are grouped by their
+    /// paths as well.
+    #[clap(long, arg_enum, value_name = "package|module", requires = "summary-bars")]
+    pub(crate) group_by: Option<GroupBy>,
+    /// Count the coverage of submodules in their ancestor at depth N in --group-by module
+    ///
+    /// The depth includes the crate name: with `--module-depth 2`, `foo::parser::lexer` is
+    /// counted in `foo::parser`.
+    #[clap(long, value_name = "N", requires = "group-by")]
+    pub(crate) module_depth: Option<usize>,
+    /// Line coverage (in percent) from which the bars of --summary-bars are colored yellow and green
+    #[clap(
+        long,
+        value_name = "LOW,HIGH",
+        requires = "summary-bars",
+        parse(try_from_str = parse_bar_thresholds)
+    )]
+    pub(crate) bar_thresholds: Option<(f64, f64)>,
+    /// Show the N functions (default: 10) with the most uncovered regions
+    ///
+    /// Functions are shown with their location, demangled names, and the number of
+    /// covered regions. Fully covered functions are not shown.
+    #[clap(long, value_name = "N", min_values = 0, max_values = 1)]
+    #[allow(clippy::option_option)] // --show-functions [N]
+    pub(crate) show_functions: Option<Option<usize>>,
+    /// Show functions excluded from coverage by `#[coverage(off)]` or `#[no_coverage]` attribute.
+    #[clap(long)]
+    pub(crate) show_excluded_functions: bool,
+    /// Show the line coverage of unsafe code per file after the report
+    ///
+    /// The lines of `unsafe` blocks and the bodies of `unsafe fn`s are found by parsing
+    /// the source files, and the covered and uncovered lines of them are counted. Unsafe
+    /// code in the arguments of macro invocations (e.g., `println!`) is not counted.
+    #[clap(long)]
+    pub(crate) unsafe_coverage: bool,
+    /// Make file paths printed by cargo-llvm-cov hyperlinks (OSC 8)
+    ///
+    /// By default, hyperlinks are used if stdout is a terminal and coloring is not disabled.
+    #[clap(long, arg_enum, value_name = "WHEN")]
+    pub(crate) hyperlinks: Option<Coloring>,
+    /// URL of hyperlinks, with `{path}` and `{line}` placeholders (e.g., `vscode://file{path}:{line}`)
+    #[clap(long, value_name = "FORMAT", default_value = "file://{path}")]
+    pub(crate) hyperlink_format: String,
+    /// Write the coverage totals to `$GITHUB_OUTPUT` as outputs of the GitHub Actions step
+    ///
+    /// `lines_percent`, `functions_percent`, `regions_percent`, and the
+    /// corresponding `*_count` and `*_covered` are written, so that the
+    /// following steps can use them (e.g., `steps.<id>.outputs.lines_percent`).
+    #[clap(long)]
+    pub(crate) github_output: bool,
+    /// Write a Markdown summary of the coverage to PATH, for posting as a pull request comment
+    ///
+    /// The summary contains the total coverage and the line coverage of each
+    /// package, and can be posted as is via the comment APIs of GitHub and
+    /// GitLab. Its first line is `<!-- cargo-llvm-cov -->`, so that the previous
+    /// comment can be found and updated.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) pr_comment: Option<Utf8PathBuf>,
+    /// Show the changes in coverage since the given JSON report in --pr-comment
+    ///
+    /// The report is generated by `cargo llvm-cov --json` on the base branch.
+    #[clap(long, value_name = "PATH", requires = "pr-comment")]
+    pub(crate) pr_comment_base: Option<Utf8PathBuf>,
+    /// List the lines changed since the given git revision that are not covered in --pr-comment
+    #[clap(long, value_name = "REV", requires = "pr-comment")]
+    pub(crate) pr_comment_diff: Option<String>,
+    /// Write annotations of the uncovered changed lines to PATH, in the format of the GitHub Checks API
+    ///
+    /// The file is a JSON array of annotation objects (with `path`, `start_line`,
+    /// `end_line`, `annotation_level`, and `message`), which can be attached to a
+    /// check run as is.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) annotations: Option<Utf8PathBuf>,
+    /// Git revision to compare with for --annotations (default to HEAD)
+    #[clap(long, value_name = "REV", requires = "annotations")]
+    pub(crate) annotations_diff: Option<String>,
+    /// Integrate with the coverage features of GitLab merge requests
+    ///
+    /// This prints the total line coverage as `Coverage: XX.X%`, which can be
+    /// extracted with `coverage: '/^Coverage: \d+\.\d+%/'` in `.gitlab-ci.yml`, and
+    /// writes a Cobertura report to `cobertura.xml` in the workspace root, for
+    /// the `coverage_report` artifact that shows the coverage in the diff of
+    /// merge requests.
+    #[clap(long)]
+    pub(crate) gitlab: bool,
+    /// Generate reports in the layout expected by the `PublishCodeCoverageResults` task of Azure Pipelines
+    ///
+    /// This implies --html, and also writes a Cobertura report to `cobertura.xml`
+    /// and a Visual Studio coverage XML summary (the XML form of `.coverage`
+    /// files) to `summary.coverage.xml` next to the `html` directory (in
+    /// `target/llvm-cov` if --output-dir is not specified). Pass one of the
+    /// summary files as `summaryFileLocation` and the `html` directory as
+    /// `reportDirectory` of the task.
+    #[clap(long, conflicts_with = "json", conflicts_with = "lcov", conflicts_with = "text")]
+    pub(crate) azure: bool,
+    /// Write a Markdown summary of the coverage to PATH, for `buildkite-agent annotate`
+    ///
+    /// The summary contains the total coverage, the line coverage of each package,
+    /// and a link to the HTML report if --html is also passed (the report needs to
+    /// be uploaded as an artifact).
+    #[clap(long, value_name = "PATH")]
+    pub(crate) buildkite_annotation: Option<Utf8PathBuf>,
+    /// Upload the coverage to Codecov
+    ///
+    /// The token is read from `CODECOV_TOKEN` environment variable. On GitHub Actions, the token
+    /// is optional: if the workflow has `id-token: write` permission, an OIDC token is used, and
+    /// otherwise the upload is tokenless, which Codecov accepts for public repositories.
+    /// This requires curl.
+    #[clap(long)]
+    pub(crate) codecov: bool,
+    /// Flag of the coverage uploaded to Codecov (can be specified multiple times)
+    #[clap(long, multiple_occurrences = true, value_name = "FLAG", requires = "codecov")]
+    pub(crate) codecov_flag: Vec<String>,
+    /// Name of the upload to Codecov
+    #[clap(long, value_name = "NAME", requires = "codecov")]
+    pub(crate) codecov_name: Option<String>,
+    /// Also write an lcov report to `lcov.info` in the workspace root, for editor extensions
+    ///
+    /// The file is replaced atomically after each run, so that extensions that
+    /// watch it (e.g., Coverage Gutters for VS Code) update their display
+    /// without any other flags. This is useful together with tools that re-run
+    /// commands on file changes, such as cargo-watch.
+    #[clap(long)]
+    pub(crate) gutters: bool,
+    /// Run COMMAND after generating reports, e.g., to upload them
+    ///
+    /// The command is run by the shell in the workspace root. The paths of the
+    /// saved reports are passed as `CARGO_LLVM_COV_REPORT_<FORMAT>` environment
+    /// variables, and a JSON object with the report paths and the coverage
+    /// totals is written to its stdin.
+    #[clap(long, value_name = "COMMAND")]
+    pub(crate) post_process: Option<String>,
+    /// POST the coverage summary as JSON to URL after generating reports
+    ///
+    /// The summary contains the coverage totals, whether the coverage
+    /// thresholds were satisfied, and metadata of the git commit and the CI
+    /// build. A failed request is reported as a warning. This requires curl.
+    #[clap(long, value_name = "URL")]
+    pub(crate) notify_url: Option<String>,
+    /// Copy the saved reports and the JUnit XML of cargo-nextest to DIR, for collecting them as one CI artifact
+    ///
+    /// The JUnit XML is found in the store directory of cargo-nextest in the
+    /// target directory, and needs to be enabled in the nextest configuration
+    /// (`[profile.<name>.junit]`). A `manifest.json` that lists the copied files
+    /// and the IDs and results of the tests in the JUnit XML is also written to DIR.
+    ///
+    /// With --no-report, the profile data and the object files of the run are copied to DIR
+    /// instead, and the report can be generated from the directories of multiple runs (e.g.,
+    /// CI jobs that test different packages) with `cargo llvm-cov merge-and-report <DIR>...`.
+    #[clap(long, value_name = "DIR")]
+    pub(crate) artifact_dir: Option<Utf8PathBuf>,
+    /// Write the lines covered by each test to PATH as JSON
+    ///
+    /// This is only supported by `cargo llvm-cov nextest`, which runs each test in its own
+    /// process: the tests are run through cargo-llvm-cov as the target runner so that the
+    /// profile data of each test is written to its own files, and the tests are identified by
+    /// `cargo nextest list`. Generating the mapping exports the coverage of each test
+    /// separately, which takes a while on large test suites.
+    ///
+    /// If the html report is also generated, hovering a covered line in it shows the tests
+    /// that executed the line.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) per_test: Option<Utf8PathBuf>,
+    /// Write the spans of uncovered code to PATH as JSON, for mutation testing tools
+    ///
+    /// Tools like cargo-mutants can use this to skip mutants in code that no test executes.
+    /// The output lists the instrumented files, and the uncovered code regions of each file
+    /// with their functions and line/column ranges. The format is versioned and stable; see
+    /// "Export uncovered spans" in README.md for details.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) uncovered_spans: Option<Utf8PathBuf>,
     /// Include build script in coverage report.
     #[clap(long)]
     pub(crate) include_build_script: bool,
+    /// Report the wall time spent on each step
+    ///
+    /// The time spent on building, running tests, merging profile data, and
+    /// generating each report is printed at the end and written to
+    /// `target/llvm-cov/timings.json`.
+    /// This is unrelated to the `--timings` flag of cargo.
+    #[clap(long)]
+    pub(crate) timings: bool,
+    /// Install llvm-tools-preview component if it is not installed
+    ///
+    /// This runs `rustup component add llvm-tools-preview` for the toolchain in use.
+    /// If this flag is not passed and stdin is a terminal, cargo-llvm-cov asks
+    /// whether to install it.
+    #[clap(long)]
+    pub(crate) auto_install_llvm_tools: bool,
+    /// Download llvm-tools if llvm-tools-preview component is not installed
+    ///
+    /// This downloads llvm-tools of the same build as rustc from the Rust
+    /// distribution server without using rustup, verifies its checksum, and
+    /// caches it in `$CARGO_HOME/cargo-llvm-cov/llvm-tools`.
+    /// This requires `curl` and `tar` commands.
+    #[clap(long, conflicts_with = "auto-install-llvm-tools")]
+    pub(crate) download_llvm_tools: bool,
+    /// Path to llvm-cov to use instead of the one in llvm-tools-preview
+    ///
+    /// This takes precedence over `LLVM_COV` environment variable.
+    /// The LLVM version of llvm-cov must be the same as the LLVM version used in rustc.
+    #[clap(long, value_name = "PATH", forbid_empty_values = true)]
+    pub(crate) llvm_cov_path: Option<PathBuf>,
+    /// Path to llvm-profdata to use instead of the one in llvm-tools-preview
+    ///
+    /// This takes precedence over `LLVM_PROFDATA` environment variable.
+    /// The LLVM version of llvm-profdata must be the same as the LLVM version used in rustc.
+    #[clap(long, value_name = "PATH", forbid_empty_values = true)]
+    pub(crate) llvm_profdata_path: Option<PathBuf>,
 }
 
 impl LlvmCovOptions {
@@ -351,9 +845,13 @@ impl LlvmCovOptions {
     }
 }
 
-#[derive(Debug, Default, Parser)]
+#[derive(Debug, Default, Clone, Parser)]
 pub(crate) struct BuildOptions {
     /// Number of parallel jobs, defaults to # of CPUs
+    ///
+    /// This is also passed to llvm-profdata and llvm-cov as `-num-threads`, and bounds the
+    /// number of llvm-cov processes that run at the same time: the one generating the report,
+    /// and the exports of the coverage data for checks and lcov-based outputs.
     // Max value is u32::MAX: https://github.com/rust-lang/cargo/blob/0.62.0/src/cargo/util/command_prelude.rs#L356
     #[clap(short, long, value_name = "N")]
     pub(crate) jobs: Option<u32>,
@@ -361,8 +859,26 @@ pub(crate) struct BuildOptions {
     #[clap(short, long)]
     pub(crate) release: bool,
     /// Build artifacts with the specified profile
+    ///
+    /// On `cargo llvm-cov nextest`, this is the nextest profile; use --cargo-profile to
+    /// specify the profile to build artifacts with.
     #[clap(long, value_name = "PROFILE-NAME")]
     pub(crate) profile: Option<String>,
+    /// Build artifacts with the specified profile on `cargo llvm-cov nextest`
+    #[clap(long, value_name = "PROFILE-NAME")]
+    pub(crate) cargo_profile: Option<String>,
+    /// Keep the `strip` setting of the profiles used to build the instrumented code
+    ///
+    /// By default, `strip` of these profiles is set to `"none"`, because llvm-cov can miss
+    /// files or functions in stripped binaries, which results in reports that silently lack
+    /// them.
+    #[clap(long)]
+    pub(crate) allow_stripped: bool,
+    /// The nextest profile (--profile on `cargo llvm-cov nextest`).
+    #[clap(skip)]
+    pub(crate) nextest_profile: Option<String>,
+    #[clap(skip)]
+    nextest: bool,
     /// Space or comma separated list of features to activate
     #[clap(short = 'F', long, multiple_occurrences = true, value_name = "FEATURES")]
     pub(crate) features: Vec<String>,
@@ -401,6 +917,63 @@ pub(crate) struct BuildOptions {
     // This flag will be propagated to both cargo and llvm-cov.
     #[clap(long, arg_enum, value_name = "WHEN")]
     pub(crate) color: Option<Coloring>,
+    /// Format of diagnostics printed by cargo-llvm-cov
+    ///
+    /// With `json`, diagnostics, the start and end of each phase, produced
+    /// reports, and the coverage summary are printed to stderr as line-delimited
+    /// JSON. The output of cargo and tests is not affected.
+    #[clap(long, arg_enum, value_name = "FMT")]
+    pub(crate) log_format: Option<LogFormat>,
+
+    /// Error format, passed to cargo
+    ///
+    /// With `json` (or another json-based format accepted by cargo), messages of
+    /// cargo-llvm-cov (`llvm-cov-artifact`, `llvm-cov-summary`, and
+    /// `llvm-cov-finished`) are printed to stdout as JSON after cargo's messages.
+    /// As with cargo, the output of tests and reports printed to stdout is not
+    /// JSON.
+    #[clap(long, multiple_occurrences = true, value_name = "FMT")]
+    pub(crate) message_format: Vec<String>,
+
+    /// Print the commands that would be executed, without running them
+    ///
+    /// Environment variables set by cargo-llvm-cov are printed with the
+    /// commands. Since test binaries are not built, object files passed to
+    /// llvm-cov are shown as a placeholder.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+    /// Run only the M-th of N partitions of the tests, for sharding tests across CI jobs
+    ///
+    /// This is passed to cargo-nextest as `--partition count:M/N` (`hash:M/N` is also
+    /// accepted), and is only supported by `cargo llvm-cov nextest`. The profile data and the
+    /// list of object files are named after the partition, so that the target directories of
+    /// all partitions can be combined into one, and the report for all partitions can be
+    /// generated by `cargo llvm-cov --no-run`.
+    #[clap(long, value_name = "M/N", parse(try_from_str = parse_partition))]
+    pub(crate) partition: Option<Partition>,
+    /// Run only the tests matching the nextest filter expression (can be specified multiple times)
+    ///
+    /// This is passed to cargo-nextest as `--filter-expr`, and is only supported by
+    /// `cargo llvm-cov nextest`. The expressions are recorded along with the list of object
+    /// files, and reports generated from the filtered tests (including those generated later
+    /// by `--no-run`) are labeled as partial coverage.
+    #[clap(
+        short = 'E',
+        long,
+        multiple_occurrences = true,
+        value_name = "EXPR",
+        parse(try_from_str = parse_filter_expr)
+    )]
+    pub(crate) filter_expr: Vec<String>,
+    /// Which attempts of tests retried by cargo-nextest to include in the coverage [default: all]
+    ///
+    /// With `last`, only the profile data of the last attempt of each test is merged, and with
+    /// `passed`, only that of the passing attempts, so that flaky tests do not inflate the
+    /// execution counts. This is only supported by `cargo llvm-cov nextest`, and runs the tests
+    /// through cargo-llvm-cov as the target runner, so it cannot be used together with another
+    /// target runner.
+    #[clap(long, arg_enum, value_name = "WHICH")]
+    pub(crate) merge_attempts: Option<MergeAttempts>,
 
     /// Use --remap-path-prefix for workspace root
     ///
@@ -409,14 +982,20 @@ pub(crate) struct BuildOptions {
     pub(crate) remap_path_prefix: bool,
     /// Include coverage of C/C++ code linked to Rust library/binary
     ///
-    /// Note that `CC`/`CXX`/`LLVM_COV`/`LLVM_PROFDATA` environment variables
-    /// must be set to Clang/LLVM compatible with the LLVM version used in rustc.
+    /// Note that `CC`/`CXX`/`LLVM_COV`/`LLVM_PROFDATA` environment variables (or
+    /// --llvm-cov-path and --llvm-profdata-path flags) must be set to Clang/LLVM
+    /// compatible with the LLVM version used in rustc.
     // TODO: support specifying languages like: --include-ffi=c,  --include-ffi=c,c++
     #[clap(long)]
     pub(crate) include_ffi: bool,
 }
 
 impl BuildOptions {
+    /// Returns `true` if a json-based `--message-format` is passed.
+    pub(crate) fn message_format_json(&self) -> bool {
+        self.message_format.iter().flat_map(|f| f.split(',')).any(|f| f.starts_with("json"))
+    }
+
     pub(crate) fn cargo_args(&self, cmd: &mut ProcessBuilder) {
         if let Some(jobs) = self.jobs {
             cmd.arg("--jobs");
@@ -426,7 +1005,7 @@ impl BuildOptions {
             cmd.arg("--release");
         }
         if let Some(profile) = &self.profile {
-            cmd.arg("--profile");
+            cmd.arg(if self.nextest { "--cargo-profile" } else { "--profile" });
             cmd.arg(profile);
         }
         for features in &self.features {
@@ -448,6 +1027,10 @@ impl BuildOptions {
             cmd.arg("--color");
             cmd.arg(color.cargo_color());
         }
+        for format in &self.message_format {
+            cmd.arg("--message-format");
+            cmd.arg(format);
+        }
 
         // If `-vv` is passed, propagate `-v` to cargo.
         if self.verbose > 1 {
@@ -485,8 +1068,8 @@ pub(crate) struct RunOptions {
     pub(crate) unstable_flags: Vec<String>,
 
     /// Arguments for the test binary
-    #[clap(last = true)]
-    pub(crate) args: Vec<String>,
+    #[clap(last = true, parse(from_os_str))]
+    pub(crate) args: Vec<OsString>,
 }
 
 impl RunOptions {
@@ -503,11 +1086,99 @@ impl RunOptions {
     }
 }
 
+#[derive(Debug, Parser)]
+pub(crate) struct MergeAndReportOptions {
+    #[clap(flatten)]
+    cov: LlvmCovOptions,
+
+    #[clap(flatten)]
+    build: BuildOptions,
+
+    #[clap(flatten)]
+    manifest: ManifestOptions,
+
+    /// Directories of the coverage data exported by `--no-report --artifact-dir`
+    #[clap(required = true, value_name = "DIR")]
+    pub(crate) dirs: Vec<Utf8PathBuf>,
+}
+
+impl MergeAndReportOptions {
+    pub(crate) fn cov(&mut self) -> LlvmCovOptions {
+        mem::take(&mut self.cov)
+    }
+
+    pub(crate) fn build(&mut self) -> BuildOptions {
+        mem::take(&mut self.build)
+    }
+
+    pub(crate) fn manifest(&mut self) -> ManifestOptions {
+        mem::take(&mut self.manifest)
+    }
+}
+
 #[derive(Debug, Parser)]
 pub(crate) struct ShowEnvOptions {
     /// Prepend "export " to each line, so that the output is suitable to be sourced by bash.
     #[clap(long)]
     pub(crate) export_prefix: bool,
+    /// Output format: `shell` or `json` [default: shell]
+    ///
+    /// `json` prints a JSON object for use by other tools, with the environment variables
+    /// (`env`, in which variables that need to be unset are mapped to `null`), the target
+    /// directory (`target_dir`), and the pattern of the names of the profile data files
+    /// (`profraw_pattern`).
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    pub(crate) format: Option<ShowEnvFormat>,
+    /// Append the environment variables to the file at `$GITHUB_ENV` instead of printing them
+    ///
+    /// The variables are available in the subsequent steps of the GitHub Actions job.
+    #[clap(long, conflicts_with = "export-prefix", conflicts_with = "format")]
+    pub(crate) github_env: bool,
+    /// Print the environment variables in the syntax of the given shell: `bash`, `fish`, `pwsh`,
+    /// or `nu`
+    ///
+    /// The values are quoted, and the variables that need to be unset are unset, so that the
+    /// output can be evaluated by the shell as is.
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "SHELL",
+        conflicts_with = "export-prefix",
+        conflicts_with = "format",
+        conflicts_with = "github-env"
+    )]
+    pub(crate) shell: Option<ShowEnvShell>,
+    /// Append the coverage flags to RUSTFLAGS and RUSTDOCFLAGS at the time the output is
+    /// evaluated, instead of setting the values at the time show-env is run
+    ///
+    /// This keeps the flags that are set after running show-env (e.g., `-C link-arg` flags set
+    /// by a later step of CI). Flags from cargo config or `CARGO_ENCODED_RUSTFLAGS` are merged at
+    /// the time show-env is run as before.
+    #[clap(long, conflicts_with = "format", conflicts_with = "github-env")]
+    pub(crate) append: bool,
+}
+
+/// `show-env --format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub(crate) enum ShowEnvFormat {
+    Shell,
+    Json,
+}
+
+/// `--group-by`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub(crate) enum GroupBy {
+    Package,
+    Module,
+}
+
+/// `show-env --shell`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub(crate) enum ShowEnvShell {
+    Bash,
+    Fish,
+    Pwsh,
+    Nu,
 }
 
 #[derive(Debug, Parser)]
@@ -515,6 +1186,57 @@ pub(crate) struct CleanOptions {
     /// Remove artifacts that may affect the coverage results of packages in the workspace.
     #[clap(long)]
     pub(crate) workspace: bool,
+    /// Remove the build artifacts of only the given packages
+    ///
+    /// The build artifacts of other packages are kept, so they are not rebuilt by the next run.
+    /// The profile data is removed as a whole, because it cannot be attributed to packages.
+    #[clap(
+        short,
+        long,
+        multiple_occurrences = true,
+        value_name = "SPEC",
+        conflicts_with = "workspace"
+    )]
+    pub(crate) package: Vec<String>,
+    /// Only remove coverage artifacts (profile data, doctest binaries, and reports) last
+    /// modified more than the given duration ago (e.g., `7d`)
+    ///
+    /// Units: `s`, `m`, `h`, `d`, and `w`.
+    #[clap(
+        long,
+        value_name = "DURATION",
+        parse(try_from_str = parse_duration),
+        conflicts_with = "workspace",
+        conflicts_with = "package"
+    )]
+    pub(crate) older_than: Option<Duration>,
+    /// Only remove the least recently modified coverage artifacts until their total size is at
+    /// most the given size (e.g., `5G`)
+    ///
+    /// Units: `K`, `M`, `G`, and `T` (powers of 1024), optionally followed by `B` or `iB`.
+    #[clap(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_size),
+        conflicts_with = "workspace",
+        conflicts_with = "package"
+    )]
+    pub(crate) max_size: Option<u64>,
+    /// Keep the reports in the output directory (e.g., `target/llvm-cov/html`)
+    ///
+    /// Only the profile data and the build artifacts are removed.
+    #[clap(long)]
+    pub(crate) keep_reports: bool,
+    /// Print the number of files and the total size removed for each category (profraw,
+    /// profdata, build artifacts, and reports)
+    ///
+    /// This is also enabled by --verbose and --dry-run. The build artifacts removed by `cargo
+    /// clean` (with --workspace or --package) are reported by cargo.
+    #[clap(long)]
+    pub(crate) summary: bool,
+    /// Print what would be removed, without removing anything
+    #[clap(long)]
+    pub(crate) dry_run: bool,
     // TODO: Currently, we are using a subdirectory of the target directory as
     //       the actual target directory. What effect should this option have
     //       on its behavior?
@@ -532,11 +1254,21 @@ pub(crate) struct CleanOptions {
 }
 
 // https://doc.rust-lang.org/nightly/cargo/commands/cargo-test.html#manifest-options
-#[derive(Debug, Default, Parser)]
+#[derive(Debug, Default, Clone, Parser)]
 pub(crate) struct ManifestOptions {
     /// Path to Cargo.toml
-    #[clap(long, value_name = "PATH")]
-    pub(crate) manifest_path: Option<Utf8PathBuf>,
+    ///
+    /// This flag can be passed multiple times to run tests in multiple workspaces (e.g., a
+    /// host workspace and a separate `no_std` workspace in one repository) and merge their
+    /// coverage into one report. This is only supported by `cargo llvm-cov` without
+    /// subcommands.
+    #[clap(long, multiple_occurrences = true, value_name = "PATH")]
+    pub(crate) manifest_path: Vec<Utf8PathBuf>,
+    /// Toolchain to use (e.g., nightly), overriding the toolchain cargo was invoked with
+    ///
+    /// This is equivalent to `cargo +<TOOLCHAIN> llvm-cov` and requires rustup.
+    #[clap(long, value_name = "TOOLCHAIN")]
+    pub(crate) toolchain: Option<String>,
     /// Require Cargo.lock and cache are up to date
     #[clap(long)]
     pub(crate) frozen: bool,
@@ -548,6 +1280,272 @@ pub(crate) struct ManifestOptions {
     pub(crate) offline: bool,
 }
 
+/// `--partition [count:|hash:]M/N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Partition {
+    pub(crate) hash: bool,
+    pub(crate) index: u32,
+    pub(crate) total: u32,
+}
+
+impl Partition {
+    /// Returns the argument of `--partition` flag of cargo-nextest.
+    pub(crate) fn nextest_arg(self) -> String {
+        format!("{}:{}/{}", if self.hash { "hash" } else { "count" }, self.index, self.total)
+    }
+
+    /// Returns the identifier of this partition (`M/N`) in the machine-readable outputs.
+    pub(crate) fn id(self) -> String {
+        format!("{}/{}", self.index, self.total)
+    }
+
+    /// Returns the suffix of the names of the files generated for this partition.
+    pub(crate) fn file_suffix(self) -> String {
+        format!("{}-of-{}", self.index, self.total)
+    }
+}
+
+/// `--merge-attempts`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub(crate) enum MergeAttempts {
+    All,
+    Last,
+    Passed,
+}
+
+impl MergeAttempts {
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Last => "last",
+            Self::Passed => "passed",
+        }
+    }
+}
+
+fn parse_partition(s: &str) -> Result<Partition, String> {
+    let (hash, rest) = if let Some(rest) = s.strip_prefix("hash:") {
+        (true, rest)
+    } else {
+        (false, s.strip_prefix("count:").unwrap_or(s))
+    };
+    let (index, total) = rest.split_once('/').ok_or("expected M/N")?;
+    let parse = |s: &str| s.trim().parse::<u32>().map_err(|_| format!("invalid number `{}`", s));
+    let (index, total) = (parse(index)?, parse(total)?);
+    if index == 0 || index > total {
+        return Err(format!("M ({}) must be between 1 and N ({})", index, total));
+    }
+    Ok(Partition { hash, index, total })
+}
+
+fn parse_cfg_name(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && s != "_";
+    if !valid {
+        return Err(format!("`{}` is not a valid cfg name", s));
+    }
+    if s == "coverage_nightly" {
+        return Err("`coverage_nightly` is already set by cargo-llvm-cov on nightly".to_owned());
+    }
+    Ok(s.to_owned())
+}
+
+/// Checks the syntax of a nextest filter expression, so that typos are reported
+/// before building the tests. The full validation is done by cargo-nextest.
+// https://nexte.st/book/filter-expressions
+fn parse_filter_expr(s: &str) -> Result<String, String> {
+    const PREDICATES: &[&str] = &[
+        "all",
+        "none",
+        "default",
+        "test",
+        "package",
+        "deps",
+        "rdeps",
+        "kind",
+        "binary",
+        "binary_id",
+        "platform",
+    ];
+    const OPERATORS: &[&str] = &["and", "or", "not"];
+    if s.trim().is_empty() {
+        return Err("empty filter expression".to_owned());
+    }
+    let mut depth = 0_u32;
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphabetic() || c == '_' {
+            let end =
+                rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let (name, r) = rest.split_at(end);
+            rest = r;
+            if OPERATORS.contains(&name) {
+                continue;
+            }
+            if !PREDICATES.contains(&name) {
+                return Err(format!("unknown predicate `{}`", name));
+            }
+            let arg =
+                rest.strip_prefix('(').ok_or_else(|| format!("expected `(` after `{}`", name))?;
+            // Regular expressions (`/.../`) may contain parentheses.
+            let arg_end = match arg.strip_prefix('/') {
+                Some(re) => {
+                    let mut escaped = false;
+                    let end = re
+                        .find(|c| {
+                            let end = c == '/' && !escaped;
+                            escaped = c == '\\' && !escaped;
+                            end
+                        })
+                        .ok_or("unclosed regular expression")?;
+                    end + 2
+                }
+                None => 0,
+            };
+            let close = arg[arg_end..].find(')').ok_or_else(|| format!("unclosed `{}(`", name))?;
+            rest = &arg[arg_end + close + 1..];
+        } else {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.checked_sub(1).ok_or("unmatched `)`")?,
+                '&' | '|' | '!' | '-' | '+' => {}
+                c if c.is_whitespace() => {}
+                c => return Err(format!("unexpected `{}`", c)),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if depth != 0 {
+        return Err("unmatched `(`".to_owned());
+    }
+    Ok(s.to_owned())
+}
+
+fn parse_failure_exit_code(s: &str) -> Result<(Failure, u8), String> {
+    let (kind, code) = s.split_once('=').ok_or("expected KIND=CODE")?;
+    let kind = match kind {
+        "test" => Failure::Test,
+        "threshold" => Failure::Threshold,
+        "report" => Failure::Report,
+        _ => return Err(format!("unknown kind `{}` (expected test, threshold, or report)", kind)),
+    };
+    let code = code.parse().map_err(|e| format!("invalid exit status `{}`: {}", code, e))?;
+    Ok((kind, code))
+}
+
+// https://doc.rust-lang.org/rustc/tests/index.html#cli-arguments
+const LIBTEST_FLAGS: &[&str] = &[
+    "bench",
+    "color",
+    "ensure-time",
+    "exact",
+    "exclude-should-panic",
+    "force-run-in-process",
+    "format",
+    "help",
+    "ignored",
+    "include-ignored",
+    "list",
+    "logfile",
+    "nocapture",
+    "quiet",
+    "report-time",
+    "show-output",
+    "shuffle",
+    "shuffle-seed",
+    "skip",
+    "test",
+    "test-threads",
+];
+
+/// Returns the flag of cargo-llvm-cov that `arg`, an argument passed to the
+/// test binary (after `--`), is likely meant to be.
+pub(crate) fn suggest_flag<C: CommandFactory>(arg: &str) -> Option<String> {
+    let name = arg.strip_prefix("--")?.split('=').next().unwrap();
+    if name.len() < 3 || LIBTEST_FLAGS.contains(&name) {
+        return None;
+    }
+    let cmd = C::command();
+    cmd.get_arguments()
+        .filter_map(clap::Arg::get_long)
+        .map(|long| (edit_distance(name, long), long))
+        .filter(|&(distance, long)| distance <= (long.len() / 5).clamp(1, 2))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, long)| format!("--{}", long))
+}
+
+/// Edit distance between two strings, counting a transposition of two
+/// adjacent characters as one edit (optimal string alignment distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<_> = a.chars().collect();
+    let b: Vec<_> = b.chars().collect();
+    // d[i][j] is the distance between a[..i] and b[..j].
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).ok_or("missing unit (e.g., `7d`)")?;
+    let (n, unit) = s.split_at(unit_start);
+    let n: u64 = n.parse().map_err(|_| format!("invalid number `{}`", n))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}`; expected s, m, h, d, or w", unit)),
+    };
+    n.checked_mul(secs).map(Duration::from_secs).ok_or_else(|| "duration too large".to_owned())
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (n, unit) = s.split_at(unit_start);
+    let n: f64 = n.parse().map_err(|_| format!("invalid number `{}`", n))?;
+    let unit = unit.strip_suffix("iB").or_else(|| unit.strip_suffix('B')).unwrap_or(unit);
+    let shift = match unit {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit `{}`; expected K, M, G, or T", unit)),
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    Ok((n * (1_u64 << shift) as f64) as u64)
+}
+
+fn parse_bar_thresholds(s: &str) -> Result<(f64, f64), String> {
+    let (low, high) = s.split_once(',').ok_or("expected LOW,HIGH")?;
+    let parse = |s: &str| match s.trim().parse::<f64>() {
+        Ok(v) if (0.0..=100.0).contains(&v) => Ok(v),
+        _ => Err(format!("invalid percentage `{}`", s)),
+    };
+    let (low, high) = (parse(low)?, parse(high)?);
+    if low > high {
+        return Err(format!("LOW ({}) must not be greater than HIGH ({})", low, high));
+    }
+    Ok((low, high))
+}
+
 impl ManifestOptions {
     pub(crate) fn cargo_args(&self, cmd: &mut ProcessBuilder) {
         // Skip --manifest-path because it is set based on Workspace::current_manifest.
@@ -567,17 +1565,23 @@ impl ManifestOptions {
 mod tests {
     use std::{
         env,
+        ffi::OsString,
         io::Write,
         panic,
         path::Path,
         process::{Command, Stdio},
+        time::Duration,
     };
 
     use anyhow::Result;
-    use clap::{CommandFactory, Parser};
+    use clap::{CommandFactory, ErrorKind, Parser};
     use fs_err as fs;
 
-    use super::{Args, Opts, MAX_TERM_WIDTH};
+    use super::{
+        normalize_args, parse_bar_thresholds, parse_cfg_name, parse_duration, parse_filter_expr,
+        parse_partition, parse_size, suggest_flag, Args, Failure, Opts, RunOptions, Subcommand,
+        MAX_TERM_WIDTH,
+    };
 
     #[test]
     fn assert_app() {
@@ -591,13 +1595,230 @@ mod tests {
         use std::{ffi::OsStr, os::unix::prelude::OsStrExt};
 
         // `cargo llvm-cov -- $'fo\x80o'`
-        Opts::try_parse_from(&[
+        let arg = OsStr::from_bytes(&[b'f', b'o', 0x80, b'o']);
+        let Opts::LlvmCov(args) =
+            Opts::try_parse_from(["cargo".as_ref(), "llvm-cov".as_ref(), "--".as_ref(), arg])
+                .unwrap();
+        assert_eq!(args.args, [arg]);
+
+        // `cargo llvm-cov nextest -- $'fo\x80o'`
+        let Opts::LlvmCov(args) = Opts::try_parse_from([
             "cargo".as_ref(),
             "llvm-cov".as_ref(),
+            "nextest".as_ref(),
             "--".as_ref(),
-            OsStr::from_bytes(&[b'f', b'o', 0x80, b'o']),
+            arg,
+        ])
+        .unwrap();
+        match args.subcommand {
+            Some(Subcommand::Nextest { passthrough_options }) => {
+                assert_eq!(passthrough_options.last().unwrap(), arg);
+            }
+            _ => panic!(),
+        }
+
+        // Other arguments are still required to be valid UTF-8.
+        Opts::try_parse_from(["cargo".as_ref(), "llvm-cov".as_ref(), "--package".as_ref(), arg])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn nextest_profile() {
+        let Opts::LlvmCov(mut args) = Opts::try_parse_from([
+            "cargo",
+            "llvm-cov",
+            "--profile",
+            "ci",
+            "--cargo-profile",
+            "coverage",
+        ])
+        .unwrap();
+        args.set_nextest();
+        let build = args.build();
+        assert_eq!(build.profile.as_deref(), Some("coverage"));
+        assert_eq!(build.nextest_profile.as_deref(), Some("ci"));
+        assert!(build.cargo_profile.is_none());
+    }
+
+    #[test]
+    fn filter_expr() {
+        for valid in [
+            "test(foo)",
+            "package(a) & !test(/^slow\\(\\)/)",
+            "(test(=a) or test(~b)) and not kind(bench)",
+            "all() - binary_id(a::b)",
+        ] {
+            assert_eq!(parse_filter_expr(valid).unwrap(), valid);
+        }
+        for invalid in
+            ["", " ", "tset(foo)", "test", "test(foo", "(test(a)", "test(a))", "test(/a)"]
+        {
+            parse_filter_expr(invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn failure_exit_code() {
+        let Opts::LlvmCov(args) = Opts::try_parse_from([
+            "cargo",
+            "llvm-cov",
+            "--failure-exit-code",
+            "test=101",
+            "--failure-exit-code",
+            "threshold=2",
         ])
-        .unwrap_err();
+        .unwrap();
+        assert_eq!(args.cov.failure_exit_code, [(Failure::Test, 101), (Failure::Threshold, 2)]);
+
+        for arg in ["test", "tests=2", "report=-1", "report=256"] {
+            Opts::try_parse_from(["cargo", "llvm-cov", "--failure-exit-code", arg]).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn suggest() {
+        assert_eq!(
+            suggest_flag::<Args>("--ignore-filename-regx").unwrap(),
+            "--ignore-filename-regex"
+        );
+        assert_eq!(suggest_flag::<Args>("--html").unwrap(), "--html");
+        assert_eq!(suggest_flag::<Args>("--hmtl=a").unwrap(), "--html");
+        assert_eq!(suggest_flag::<RunOptions>("--open").unwrap(), "--open");
+        assert_eq!(suggest_flag::<Args>("--nocapture"), None);
+        assert_eq!(suggest_flag::<Args>("--test-threads=1"), None);
+        assert_eq!(suggest_flag::<Args>("--my-test-flag"), None);
+        assert_eq!(suggest_flag::<Args>("-q"), None);
+    }
+
+    #[test]
+    fn bar_thresholds() {
+        assert_eq!(parse_bar_thresholds("50,80"), Ok((50.0, 80.0)));
+        assert_eq!(parse_bar_thresholds("0, 100"), Ok((0.0, 100.0)));
+        for arg in ["50", "80,50", "50,101", "a,b"] {
+            parse_bar_thresholds(arg).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn direct_invocation() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let (normalized, by_cargo) =
+            normalize_args(args(&["/bin/cargo-llvm-cov", "llvm-cov", "--lcov"]));
+        assert_eq!(
+            (normalized, by_cargo),
+            (args(&["/bin/cargo-llvm-cov", "llvm-cov", "--lcov"]), true)
+        );
+        let (normalized, by_cargo) = normalize_args(args(&["cargo-llvm-cov", "--lcov"]));
+        assert_eq!(
+            (normalized, by_cargo),
+            (args(&["cargo-llvm-cov", "llvm-cov", "--lcov"]), false)
+        );
+        let (normalized, by_cargo) = normalize_args(args(&["cargo-llvm-cov"]));
+        assert_eq!((normalized, by_cargo), (args(&["cargo-llvm-cov", "llvm-cov"]), false));
+        let Opts::LlvmCov(args) =
+            Opts::try_parse_from(normalize_args(args(&["cargo-llvm-cov", "--lcov"])).0).unwrap();
+        assert!(args.subcommand.is_none());
+    }
+
+    #[test]
+    fn cfg_name() {
+        assert_eq!(parse_cfg_name("llvm_cov"), Ok("llvm_cov".to_owned()));
+        assert_eq!(parse_cfg_name("_cov2"), Ok("_cov2".to_owned()));
+        for arg in ["", "_", "2cov", "cov-erage", "a b", "coverage_nightly"] {
+            parse_cfg_name(arg).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn partition() {
+        let p = parse_partition("2/3").unwrap();
+        assert_eq!(
+            (p.nextest_arg(), p.file_suffix()),
+            ("count:2/3".to_owned(), "2-of-3".to_owned())
+        );
+        assert_eq!(parse_partition("hash:1/2").unwrap().nextest_arg(), "hash:1/2");
+        assert_eq!(parse_partition("count:3/3").unwrap().nextest_arg(), "count:3/3");
+        for arg in ["1", "0/2", "3/2", "a/2", "slice:1/2"] {
+            parse_partition(arg).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn clean_limits() {
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_size("5G").unwrap(), 5 << 30);
+        assert_eq!(parse_size("1.5MiB").unwrap(), 3 << 19);
+        assert_eq!(parse_size("100").unwrap(), 100);
+        for arg in ["7", "d", "-1d", "7y"] {
+            parse_duration(arg).unwrap_err();
+        }
+        for arg in ["G", "5X", "1..2M"] {
+            parse_size(arg).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        #[track_caller]
+        fn assert_conflict(args: &[&str]) {
+            let err = Opts::try_parse_from(["cargo", "llvm-cov"].iter().chain(args)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{:?}", args);
+        }
+        #[track_caller]
+        fn assert_requires(args: &[&str]) {
+            let err = Opts::try_parse_from(["cargo", "llvm-cov"].iter().chain(args)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument, "{:?}", args);
+        }
+
+        assert_conflict(&["--doc", "--bin", "a"]);
+        assert_conflict(&["--doctests", "--lib"]);
+        assert_conflict(&["--no-run", "--no-report"]);
+        assert_conflict(&["--no-cfg-coverage", "--cfg-coverage-name", "a"]);
+        assert_conflict(&["--auto-install-llvm-tools", "--download-llvm-tools"]);
+        assert_conflict(&["--json", "--lcov"]);
+        assert_conflict(&["--summary-only", "--text"]);
+        assert_conflict(&["--summary-only", "--html"]);
+        assert_conflict(&["--output-path", "a", "--html"]);
+        assert_conflict(&["--output-path", "a", "--open"]);
+        assert_conflict(&["--output-dir", "a", "--json"]);
+        assert_conflict(&["--output-dir", "a", "--output-path", "b"]);
+        assert_conflict(&["--summary-bars", "--lcov"]);
+        assert_conflict(&["--json", "--functions", "--summary-only"]);
+        assert_conflict(&["--csv", "--lcov"]);
+        assert_conflict(&["--csv", "--output-dir", "a"]);
+        assert_conflict(&["--text", "--uncovered-only", "--output-dir", "a"]);
+        for arg in [
+            &["--json"][..],
+            &["--lcov"],
+            &["--csv"],
+            &["--text"],
+            &["--html"],
+            &["--open"],
+            &["--summary-only"],
+            &["--output-path", "a"],
+            &["--output-dir", "a"],
+            &["--fail-under-lines", "50"],
+            &["--fail-uncovered-lines", "1"],
+            &["--show-missing-lines"],
+            &["--summary-bars"],
+            &["--unsafe-coverage"],
+            &["--uncovered-spans", "a"],
+        ] {
+            assert_conflict(&[&["--no-report"][..], arg].concat());
+        }
+        assert_conflict(&["run", "--no-report", "--html"]);
+
+        assert_requires(&["--exclude", "a"]);
+        assert_requires(&["--coverage-target-only"]);
+        assert_requires(&["--bar-thresholds", "50,80"]);
+        assert_requires(&["--report-affected-only"]);
+        assert_requires(&["--uncovered-only"]);
+        assert_requires(&["--group-by", "module"]);
+        assert_requires(&["--functions"]);
+        assert_requires(&["--summary-bars", "--module-depth", "2"]);
+        assert_requires(&["--text", "--context", "1"]);
+        assert_requires(&["merge-and-report", "--lcov"]);
     }
 
     // https://github.com/clap-rs/clap/issues/1772
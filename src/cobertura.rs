@@ -0,0 +1,278 @@
+// Conversion of coverage data in lcov format to Cobertura XML (--gitlab and --azure).
+//
+// Refs:
+// - https://docs.gitlab.com/ee/ci/testing/test_coverage_visualization.html
+// - https://learn.microsoft.com/en-us/azure/devops/pipelines/tasks/reference/publish-code-coverage-results-v1
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+#[derive(Default)]
+struct Line {
+    hits: u64,
+    /// The number of branches and covered branches.
+    branches: Option<(u64, u64)>,
+}
+
+#[derive(Default)]
+struct Counts {
+    lines: u64,
+    lines_covered: u64,
+    branches: u64,
+    branches_covered: u64,
+}
+
+impl Counts {
+    fn add(&mut self, lines: &BTreeMap<u64, Line>) {
+        for line in lines.values() {
+            self.lines += 1;
+            self.lines_covered += u64::from(line.hits > 0);
+            if let Some((count, covered)) = line.branches {
+                self.branches += count;
+                self.branches_covered += covered;
+            }
+        }
+    }
+
+    fn attrs(&self) -> String {
+        format!(
+            "line-rate=\"{}\" branch-rate=\"{}\"",
+            rate(self.lines, self.lines_covered),
+            rate(self.branches, self.branches_covered)
+        )
+    }
+}
+
+/// Package name -> filename (relative to the workspace root) -> line number -> line.
+type Files<'a> = BTreeMap<&'a str, BTreeMap<Utf8PathBuf, BTreeMap<u64, Line>>>;
+
+/// Converts the coverage data in lcov format to Cobertura XML.
+///
+/// `packages` is the list of the directories and names of the packages. Files
+/// that do not belong to any package are put in a package named `(other)`.
+/// Each file is a class whose filename is relative to the workspace root, as
+/// GitLab requires. Methods are not included because neither GitLab nor Azure
+/// Pipelines uses them.
+pub(crate) fn from_lcov(
+    lcov: &str,
+    workspace_root: &Utf8Path,
+    packages: &[(Utf8PathBuf, String)],
+) -> String {
+    render(&parse(lcov, workspace_root, packages), workspace_root)
+}
+
+fn parse<'a>(
+    lcov: &str,
+    workspace_root: &Utf8Path,
+    packages: &'a [(Utf8PathBuf, String)],
+) -> Files<'a> {
+    let mut files = Files::new();
+    let mut current = None;
+    for line in lcov.lines() {
+        if let Some(filename) = line.strip_prefix("SF:") {
+            let path = absolute_path(filename, workspace_root);
+            let package = package_of(&path, packages);
+            let path = path.strip_prefix(workspace_root).unwrap_or(&path).to_owned();
+            current = Some(files.entry(package).or_default().entry(path).or_default());
+        } else if line == "end_of_record" {
+            current = None;
+        } else if let (Some(lines), Some(da)) = (&mut current, line.strip_prefix("DA:")) {
+            // DA:<line number>,<execution count>[,<checksum>]
+            let mut da = da.split(',');
+            if let (Some(Ok(number)), Some(Ok(hits))) =
+                (da.next().map(str::parse), da.next().map(str::parse::<u64>))
+            {
+                lines.entry(number).or_default().hits += hits;
+            }
+        } else if let (Some(lines), Some(brda)) = (&mut current, line.strip_prefix("BRDA:")) {
+            // BRDA:<line number>,<block number>,<branch number>,<taken>
+            let brda: Vec<_> = brda.split(',').collect();
+            if let (Some(Ok(number)), Some(taken)) = (brda.first().map(|n| n.parse()), brda.get(3))
+            {
+                let branches = lines.entry(number).or_default().branches.get_or_insert((0, 0));
+                branches.0 += 1;
+                branches.1 += u64::from(*taken != "-" && *taken != "0");
+            }
+        }
+    }
+    files
+}
+
+/// Returns the absolute path of a filename in the lcov report.
+pub(crate) fn absolute_path(filename: &str, workspace_root: &Utf8Path) -> Utf8PathBuf {
+    let path = Utf8PathBuf::from(filename);
+    if path.is_relative() {
+        // --remap-path-prefix strips the workspace root.
+        workspace_root.join(path)
+    } else {
+        path
+    }
+}
+
+/// Returns the name of the package that the file belongs to.
+pub(crate) fn package_of<'a>(path: &Utf8Path, packages: &'a [(Utf8PathBuf, String)]) -> &'a str {
+    // Match nested packages before their parents.
+    packages
+        .iter()
+        .filter(|(dir, _)| path.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.as_str().len())
+        .map_or("(other)", |(_, name)| name)
+}
+
+fn render(files: &Files<'_>, workspace_root: &Utf8Path) -> String {
+    let mut total = Counts::default();
+    for lines in files.values().flat_map(BTreeMap::values) {
+        total.add(lines);
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" ?>\n");
+    out.push_str(
+        "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+    );
+    let _ = writeln!(
+        out,
+        "<coverage lines-valid=\"{}\" lines-covered=\"{}\" branches-valid=\"{}\" \
+         branches-covered=\"{}\" {} complexity=\"0\" version=\"cargo-llvm-cov {}\" timestamp=\"{}\">",
+        total.lines,
+        total.lines_covered,
+        total.branches,
+        total.branches_covered,
+        total.attrs(),
+        env!("CARGO_PKG_VERSION"),
+        timestamp
+    );
+    let _ = writeln!(
+        out,
+        "  <sources>\n    <source>{}</source>\n  </sources>",
+        escape(workspace_root.as_str())
+    );
+    out.push_str("  <packages>\n");
+    for (package, files) in files {
+        let mut counts = Counts::default();
+        for lines in files.values() {
+            counts.add(lines);
+        }
+        let _ = writeln!(
+            out,
+            "    <package name=\"{}\" {} complexity=\"0\">\n      <classes>",
+            escape(package),
+            counts.attrs()
+        );
+        for (filename, lines) in files {
+            let mut counts = Counts::default();
+            counts.add(lines);
+            let filename = escape(&filename.as_str().replace('\\', "/"));
+            let _ = writeln!(
+                out,
+                "        <class name=\"{}\" filename=\"{}\" {} complexity=\"0\">\n          \
+                 <methods/>\n          <lines>",
+                filename,
+                filename,
+                counts.attrs()
+            );
+            for (number, line) in lines {
+                let _ =
+                    write!(out, "            <line number=\"{}\" hits=\"{}\"", number, line.hits);
+                match line.branches {
+                    Some((count, covered)) => {
+                        let _ = writeln!(
+                            out,
+                            " branch=\"true\" condition-coverage=\"{}% ({}/{})\"/>",
+                            covered * 100 / count,
+                            covered,
+                            count
+                        );
+                    }
+                    None => out.push_str(" branch=\"false\"/>\n"),
+                }
+            }
+            out.push_str("          </lines>\n        </class>\n");
+        }
+        out.push_str("      </classes>\n    </package>\n");
+    }
+    out.push_str("  </packages>\n</coverage>\n");
+    out
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn rate(count: u64, covered: u64) -> String {
+    if count == 0 {
+        "1".to_owned()
+    } else {
+        let rate = format!("{:.4}", covered as f64 / count as f64);
+        rate.trim_end_matches('0').trim_end_matches('.').to_owned()
+    }
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert() {
+        let lcov = "\
+SF:/ws/a/src/lib.rs
+FN:1,f
+DA:1,2
+DA:2,0
+BRDA:1,0,0,1
+BRDA:1,0,1,-
+end_of_record
+SF:/ws/src/main.rs
+DA:3,1
+end_of_record
+";
+        let packages = [(Utf8PathBuf::from("/ws/a"), "a".to_owned())];
+        let out = from_lcov(lcov, Utf8Path::new("/ws"), &packages);
+        let out: Vec<_> = out.lines().filter(|l| !l.contains("timestamp")).collect();
+        assert_eq!(out, [
+            "<?xml version=\"1.0\" ?>",
+            "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">",
+            "  <sources>",
+            "    <source>/ws</source>",
+            "  </sources>",
+            "  <packages>",
+            "    <package name=\"(other)\" line-rate=\"1\" branch-rate=\"1\" complexity=\"0\">",
+            "      <classes>",
+            "        <class name=\"src/main.rs\" filename=\"src/main.rs\" line-rate=\"1\" branch-rate=\"1\" complexity=\"0\">",
+            "          <methods/>",
+            "          <lines>",
+            "            <line number=\"3\" hits=\"1\" branch=\"false\"/>",
+            "          </lines>",
+            "        </class>",
+            "      </classes>",
+            "    </package>",
+            "    <package name=\"a\" line-rate=\"0.5\" branch-rate=\"0.5\" complexity=\"0\">",
+            "      <classes>",
+            "        <class name=\"a/src/lib.rs\" filename=\"a/src/lib.rs\" line-rate=\"0.5\" branch-rate=\"0.5\" complexity=\"0\">",
+            "          <methods/>",
+            "          <lines>",
+            "            <line number=\"1\" hits=\"2\" branch=\"true\" condition-coverage=\"50% (1/2)\"/>",
+            "            <line number=\"2\" hits=\"0\" branch=\"false\"/>",
+            "          </lines>",
+            "        </class>",
+            "      </classes>",
+            "    </package>",
+            "  </packages>",
+            "</coverage>",
+        ]);
+    }
+
+    #[test]
+    fn line_rate() {
+        assert_eq!(rate(0, 0), "1");
+        assert_eq!(rate(3, 1), "0.3333");
+        assert_eq!(rate(4, 2), "0.5");
+        assert_eq!(rate(2, 2), "1");
+    }
+}
@@ -0,0 +1,237 @@
+// Uploading coverage to Codecov (--codecov) with the v4 upload protocol.
+
+use std::{fmt::Write as _, thread, time::Duration};
+
+use anyhow::{bail, format_err, Context as _, Result};
+
+use crate::{
+    ci::{self, Build, Ci},
+    context::Context,
+    env, fs,
+    process::ProcessBuilder,
+    term,
+};
+
+const DEFAULT_URL: &str = "https://codecov.io";
+const RETRIES: u32 = 3;
+
+/// Handles --codecov.
+pub(crate) fn upload(cx: &Context, lcov: &str) -> Result<()> {
+    let url = env::var("CODECOV_URL")?.unwrap_or_else(|| DEFAULT_URL.to_owned());
+    let token = match env::var("CODECOV_TOKEN")? {
+        Some(token) => Some(token),
+        None => github_oidc_token(&url)?,
+    };
+    let query = query(cx, token.is_none())?;
+    let body = body(cx, lcov)?;
+
+    let mut res = Err(format_err!("no attempt"));
+    for attempt in 1..=RETRIES {
+        res = try_upload(&url, token.as_deref(), &query, &body);
+        match &res {
+            Err(e) if attempt < RETRIES => {
+                warn!(
+                    "failed to upload coverage to Codecov (attempt {}/{}): {:#}",
+                    attempt, RETRIES, e
+                );
+                thread::sleep(Duration::from_secs(2_u64.pow(attempt)));
+            }
+            _ => break,
+        }
+    }
+    let report_url = res.context("failed to upload coverage to Codecov")?;
+    status!("Uploaded", "coverage to Codecov: {}", report_url);
+    Ok(())
+}
+
+/// Sends the report and returns the URL of the report on Codecov.
+fn try_upload(url: &str, token: Option<&str>, query: &str, body: &str) -> Result<String> {
+    let mut cmd = curl();
+    cmd.args(["-X", "POST", "-H", "Accept: text/plain", "-H", "X-Reduced-Redundancy: false"]);
+    cmd.arg("--data").arg("");
+    cmd.arg(format!("{}/upload/v4?{}", url.trim_end_matches('/'), query));
+    if let Some(token) = token {
+        cmd.stdin(format!("header = \"Authorization: token {}\"\n", token));
+    }
+    let res = cmd.read()?;
+    let mut lines = res.lines().map(str::trim).filter(|l| !l.is_empty());
+    let (report_url, upload_url) = match (lines.next(), lines.next()) {
+        (Some(report_url), Some(upload_url)) => (report_url, upload_url),
+        _ => bail!("unexpected response from Codecov: {}", res),
+    };
+
+    let mut cmd = curl();
+    cmd.args(["-X", "PUT", "-H", "Content-Type: text/plain", "--data-binary", "@-"]);
+    cmd.arg(upload_url);
+    cmd.stdin(body);
+    cmd.read()?;
+    Ok(report_url.to_owned())
+}
+
+fn curl() -> ProcessBuilder {
+    // Read the config (headers with the token) from stdin, which is empty
+    // unless the request needs a token.
+    let mut cmd = cmd!("curl", "--config", "-", "--fail", "--silent", "--show-error");
+    cmd.args(["--connect-timeout", "30", "--max-time", "300"]);
+    cmd.stdin("");
+    cmd
+}
+
+/// Requests an OIDC token for Codecov on GitHub Actions, if the workflow has
+/// the `id-token: write` permission. The audience of the token is the URL of
+/// the Codecov instance, which is different for self-hosted instances.
+// https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/about-security-hardening-with-openid-connect
+fn github_oidc_token(codecov_url: &str) -> Result<Option<String>> {
+    let (url, request_token) = match (
+        env::var("ACTIONS_ID_TOKEN_REQUEST_URL")?,
+        env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN")?,
+    ) {
+        (Some(url), Some(token)) => (url, token),
+        _ => return Ok(None),
+    };
+    let mut cmd = curl();
+    cmd.arg(oidc_request_url(&url, codecov_url));
+    cmd.stdin(format!("header = \"Authorization: bearer {}\"\n", request_token));
+    let res: serde_json::Value =
+        serde_json::from_str(&cmd.read().context("failed to request OIDC token for Codecov")?)?;
+    match res["value"].as_str() {
+        Some(token) => {
+            if term::verbose() {
+                info!("using OIDC token for Codecov upload");
+            }
+            Ok(Some(token.to_owned()))
+        }
+        None => bail!("unexpected response from OIDC token request"),
+    }
+}
+
+/// Returns the URL to request an OIDC token for the given Codecov instance.
+fn oidc_request_url(request_url: &str, codecov_url: &str) -> String {
+    format!("{}&audience={}", request_url, encode(codecov_url.trim_end_matches('/')))
+}
+
+/// Returns the query string of the upload request.
+// https://docs.codecov.com/reference/upload
+fn query(cx: &Context, tokenless: bool) -> Result<String> {
+    let mut params: Vec<(&str, String)> =
+        vec![("package", format!("cargo-llvm-cov-{}", env!("CARGO_PKG_VERSION")))];
+    if ci::detect() == Some(Ci::GitHubActions) {
+        let build = Build::get(Ci::GitHubActions)?;
+        let slug = env::var("GITHUB_REPOSITORY")?.unwrap_or_default();
+        params.push(("service", build.service.to_owned()));
+        params.push(("slug", slug.clone()));
+        params.push(("build", build.id.unwrap_or_default()));
+        params.push(("build_url", build.url.unwrap_or_default()));
+        params.push(("job", env::var("GITHUB_WORKFLOW")?.unwrap_or_default()));
+        // refs/pull/<number>/merge
+        if let Some(pr) = env::var("GITHUB_REF")?
+            .as_deref()
+            .and_then(|r| r.strip_prefix("refs/pull/"))
+            .and_then(|r| r.strip_suffix("/merge"))
+        {
+            params.push(("pr", pr.to_owned()));
+        }
+        let event = github_event()?;
+        let head = &event["pull_request"]["head"];
+        let mut branch = build.branch.unwrap_or_default();
+        // Tokenless uploads are accepted for pull requests from forks, whose
+        // branch is given as `<owner>:<branch>`.
+        if tokenless && head["repo"]["full_name"].as_str().map_or(false, |name| name != slug) {
+            if let Some(label) = head["label"].as_str() {
+                branch = label.to_owned();
+            }
+        }
+        params.push(("branch", branch));
+        // On pull_request events, GITHUB_SHA is the merge commit, which
+        // Codecov does not know.
+        let commit = match head["sha"].as_str() {
+            Some(sha) => sha.to_owned(),
+            None => env::var("GITHUB_SHA")?.unwrap_or_default(),
+        };
+        params.push(("commit", commit));
+    } else {
+        params.push(("commit", ci::git(cx, &["rev-parse", "HEAD"])?));
+        params.push(("branch", ci::git(cx, &["rev-parse", "--abbrev-ref", "HEAD"])?));
+    }
+    if !cx.cov.codecov_flag.is_empty() {
+        params.push(("flags", cx.cov.codecov_flag.join(",")));
+    }
+    if let Some(name) = &cx.cov.codecov_name {
+        params.push(("name", name.clone()));
+    }
+
+    let mut query = String::new();
+    for (key, value) in params {
+        if value.is_empty() {
+            continue;
+        }
+        if !query.is_empty() {
+            query.push('&');
+        }
+        let _ = write!(query, "{}={}", key, encode(&value));
+    }
+    Ok(query)
+}
+
+/// Returns the payload of the event that triggered the workflow, or null if
+/// it is not available.
+fn github_event() -> Result<serde_json::Value> {
+    match env::var("GITHUB_EVENT_PATH")? {
+        Some(path) => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+/// Returns the body of the upload: the files in the repository (which Codecov
+/// uses to fix the paths in the report), followed by the report.
+fn body(cx: &Context, lcov: &str) -> Result<String> {
+    let mut body = ci::git(cx, &["ls-files"])?;
+    body.push_str("\n<<<<<< network\n# path=lcov.info\n");
+    body.push_str(lcov);
+    if !body.ends_with('\n') {
+        body.push('\n');
+    }
+    body.push_str("<<<<<< EOF\n");
+    Ok(body)
+}
+
+/// Percent-encodes the given string for use in a query string.
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char);
+            }
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, oidc_request_url, DEFAULT_URL};
+
+    #[test]
+    fn percent_encode() {
+        assert_eq!(encode("owner/repo"), "owner%2Frepo");
+        assert_eq!(encode("a b&c=d"), "a%20b%26c%3Dd");
+        assert_eq!(encode("cargo-llvm-cov_0.4.11~"), "cargo-llvm-cov_0.4.11~");
+    }
+
+    #[test]
+    fn oidc_audience() {
+        let request_url = "https://token.actions.githubusercontent.com/?api-version=2.0";
+        assert_eq!(
+            oidc_request_url(request_url, DEFAULT_URL),
+            format!("{}&audience=https%3A%2F%2Fcodecov.io", request_url)
+        );
+        assert_eq!(
+            oidc_request_url(request_url, "https://codecov.example.com/"),
+            format!("{}&audience=https%3A%2F%2Fcodecov.example.com", request_url)
+        );
+    }
+}
@@ -0,0 +1,226 @@
+// Markdown summaries of the coverage for pull request comments (--pr-comment)
+// and Buildkite annotations (--buildkite-annotation).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Write as _,
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+use crate::{
+    changed,
+    context::Context,
+    fs, object_list,
+    summary::{self, Row},
+};
+
+/// The first line of the summary.
+const MARKER: &str = "<!-- cargo-llvm-cov -->";
+const KINDS: &[&str] = &["lines", "functions", "regions"];
+
+/// Handles --pr-comment.
+pub(crate) fn render(cx: &Context, json: &LlvmCovJsonExport) -> Result<String> {
+    let base = match &cx.cov.pr_comment_base {
+        Some(base) => Some(
+            LlvmCovJsonExport::from_reader(fs::File::open(base)?, None)
+                .with_context(|| format!("failed to parse {}", base))?,
+        ),
+        None => None,
+    };
+    let uncovered = match &cx.cov.pr_comment_diff {
+        Some(rev) => Some(changed::uncovered_changed_lines(cx, json, rev)?),
+        None => None,
+    };
+
+    let mut out = String::new();
+    out.push_str(MARKER);
+    out.push_str("\n## Coverage\n\n");
+    filter_note(&mut out, cx);
+    totals(&mut out, json, base.as_ref())?;
+    packages(
+        &mut out,
+        &summary::package_rows(cx, json),
+        base.as_ref().map(|base| summary::package_rows(cx, base)).as_deref(),
+    );
+    if let Some((changed, uncovered)) = &uncovered {
+        uncovered_lines(&mut out, cx, *changed, uncovered);
+    }
+    Ok(out)
+}
+
+/// Handles --buildkite-annotation.
+// https://buildkite.com/docs/agent/v3/cli-annotate
+pub(crate) fn render_buildkite_annotation(
+    cx: &Context,
+    json: &LlvmCovJsonExport,
+) -> Result<String> {
+    let mut out = String::new();
+    out.push_str("#### Coverage\n\n");
+    filter_note(&mut out, cx);
+    totals(&mut out, json, None)?;
+    packages(&mut out, &summary::package_rows(cx, json), None);
+    if let (true, Some(output_dir)) = (cx.cov.html, &cx.cov.output_dir) {
+        // Links to artifacts are relative to the directory the artifacts are
+        // uploaded from, which is usually the current directory.
+        let index = output_dir.join("html/index.html");
+        let cwd = env::current_dir()?;
+        let index = index.strip_prefix(&cwd).unwrap_or(&index);
+        let _ = writeln!(out, "\n[HTML report](artifact://{})", index.as_str().replace('\\', "/"));
+    }
+    Ok(out)
+}
+
+/// Labels the coverage of tests filtered by --filter-expr as partial.
+fn filter_note(out: &mut String, cx: &Context) {
+    let filter_exprs = object_list::filter_exprs(cx);
+    if filter_exprs.is_empty() {
+        return;
+    }
+    let filter_exprs: Vec<_> = filter_exprs.iter().map(|expr| format!("`{}`", expr)).collect();
+    let _ = writeln!(
+        out,
+        "> **Partial coverage:** only tests matching {} were run.\n",
+        filter_exprs.join(", ")
+    );
+}
+
+fn totals(
+    out: &mut String,
+    json: &LlvmCovJsonExport,
+    base: Option<&LlvmCovJsonExport>,
+) -> Result<()> {
+    out.push_str("| | Covered | Total | Coverage |\n|:--|--:|--:|--:|\n");
+    for &kind in KINDS {
+        let (count, covered) = json.get_totals(kind)?;
+        let percent = summary::percent(count, covered);
+        let _ =
+            write!(out, "| **{}** | {} | {} | {:.2}%", capitalize(kind), covered, count, percent);
+        if let Some(base) = base {
+            let (count, covered) = base.get_totals(kind)?;
+            let _ =
+                write!(out, " ({})", summary::delta(percent - summary::percent(count, covered)));
+        }
+        out.push_str(" |\n");
+    }
+    Ok(())
+}
+
+fn packages(out: &mut String, rows: &[Row], base: Option<&[Row]>) {
+    // A table of one package repeats the totals.
+    if rows.len() < 2 {
+        return;
+    }
+    let _ = write!(
+        out,
+        "\n<details>\n<summary>Line coverage by package</summary>\n\n\
+         | Package | Covered | Total | Coverage |{}\n|:--|--:|--:|--:|{}\n",
+        if base.is_some() { " Change |" } else { "" },
+        if base.is_some() { "--:|" } else { "" },
+    );
+    for row in rows {
+        let _ = write!(
+            out,
+            "| {} | {} | {} | {:.2}% |",
+            row.name,
+            row.covered,
+            row.count,
+            row.percent()
+        );
+        if let Some(base) = base {
+            match base.iter().find(|base| base.name == row.name) {
+                Some(base) => {
+                    let _ = write!(out, " {} |", summary::delta(row.percent() - base.percent()));
+                }
+                None => out.push_str(" new |"),
+            }
+        }
+        out.push('\n');
+    }
+    out.push_str("\n</details>\n");
+}
+
+fn uncovered_lines(
+    out: &mut String,
+    cx: &Context,
+    changed: usize,
+    uncovered: &BTreeMap<String, BTreeSet<u64>>,
+) {
+    let count: usize = uncovered.values().map(BTreeSet::len).sum();
+    let _ = writeln!(
+        out,
+        "\n**Changed lines:** {} of {} covered ({:.2}%)",
+        changed - count,
+        changed,
+        summary::percent(changed as u64, (changed - count) as u64)
+    );
+    if uncovered.is_empty() {
+        return;
+    }
+    out.push_str(
+        "\n<details>\n<summary>Uncovered changed lines</summary>\n\n\
+         | File | Lines |\n|:--|:--|\n",
+    );
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    for (filename, lines) in uncovered {
+        let filename = Utf8Path::new(filename);
+        let filename = filename.strip_prefix(workspace_root).unwrap_or(filename);
+        let _ = writeln!(out, "| `{}` | {} |", filename, ranges(lines));
+    }
+    out.push_str("\n</details>\n");
+}
+
+/// Formats the line numbers as comma-separated ranges (e.g., `1-3, 5`).
+fn ranges(lines: &BTreeSet<u64>) -> String {
+    let mut out = String::new();
+    for (start, end) in changed::line_ranges(lines) {
+        if !out.is_empty() {
+            out.push_str(", ");
+        }
+        if start == end {
+            let _ = write!(out, "{}", start);
+        } else {
+            let _ = write!(out, "{}-{}", start, end);
+        }
+    }
+    out
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_ranges() {
+        let lines: BTreeSet<u64> = [1, 2, 3, 5, 7, 8].into_iter().collect();
+        assert_eq!(ranges(&lines), "1-3, 5, 7-8");
+        assert_eq!(ranges(&BTreeSet::new()), "");
+    }
+
+    #[test]
+    fn packages_table() {
+        let row =
+            |name: &str, count, covered| Row { name: name.to_owned(), dir: None, count, covered };
+        let mut out = String::new();
+        packages(&mut out, &[row("a", 4, 3), row("b", 2, 2)], Some(&[row("a", 4, 2)]));
+        assert_eq!(
+            out,
+            "\n<details>\n<summary>Line coverage by package</summary>\n\n\
+             | Package | Covered | Total | Coverage | Change |\n|:--|--:|--:|--:|--:|\n\
+             | a | 3 | 4 | 75.00% | +25.0 |\n\
+             | b | 2 | 2 | 100.00% | new |\n\
+             \n</details>\n"
+        );
+    }
+}
@@ -1,12 +1,13 @@
 // Refs:
 // - https://doc.rust-lang.org/nightly/cargo/reference/config.html
 
-use std::{collections::BTreeMap, ffi::OsStr};
+use std::{collections::BTreeMap, ffi::OsStr, iter::Peekable, vec};
 
 use anyhow::{format_err, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use serde::Deserialize;
 
-use crate::{env, term::Coloring};
+use crate::{cli::LlvmCovOptions, env, process::ProcessBuilder, profile::Profile, term::Coloring};
 
 // Note: We don't need to get configuration values like net.offline here,
 // because those are configuration that need to be applied only to cargo,
@@ -21,10 +22,53 @@ pub(crate) struct Config {
     pub(crate) doc: Doc,
     #[serde(default)]
     term: Term,
+    #[serde(default, rename = "llvm-cov")]
+    llvm_cov: LlvmCov,
+    #[serde(default)]
+    pub(crate) profile: BTreeMap<String, Profile>,
+
+    // Resolved by Config::resolve_flags.
+    #[serde(skip)]
+    rustflags: Option<Flags>,
+    #[serde(skip)]
+    rustdocflags: Option<Flags>,
+}
+
+/// Flags that cargo passes to rustc or rustdoc.
+#[derive(Debug, Clone)]
+pub(crate) struct Flags {
+    pub(crate) args: Vec<String>,
+    /// Whether the flags are from `CARGO_ENCODED_*` environment variable.
+    pub(crate) encoded: bool,
+    /// Where the flags are from.
+    pub(crate) source: String,
+}
+
+impl Flags {
+    fn new(flags: &StringOrArray, source: impl Into<String>) -> Self {
+        let flags = match flags {
+            StringOrArray::String(s) => s.split_whitespace().map(str::to_owned).collect(),
+            StringOrArray::Array(v) => v.clone(),
+        };
+        Self { args: flags, encoded: false, source: source.into() }
+    }
+
+    fn from_env(name: &str) -> Result<Option<Self>> {
+        let encoded_name = format!("CARGO_ENCODED_{}", name);
+        if let Some(flags) = env::var(&encoded_name)? {
+            let flags = if flags.is_empty() {
+                vec![]
+            } else {
+                flags.split('\x1f').map(str::to_owned).collect()
+            };
+            return Ok(Some(Self { args: flags, encoded: true, source: encoded_name }));
+        }
+        Ok(env::var(name)?.map(|flags| Self::new(&StringOrArray::String(flags), name)))
+    }
 }
 
 impl Config {
-    pub(crate) fn new(cargo: &OsStr, target: Option<&str>, host: Option<&str>) -> Result<Self> {
+    pub(crate) fn new(cargo: &OsStr, target: Option<&str>) -> Result<Self> {
         // Use unstable cargo-config because there is no other good way.
         // However, it is unstable and can break, so allow errors.
         // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#cargo-config
@@ -42,12 +86,12 @@ impl Config {
                 Self::default()
             }
         };
-        config.apply_env(target, host)?;
+        config.apply_env(target)?;
         Ok(config)
     }
 
     // Apply configuration environment variables
-    fn apply_env(&mut self, target: Option<&str>, host: Option<&str>) -> Result<()> {
+    fn apply_env(&mut self, target: Option<&str>) -> Result<()> {
         // Environment variables are prefer over config values.
         // https://doc.rust-lang.org/nightly/cargo/reference/config.html#environment-variables
 
@@ -84,39 +128,6 @@ impl Config {
         } else if let Some(target) = env::var("CARGO_BUILD_TARGET")? {
             self.build.target = Some(target);
         }
-        let target = self.build.target.as_deref().or(host);
-
-        // 1. RUSTFLAGS
-        // 2. target.<triple>.rustflags (CARGO_TARGET_<triple>_RUSTFLAGS) and target.<cfg>.rustflags
-        // 3. build.rustflags (CARGO_BUILD_RUSTFLAGS)
-        // Note: target.<cfg>.rustflags is currently ignored
-        // https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustflags
-        if let Some(rustflags) = env::var("RUSTFLAGS")? {
-            self.build.rustflags = Some(StringOrArray::String(rustflags));
-        } else if let Some(target) = target {
-            if let Some(rustflags) = env::var(&format!(
-                "CARGO_TARGET_{}_RUSTFLAGS",
-                target.to_uppercase().replace('-', "_")
-            ))? {
-                self.build.rustflags = Some(StringOrArray::String(rustflags));
-            } else if let Some(Target { rustflags: Some(rustflags) }) = self.target.get(target) {
-                self.build.rustflags = Some(rustflags.clone());
-            } else if let Some(rustflags) = env::var("CARGO_BUILD_RUSTFLAGS")? {
-                self.build.rustflags = Some(StringOrArray::String(rustflags));
-            }
-        } else if let Some(rustflags) = env::var("CARGO_BUILD_RUSTFLAGS")? {
-            self.build.rustflags = Some(StringOrArray::String(rustflags));
-        }
-
-        // 1. RUSTDOCFLAGS
-        // 2. build.rustdocflags (CARGO_BUILD_RUSTDOCFLAGS)
-        // https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustdocflags
-        if let Some(rustdocflags) = env::var("RUSTDOCFLAGS")? {
-            self.build.rustdocflags = Some(StringOrArray::String(rustdocflags));
-        } else if let Some(rustdocflags) = env::var("CARGO_BUILD_RUSTDOCFLAGS")? {
-            self.build.rustdocflags = Some(StringOrArray::String(rustdocflags));
-        }
-
         // doc.browser config value is prefer over BROWSER environment variable.
         // https://github.com/rust-lang/cargo/blob/0.62.0/src/cargo/ops/cargo_doc.rs#L52-L53
         if self.doc.browser.is_none() {
@@ -132,6 +143,33 @@ impl Config {
             self.term.color =
                 Some(clap::ArgEnum::from_str(&color, false).map_err(|e| format_err!("{}", e))?);
         }
+        // Widely used conventions that are not supported by cargo itself. Resolve
+        // them here so that the same coloring is passed to cargo and llvm-cov.
+        // CLICOLOR_FORCE is preferred over NO_COLOR, as in other tools.
+        // https://bixense.com/clicolors
+        // https://no-color.org
+        if self.term.color.is_none() {
+            if env::var("CLICOLOR_FORCE")?.map_or(false, |v| v != "0") {
+                self.term.color = Some(Coloring::Always);
+            } else if env::var_os("NO_COLOR").is_some()
+                || env::var("CLICOLOR")?.map_or(false, |v| v == "0")
+            {
+                self.term.color = Some(Coloring::Never);
+            }
+        }
+
+        // Same as cargo, environment variables are preferred over config values.
+        if let Some(format) = env::var("CARGO_LLVM_COV_FORMAT")? {
+            self.llvm_cov.format = Some(
+                clap::ArgEnum::from_str(&format, false)
+                    .map_err(|e| format_err!("invalid CARGO_LLVM_COV_FORMAT: {}", e))?,
+            );
+        }
+        if let Some(output_path) = env::var("CARGO_LLVM_COV_OUTPUT_PATH")? {
+            // Unlike the config value, this is relative to the current directory.
+            let current_dir = Utf8PathBuf::try_from(env::current_dir()?)?;
+            self.llvm_cov.output_path = Some(current_dir.join(output_path));
+        }
         Ok(())
     }
 
@@ -153,14 +191,127 @@ impl Config {
         }
     }
 
-    pub(crate) fn rustflags(&self) -> Option<String> {
-        // Refer only build.rustflags because Self::apply_env update build.rustflags
-        // based on target.<..>.rustflags.
-        self.build.rustflags.as_ref().map(ToString::to_string)
+    /// Applies the default report format (`llvm-cov.format`) and output path
+    /// (`llvm-cov.output-path`) if no report format is specified by CLI flags.
+    /// Applies the default report format and output path. A relative output
+    /// path in the config is resolved against the workspace root, so that the
+    /// report is written to the same place regardless of the current directory.
+    pub(crate) fn merge_to_cov(&self, cov: &mut LlvmCovOptions, workspace_root: &Utf8Path) {
+        let format = match self.llvm_cov.format {
+            Some(format) => format,
+            None => return,
+        };
+        if cov.json
+            || cov.lcov
+            || cov.text
+            || cov.html
+            || cov.open.is_some()
+            || cov.summary_bars
+            || cov.template.is_some()
+            || cov.no_report
+        {
+            return;
+        }
+        match format {
+            ReportFormat::Json => cov.json = true,
+            ReportFormat::Lcov => cov.lcov = true,
+            // --summary-only cannot be used together with --text or --html.
+            ReportFormat::Text | ReportFormat::Html if cov.summary_only => return,
+            ReportFormat::Text => cov.text = true,
+            ReportFormat::Html => cov.html = true,
+        }
+        if cov.output_path.is_none() && format != ReportFormat::Html {
+            cov.output_path = self.llvm_cov.output_path.as_ref().map(|p| workspace_root.join(p));
+        }
     }
 
-    pub(crate) fn rustdocflags(&self) -> Option<String> {
-        self.build.rustdocflags.as_ref().map(ToString::to_string)
+    /// Resolves the flags that cargo passes to rustc and rustdoc, in the same
+    /// way as cargo.
+    ///
+    /// This needs to be called after the path to rustc has been determined
+    /// because `target.<cfg>.rustflags` requires the cfgs of the target.
+    pub(crate) fn resolve_flags(&mut self, rustc: &ProcessBuilder, host: &str) -> Result<()> {
+        // 1. CARGO_ENCODED_RUSTFLAGS
+        // 2. RUSTFLAGS
+        // 3. target.<triple>.rustflags (CARGO_TARGET_<triple>_RUSTFLAGS) and target.<cfg>.rustflags
+        // 4. build.rustflags (CARGO_BUILD_RUSTFLAGS)
+        // https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustflags
+        self.rustflags = Flags::from_env("RUSTFLAGS")?;
+        if self.rustflags.is_none() {
+            self.rustflags = self.target_rustflags(rustc, host)?;
+        }
+        if self.rustflags.is_none() {
+            if let Some(rustflags) = env::var("CARGO_BUILD_RUSTFLAGS")? {
+                let rustflags = StringOrArray::String(rustflags);
+                self.rustflags = Some(Flags::new(&rustflags, "CARGO_BUILD_RUSTFLAGS"));
+            } else if let Some(rustflags) = &self.build.rustflags {
+                self.rustflags = Some(Flags::new(rustflags, "build.rustflags"));
+            }
+        }
+
+        // 1. CARGO_ENCODED_RUSTDOCFLAGS
+        // 2. RUSTDOCFLAGS
+        // 3. build.rustdocflags (CARGO_BUILD_RUSTDOCFLAGS)
+        // https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustdocflags
+        self.rustdocflags = Flags::from_env("RUSTDOCFLAGS")?;
+        if self.rustdocflags.is_none() {
+            if let Some(rustdocflags) = env::var("CARGO_BUILD_RUSTDOCFLAGS")? {
+                let rustdocflags = StringOrArray::String(rustdocflags);
+                self.rustdocflags = Some(Flags::new(&rustdocflags, "CARGO_BUILD_RUSTDOCFLAGS"));
+            } else if let Some(rustdocflags) = &self.build.rustdocflags {
+                self.rustdocflags = Some(Flags::new(rustdocflags, "build.rustdocflags"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Joins target.<triple>.rustflags and all target.<cfg>.rustflags that
+    /// match the target.
+    fn target_rustflags(&self, rustc: &ProcessBuilder, host: &str) -> Result<Option<Flags>> {
+        let target = self.build.target.as_deref().unwrap_or(host);
+        let mut flags = vec![];
+        let mut sources = vec![];
+        let env_name =
+            format!("CARGO_TARGET_{}_RUSTFLAGS", target.to_uppercase().replace('-', "_"));
+        if let Some(rustflags) = env::var(&env_name)? {
+            flags.extend(Flags::new(&StringOrArray::String(rustflags), "").args);
+            sources.push(env_name);
+        } else if let Some(Target { rustflags: Some(rustflags) }) = self.target.get(target) {
+            flags.extend(Flags::new(rustflags, "").args);
+            sources.push(format!("target.{}.rustflags", target));
+        }
+
+        let mut cfgs = None;
+        for (key, value) in &self.target {
+            let rustflags = match value {
+                Target { rustflags: Some(rustflags) } if key.starts_with("cfg(") => rustflags,
+                _ => continue,
+            };
+            let expr = CfgExpr::parse(key)
+                .ok_or_else(|| format_err!("failed to parse `{}` in cargo config", key))?;
+            if cfgs.is_none() {
+                let mut cmd = rustc.clone();
+                cmd.args(["--print", "cfg", "--target", target]);
+                cfgs = Some(cmd.read()?.lines().map(str::to_owned).collect::<Vec<_>>());
+            }
+            if expr.matches(cfgs.as_ref().unwrap()) {
+                flags.extend(Flags::new(rustflags, "").args);
+                sources.push(format!("target.'{}'.rustflags", key));
+            }
+        }
+
+        if sources.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Flags { args: flags, encoded: false, source: sources.join(" and ") }))
+    }
+
+    pub(crate) fn rustflags(&self) -> Option<&Flags> {
+        self.rustflags.as_ref()
+    }
+
+    pub(crate) fn rustdocflags(&self) -> Option<&Flags> {
+        self.rustdocflags.as_ref()
     }
 }
 
@@ -205,6 +356,26 @@ struct Term {
     color: Option<Coloring>,
 }
 
+// Configuration of cargo-llvm-cov itself. This is not a cargo config, but is
+// read from the same files so that it can be set per project.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct LlvmCov {
+    // llvm-cov.format (CARGO_LLVM_COV_FORMAT)
+    format: Option<ReportFormat>,
+    // llvm-cov.output-path (CARGO_LLVM_COV_OUTPUT_PATH)
+    output_path: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "kebab-case")]
+enum ReportFormat {
+    Text,
+    Html,
+    Json,
+    Lcov,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum StringOrArray {
@@ -236,3 +407,125 @@ impl ToString for StringOrArray {
         }
     }
 }
+
+/// A cfg expression used in `target.<cfg>` tables.
+// https://doc.rust-lang.org/nightly/reference/conditional-compilation.html
+#[derive(Debug, PartialEq)]
+enum CfgExpr {
+    Name(String),
+    KeyPair(String, String),
+    Not(Box<CfgExpr>),
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+}
+
+impl CfgExpr {
+    /// Parses `cfg(<expr>)`.
+    fn parse(s: &str) -> Option<Self> {
+        let mut tokens = s
+            .strip_prefix("cfg(")?
+            .strip_suffix(')')?
+            .split_inclusive(|c: char| c == '(' || c == ')' || c == ',' || c == '=')
+            .flat_map(|t| match t.char_indices().last() {
+                Some((i, c @ ('(' | ')' | ',' | '='))) => [t[..i].trim(), &t[i..i + c.len_utf8()]],
+                _ => [t.trim(), ""],
+            })
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        let expr = Self::parse_expr(&mut tokens)?;
+        if tokens.next().is_some() {
+            return None;
+        }
+        Some(expr)
+    }
+
+    fn parse_expr(tokens: &mut Peekable<vec::IntoIter<&str>>) -> Option<Self> {
+        let name = tokens.next()?;
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+        match tokens.peek() {
+            Some(&"=") => {
+                tokens.next();
+                let value = tokens.next()?.strip_prefix('"')?.strip_suffix('"')?;
+                Some(Self::KeyPair(name.to_owned(), value.to_owned()))
+            }
+            Some(&"(") => {
+                tokens.next();
+                let mut list = vec![];
+                loop {
+                    if tokens.peek() == Some(&")") {
+                        tokens.next();
+                        break;
+                    }
+                    list.push(Self::parse_expr(tokens)?);
+                    match tokens.next()? {
+                        "," => {}
+                        ")" => break,
+                        _ => return None,
+                    }
+                }
+                match name {
+                    "all" => Some(Self::All(list)),
+                    "any" => Some(Self::Any(list)),
+                    "not" if list.len() == 1 => Some(Self::Not(Box::new(list.pop().unwrap()))),
+                    _ => None,
+                }
+            }
+            _ => Some(Self::Name(name.to_owned())),
+        }
+    }
+
+    /// Evaluates the expression with the output of `rustc --print cfg`.
+    fn matches(&self, cfgs: &[String]) -> bool {
+        match self {
+            Self::Name(name) => cfgs.iter().any(|c| c == name),
+            Self::KeyPair(key, value) => {
+                cfgs.iter().any(|c| c.split_once('=') == Some((key, &format!("\"{}\"", value))))
+            }
+            Self::Not(expr) => !expr.matches(cfgs),
+            Self::All(list) => list.iter().all(|e| e.matches(cfgs)),
+            Self::Any(list) => list.iter().any(|e| e.matches(cfgs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cfg_expr() {
+        let cfgs: Vec<_> =
+            ["debug_assertions", "target_arch=\"x86_64\"", "target_os=\"linux\"", "unix"]
+                .iter()
+                .map(|s| (*s).to_owned())
+                .collect();
+        let matches = |s: &str| CfgExpr::parse(s).unwrap().matches(&cfgs);
+        assert!(matches("cfg(unix)"));
+        assert!(!matches("cfg(windows)"));
+        assert!(matches(r#"cfg(target_arch = "x86_64")"#));
+        assert!(matches(r#"cfg(target_arch="x86_64")"#));
+        assert!(!matches(r#"cfg(target_arch = "aarch64")"#));
+        assert!(matches(r#"cfg(all(unix, target_os = "linux"))"#));
+        assert!(!matches(r#"cfg(all(unix, target_os = "macos"))"#));
+        assert!(matches(r#"cfg(any(windows, target_os = "linux",))"#));
+        assert!(matches("cfg(not(windows))"));
+        assert!(matches("cfg(all())"));
+        assert!(!matches("cfg(any())"));
+
+        for s in [
+            "unix",
+            "cfg(unix",
+            "cfg()",
+            "cfg(not(unix, windows))",
+            "cfg(foo(unix))",
+            "cfg(a = b)",
+            "cfg(unix windows)",
+        ] {
+            assert_eq!(CfgExpr::parse(s), None, "{}", s);
+        }
+    }
+}
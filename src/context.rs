@@ -1,16 +1,24 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use anyhow::{bail, Result};
-use camino::Utf8PathBuf;
+use anyhow::{bail, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata::PackageId;
 use regex::Regex;
+use walkdir::WalkDir;
 
 use crate::{
-    cargo::Workspace,
+    cargo::{self, Workspace},
+    ci,
     cli::{BuildOptions, LlvmCovOptions, ManifestOptions},
-    env,
-    process::ProcessBuilder,
-    term,
+    env, llvm_tools,
+    process::{self, ProcessBuilder},
+    term::{self, Coloring},
+    timings::Timings,
 };
 
 pub(crate) struct Context {
@@ -22,6 +30,8 @@ pub(crate) struct Context {
 
     pub(crate) doctests: bool,
     pub(crate) no_run: bool,
+    /// The directories of coverage data given to `merge-and-report`.
+    pub(crate) import_dirs: Vec<Utf8PathBuf>,
 
     pub(crate) workspace_members: WorkspaceMembers,
     pub(crate) build_script_re: Regex,
@@ -29,9 +39,11 @@ pub(crate) struct Context {
 
     // Paths to executables.
     pub(crate) current_exe: PathBuf,
-    // Path to llvm-cov, can be overridden with `LLVM_COV` environment variable.
+    // Path to llvm-cov, can be overridden with `--llvm-cov-path` flag or
+    // `LLVM_COV` environment variable.
     pub(crate) llvm_cov: PathBuf,
-    // Path to llvm-profdata, can be overridden with `LLVM_PROFDATA` environment variable.
+    // Path to llvm-profdata, can be overridden with `--llvm-profdata-path` flag
+    // or `LLVM_PROFDATA` environment variable.
     pub(crate) llvm_profdata: PathBuf,
 
     /// `CARGO_LLVM_COV_FLAGS` environment variable to pass additional flags
@@ -40,6 +52,10 @@ pub(crate) struct Context {
     /// `CARGO_LLVM_PROFDATA_FLAGS` environment variable to pass additional flags
     /// to llvm-profdata. (value: space-separated list)
     pub(crate) cargo_llvm_profdata_flags: Option<String>,
+
+    pub(crate) timings: Timings,
+    /// The format of the URL of hyperlinks, if hyperlinks are enabled.
+    pub(crate) hyperlink_format: Option<String>,
 }
 
 impl Context {
@@ -54,12 +70,31 @@ impl Context {
         no_run: bool,
         show_env: bool,
     ) -> Result<Self> {
-        let ws = Workspace::new(&manifest, build.target.as_deref(), doctests, show_env)?;
+        term::log_json::set(build.log_format == Some(term::LogFormat::Json));
+        term::message_json::set(build.message_format_json());
+        for &(kind, code) in &cov.failure_exit_code {
+            term::set_exit_code(kind, code);
+        }
+        let timings = Timings::new(cov.timings);
+        let mut ws = timings.time("setup", || {
+            Workspace::new(&manifest, build.target.as_deref(), doctests, show_env)
+        })?;
+        if let Some(partition) = build.partition {
+            ws.set_partition(partition);
+        }
         ws.config.merge_to_args(&mut build.target, &mut build.verbose, &mut build.color);
+        ws.config.merge_to_cov(&mut cov, &ws.metadata.workspace_root);
+        let ci = ci::apply_defaults(&mut build);
         term::set_coloring(&mut build.color);
         term::verbose::set(build.verbose != 0);
+        if let (Some(ci), true) = (ci, build.verbose != 0) {
+            status!("Detected", "{}", ci.name());
+        }
+        if build.verbose > 1 {
+            print_coverage_env();
+        }
 
-        cov.html |= cov.open;
+        cov.html |= cov.open.is_some() || cov.azure;
         if cov.output_dir.is_some() && !cov.show() {
             // If the format flag is not specified, this flag is no-op.
             cov.output_dir = None;
@@ -78,6 +113,7 @@ impl Context {
         if cov.output_dir.is_none() && cov.html {
             cov.output_dir = Some(ws.output_dir.clone());
         }
+        let hyperlink_format = hyperlink_format(&build, &cov)?;
 
         // target-libdir (without --target flag) returns $sysroot/lib/rustlib/$host_triple/lib
         // llvm-tools exists in $sysroot/lib/rustlib/$host_triple/bin
@@ -86,43 +122,35 @@ impl Context {
         let mut rustlib: Utf8PathBuf = ws.rustc_print("target-libdir")?.into();
         rustlib.pop(); // lib
         rustlib.push("bin");
-        let llvm_cov: PathBuf = match env::var_os("LLVM_COV") {
-            Some(llvm_cov) => llvm_cov.into(),
+        let llvm_cov = llvm_tool_override(cov.llvm_cov_path.take(), "--llvm-cov-path", "LLVM_COV")?;
+        let llvm_cov: PathBuf = match llvm_cov {
+            Some(llvm_cov) => llvm_cov,
             None => {
                 let llvm_cov = rustlib.join(format!("{}{}", "llvm-cov", env::consts::EXE_SUFFIX));
                 // Check if required tools are installed.
-                if !llvm_cov.exists() {
-                    let sysroot: Utf8PathBuf = ws.rustc_print("sysroot")?.into();
-                    let toolchain = sysroot.file_name().unwrap();
-                    // Include --toolchain flag in the suggestion because the user may be
-                    // using toolchain override shorthand (+toolchain).
-                    bail!(
-                        "failed to find llvm-tools-preview, please install llvm-tools-preview \
-                         with `rustup component add llvm-tools-preview --toolchain {}`",
-                        toolchain,
-                    );
+                if llvm_cov.exists() {
+                    llvm_cov.into()
+                } else {
+                    install_llvm_tools(&ws, &cov, &llvm_cov)?.into()
                 }
-                llvm_cov.into()
             }
         };
-        let llvm_profdata: PathBuf = match env::var_os("LLVM_PROFDATA") {
-            Some(llvm_profdata) => llvm_profdata.into(),
+        let llvm_profdata = llvm_tool_override(
+            cov.llvm_profdata_path.take(),
+            "--llvm-profdata-path",
+            "LLVM_PROFDATA",
+        )?;
+        let llvm_profdata: PathBuf = match llvm_profdata {
+            Some(llvm_profdata) => llvm_profdata,
             None => {
                 let llvm_profdata =
                     rustlib.join(format!("{}{}", "llvm-profdata", env::consts::EXE_SUFFIX));
                 // Check if required tools are installed.
-                if !llvm_profdata.exists() {
-                    let sysroot: Utf8PathBuf = ws.rustc_print("sysroot")?.into();
-                    let toolchain = sysroot.file_name().unwrap();
-                    // Include --toolchain flag in the suggestion because the user may be
-                    // using toolchain override shorthand (+toolchain).
-                    bail!(
-                        "failed to find llvm-tools-preview, please install llvm-tools-preview \
-                         with `rustup component add llvm-tools-preview --toolchain {}`",
-                        toolchain,
-                    );
+                if llvm_profdata.exists() {
+                    llvm_profdata.into()
+                } else {
+                    install_llvm_tools(&ws, &cov, &llvm_profdata)?.into()
                 }
-                llvm_profdata.into()
             }
         };
 
@@ -132,6 +160,7 @@ impl Context {
         }
 
         let build_script_re = pkg_hash_re(&ws, &workspace_members.included);
+        check_vendored_patterns(&ws.metadata, &cov.include_vendored);
 
         Ok(Self {
             ws,
@@ -140,26 +169,66 @@ impl Context {
             cov,
             doctests,
             no_run,
+            import_dirs: vec![],
             workspace_members,
             build_script_re,
             current_dir: env::current_dir().unwrap(),
-            current_exe: match env::current_exe() {
-                Ok(exe) => exe,
-                Err(e) => {
-                    let exe = format!("cargo-llvm-cov{}", env::consts::EXE_SUFFIX);
-                    warn!("failed to get current executable, assuming {} in PATH as current executable: {}", exe, e);
-                    exe.into()
-                }
-            },
+            current_exe: current_exe(),
             llvm_cov,
             llvm_profdata,
             cargo_llvm_cov_flags: env::var("CARGO_LLVM_COV_FLAGS")?,
             cargo_llvm_profdata_flags: env::var("CARGO_LLVM_PROFDATA_FLAGS")?,
+            timings,
+            hyperlink_format,
         })
     }
 
+    /// Checks that llvm-profdata and llvm-cov use the same LLVM major version
+    /// as rustc. Otherwise, they fail with an error that is hard to understand,
+    /// such as "unsupported instrumentation profile format version".
+    pub(crate) fn check_llvm_version(&self) -> Result<()> {
+        let rustc_version = match &self.ws.llvm_version {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        for tool in [&self.llvm_profdata, &self.llvm_cov] {
+            // If the tool cannot be run, it will be reported when it is actually used.
+            let output = match cmd!(tool, "--version").read() {
+                Ok(output) => output,
+                Err(_) => continue,
+            };
+            let tool_version = match cargo::llvm_version(&output) {
+                Some(version) => version,
+                None => continue,
+            };
+            if term::verbose() {
+                status!("Using", "{} (LLVM {})", tool.display(), tool_version);
+            }
+            if cargo::llvm_major_version(tool_version) != cargo::llvm_major_version(rustc_version) {
+                bail!(
+                    "LLVM version mismatch: {} uses LLVM {}, but {} uses LLVM {}; \
+                     consider installing llvm-tools-preview for the toolchain in use, \
+                     or passing --llvm-cov-path and --llvm-profdata-path flags (or setting \
+                     LLVM_COV and LLVM_PROFDATA environment variables) with LLVM tools of \
+                     the same major version as rustc",
+                    tool.display(),
+                    tool_version,
+                    self.ws.rustc(),
+                    rustc_version,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a process builder for LLVM tools.
     pub(crate) fn process(&self, program: impl Into<OsString>) -> ProcessBuilder {
+        // Each process uses its own response file because processes may run concurrently.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
         let mut cmd = cmd!(program);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        cmd.response_file(self.ws.target_dir.join(format!("{}-{}.rsp", self.ws.name, n)));
         // cargo displays env vars only with -vv.
         if self.build.verbose > 1 {
             cmd.display_env_vars();
@@ -170,6 +239,160 @@ impl Context {
     pub(crate) fn cargo(&self) -> ProcessBuilder {
         self.ws.cargo(self.build.verbose)
     }
+
+    /// Excludes workspace members other than the given packages from the report.
+    pub(crate) fn retain_report_members(&mut self, ids: &[PackageId]) {
+        let (included, excluded): (Vec<_>, Vec<_>) =
+            self.workspace_members.included.drain(..).partition(|id| ids.contains(id));
+        self.workspace_members.included = included;
+        self.workspace_members.excluded.extend(excluded);
+        self.build_script_re = pkg_hash_re(&self.ws, &self.workspace_members.included);
+    }
+}
+
+/// Returns the format of the URL of hyperlinks if hyperlinks are enabled.
+fn hyperlink_format(build: &BuildOptions, cov: &LlvmCovOptions) -> Result<Option<String>> {
+    if !cov.hyperlink_format.contains("{path}") {
+        bail!("--hyperlink-format must contain `{{path}}` placeholder");
+    }
+    let enabled = match cov.hyperlinks.unwrap_or(Coloring::Auto) {
+        Coloring::Always => true,
+        Coloring::Never => false,
+        Coloring::Auto => {
+            build.color != Some(Coloring::Never)
+                && atty::is(atty::Stream::Stdout)
+                && env::var_os("TERM").map_or(true, |term| term != "dumb")
+        }
+    };
+    Ok(if enabled { Some(cov.hyperlink_format.clone()) } else { None })
+}
+
+fn current_exe() -> PathBuf {
+    match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            let exe = format!("cargo-llvm-cov{}", env::consts::EXE_SUFFIX);
+            warn!(
+                "failed to get current executable, assuming {} in PATH as current executable: {}",
+                exe, e
+            );
+            exe.into()
+        }
+    }
+}
+
+/// Prints the environment variables inherited by cargo-llvm-cov that affect
+/// the build or the coverage report, for `-vv`.
+fn print_coverage_env() {
+    for (key, value) in env::vars_os() {
+        let key = match key.to_str() {
+            Some(key) => key,
+            None => continue,
+        };
+        let relevant = key.starts_with("CARGO_")
+            || matches!(
+                key,
+                "RUSTFLAGS"
+                    | "RUSTDOCFLAGS"
+                    | "RUSTC"
+                    | "RUSTDOC"
+                    | "RUSTC_WRAPPER"
+                    | "RUSTC_WORKSPACE_WRAPPER"
+                    | "RUSTUP_TOOLCHAIN"
+                    | "LLVM_PROFILE_FILE"
+                    | "LLVM_COV"
+                    | "LLVM_PROFDATA"
+            );
+        if relevant {
+            status!(
+                "Inheriting",
+                "{}={}",
+                key,
+                process::escape_env_value(&value.to_string_lossy())
+            );
+        }
+    }
+}
+
+/// Returns the path to the LLVM tool specified by the flag or the environment variable.
+fn llvm_tool_override(path: Option<PathBuf>, flag: &str, env_var: &str) -> Result<Option<PathBuf>> {
+    let (path, source) = match path {
+        Some(path) => (path, flag),
+        None => match env::var_os(env_var) {
+            Some(path) => (path.into(), env_var),
+            None => return Ok(None),
+        },
+    };
+    // A bare program name is searched in PATH when it is run.
+    let has_dir = path.parent().map_or(false, |dir| !dir.as_os_str().is_empty());
+    if has_dir && !path.is_file() {
+        bail!("{} specified by {} does not exist", path.display(), source);
+    }
+    Ok(Some(path))
+}
+
+/// Downloads llvm-tools if `--download-llvm-tools` is passed, or installs
+/// llvm-tools-preview if `--auto-install-llvm-tools` is passed or the user
+/// agrees to install it, and returns the path to the tool. Otherwise, returns
+/// an error.
+fn install_llvm_tools(
+    ws: &Workspace,
+    cov: &LlvmCovOptions,
+    tool: &Utf8Path,
+) -> Result<Utf8PathBuf> {
+    if cov.download_llvm_tools {
+        return Ok(llvm_tools::download(ws)?.join(tool.file_name().unwrap()));
+    }
+    let sysroot: Utf8PathBuf = ws.rustc_print("sysroot")?.into();
+    let toolchain = sysroot.file_name().unwrap();
+    if cov.auto_install_llvm_tools
+        || term::confirm(&format!(
+            "llvm-tools-preview is not installed; install it for toolchain {}?",
+            toolchain
+        ))
+    {
+        status!("Installing", "llvm-tools-preview for toolchain {}", toolchain);
+        cmd!("rustup", "component", "add", "llvm-tools-preview", "--toolchain", toolchain)
+            .run()
+            .context("failed to install llvm-tools-preview")?;
+        if tool.exists() {
+            return Ok(tool.to_owned());
+        }
+    }
+    // Include --toolchain flag in the suggestion because the user may be
+    // using toolchain override shorthand (+toolchain).
+    bail!(
+        "failed to find llvm-tools-preview, please install llvm-tools-preview \
+         with `rustup component add llvm-tools-preview --toolchain {}`, \
+         or pass --auto-install-llvm-tools (or --download-llvm-tools if rustup is not available)",
+        toolchain,
+    );
+}
+
+/// Returns the directories of the dependencies whose package names match any of
+/// the given patterns.
+pub(crate) fn vendored_dirs<'a>(
+    metadata: &'a cargo_metadata::Metadata,
+    patterns: &[glob::Pattern],
+) -> Vec<&'a Utf8Path> {
+    if patterns.is_empty() {
+        return vec![];
+    }
+    metadata
+        .packages
+        .iter()
+        .filter(|p| !metadata.workspace_members.contains(&p.id))
+        .filter(|p| patterns.iter().any(|pattern| pattern.matches(&p.name)))
+        .map(|p| p.manifest_path.parent().unwrap())
+        .collect()
+}
+
+fn check_vendored_patterns(metadata: &cargo_metadata::Metadata, patterns: &[glob::Pattern]) {
+    for pattern in patterns {
+        if vendored_dirs(metadata, slice::from_ref(pattern)).is_empty() {
+            warn!("--include-vendored {} did not match any dependency", pattern);
+        }
+    }
 }
 
 fn pkg_hash_re(ws: &Workspace, pkg_ids: &[PackageId]) -> Regex {
@@ -221,4 +444,98 @@ impl WorkspaceMembers {
 
         Self { excluded, included }
     }
+
+    /// Returns the paths of the files and directories that belong to the
+    /// excluded packages.
+    pub(crate) fn excluded_paths(&self, metadata: &cargo_metadata::Metadata) -> Vec<Utf8PathBuf> {
+        let dir = |id: &PackageId| metadata[id].manifest_path.parent().unwrap();
+        let excluded: Vec<_> = self.excluded.iter().map(dir).collect();
+        let included: Vec<_> = self.included.iter().map(dir).collect();
+        resolve_excluded_paths(&excluded, &included)
+    }
+}
+
+/// Resolves the paths of the files that belong to the packages in the
+/// `excluded` directories, that is, the files in these directories except for
+/// the files in the `included` package directories nested in them.
+///
+/// Directories that do not contain any included package are returned as is
+/// instead of listing their files.
+pub(crate) fn resolve_excluded_paths(
+    excluded: &[&Utf8Path],
+    included: &[&Utf8Path],
+) -> Vec<Utf8PathBuf> {
+    let mut paths = vec![];
+    for &excluded in excluded {
+        // Note that `starts_with` compares whole components, so `a/b` does not
+        // start with `a/b-c`.
+        let nested: Vec<_> = included.iter().filter(|i| i.starts_with(excluded)).collect();
+        if nested.is_empty() {
+            paths.push(excluded.to_owned());
+            continue;
+        }
+        let mut walker = WalkDir::new(excluded).min_depth(1).into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let path = match Utf8Path::from_path(entry.path()) {
+                Some(path) => path,
+                None => continue,
+            };
+            if entry.file_type().is_dir() {
+                if nested.iter().any(|&&i| i == path) {
+                    // The directory of an included package.
+                    walker.skip_current_dir();
+                    continue;
+                }
+                if nested.iter().any(|i| i.starts_with(path)) {
+                    // Contains an included package.
+                    continue;
+                }
+                walker.skip_current_dir();
+            }
+            paths.push(path.to_owned());
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs;
+
+    #[test]
+    fn excluded_paths() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmpdir.path()).unwrap();
+        for dir in ["src", "tests", "crate1/src", "crate10/src", "a/src", "a/b/src", "a/b-c/src"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["build.rs", "src/lib.rs", "a/lib.rs", "crate1/src/lib.rs"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let resolve = |excluded: &[&str], included: &[&str]| {
+            let excluded: Vec<_> = excluded.iter().map(|p| root.join(p)).collect();
+            let included: Vec<_> = included.iter().map(|p| root.join(p)).collect();
+            let mut paths = resolve_excluded_paths(
+                &excluded.iter().map(|p| &**p).collect::<Vec<_>>(),
+                &included.iter().map(|p| &**p).collect::<Vec<_>>(),
+            );
+            paths.sort();
+            paths.iter().map(|p| p.strip_prefix(root).unwrap().to_string()).collect::<Vec<_>>()
+        };
+
+        // Packages with overlapping names are not affected.
+        assert_eq!(resolve(&["crate1"], &["", "crate10"]), ["crate1"]);
+        assert_eq!(resolve(&["a/b"], &["a", "a/b-c"]), ["a/b"]);
+        // Nested included packages are retained.
+        assert_eq!(resolve(&["a"], &["a/b"]), ["a/b-c", "a/lib.rs", "a/src"]);
+        assert_eq!(resolve(&[""], &["crate1", "a/b"]), [
+            "a/b-c", "a/lib.rs", "a/src", "build.rs", "crate10", "src", "tests"
+        ]);
+    }
 }
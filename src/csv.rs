@@ -0,0 +1,52 @@
+// Region-level CSV export (--csv).
+
+use std::{borrow::Cow, fmt::Write as _};
+
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+const HEADER: &str =
+    "filename,function,line_start,column_start,line_end,column_end,execution_count,kind";
+
+/// Returns a row for each region of each function instantiation, so the regions
+/// of a generic function are listed once per instantiation.
+pub(crate) fn regions(json: &LlvmCovJsonExport) -> String {
+    let mut out = String::new();
+    out.push_str(HEADER);
+    out.push('\n');
+    for r in json.get_regions() {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            field(&r.filename),
+            field(&r.function),
+            r.line_start,
+            r.column_start,
+            r.line_end,
+            r.column_end,
+            r.execution_count,
+            r.kind
+        );
+    }
+    out
+}
+
+/// Quotes the field if needed (RFC 4180).
+fn field(s: &str) -> Cow<'_, str> {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote() {
+        assert_eq!(field("src/lib.rs"), "src/lib.rs");
+        assert_eq!(field("<a::B<u8, u16>>::f"), "\"<a::B<u8, u16>>::f\"");
+        assert_eq!(field("a\"b"), "\"a\"\"b\"");
+    }
+}
@@ -1,7 +1,16 @@
-pub(crate) use std::fs::Metadata;
-use std::{ffi::OsStr, io, path::Path};
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    ffi::OsStr,
+    hash::Hasher,
+    io::{self, BufRead},
+    path::Path,
+};
 
-pub(crate) use fs_err::{create_dir_all, read_dir, symlink_metadata, write};
+pub(crate) use fs_err::{
+    canonicalize, copy, create_dir_all, metadata, read, read_dir, read_to_string, rename,
+    symlink_metadata, write, File, OpenOptions,
+};
 
 /// Removes a file from the filesystem **if exists**.
 pub(crate) fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
@@ -11,6 +20,16 @@ pub(crate) fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
     }
 }
 
+/// Writes to a temporary file next to the given path and renames it, so that
+/// readers never see a partially written file.
+pub(crate) fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    write(&tmp, contents)?;
+    rename(&tmp, path)
+}
+
 /// Removes a directory at this path **if exists**.
 pub(crate) fn remove_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
     match fs_err::remove_dir_all(path.as_ref()) {
@@ -19,6 +38,22 @@ pub(crate) fn remove_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
     }
 }
 
+/// Hashes the content of the file.
+pub(crate) fn hash_file(path: impl AsRef<Path>) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    let mut file = io::BufReader::new(File::open(path.as_ref())?);
+    loop {
+        let buf = file.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.write(buf);
+        let len = buf.len();
+        file.consume(len);
+    }
+    Ok(hasher.finish())
+}
+
 pub(crate) fn file_stem_recursive(path: &Path) -> Option<&OsStr> {
     let mut file_name = path.file_name()?;
     while let Some(stem) = Path::new(file_name).file_stem() {
@@ -29,3 +64,70 @@ pub(crate) fn file_stem_recursive(path: &Path) -> Option<&OsStr> {
     }
     Some(file_name)
 }
+
+/// Normalizes the spelling of a Windows path: removes the verbatim prefix
+/// (`\\?\`), uses an uppercase drive letter, and uses backslashes as separators.
+pub(crate) fn normalize_windows_path(path: &str) -> Cow<'_, str> {
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    let (drive, rest) = split_drive(path);
+    match drive {
+        Some(drive) if drive.is_ascii_lowercase() || rest.contains('/') => {
+            Cow::Owned(format!("{}:{}", drive.to_ascii_uppercase(), rest.replace('/', "\\")))
+        }
+        None if rest.contains('/') => Cow::Owned(rest.replace('/', "\\")),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// Converts the path to a regex that matches the path. On Windows, the regex
+/// also matches different spellings of the path (see `normalize_windows_path`).
+pub(crate) fn path_regex(path: &str, windows: bool) -> String {
+    if !windows {
+        return regex::escape(path);
+    }
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    let (drive, rest) = split_drive(path);
+    let mut out = String::from(r"(\\\\\?\\)?");
+    if let Some(drive) = drive {
+        out.push('[');
+        out.push(drive.to_ascii_lowercase());
+        out.push(drive.to_ascii_uppercase());
+        out.push_str("]:");
+    }
+    let components: Vec<_> = rest.split(|c| c == '\\' || c == '/').map(regex::escape).collect();
+    out.push_str(&components.join(r"[\\/]"));
+    out
+}
+
+fn split_drive(path: &str) -> (Option<char>, &str) {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => (Some(drive), &path[2..]),
+        _ => (None, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path() {
+        assert_eq!(normalize_windows_path(r"C:\a\b.rs"), r"C:\a\b.rs");
+        assert_eq!(normalize_windows_path(r"c:\a\b.rs"), r"C:\a\b.rs");
+        assert_eq!(normalize_windows_path(r"\\?\C:\a\b.rs"), r"C:\a\b.rs");
+        assert_eq!(normalize_windows_path(r"C:\a/b.rs"), r"C:\a\b.rs");
+        assert_eq!(normalize_windows_path("a/b.rs"), r"a\b.rs");
+    }
+
+    #[test]
+    fn path_to_regex() {
+        assert_eq!(path_regex("/a/b.rs", false), r"/a/b\.rs");
+        let re = regex::Regex::new(&format!("^{}$", path_regex(r"\\?\C:\a\b.rs", true))).unwrap();
+        for path in [r"C:\a\b.rs", r"c:\a\b.rs", r"\\?\C:\a\b.rs", r"C:\a/b.rs", "c:/a/b.rs"] {
+            assert!(re.is_match(path), "{}", path);
+        }
+        assert!(!re.is_match(r"D:\a\b.rs"));
+        assert!(!re.is_match(r"C:\a\bxrs"));
+    }
+}
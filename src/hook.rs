@@ -0,0 +1,47 @@
+// Running a command after generating reports (--post-process).
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+use crate::{context::Context, object_list, summary, term};
+
+/// Handles --post-process. The standard output of the command is redirected
+/// to stderr, so that it is not mixed with reports written to stdout.
+pub(crate) fn run(
+    cx: &Context,
+    command: &str,
+    reports: &[(&str, Utf8PathBuf)],
+    json: &LlvmCovJsonExport,
+) -> Result<()> {
+    let mut input = serde_json::json!({
+        "workspace_root": cx.ws.metadata.workspace_root,
+        "reports": reports
+            .iter()
+            .map(|(format, path)| serde_json::json!({ "format": format, "path": path }))
+            .collect::<Vec<_>>(),
+        "totals": summary::totals(json)?,
+        "success": !term::error(),
+    });
+    if let Some(partition) = cx.build.partition {
+        input["partition"] = partition.id().into();
+    }
+    let filter_exprs = object_list::filter_exprs(cx);
+    if !filter_exprs.is_empty() {
+        input["filter_exprs"] = filter_exprs.into();
+    }
+
+    let mut cmd =
+        if cfg!(windows) { cmd!("cmd", "/C", command) } else { cmd!("sh", "-c", command) };
+    cmd.dir(&cx.ws.metadata.workspace_root);
+    for (format, path) in reports {
+        cmd.env(format!("CARGO_LLVM_COV_REPORT_{}", format.to_ascii_uppercase()), path.as_str());
+    }
+    cmd.stdin(serde_json::to_vec(&input)?);
+    cmd.stdout_to_stderr();
+    if term::verbose() {
+        status!("Running", "{}", cmd);
+    }
+    cmd.run().with_context(|| format!("post-process command `{}` failed", command))?;
+    Ok(())
+}
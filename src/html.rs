@@ -0,0 +1,670 @@
+// Incremental regeneration and post-processing of html reports.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    env,
+    fmt::Write as _,
+    hash::Hasher,
+    path::{Component, Path},
+};
+
+use anyhow::{bail, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{context::Context, fs};
+
+const STATE_FILE: &str = ".cargo-llvm-cov-state.json";
+const ROW_START: &str = "<tr class='light-row'>";
+const TOTALS_ROW_START: &str = "<tr class='light-row-bold'>";
+const ROW_END: &str = "</tr>";
+/// The maximum number of tests listed in the tooltip of a line.
+const MAX_TESTS_IN_TOOLTIP: usize = 10;
+const INDEX_SCRIPT_MARKER: &str = "<!-- cargo-llvm-cov: sort and filter -->";
+const INDEX_SCRIPT: &str = r"<style>
+.cargo-llvm-cov-filter { margin: 0 0 8px; padding: 4px; width: 300px; }
+tr:first-child > td.column-entry-bold { cursor: pointer; }
+td[aria-sort=ascending]::after { content: ' \25B2'; }
+td[aria-sort=descending]::after { content: ' \25BC'; }
+</style>
+<script>
+(function () {
+  var table = document.querySelector('table');
+  var rows = table ? Array.prototype.slice.call(table.querySelectorAll('tr.light-row')) : [];
+  if (!rows.length) return;
+  var header = table.querySelector('tr');
+  var totals = table.querySelector('tr.light-row-bold');
+  var input = document.createElement('input');
+  input.type = 'search';
+  input.placeholder = 'Filter files';
+  input.className = 'cargo-llvm-cov-filter';
+  table.parentNode.insertBefore(input, table);
+  input.addEventListener('input', function () {
+    var query = input.value.toLowerCase();
+    rows.forEach(function (row) {
+      var file = row.cells[0].textContent.toLowerCase();
+      row.style.display = file.indexOf(query) === -1 ? 'none' : '';
+    });
+  });
+  // Sort by filename or percentage; cells without anything to cover come last.
+  function key(row, i) {
+    var text = row.cells[i].textContent;
+    if (i === 0) return text;
+    var m = /\((\d+)\/(\d+)\)/.exec(text);
+    return m && +m[2] > 0 ? m[1] / m[2] : -1;
+  }
+  var sorted = -1, ascending = true;
+  Array.prototype.forEach.call(header.cells, function (cell, i) {
+    cell.addEventListener('click', function () {
+      ascending = sorted === i ? !ascending : true;
+      sorted = i;
+      rows.sort(function (a, b) {
+        var x = key(a, i), y = key(b, i);
+        return (x < y ? -1 : x > y ? 1 : 0) * (ascending ? 1 : -1);
+      });
+      rows.forEach(function (row) { row.parentNode.insertBefore(row, totals); });
+      Array.prototype.forEach.call(header.cells, function (c, j) {
+        c.setAttribute('aria-sort', j !== i ? 'none' : ascending ? 'ascending' : 'descending');
+      });
+    });
+  });
+})();
+</script>
+";
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Options that affect all pages.
+    options: String,
+    /// Files -> hash of the source code and the coverage data.
+    files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Plan {
+    /// All pages are up to date.
+    Fresh,
+    /// Only the pages of the given files need to be regenerated.
+    Partial(Vec<String>),
+    /// All pages need to be regenerated.
+    Full,
+}
+
+impl State {
+    pub(crate) fn new(cx: &Context, json: &LlvmCovJsonExport) -> Self {
+        let mut options = String::new();
+        let _ = writeln!(options, "{}", cx.llvm_cov.display());
+        let _ = writeln!(options, "{}", cx.cov.hide_instantiations);
+        let _ = writeln!(options, "{:?}", cx.cov.function_regex);
+        let _ = writeln!(options, "{:?}", cx.build.color);
+        let _ = writeln!(options, "{:?}", cx.cargo_llvm_cov_flags);
+        let _ = writeln!(options, "{}", cx.current_exe.display());
+        let _ = writeln!(options, "{:?}", detail_args(json));
+
+        let mut files = BTreeMap::new();
+        for (file, coverage_hash) in json.file_hashes() {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u64(coverage_hash);
+            match fs::read(cx.ws.metadata.workspace_root.join(&file)) {
+                Ok(source) => hasher.write(&source),
+                // The source file may be generated or no longer exist.
+                Err(_) => continue,
+            }
+            files.insert(file, format!("{:016x}", hasher.finish()));
+        }
+        Self { options, files }
+    }
+
+    /// Determines which pages need to be regenerated.
+    pub(crate) fn plan(&self, cx: &Context, html_dir: &Utf8Path) -> Plan {
+        if !html_dir.join("index.html").exists() {
+            return Plan::Full;
+        }
+        let prev = fs::read_to_string(html_dir.join(STATE_FILE))
+            .ok()
+            .and_then(|s| serde_json::from_str::<Self>(&s).ok());
+        // Paths are relative to the workspace root, so llvm-cov cannot find
+        // the source files given as arguments.
+        let full = cx.build.remap_path_prefix
+            // Totals in the index page are recomputed with the default watermarks.
+            || cx.cargo_llvm_cov_flags.as_ref().map_or(false, |f| f.contains("coverage-watermark"));
+        self.compare(prev.as_ref(), full)
+    }
+
+    /// Compares with the state of the previous run. If `full` is `true`, the
+    /// pages cannot be regenerated separately.
+    fn compare(&self, prev: Option<&Self>, full: bool) -> Plan {
+        let prev = match prev {
+            Some(prev) => prev,
+            // The report was generated by an older version or is broken.
+            None => return Plan::Full,
+        };
+        if self == prev {
+            return Plan::Fresh;
+        }
+        if full || self.options != prev.options || !self.files.keys().eq(prev.files.keys()) {
+            return Plan::Full;
+        }
+        let changed: Vec<_> = self
+            .files
+            .iter()
+            .filter(|&(file, hash)| prev.files.get(file) != Some(hash))
+            .map(|(file, _)| file.clone())
+            .collect();
+        // Regenerating most of the pages separately is no faster than regenerating all.
+        if changed.len() * 2 > self.files.len() {
+            return Plan::Full;
+        }
+        Plan::Partial(changed)
+    }
+
+    pub(crate) fn save(&self, html_dir: &Utf8Path) -> Result<()> {
+        fs::write(html_dir.join(STATE_FILE), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Copies the pages generated in `partial_dir` to `html_dir` and updates the index page.
+pub(crate) fn merge(html_dir: &Utf8Path, partial_dir: &Utf8Path) -> Result<()> {
+    for entry in WalkDir::new(partial_dir.join("coverage")).into_iter().filter_map(Result::ok) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let dst = html_dir
+            .join("coverage")
+            .as_std_path()
+            .join(path.strip_prefix(partial_dir.join("coverage"))?);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path, dst)?;
+    }
+
+    let index = fs::read_to_string(html_dir.join("index.html"))?;
+    let partial_index = fs::read_to_string(partial_dir.join("index.html"))?;
+    let index = merge_index(&index, &partial_index).context("failed to update index.html")?;
+    fs::write(html_dir.join("index.html"), index)?;
+    fs::remove_dir_all(partial_dir)?;
+    Ok(())
+}
+
+/// Returns the flags of `llvm-cov show` to show branch and MC/DC coverage in
+/// the pages and the index, if the coverage data has them (collected with
+/// `-Z coverage-options=branch` or `-Z coverage-options=mcdc`).
+pub(crate) fn detail_args(json: &LlvmCovJsonExport) -> Vec<&'static str> {
+    let has = |kind| json.get_totals(kind).map_or(false, |(count, _)| count > 0);
+    let mut args = vec![];
+    if has("branches") {
+        args.push("-show-branches=count");
+    }
+    // The totals of MC/DC are exported by LLVM 18+.
+    if has("mcdc") {
+        args.push("-show-mcdc");
+        args.push("-show-mcdc-summary");
+    }
+    args
+}
+
+/// Adds sortable columns and a filter box to the index page, if not yet added.
+pub(crate) fn enhance_index(html_dir: &Utf8Path) -> Result<()> {
+    let path = html_dir.join("index.html");
+    let index = fs::read_to_string(&path)?;
+    if let Some(index) = insert_index_script(&index) {
+        fs::write(path, index)?;
+    }
+    Ok(())
+}
+
+fn insert_index_script(index: &str) -> Option<String> {
+    if index.contains(INDEX_SCRIPT_MARKER) {
+        return None;
+    }
+    let pos = index.rfind("</body>").unwrap_or(index.len());
+    Some(format!("{}{}\n{}{}", &index[..pos], INDEX_SCRIPT_MARKER, INDEX_SCRIPT, &index[pos..]))
+}
+
+/// Annotates the covered lines in the pages with the tests that executed them
+/// (--per-test). `tests` is the output of `per_test` module: tests -> files
+/// (relative to the workspace root) -> covered lines.
+pub(crate) fn annotate_tests<T: AsRef<str>>(
+    html_dir: &Utf8Path,
+    workspace_root: &Utf8Path,
+    tests: &BTreeMap<T, BTreeMap<String, BTreeSet<u64>>>,
+) -> Result<()> {
+    let mut files: BTreeMap<&str, BTreeMap<u64, Vec<&str>>> = BTreeMap::new();
+    for (test, covered) in tests {
+        for (file, lines) in covered {
+            let file = files.entry(file).or_default();
+            for &line in lines {
+                file.entry(line).or_default().push(test.as_ref());
+            }
+        }
+    }
+    for (file, lines) in &files {
+        // With --remap-path-prefix, the pages are at the relative paths.
+        let mut path = page(html_dir, workspace_root.join(file).as_str());
+        if !path.exists() {
+            path = page(html_dir, file);
+        }
+        let html = match fs::read_to_string(&path) {
+            Ok(html) => html,
+            Err(_) => continue,
+        };
+        fs::write(&path, annotate_page(&html, lines))?;
+    }
+    Ok(())
+}
+
+/// Sets the tooltip of the execution count cell of each line, replacing the
+/// tooltip of the previous run.
+fn annotate_page(html: &str, lines: &BTreeMap<u64, Vec<&str>>) -> String {
+    const ANCHOR: &str = "<a name='L";
+    const CELL: &str = "<td class='covered-line'";
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(ANCHOR) {
+        let start = start + ANCHOR.len();
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let tests = rest
+            .find('\'')
+            .and_then(|len| rest[..len].parse::<u64>().ok())
+            .and_then(|line| lines.get(&line));
+        let row_end = rest.find(ROW_END).unwrap_or(rest.len());
+        let cell = rest[..row_end].find(CELL).and_then(|cell| {
+            let len = rest[cell..].find('>')?;
+            Some((cell, cell + len))
+        });
+        if let (Some(tests), Some((cell, cell_end))) = (tests, cell) {
+            out.push_str(&rest[..cell]);
+            let _ = write!(out, "{} title='{}'", CELL, tooltip(tests));
+            rest = &rest[cell_end..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn tooltip(tests: &[&str]) -> String {
+    let mut text =
+        format!("Executed by {} test{}:", tests.len(), if tests.len() == 1 { "" } else { "s" });
+    for test in tests.iter().take(MAX_TESTS_IN_TOOLTIP) {
+        text.push('\n');
+        text.push_str(test);
+    }
+    if tests.len() > MAX_TESTS_IN_TOOLTIP {
+        let _ = write!(text, "\n... and {} more", tests.len() - MAX_TESTS_IN_TOOLTIP);
+    }
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&#39;")
+        .replace('\n', "&#10;")
+}
+
+/// Returns the file in the report that matches the path or module path given
+/// to `--open`.
+pub(crate) fn find_file<'a>(
+    filenames: impl IntoIterator<Item = &'a str>,
+    query: &str,
+) -> Result<&'a str> {
+    let filenames: Vec<_> = filenames.into_iter().collect();
+    // A path to an existing file, relative to the current directory.
+    if let Ok(path) = env::current_dir().and_then(|dir| fs::canonicalize(dir.join(query))) {
+        if let Some(file) =
+            filenames.iter().find(|f| fs::canonicalize(f).ok() == Some(path.clone()))
+        {
+            return Ok(file);
+        }
+    }
+    let candidates: Vec<_> =
+        filenames.iter().copied().filter(|file| matches_file(file, query)).collect();
+    match candidates.len() {
+        1 => Ok(candidates[0]),
+        0 => bail!("no file matching `{}` in the coverage report", query),
+        _ => bail!(
+            "multiple files matching `{}` in the coverage report:\n    {}",
+            query,
+            candidates.join("\n    ")
+        ),
+    }
+}
+
+/// Returns `true` if the trailing components of `file` match `query`, which is
+/// either a path (`src/parser.rs`) or a module path (`parser::lexer`).
+fn matches_file(file: &str, query: &str) -> bool {
+    let file = Path::new(file);
+    if Path::new(query).extension().map_or(false, |ext| ext == "rs") {
+        return file.ends_with(query);
+    }
+    let module = query.trim_start_matches("crate::").replace("::", "/");
+    file.ends_with(format!("{}.rs", module)) || file.ends_with(format!("{}/mod.rs", module))
+}
+
+/// Returns the path of the page of `file` in the html report.
+pub(crate) fn page(html_dir: &Utf8Path, file: &str) -> Utf8PathBuf {
+    // llvm-cov puts pages at the path of the source file without the root
+    // (e.g., `/` or `C:\`) in the `coverage` directory.
+    let mut path = html_dir.join("coverage");
+    for component in Path::new(file).components() {
+        if let Component::Normal(c) = component {
+            path.push(c.to_string_lossy().as_ref());
+        }
+    }
+    let mut path = path.into_string();
+    path.push_str(".html");
+    path.into()
+}
+
+/// Replaces the rows in `index` with the rows for the same files in
+/// `partial_index`, and recomputes the totals row.
+fn merge_index(index: &str, partial_index: &str) -> Option<String> {
+    let mut new_rows = BTreeMap::new();
+    for row in rows(partial_index) {
+        new_rows.insert(href(row)?, row);
+    }
+
+    let mut out = String::with_capacity(index.len());
+    let mut totals: Vec<(u64, u64)> = vec![];
+    let mut replaced = BTreeSet::new();
+    let mut rest = index;
+    while let Some(start) = rest.find(ROW_START) {
+        let len = rest[start..].find(ROW_END)? + ROW_END.len();
+        let mut row = &rest[start..start + len];
+        if let Some(new_row) = new_rows.get(href(row)?) {
+            replaced.insert(href(row)?);
+            row = new_row;
+        }
+        for (i, cell) in cells(row).skip(1).enumerate() {
+            let (covered, count) = counts(cell)?;
+            if totals.len() <= i {
+                totals.push((0, 0));
+            }
+            totals[i].0 += covered;
+            totals[i].1 += count;
+        }
+        out.push_str(&rest[..start]);
+        out.push_str(row);
+        rest = &rest[start + len..];
+    }
+    if replaced.len() != new_rows.len() {
+        return None;
+    }
+
+    let start = rest.find(TOTALS_ROW_START)?;
+    let len = rest[start..].find(ROW_END)? + ROW_END.len();
+    let totals_row = &rest[start..start + len];
+    out.push_str(&rest[..start]);
+    out.push_str(TOTALS_ROW_START);
+    out.push_str(cells(totals_row).next()?);
+    for (covered, count) in totals {
+        out.push_str(&cell(covered, count));
+    }
+    out.push_str(ROW_END);
+    out.push_str(&rest[start + len..]);
+    Some(out)
+}
+
+fn rows(index: &str) -> impl Iterator<Item = &str> {
+    index.match_indices(ROW_START).filter_map(move |(start, _)| {
+        let len = index[start..].find(ROW_END)? + ROW_END.len();
+        Some(&index[start..start + len])
+    })
+}
+
+/// Gets the link target of the row, which uniquely identifies the file.
+fn href(row: &str) -> Option<&str> {
+    let start = row.find("<a href='")? + "<a href='".len();
+    let len = row[start..].find('\'')?;
+    Some(&row[start..start + len])
+}
+
+fn cells(row: &str) -> impl Iterator<Item = &str> {
+    row.match_indices("<td").filter_map(move |(start, _)| {
+        let len = row[start..].find("</td>")? + "</td>".len();
+        Some(&row[start..start + len])
+    })
+}
+
+/// Parses `(covered/count)` in the cell.
+fn counts(cell: &str) -> Option<(u64, u64)> {
+    let start = cell.rfind('(')? + 1;
+    let len = cell[start..].find(')')?;
+    let (covered, count) = cell[start..start + len].split_once('/')?;
+    Some((covered.parse().ok()?, count.parse().ok()?))
+}
+
+// https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/tools/llvm-cov/SourceCoverageViewHTML.cpp#L289-L306
+#[allow(clippy::cast_precision_loss)]
+fn cell(covered: u64, count: u64) -> String {
+    let percent = if count == 0 { 0.0 } else { covered as f64 * 100.0 / count as f64 };
+    let text = if count == 0 {
+        format!("- ({}/{})", covered, count)
+    } else {
+        format!("{:7.2}% ({}/{})", percent, covered, count)
+    };
+    // Default watermarks of llvm-cov are 100 (high) and 80 (low).
+    let class = if percent >= 100.0 {
+        "column-entry-green"
+    } else if percent < 80.0 {
+        "column-entry-red"
+    } else {
+        "column-entry-yellow"
+    };
+    format!("<td class='{}'><pre>{}</pre></td>", class, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(rows: &[&str], totals: &str) -> String {
+        format!("<html><table><tr><td>Filename</td></tr>{}{}</table></html>", rows.join(""), totals)
+    }
+
+    fn row(file: &str, lines: (u64, u64), regions: (u64, u64)) -> String {
+        format!(
+            "{}<td><pre><a href='coverage/{}.html'>{}</a></pre></td>{}{}{}",
+            ROW_START,
+            file,
+            file,
+            cell(lines.0, lines.1),
+            cell(regions.0, regions.1),
+            ROW_END
+        )
+    }
+
+    fn totals(lines: (u64, u64), regions: (u64, u64)) -> String {
+        format!(
+            "{}<td><pre>Totals</pre></td>{}{}{}",
+            TOTALS_ROW_START,
+            cell(lines.0, lines.1),
+            cell(regions.0, regions.1),
+            ROW_END
+        )
+    }
+
+    #[test]
+    fn merge_index_rows() {
+        let old = index(
+            &[&row("a.rs", (1, 2), (1, 1)), &row("b.rs", (0, 2), (0, 0))],
+            &totals((1, 4), (1, 1)),
+        );
+        let partial = index(&[&row("b.rs", (2, 2), (1, 3))], &totals((2, 2), (1, 3)));
+        assert_eq!(
+            merge_index(&old, &partial).unwrap(),
+            index(
+                &[&row("a.rs", (1, 2), (1, 1)), &row("b.rs", (2, 2), (1, 3))],
+                &totals((3, 4), (2, 4))
+            )
+        );
+
+        // Unknown file.
+        let partial = index(&[&row("c.rs", (2, 2), (1, 3))], &totals((2, 2), (1, 3)));
+        assert_eq!(merge_index(&old, &partial), None);
+    }
+
+    #[test]
+    fn compare_state() {
+        let state = |options: &str, files: &[(&str, &str)]| State {
+            options: options.to_owned(),
+            files: files.iter().map(|&(f, h)| (f.to_owned(), h.to_owned())).collect(),
+        };
+        let prev = state("a", &[("a.rs", "1"), ("b.rs", "2"), ("c.rs", "3")]);
+        assert_eq!(prev.compare(Some(&prev), false), Plan::Fresh);
+        assert_eq!(prev.compare(None, false), Plan::Full);
+
+        let new = state("a", &[("a.rs", "1"), ("b.rs", "4"), ("c.rs", "3")]);
+        assert_eq!(new.compare(Some(&prev), false), Plan::Partial(vec!["b.rs".to_owned()]));
+        assert_eq!(new.compare(Some(&prev), true), Plan::Full);
+        // Options changed.
+        let new = state("b", &[("a.rs", "1"), ("b.rs", "4"), ("c.rs", "3")]);
+        assert_eq!(new.compare(Some(&prev), false), Plan::Full);
+        // Files added or removed.
+        let new = state("a", &[("a.rs", "1"), ("b.rs", "2")]);
+        assert_eq!(new.compare(Some(&prev), false), Plan::Full);
+        // Most files changed.
+        let new = state("a", &[("a.rs", "4"), ("b.rs", "5"), ("c.rs", "3")]);
+        assert_eq!(new.compare(Some(&prev), false), Plan::Full);
+    }
+
+    #[test]
+    fn merge_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmpdir.path()).unwrap();
+        let html_dir = dir.join("html");
+        let partial_dir = dir.join("html.partial");
+        let old = index(
+            &[&row("a.rs", (1, 2), (1, 1)), &row("b.rs", (0, 2), (0, 0))],
+            &totals((1, 4), (1, 1)),
+        );
+        fs::create_dir_all(html_dir.join("coverage")).unwrap();
+        fs::write(html_dir.join("index.html"), &old).unwrap();
+        fs::write(html_dir.join("coverage/a.rs.html"), "a").unwrap();
+        fs::write(html_dir.join("coverage/b.rs.html"), "b").unwrap();
+        let partial = index(&[&row("b.rs", (2, 2), (1, 3))], &totals((2, 2), (1, 3)));
+        fs::create_dir_all(partial_dir.join("coverage")).unwrap();
+        fs::write(partial_dir.join("index.html"), &partial).unwrap();
+        fs::write(partial_dir.join("coverage/b.rs.html"), "b2").unwrap();
+
+        merge(&html_dir, &partial_dir).unwrap();
+        assert_eq!(fs::read_to_string(html_dir.join("coverage/a.rs.html")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(html_dir.join("coverage/b.rs.html")).unwrap(), "b2");
+        assert_eq!(
+            fs::read_to_string(html_dir.join("index.html")).unwrap(),
+            merge_index(&old, &partial).unwrap()
+        );
+        assert!(!partial_dir.exists());
+
+        // The partial report is missing.
+        assert!(merge(&html_dir, &partial_dir).is_err());
+        // The index page cannot be updated.
+        fs::create_dir_all(partial_dir.join("coverage")).unwrap();
+        let partial = index(&[&row("c.rs", (2, 2), (1, 3))], &totals((2, 2), (1, 3)));
+        fs::write(partial_dir.join("index.html"), partial).unwrap();
+        assert!(merge(&html_dir, &partial_dir).is_err());
+    }
+
+    #[test]
+    fn index_script() {
+        let old = index(&[&row("a.rs", (1, 2), (1, 1))], &totals((1, 2), (1, 1)));
+        let old = old.replace("</html>", "</body></html>");
+        let new = insert_index_script(&old).unwrap();
+        assert!(new.starts_with(&old[..old.len() - "</body></html>".len()]));
+        assert!(new.ends_with("</script>\n</body></html>"));
+        assert_eq!(insert_index_script(&new), None);
+        // The script is kept when the rows are updated.
+        let partial = index(&[&row("a.rs", (2, 2), (1, 1))], &totals((2, 2), (1, 1)));
+        assert!(merge_index(&new, &partial).unwrap().contains(INDEX_SCRIPT_MARKER));
+    }
+
+    #[test]
+    fn annotate_tests_page() {
+        let line = |n: u64, count: &str| {
+            let cell = if count.is_empty() {
+                "<td class='skipped-line'></td>".to_owned()
+            } else {
+                format!("<td class='covered-line'><pre>{}</pre></td>", count)
+            };
+            format!(
+                "<tr><td class='line-number'><a name='L{0}' href='#L{0}'><pre>{0}</pre></a></td>\
+                 {1}<td class='code'><pre>fn f() {{}}</pre></td></tr>",
+                n, cell
+            )
+        };
+        let page = format!("<table>{}{}{}</table>", line(1, "2"), line(2, ""), line(3, "1"));
+        let mut lines = BTreeMap::new();
+        lines.insert(1, vec!["a tests::x", "a tests::<y>"]);
+        lines.insert(3, vec!["a tests::x"]);
+        let annotated = annotate_page(&page, &lines);
+        assert_eq!(
+            annotated,
+            page.replacen(
+                "<td class='covered-line'>",
+                "<td class='covered-line' title='Executed by 2 tests:&#10;a tests::x&#10;a tests::&lt;y&gt;'>",
+                1
+            )
+            .replacen(
+                "<td class='covered-line'><pre>1",
+                "<td class='covered-line' title='Executed by 1 test:&#10;a tests::x'><pre>1",
+                1
+            )
+        );
+        // The tooltips of the previous run are replaced.
+        let mut lines = BTreeMap::new();
+        lines.insert(3, vec!["a tests::z"]);
+        assert_eq!(
+            annotate_page(&annotated, &lines),
+            page.replacen(
+                "<td class='covered-line'>",
+                "<td class='covered-line' title='Executed by 2 tests:&#10;a tests::x&#10;a tests::&lt;y&gt;'>",
+                1
+            )
+            .replacen(
+                "<td class='covered-line'><pre>1",
+                "<td class='covered-line' title='Executed by 1 test:&#10;a tests::z'><pre>1",
+                1
+            )
+        );
+        let many: Vec<_> = (0..12).map(|_| "t").collect();
+        assert!(tooltip(&many).ends_with("&#10;... and 2 more"));
+    }
+
+    #[test]
+    fn find_file_by_path_or_module() {
+        let files =
+            ["/ws/src/lib.rs", "/ws/src/parser.rs", "/ws/src/parser/lexer.rs", "/ws/src/a/mod.rs"];
+        assert_eq!(find_file(files, "src/parser.rs").unwrap(), "/ws/src/parser.rs");
+        assert_eq!(find_file(files, "parser").unwrap(), "/ws/src/parser.rs");
+        assert_eq!(find_file(files, "crate::parser::lexer").unwrap(), "/ws/src/parser/lexer.rs");
+        assert_eq!(find_file(files, "a").unwrap(), "/ws/src/a/mod.rs");
+        assert!(find_file(files, "arser.rs").is_err());
+        assert!(find_file(files, "main").is_err());
+        let files = ["/ws/a/src/lib.rs", "/ws/b/src/lib.rs"];
+        assert!(find_file(files, "src/lib.rs").is_err());
+        assert_eq!(find_file(files, "b/src/lib.rs").unwrap(), "/ws/b/src/lib.rs");
+    }
+
+    #[test]
+    fn page_path() {
+        assert_eq!(
+            page(Utf8Path::new("target/llvm-cov/html"), "/ws/src/parser.rs"),
+            "target/llvm-cov/html/coverage/ws/src/parser.rs.html"
+        );
+        assert_eq!(
+            page(Utf8Path::new("target/llvm-cov/html"), "src/parser.rs"),
+            "target/llvm-cov/html/coverage/src/parser.rs.html"
+        );
+    }
+
+    #[test]
+    fn format_cell() {
+        assert_eq!(cell(1, 1), "<td class='column-entry-green'><pre> 100.00% (1/1)</pre></td>");
+        assert_eq!(cell(9, 10), "<td class='column-entry-yellow'><pre>  90.00% (9/10)</pre></td>");
+        assert_eq!(cell(1, 3), "<td class='column-entry-red'><pre>  33.33% (1/3)</pre></td>");
+        assert_eq!(cell(0, 0), "<td class='column-entry-red'><pre>- (0/0)</pre></td>");
+    }
+}
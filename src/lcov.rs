@@ -0,0 +1,108 @@
+// Post-processing of coverage data in lcov format.
+// https://github.com/linux-test-project/lcov/blob/v1.16/man/geninfo.1#L989-L1171
+
+use std::{io, mem};
+
+use crate::fs;
+
+/// Normalizes the spelling of the paths of files (see `fs::normalize_windows_path`).
+pub(crate) fn normalize_windows_paths(lcov: &str) -> String {
+    let mut out = String::with_capacity(lcov.len());
+    for line in lcov.lines() {
+        match line.strip_prefix("SF:") {
+            Some(path) => {
+                out.push_str("SF:");
+                out.push_str(&fs::normalize_windows_path(path));
+            }
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Sorts the records of files in the coverage data by filename.
+///
+/// Returns `None` if the records are already sorted.
+pub(crate) fn sort(lcov: &str) -> Option<String> {
+    const END: &str = "end_of_record\n";
+
+    let mut records = vec![];
+    let mut rest = lcov;
+    while let Some(i) = rest.find(END) {
+        records.push(&rest[..i + END.len()]);
+        rest = &rest[i + END.len()..];
+    }
+    if records.windows(2).all(|w| filename(w[0]) <= filename(w[1])) {
+        return None;
+    }
+    records.sort_by_key(|record| filename(record));
+
+    let mut out = String::with_capacity(lcov.len());
+    for record in records {
+        out.push_str(record);
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Reads the records of files in the coverage data, sorted by filename.
+///
+/// Anything after the last `end_of_record` line is returned as the last record.
+pub(crate) fn read_sorted(reader: impl io::BufRead) -> io::Result<Vec<String>> {
+    let mut records = vec![];
+    let mut record = String::new();
+    for line in reader.lines() {
+        let line = line?;
+        record.push_str(&line);
+        record.push('\n');
+        if line == "end_of_record" {
+            records.push(mem::take(&mut record));
+        }
+    }
+    records.sort_by(|a, b| filename(a).cmp(&filename(b)));
+    if !record.is_empty() {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Returns the filename of a record in the coverage data.
+pub(crate) fn filename(record: &str) -> Option<&str> {
+    record.lines().find_map(|line| line.strip_prefix("SF:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_records() {
+        let a = "SF:a.rs\nDA:1,1\nLF:1\nLH:1\nend_of_record\n";
+        let b = "SF:b.rs\nDA:1,0\nLF:1\nLH:0\nend_of_record\n";
+        assert_eq!(sort(&format!("{}{}", a, b)), None);
+        assert_eq!(sort(&format!("{}{}", b, a)), Some(format!("{}{}", a, b)));
+        assert_eq!(sort(""), None);
+    }
+
+    #[test]
+    fn read_sorted_records() {
+        let a = "SF:a.rs\nDA:1,1\nLF:1\nLH:1\nend_of_record\n";
+        let b = "SF:b.rs\nDA:1,0\nLF:1\nLH:0\nend_of_record\n";
+        let records = read_sorted(format!("{}{}", b, a).as_bytes()).unwrap();
+        assert_eq!(records, [a, b]);
+        assert_eq!(filename(&records[1]), Some("b.rs"));
+        // The last record may not end with a newline.
+        let records = read_sorted(format!("{}{}", b, a.trim_end()).as_bytes()).unwrap();
+        assert_eq!(records, [a, b]);
+        assert!(read_sorted(&b""[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(
+            normalize_windows_paths("SF:c:\\a/b.rs\nDA:1,1\nend_of_record\n"),
+            "SF:C:\\a\\b.rs\nDA:1,1\nend_of_record\n"
+        );
+    }
+}
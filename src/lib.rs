@@ -2,7 +2,7 @@
 #![warn(rust_2018_idioms, single_use_lifetimes, unreachable_pub)]
 #![warn(clippy::pedantic)]
 #![allow(clippy::single_match_else)]
-// All items are not public APIs.
+// All items are not public APIs. Use cargo-llvm-cov-core crate instead.
 #![doc(hidden)]
 
-pub mod json;
+pub use cargo_llvm_cov_core::json;
@@ -0,0 +1,283 @@
+// Downloading llvm-tools without rustup (--download-llvm-tools).
+
+use std::{env, io};
+
+use anyhow::{bail, format_err, Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+
+use crate::{cargo::Workspace, fs};
+
+const DIST_SERVER: &str = "https://static.rust-lang.org/dist";
+const COMPONENT: &str = "llvm-tools-preview";
+
+struct RustcVersion {
+    release: String,
+    commit_hash: String,
+    commit_date: String,
+}
+
+/// Returns the directory containing llvm-cov and llvm-profdata of the
+/// llvm-tools for the rustc in use, downloading them if they are not cached.
+pub(crate) fn download(ws: &Workspace) -> Result<Utf8PathBuf> {
+    let mut cmd = ws.rustc();
+    cmd.args(["--version", "--verbose"]);
+    let verbose_version = cmd.read()?;
+    let version = rustc_version(&verbose_version).ok_or_else(|| {
+        format_err!("unexpected version output from `{}`: {}", cmd, verbose_version)
+    })?;
+    if version.commit_hash.len() < 9 || !version.commit_hash.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        bail!("--download-llvm-tools is not supported for rustc built from source");
+    }
+    let host = &ws.host_triple;
+
+    let dir =
+        cache_dir()?.join(format!("{}-{}-{}", version.release, &version.commit_hash[..9], host));
+    let bin_dir = dir.join(COMPONENT).join("lib").join("rustlib").join(host).join("bin");
+    if ["llvm-cov", "llvm-profdata"]
+        .iter()
+        .all(|tool| bin_dir.join(format!("{}{}", tool, env::consts::EXE_SUFFIX)).exists())
+    {
+        return Ok(bin_dir);
+    }
+
+    let (url, hash) = find_package(&version, host)?;
+    status!("Downloading", "{}", url);
+    let tmp_dir = Utf8PathBuf::from(format!("{}.tmp", dir));
+    fs::remove_dir_all(&tmp_dir)?;
+    fs::create_dir_all(&tmp_dir)?;
+    let archive = tmp_dir.join("llvm-tools.tar.gz");
+    curl(&url).arg("--output").arg(&archive).run()?;
+    let actual = sha256_file(&archive)?;
+    if actual != hash {
+        bail!("checksum mismatch for {}: expected {}, but got {}", url, hash, actual);
+    }
+    // The archive contains a `<package>-<version>-<host>` directory.
+    cmd!("tar", "-xzf", &archive, "-C", &tmp_dir, "--strip-components=1")
+        .run()
+        .context("failed to extract llvm-tools")?;
+    fs::remove_file(&archive)?;
+    fs::remove_dir_all(&dir)?;
+    fs::rename(&tmp_dir, &dir)?;
+    Ok(bin_dir)
+}
+
+/// `$CARGO_HOME/cargo-llvm-cov/llvm-tools`, which contains a directory for each
+/// build of rustc.
+fn cache_dir() -> Result<Utf8PathBuf> {
+    let cargo_home = home::cargo_home().context("failed to find cargo home directory")?;
+    let cargo_home = Utf8PathBuf::from_path_buf(cargo_home)
+        .map_err(|p| format_err!("{} is not valid UTF-8", p.display()))?;
+    Ok(cargo_home.join("cargo-llvm-cov").join("llvm-tools"))
+}
+
+fn curl(url: &str) -> crate::process::ProcessBuilder {
+    cmd!(
+        "curl",
+        "--proto",
+        "=https",
+        "--tlsv1.2",
+        "--fail",
+        "--silent",
+        "--show-error",
+        "--location",
+        url
+    )
+}
+
+fn rustc_version(verbose_version: &str) -> Option<RustcVersion> {
+    let field = |name: &str| {
+        verbose_version
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .map(str::to_owned)
+    };
+    Some(RustcVersion {
+        release: field("release")?,
+        commit_hash: field("commit-hash")?,
+        commit_date: field("commit-date")?,
+    })
+}
+
+/// Returns the URL and the SHA-256 checksum of the llvm-tools package for the
+/// given build of rustc.
+fn find_package(version: &RustcVersion, host: &str) -> Result<(String, String)> {
+    let short_hash = &version.commit_hash[..9];
+    let mut last_error = None;
+    for manifest_url in manifest_urls(version) {
+        // There is no manifest for a date on which no release was made.
+        let manifest = match curl(&manifest_url).read() {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                last_error = Some(e);
+                continue;
+            }
+        };
+        // A release on the date may be built from another commit.
+        let rustc = manifest_value(&manifest, "pkg.rustc", "version").unwrap_or_default();
+        if !rustc.contains(&format!("({} ", short_hash)) {
+            continue;
+        }
+        let section = format!("pkg.{}.target.{}", COMPONENT, host);
+        if manifest_value(&manifest, &section, "available") != Some("true") {
+            bail!("llvm-tools is not available for {} in rust {}", host, version.release);
+        }
+        match (
+            manifest_value(&manifest, &section, "url"),
+            manifest_value(&manifest, &section, "hash"),
+        ) {
+            (Some(url), Some(hash)) => return Ok((url.to_owned(), hash.to_owned())),
+            _ => bail!("unexpected channel manifest {}", manifest_url),
+        }
+    }
+    let msg = format!(
+        "failed to find llvm-tools for rustc {} ({} {}) on {}",
+        version.release, short_hash, version.commit_date, DIST_SERVER
+    );
+    match last_error {
+        Some(e) => Err(e.context(msg)),
+        None => Err(format_err!(msg)),
+    }
+}
+
+/// Returns the URLs of the channel manifests that may contain the given build of rustc.
+fn manifest_urls(version: &RustcVersion) -> Vec<String> {
+    let channel = match version.release.split_once('-') {
+        None => return vec![format!("{}/channel-rust-{}.toml", DIST_SERVER, version.release)],
+        Some((_, pre)) if pre.starts_with("beta") => "beta",
+        Some(_) => "nightly",
+    };
+    // Nightly and beta releases are built from the commit of the previous day,
+    // or of an earlier day if the build on the following day failed.
+    let mut urls = vec![];
+    let mut date = version.commit_date.clone();
+    for _ in 0..3 {
+        date = match next_day(&date) {
+            Some(date) => date,
+            None => break,
+        };
+        urls.push(format!("{}/{}/channel-rust-{}.toml", DIST_SERVER, date, channel));
+    }
+    urls
+}
+
+fn next_day(date: &str) -> Option<String> {
+    let mut iter = date.splitn(3, '-').map(|n| n.parse::<u32>().ok());
+    let (year, month, day) = (iter.next()??, iter.next()??, iter.next()??);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let (year, month, day) = if day < days {
+        (year, month, day + 1)
+    } else if month < 12 {
+        (year, month + 1, 1)
+    } else {
+        (year + 1, 1, 1)
+    };
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// Returns the value of `key` in the `[section]` table of a channel manifest.
+///
+/// Channel manifests are generated TOML files, where each key-value pair is on
+/// its own line.
+fn manifest_value<'a>(manifest: &'a str, section: &str, key: &str) -> Option<&'a str> {
+    let mut in_section = false;
+    for line in manifest.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            in_section = header.strip_suffix(']') == Some(section);
+        } else if in_section {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim() == key {
+                    return Some(v.trim().trim_matches('"'));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Returns the SHA-256 digest of the file as a lowercase hex string.
+fn sha256_file(path: &Utf8Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(dir.path()).unwrap().join("a");
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn channel_manifest() {
+        let manifest = r#"
+manifest-version = "2"
+date = "2022-11-03"
+[pkg.rustc]
+version = "1.65.0 (897e37553 2022-11-02)"
+[pkg.llvm-tools-preview]
+version = "1.65.0 (897e37553 2022-11-02)"
+[pkg.llvm-tools-preview.target.x86_64-unknown-linux-gnu]
+available = true
+url = "https://static.rust-lang.org/dist/2022-11-03/llvm-tools-1.65.0-x86_64-unknown-linux-gnu.tar.gz"
+hash = "0123abcd"
+[pkg.llvm-tools-preview.target.x86_64-unknown-linux-musl]
+available = false
+"#;
+        let section = "pkg.llvm-tools-preview.target.x86_64-unknown-linux-gnu";
+        assert_eq!(
+            manifest_value(manifest, "pkg.rustc", "version"),
+            Some("1.65.0 (897e37553 2022-11-02)")
+        );
+        assert_eq!(manifest_value(manifest, section, "available"), Some("true"));
+        assert_eq!(manifest_value(manifest, section, "hash"), Some("0123abcd"));
+        assert_eq!(manifest_value(manifest, section, "xz_hash"), None);
+        assert_eq!(
+            manifest_value(
+                manifest,
+                "pkg.llvm-tools-preview.target.x86_64-unknown-linux-musl",
+                "available"
+            ),
+            Some("false")
+        );
+
+        assert_eq!(next_day("2022-11-02").as_deref(), Some("2022-11-03"));
+        assert_eq!(next_day("2024-02-28").as_deref(), Some("2024-02-29"));
+        assert_eq!(next_day("2023-02-28").as_deref(), Some("2023-03-01"));
+        assert_eq!(next_day("2022-12-31").as_deref(), Some("2023-01-01"));
+        assert_eq!(next_day("unknown"), None);
+
+        let version = |release: &str| RustcVersion {
+            release: release.to_owned(),
+            commit_hash: "897e37553bba8b42751c67658967889d11ecd120".to_owned(),
+            commit_date: "2022-12-31".to_owned(),
+        };
+        assert_eq!(manifest_urls(&version("1.65.0")), [
+            "https://static.rust-lang.org/dist/channel-rust-1.65.0.toml"
+        ]);
+        assert_eq!(manifest_urls(&version("1.67.0-nightly")), [
+            "https://static.rust-lang.org/dist/2023-01-01/channel-rust-nightly.toml",
+            "https://static.rust-lang.org/dist/2023-01-02/channel-rust-nightly.toml",
+            "https://static.rust-lang.org/dist/2023-01-03/channel-rust-nightly.toml",
+        ]);
+        assert_eq!(manifest_urls(&version("1.66.0-beta.5")).len(), 3);
+        assert!(manifest_urls(&version("1.66.0-beta.5"))[0].ends_with("channel-rust-beta.toml"));
+    }
+}
@@ -14,63 +14,126 @@ mod term;
 #[macro_use]
 mod process;
 
+mod annotations;
+mod artifacts;
+mod attempts;
 mod cargo;
+mod changed;
+mod ci;
 mod clean;
 mod cli;
+mod cobertura;
+mod codecov;
+mod comment;
 mod config;
 mod context;
+mod csv;
 mod demangler;
 mod env;
 mod fs;
+mod hook;
+mod html;
+mod lcov;
+mod llvm_tools;
+mod markers;
+mod merge;
+mod metadata_cache;
+mod notify;
+mod object_list;
+mod per_test;
+mod profile;
+mod profraw;
+mod progress;
+mod report;
+mod shell;
+mod summary;
+mod template;
+mod timings;
+mod treemap;
+mod uncovered;
+mod uncovered_spans;
+mod unsafe_coverage;
+mod vscoverage;
 
 use std::{
-    collections::HashMap,
-    ffi::{OsStr, OsString},
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
     fmt::Write as _,
-    io,
+    io, mem,
     path::Path,
 };
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Error, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use cargo_llvm_cov::json;
+use cargo_llvm_cov_core::json;
 use clap::Parser;
-use cli::{RunOptions, ShowEnvOptions};
+use cli::{
+    LlvmCovOptions, ManifestOptions, MergeAndReportOptions, RunOptions, ShowEnvFormat,
+    ShowEnvOptions,
+};
 use regex::Regex;
 use walkdir::WalkDir;
 
 use crate::{
+    cargo::Workspace,
     cli::{Args, Opts, Subcommand},
-    config::StringOrArray,
+    config::Flags,
     context::Context,
-    json::LlvmCovJsonExport,
+    object_list::ObjectList,
     process::ProcessBuilder,
-    term::Coloring,
 };
 
 fn main() {
     if let Err(e) = try_main() {
         error!("{:#}", e);
     }
-    if term::error()
+    let failed = term::error()
         || term::warn()
-            && env::var_os("CARGO_LLVM_COV_DENY_WARNINGS").filter(|v| v == "true").is_some()
-    {
-        std::process::exit(1)
+            && env::var_os("CARGO_LLVM_COV_DENY_WARNINGS").filter(|v| v == "true").is_some();
+    if term::log_json() {
+        let exit_code = if failed { term::exit_code() } else { 0 };
+        term::print_event(&serde_json::json!({
+            "reason": "finished",
+            "success": !failed,
+            "exit_code": exit_code,
+        }));
+    }
+    if term::message_json() {
+        term::print_message(&serde_json::json!({
+            "reason": "llvm-cov-finished",
+            "success": !failed,
+        }));
+    }
+    if failed {
+        std::process::exit(term::exit_code())
     }
 }
 
+/// Parses the arguments, accepting both `cargo llvm-cov <ARGS>...` and
+/// `cargo-llvm-cov <ARGS>...`.
+fn parse_args() -> Args {
+    let (args, by_cargo) = cli::normalize_args(env::args_os());
+    if !by_cargo {
+        cargo::set_run_directly();
+    }
+    let Opts::LlvmCov(args) = Opts::parse_from(args);
+    args
+}
+
 fn try_main() -> Result<()> {
-    let Opts::LlvmCov(mut args) = Opts::parse();
+    let mut args = parse_args();
+
+    check_nested(&args)?;
+    warn_mistyped_test_args(&args);
 
     match args.subcommand.take() {
         Some(Subcommand::Demangle) => {
             demangler::run()?;
         }
 
-        Some(Subcommand::Clean(options)) => {
-            clean::run(options)?;
-        }
+        Some(Subcommand::NextestRunner { args }) => attempts::run(&args)?,
+
+        Some(Subcommand::Clean(options)) => clean::run(options)?,
 
         Some(Subcommand::Run(mut args)) => {
             let cx = &Context::new(
@@ -84,57 +147,66 @@ fn try_main() -> Result<()> {
                 false,
             )?;
 
-            clean::clean_partial(cx)?;
+            cx.timings.time("clean", || clean::clean_partial(cx, &build_fingerprint(cx)))?;
             create_dirs(cx)?;
 
             run_run(cx, &args)?;
 
-            if !cx.cov.no_report {
-                generate_report(cx)?;
+            if cx.cov.no_report {
+                save_object_files(cx)?;
+            } else {
+                report::generate(cx)?;
             }
+            cx.timings.finish(&cx.ws)?;
         }
 
+        Some(Subcommand::MergeAndReport(options)) => merge_and_report(*options)?,
+
         Some(Subcommand::ShowEnv(options)) => {
             let cx = &context_from_args(&mut args, true)?;
-            let stdout = io::stdout();
-            let writer = &mut ShowEnvWriter { target: stdout.lock(), options };
-            set_env(cx, writer);
-            writer.set("CARGO_LLVM_COV_TARGET_DIR", cx.ws.metadata.target_directory.as_str());
+            show_env(cx, options)?;
         }
 
         Some(Subcommand::Nextest { passthrough_options }) => {
-            let cx = &context_from_args(
-                &mut Args::try_parse_from(
-                    [
-                        // fake argv[0] to help clap parse
-                        "nextest".to_string(),
-                    ]
-                    .iter()
-                    // real pass-through args
-                    .chain(passthrough_options.iter()),
-                )?,
-                false,
+            let mut nextest_args = Args::try_parse_from(
+                [
+                    // fake argv[0] to help clap parse
+                    OsString::from("nextest"),
+                ]
+                .iter()
+                // real pass-through args
+                .chain(passthrough_options.iter()),
             )?;
+            nextest_args.set_nextest();
+            let cx = &context_from_args(&mut nextest_args, false)?;
 
-            clean::clean_partial(cx)?;
+            cx.timings.time("clean", || clean::clean_partial(cx, &build_fingerprint(cx)))?;
             create_dirs(cx)?;
             match (args.no_run, cx.cov.no_report) {
                 (false, false) => {
                     run_nextest(cx, &args)?;
-                    generate_report(cx)?;
+                    report::generate(cx)?;
                 }
                 (false, true) => {
                     run_nextest(cx, &args)?;
+                    save_object_files(cx)?;
                 }
                 (true, false) => {
-                    generate_report(cx)?;
+                    report::generate(cx)?;
                 }
                 (true, true) => unreachable!(),
             }
+            cx.timings.finish(&cx.ws)?;
         }
 
+        None if args.manifest_paths().len() > 1 => run_workspaces(args)?,
+
         None => {
-            let cx = &context_from_args(&mut args, false)?;
+            let mut cx = context_from_args(&mut args, false)?;
+            if !apply_changed_since(&mut cx, &mut args)? {
+                return Ok(());
+            }
+            let cx = &cx;
             let tmp = term::warn(); // The following warnings should not be promoted to an error.
             if args.doctests {
                 warn!("--doctests option is unstable");
@@ -145,29 +217,254 @@ fn try_main() -> Result<()> {
             }
             term::warn::set(tmp);
 
-            clean::clean_partial(cx)?;
+            cx.timings.time("clean", || clean::clean_partial(cx, &build_fingerprint(cx)))?;
             create_dirs(cx)?;
             match (args.no_run, cx.cov.no_report) {
                 (false, false) => {
                     run_test(cx, &args)?;
-                    generate_report(cx)?;
+                    report::generate(cx)?;
                 }
                 (false, true) => {
                     run_test(cx, &args)?;
+                    save_object_files(cx)?;
                 }
                 (true, false) => {
-                    generate_report(cx)?;
+                    report::generate(cx)?;
                 }
                 (true, true) => unreachable!(),
             }
+            cx.timings.finish(&cx.ws)?;
+        }
+    }
+    Ok(())
+}
+
+fn show_env(cx: &Context, options: ShowEnvOptions) -> Result<()> {
+    if options.format == Some(ShowEnvFormat::Json) || options.github_env {
+        if options.export_prefix {
+            bail!("--export-prefix may not be used together with --format json");
+        }
+        let mut env = BTreeMap::<String, Option<String>>::new();
+        set_show_env(cx, &mut env);
+        if options.github_env {
+            return ci::write_github_env(&env);
         }
+        let out = serde_json::json!({
+            "env": env,
+            "target_dir": cx.ws.target_dir,
+            "profraw_pattern": cx.ws.profraw_pattern(cx.build.partition),
+        });
+        println!("{}", serde_json::to_string(&out)?);
+        return Ok(());
     }
+    let stdout = io::stdout();
+    let writer = &mut ShowEnvWriter { target: stdout.lock(), options };
+    set_show_env(cx, writer);
     Ok(())
 }
 
+fn set_show_env(cx: &Context, env: &mut impl EnvTarget) {
+    set_env(cx, env);
+    // The environment variables corresponding to the cargo flags that the test
+    // subcommand passes, so that plain cargo builds in the same way.
+    if let Some(target) = &cx.build.target {
+        env.set("CARGO_BUILD_TARGET", target);
+    }
+    if cx.doctests {
+        // `-Z doctest-in-workspace`
+        // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#doctest-in-workspace
+        env.set("CARGO_UNSTABLE_DOCTEST_IN_WORKSPACE", "true");
+    }
+    env.set("CARGO_LLVM_COV_TARGET_DIR", cx.ws.metadata.target_directory.as_str());
+    // Marker to detect builds by cargo-llvm-cov in this environment.
+    env.set("CARGO_LLVM_COV_SHOW_ENV", "1");
+}
+
+/// Runs tests in each of the workspaces given by multiple --manifest-path, and
+/// generates one report from the coverage data of all of them.
+///
+/// Each workspace is run as with `--no-report --artifact-dir`, exporting its
+/// coverage data to the target directory of the first workspace, and the
+/// report is generated from these directories as with `merge-and-report`.
+fn run_workspaces(mut args: Args) -> Result<()> {
+    if args.no_run {
+        bail!("--no-run may not be used together with multiple --manifest-path");
+    }
+    if args.changed_since.is_some() {
+        bail!("--changed-since may not be used together with multiple --manifest-path");
+    }
+    let build = args.build();
+    if build.cargo_profile.is_some() {
+        bail!(
+            "--cargo-profile is only supported by `cargo llvm-cov nextest`; use --profile instead"
+        );
+    }
+    let mut manifest = args.manifest();
+    let cov = args.cov();
+    let manifest_paths = mem::take(&mut manifest.manifest_path);
+    let manifest_options = |manifest_path: &Utf8PathBuf| ManifestOptions {
+        manifest_path: vec![manifest_path.clone()],
+        ..manifest.clone()
+    };
+    if args.doctests {
+        warn!("--doctests option is unstable");
+    }
+
+    let mut dirs: Vec<Utf8PathBuf> = vec![];
+    for manifest_path in &manifest_paths {
+        let mut cx = Context::new(
+            build.clone(),
+            manifest_options(manifest_path),
+            LlvmCovOptions { no_report: true, ..cov.clone() },
+            &args.exclude,
+            &args.exclude_from_report,
+            args.doctests,
+            false,
+            false,
+        )?;
+        let dir = match dirs.first() {
+            Some(first) => first.with_file_name(dirs.len().to_string()),
+            None => {
+                let dir = cx.ws.target_dir.join("workspaces");
+                fs::remove_dir_all(&dir)?;
+                dir.join("0")
+            }
+        };
+        cx.cov.artifact_dir = Some(dir.clone());
+        let cx = &cx;
+        status!("Testing", "workspace at {}", cx.ws.metadata.workspace_root);
+        cx.timings.time("clean", || clean::clean_partial(cx, &build_fingerprint(cx)))?;
+        create_dirs(cx)?;
+        run_test(cx, &args)?;
+        save_object_files(cx)?;
+        cx.timings.finish(&cx.ws)?;
+        dirs.push(dir);
+    }
+
+    let mut cx = Context::new(
+        build,
+        manifest_options(&manifest_paths[0]),
+        cov,
+        &args.exclude,
+        &args.exclude_from_report,
+        args.doctests,
+        true,
+        false,
+    )?;
+    cx.import_dirs = dirs;
+    let cx = &cx;
+    create_dirs(cx)?;
+    report::generate(cx)?;
+    cx.timings.finish(&cx.ws)
+}
+
+/// Generates the report from the coverage data exported by `--no-report --artifact-dir`.
+fn merge_and_report(mut options: MergeAndReportOptions) -> Result<()> {
+    let mut cx = Context::new(
+        options.build(),
+        options.manifest(),
+        options.cov(),
+        &[],
+        &[],
+        false,
+        true,
+        false,
+    )?;
+    cx.import_dirs = mem::take(&mut options.dirs);
+    let cx = &cx;
+    create_dirs(cx)?;
+    report::generate(cx)?;
+    cx.timings.finish(&cx.ws)
+}
+
+/// Warns about arguments passed to the test binary (after `--`) that look like
+/// flags of cargo-llvm-cov.
+fn warn_mistyped_test_args(args: &Args) {
+    let (test_args, suggest): (_, fn(&str) -> Option<String>) = match &args.subcommand {
+        None => (&args.args, cli::suggest_flag::<Args>),
+        Some(Subcommand::Run(run)) => (&run.args, cli::suggest_flag::<RunOptions>),
+        Some(_) => return,
+    };
+    for arg in test_args {
+        let suggestion = match arg.to_str().and_then(suggest) {
+            Some(suggestion) => suggestion,
+            None => continue,
+        };
+        warn!(
+            "`{}` is passed to the test binary because it is after `--`; \
+             if you meant `{}` flag of cargo-llvm-cov, pass it before `--`",
+            arg.to_string_lossy(),
+            suggestion
+        );
+    }
+}
+
+/// Checks that cargo-llvm-cov is not run in the environment set up by another
+/// cargo-llvm-cov, in which the flags and the paths of profile data of the
+/// outer one would be mixed into the inner one.
+///
+/// Subcommands that do not build and generating a report without building
+/// (`--no-run`) are fine.
+fn check_nested(args: &Args) -> Result<()> {
+    if args.no_run
+        || matches!(
+            args.subcommand,
+            Some(
+                Subcommand::Demangle
+                    | Subcommand::Clean(_)
+                    | Subcommand::NextestRunner { .. }
+                    | Subcommand::MergeAndReport(_)
+            )
+        )
+        || env::var_os("CARGO_LLVM_COV").is_none()
+    {
+        return Ok(());
+    }
+    if env::var_os("CARGO_LLVM_COV_SHOW_ENV").is_some() {
+        bail!(
+            "cargo-llvm-cov cannot build in the environment set up by `cargo llvm-cov show-env`; \
+             use cargo (e.g., `cargo test`) directly and then `cargo llvm-cov --no-run` to \
+             generate the report, or run cargo-llvm-cov in a fresh environment"
+        );
+    }
+    bail!(
+        "cargo-llvm-cov cannot build in the environment set up by another cargo-llvm-cov \
+         (e.g., from tests run by cargo-llvm-cov); if this is intended, remove CARGO_LLVM_COV, \
+         RUSTFLAGS, RUSTDOCFLAGS, and LLVM_PROFILE_FILE environment variables when spawning it"
+    )
+}
+
+/// Handles --changed-since. Returns `false` if there are no packages to test.
+fn apply_changed_since(cx: &mut Context, args: &mut Args) -> Result<bool> {
+    let rev = match &args.changed_since {
+        Some(rev) => rev,
+        None => return Ok(true),
+    };
+    let affected = changed::affected_packages(cx, rev)?;
+    if affected.is_empty() {
+        info!("no packages are affected by changes since {}", rev);
+        return Ok(false);
+    }
+    args.package = affected.iter().map(|id| cx.ws.metadata[id].name.clone()).collect();
+    if args.report_affected_only {
+        cx.retain_report_members(&affected);
+    }
+    Ok(true)
+}
+
 fn context_from_args(args: &mut Args, show_env: bool) -> Result<Context> {
+    let build = args.build();
+    // On `cargo llvm-cov nextest`, this has been moved to --profile by Args::set_nextest.
+    if build.cargo_profile.is_some() {
+        bail!(
+            "--cargo-profile is only supported by `cargo llvm-cov nextest`; use --profile instead"
+        );
+    }
+    if args.no_run && !build.filter_expr.is_empty() {
+        bail!("--filter-expr may not be used together with --no-run; the filter of the previous run is used");
+    }
     Context::new(
-        args.build(),
+        build,
         args.manifest(),
         args.cov(),
         &args.exclude,
@@ -179,6 +476,9 @@ fn context_from_args(args: &mut Args, show_env: bool) -> Result<Context> {
 }
 
 fn create_dirs(cx: &Context) -> Result<()> {
+    if cx.build.dry_run {
+        return Ok(());
+    }
     fs::create_dir_all(&cx.ws.target_dir)?;
 
     if let Some(output_dir) = &cx.cov.output_dir {
@@ -199,12 +499,40 @@ fn create_dirs(cx: &Context) -> Result<()> {
 
 trait EnvTarget {
     fn set(&mut self, key: &str, value: &str);
+    fn unset(&mut self, key: &str);
+    /// Appends `flags` to the value of `key` at the time the environment is
+    /// applied, and returns `true` if supported (`show-env --append`).
+    fn append(&mut self, _key: &str, _flags: &str) -> bool {
+        false
+    }
 }
 
 impl EnvTarget for ProcessBuilder {
     fn set(&mut self, key: &str, value: &str) {
         self.env(key, value);
     }
+    fn unset(&mut self, key: &str) {
+        self.env_remove(key);
+    }
+}
+
+impl EnvTarget for BTreeMap<String, String> {
+    fn set(&mut self, key: &str, value: &str) {
+        self.insert(key.to_owned(), value.to_owned());
+    }
+    fn unset(&mut self, key: &str) {
+        self.remove(key);
+    }
+}
+
+// Used by `show-env --format json`, in which unset variables are `null`.
+impl EnvTarget for BTreeMap<String, Option<String>> {
+    fn set(&mut self, key: &str, value: &str) {
+        self.insert(key.to_owned(), Some(value.to_owned()));
+    }
+    fn unset(&mut self, key: &str) {
+        self.insert(key.to_owned(), None);
+    }
 }
 
 struct ShowEnvWriter<W: io::Write> {
@@ -214,6 +542,11 @@ struct ShowEnvWriter<W: io::Write> {
 
 impl<W: io::Write> EnvTarget for ShowEnvWriter<W> {
     fn set(&mut self, key: &str, value: &str) {
+        if let Some(shell) = self.options.shell {
+            writeln!(self.target, "{}", shell::set(shell, key, value))
+                .expect("failed to write to stdout");
+            return;
+        }
         writeln!(
             self.target,
             r#"{prefix}{key}="{value}""#,
@@ -223,12 +556,45 @@ impl<W: io::Write> EnvTarget for ShowEnvWriter<W> {
         )
         .expect("failed to write to stdout");
     }
+    fn unset(&mut self, key: &str) {
+        if let Some(shell) = self.options.shell {
+            writeln!(self.target, "{}", shell::unset(shell, key))
+                .expect("failed to write to stdout");
+            return;
+        }
+        // There is no way to express this without the export prefix.
+        if self.options.export_prefix {
+            writeln!(self.target, "unset {}", key).expect("failed to write to stdout");
+        }
+    }
+    fn append(&mut self, key: &str, flags: &str) -> bool {
+        if !self.options.append {
+            return false;
+        }
+        if let Some(shell) = self.options.shell {
+            writeln!(self.target, "{}", shell::append(shell, key, flags))
+                .expect("failed to write to stdout");
+            return true;
+        }
+        writeln!(
+            self.target,
+            r#"{prefix}{key}="${{{key}}}{flags}""#,
+            prefix = if self.options.export_prefix { "export " } else { "" },
+            key = key,
+            flags = flags,
+        )
+        .expect("failed to write to stdout");
+        true
+    }
 }
 
 fn set_env(cx: &Context, env: &mut impl EnvTarget) {
-    let llvm_profile_file = cx.ws.target_dir.join(format!("{}-%m.profraw", cx.ws.name));
+    let llvm_profile_file = cx.ws.profraw_pattern(cx.build.partition);
+    let cfg_coverage = cx.cov.cfg_coverage_name.as_deref().unwrap_or("coverage");
+    // https://doc.rust-lang.org/nightly/rustc/check-cfg.html
+    let check_cfg = format!(" --check-cfg=cfg({},coverage_nightly)", cfg_coverage);
 
-    let rustflags = &mut cx.ws.config.rustflags().unwrap_or_default();
+    let rustflags = &mut String::new();
     if cx.ws.stable_coverage {
         rustflags.push_str(" -C instrument-coverage");
     } else {
@@ -244,14 +610,22 @@ fn set_env(cx: &Context, env: &mut impl EnvTarget) {
         }
     }
     if cx.build.remap_path_prefix {
-        let _ = write!(rustflags, " --remap-path-prefix {}/=", cx.ws.metadata.workspace_root);
+        let _ = write!(
+            rustflags,
+            " --remap-path-prefix {}{}=",
+            cx.ws.metadata.workspace_root,
+            std::path::MAIN_SEPARATOR
+        );
     }
     if !cx.cov.no_cfg_coverage {
-        rustflags.push_str(" --cfg coverage");
+        let _ = write!(rustflags, " --cfg {}", cfg_coverage);
     }
     if cx.ws.nightly && !cx.cov.no_cfg_coverage_nightly {
         rustflags.push_str(" --cfg coverage_nightly");
     }
+    if cx.ws.check_cfg && !cx.cov.no_check_cfg {
+        rustflags.push_str(&check_cfg);
+    }
     if cx.build.target.is_none() {
         // https://github.com/dtolnay/trybuild/pull/121
         // https://github.com/dtolnay/trybuild/issues/122
@@ -260,9 +634,8 @@ fn set_env(cx: &Context, env: &mut impl EnvTarget) {
     }
 
     // https://doc.rust-lang.org/nightly/rustc/instrument-coverage.html#including-doc-tests
-    let rustdocflags = &mut cx.ws.config.rustdocflags();
+    let rustdocflags = &mut String::new();
     if cx.doctests {
-        let rustdocflags = rustdocflags.get_or_insert_with(String::new);
         if cx.ws.stable_coverage {
             rustdocflags.push_str(" -C instrument-coverage");
         } else {
@@ -275,24 +648,17 @@ fn set_env(cx: &Context, env: &mut impl EnvTarget) {
             rustdocflags.push_str(" -C codegen-units=1");
         }
         if !cx.cov.no_cfg_coverage {
-            rustdocflags.push_str(" --cfg coverage");
+            let _ = write!(rustdocflags, " --cfg {}", cfg_coverage);
         }
         if cx.ws.nightly && !cx.cov.no_cfg_coverage_nightly {
             rustdocflags.push_str(" --cfg coverage_nightly");
         }
+        if cx.ws.check_cfg && !cx.cov.no_check_cfg {
+            rustdocflags.push_str(&check_cfg);
+        }
     }
 
-    match (cx.build.coverage_target_only, &cx.build.target) {
-        (true, Some(coverage_target)) => env.set(
-            &format!("CARGO_TARGET_{}_RUSTFLAGS", coverage_target.to_uppercase().replace('-', "_")),
-            rustflags,
-        ),
-        _ => env.set("RUSTFLAGS", rustflags),
-    }
-
-    if let Some(rustdocflags) = rustdocflags {
-        env.set("RUSTDOCFLAGS", rustdocflags);
-    }
+    set_flags_env(cx, env, rustflags, rustdocflags);
     if cx.build.include_ffi {
         // https://github.com/rust-lang/cc-rs/blob/1.0.73/src/lib.rs#L2347-L2365
         // Environment variables that use hyphens are not available in many environments, so we ignore them for now.
@@ -322,19 +688,164 @@ fn set_env(cx: &Context, env: &mut impl EnvTarget) {
         env.set(cxxflags_key, &cxxflags);
     }
     env.set("LLVM_PROFILE_FILE", llvm_profile_file.as_str());
+    set_strip_env(cx, env);
+    if let Some(toolchain) = &cx.ws.toolchain {
+        // Make sure cargo, rustc, and rustdoc of the same toolchain are used.
+        env.set("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    // Marker to detect nested invocations.
+    env.set("CARGO_LLVM_COV", "1");
     env.set("CARGO_INCREMENTAL", "0");
     // Workaround for https://github.com/rust-lang/rust/issues/91092
     env.set("RUST_TEST_THREADS", "1");
 }
 
+/// Sets the environment variables to pass the flags to rustc and rustdoc.
+fn set_flags_env(cx: &Context, env: &mut impl EnvTarget, rustflags: &str, rustdocflags: &str) {
+    match (cx.build.coverage_target_only, &cx.build.target) {
+        (true, Some(coverage_target)) => {
+            // Target-specific flags are ignored if these are set.
+            env.unset("CARGO_ENCODED_RUSTFLAGS");
+            env.unset("RUSTFLAGS");
+            let base = cx.ws.config.rustflags().map(|f| f.args.join(" ")).unwrap_or_default();
+            env.set(
+                &format!(
+                    "CARGO_TARGET_{}_RUSTFLAGS",
+                    coverage_target.to_uppercase().replace('-', "_")
+                ),
+                &format!("{}{}", base, rustflags),
+            );
+        }
+        _ => set_merged_flags(env, "RUSTFLAGS", cx.ws.config.rustflags(), rustflags),
+    }
+
+    if cx.doctests || cx.ws.config.rustdocflags().is_some() {
+        set_merged_flags(env, "RUSTDOCFLAGS", cx.ws.config.rustdocflags(), rustdocflags);
+    }
+}
+
+/// Sets `flags` merged with the flags from the environment or cargo config.
+///
+/// If the target supports it (`show-env --append`) and the flags are not from
+/// other sources than the environment variable itself, `flags` are appended to
+/// the value at the time the environment is applied instead, so that the flags
+/// set after running show-env are kept.
+fn set_merged_flags(env: &mut impl EnvTarget, name: &str, base: Option<&Flags>, flags: &str) {
+    if base.map_or(true, |base| base.source == name) && env.append(name, flags) {
+        return;
+    }
+    let (key, value) = merge_flags(name, base, flags);
+    env.set(&key, &value);
+}
+
+/// Disables `strip` of the profiles used to build the instrumented code,
+/// unless --allow-stripped is passed.
+///
+/// Stripping symbols (`strip = "symbols"` or `strip = true`) can remove
+/// information that llvm-cov needs to read the coverage mapping from the
+/// binaries, which results in reports that silently miss files or functions.
+/// This overrides the value set by the environment variable too, and
+/// `set_cargo_env` tells which settings are overridden.
+// https://doc.rust-lang.org/nightly/cargo/reference/profiles.html#strip
+fn set_strip_env(cx: &Context, env: &mut impl EnvTarget) {
+    if cx.build.allow_stripped {
+        return;
+    }
+    for profile in profile::build_profiles(&cx.build) {
+        env.set(&profile::env_key(profile, "STRIP"), "none");
+    }
+}
+
+/// Appends `flags` to the flags from the environment or cargo config, and
+/// returns the environment variable to pass them and its value.
+///
+/// `CARGO_ENCODED_*` is used if the flags are from it or contain spaces.
+fn merge_flags(name: &str, base: Option<&Flags>, flags: &str) -> (String, String) {
+    match base {
+        Some(base) if base.encoded || base.args.iter().any(|f| f.contains(' ')) => {
+            let flags: Vec<_> = base
+                .args
+                .iter()
+                .map(String::as_str)
+                .chain(flags.split(' '))
+                .filter(|f| !f.is_empty())
+                .collect();
+            (format!("CARGO_ENCODED_{}", name), flags.join("\x1f"))
+        }
+        Some(base) => (name.to_owned(), format!("{}{}", base.args.join(" "), flags)),
+        None => (name.to_owned(), flags.to_owned()),
+    }
+}
+
+/// Sets the environment variables to the cargo process that builds (and runs)
+/// the instrumented code.
+fn set_cargo_env(cx: &Context, cargo: &mut ProcessBuilder) -> Result<()> {
+    if !cx.build.allow_stripped {
+        profile::check_strip(&cx.ws, &cx.build)?;
+    }
+    set_env(cx, cargo);
+    if term::verbose() {
+        if let Some(flags) = cx.ws.config.rustflags() {
+            status!("Using", "flags from {}: {}", flags.source, flags.args.join(" "));
+        }
+        if let Some(flags) = cx.ws.config.rustdocflags() {
+            status!("Using", "flags from {}: {}", flags.source, flags.args.join(" "));
+        }
+        let mut env = BTreeMap::<String, String>::new();
+        set_env(cx, &mut env);
+        for (key, value) in env {
+            if key.ends_with("RUSTFLAGS") || key.ends_with("RUSTDOCFLAGS") {
+                status!("Setting", "{}={}", key, value.replace('\x1f', " "));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns a string that identifies the configuration that affects which
+/// build artifacts are generated.
+fn build_fingerprint(cx: &Context) -> String {
+    let mut env = BTreeMap::<String, String>::new();
+    set_env(cx, &mut env);
+    let mut fingerprint = String::new();
+    for (key, value) in env {
+        let _ = writeln!(fingerprint, "{}={}", key, value);
+    }
+    let _ = writeln!(fingerprint, "release={}", cx.build.release);
+    let _ = writeln!(fingerprint, "profile={:?}", cx.build.profile);
+    let _ = writeln!(fingerprint, "features={:?}", cx.build.features);
+    let _ = writeln!(fingerprint, "all-features={}", cx.build.all_features);
+    let _ = writeln!(fingerprint, "no-default-features={}", cx.build.no_default_features);
+    let _ = writeln!(fingerprint, "target={:?}", cx.build.target);
+    fingerprint
+}
+
 fn has_z_flag(args: &Args, name: &str) -> bool {
     args.unstable_flags.iter().any(|f| f == name)
 }
 
+/// Rejects the options that are only supported by `cargo llvm-cov nextest`.
+fn check_nextest_only_options(cx: &Context) -> Result<()> {
+    if cx.build.partition.is_some() {
+        bail!("--partition is only supported by `cargo llvm-cov nextest`");
+    }
+    if !cx.build.filter_expr.is_empty() {
+        bail!("--filter-expr is only supported by `cargo llvm-cov nextest`");
+    }
+    if cx.build.merge_attempts.is_some() {
+        bail!("--merge-attempts is only supported by `cargo llvm-cov nextest`");
+    }
+    if cx.cov.per_test.is_some() && !cx.no_run {
+        bail!("--per-test is only supported by `cargo llvm-cov nextest`");
+    }
+    Ok(())
+}
+
 fn run_test(cx: &Context, args: &Args) -> Result<()> {
+    check_nextest_only_options(cx)?;
     let mut cargo = cx.cargo();
 
-    set_env(cx, &mut cargo);
+    set_cargo_env(cx, &mut cargo)?;
 
     cargo.arg("test");
     if cx.doctests && !has_z_flag(args, "doctest-in-workspace") {
@@ -343,27 +854,34 @@ fn run_test(cx: &Context, args: &Args) -> Result<()> {
         cargo.arg("doctest-in-workspace");
     }
 
-    if args.ignore_run_fail {
+    // Build separately if --timings is passed to measure the build and the test run separately.
+    if args.ignore_run_fail || cx.cov.timings {
         let mut cargo_no_run = cargo.clone();
         if !args.no_run {
             cargo_no_run.arg("--no-run");
         }
         cargo::test_args(cx, args, &mut cargo_no_run);
-        if term::verbose() {
-            status!("Running", "{}", cargo_no_run);
-            cargo_no_run.stdout_to_stderr().run()?;
-        } else {
-            // Capture output to prevent duplicate warnings from appearing in two runs.
-            cargo_no_run.run_with_output()?;
+        if !dry_run(cx, &mut cargo_no_run) {
+            cx.timings.time("build", || {
+                if term::verbose() {
+                    status!("Running", "{}", cargo_no_run);
+                    cargo_no_run.stdout_to_stderr().run()
+                } else {
+                    // Capture output to prevent duplicate warnings from appearing in two runs.
+                    cargo_no_run.run_with_output()
+                }
+            })?;
         }
         drop(cargo_no_run);
+    }
 
+    if args.ignore_run_fail {
         cargo.arg("--no-fail-fast");
         cargo::test_args(cx, args, &mut cargo);
         if term::verbose() {
             status!("Running", "{}", cargo);
         }
-        if let Err(e) = cargo.stdout_to_stderr().run() {
+        if let Err(e) = run_tests(cx, "test", &mut cargo) {
             warn!("{}", e);
         }
     } else {
@@ -371,16 +889,44 @@ fn run_test(cx: &Context, args: &Args) -> Result<()> {
         if term::verbose() {
             status!("Running", "{}", cargo);
         }
-        cargo.stdout_to_stderr().run()?;
+        run_tests(cx, "test", &mut cargo).map_err(test_failure)?;
     }
 
     Ok(())
 }
 
+/// Runs the command that runs tests (or binaries).
+fn run_tests(cx: &Context, step: &str, cargo: &mut ProcessBuilder) -> Result<()> {
+    if dry_run(cx, cargo) {
+        return Ok(());
+    }
+    cx.timings.time(step, || cargo.stdout_to_stderr().run())?;
+    Ok(())
+}
+
+/// Records the error of [`run_tests`] as a test failure. Only used where the
+/// error fails the run, so that `--ignore-run-fail` leaves the exit code to
+/// later failures.
+fn test_failure(e: Error) -> Error {
+    term::set_failure(term::Failure::Test);
+    e
+}
+
+/// Prints the command with the environment variables set by cargo-llvm-cov
+/// if `--dry-run` is passed. Returns `true` if the command should not be run.
+fn dry_run(cx: &Context, cmd: &mut ProcessBuilder) -> bool {
+    if !cx.build.dry_run {
+        return false;
+    }
+    cmd.display_env_vars();
+    status!("Would run", "{}", cmd);
+    true
+}
+
 fn run_nextest(cx: &Context, args: &Args) -> Result<()> {
     let mut cargo = cx.cargo();
 
-    set_env(cx, &mut cargo);
+    set_cargo_env(cx, &mut cargo)?;
 
     cargo.arg("nextest").arg("run");
 
@@ -389,18 +935,23 @@ fn run_nextest(cx: &Context, args: &Args) -> Result<()> {
     }
 
     cargo::test_args(cx, args, &mut cargo);
+    attempts::set_runner(cx, &mut cargo)?;
 
     if term::verbose() {
         status!("Running", "{}", cargo);
     }
-    cargo.stdout_to_stderr().run()?;
+    run_tests(cx, "build and test", &mut cargo).map_err(test_failure)?;
+    if cx.cov.per_test.is_some() {
+        per_test::save_test_list(cx, args)?;
+    }
     Ok(())
 }
 
 fn run_run(cx: &Context, args: &RunOptions) -> Result<()> {
+    check_nextest_only_options(cx)?;
     let mut cargo = cx.cargo();
 
-    set_env(cx, &mut cargo);
+    set_cargo_env(cx, &mut cargo)?;
 
     cargo.arg("run");
     cargo::run_args(cx, args, &mut cargo);
@@ -408,160 +959,39 @@ fn run_run(cx: &Context, args: &RunOptions) -> Result<()> {
     if term::verbose() {
         status!("Running", "{}", cargo);
     }
-    cargo.stdout_to_stderr().run()?;
+    run_tests(cx, "build and run", &mut cargo).map_err(test_failure)?;
     Ok(())
 }
 
-fn generate_report(cx: &Context) -> Result<()> {
-    merge_profraw(cx).context("failed to merge profile data")?;
-
-    let object_files = object_files(cx).context("failed to collect object files")?;
-    let ignore_filename_regex = ignore_filename_regex(cx);
-    for format in Format::from_args(cx) {
-        format
-            .generate_report(cx, &object_files, ignore_filename_regex.as_ref())
-            .context("failed to generate report")?;
-    }
-
-    if cx.cov.fail_under_lines.is_some()
-        || cx.cov.fail_uncovered_functions.is_some()
-        || cx.cov.fail_uncovered_lines.is_some()
-        || cx.cov.fail_uncovered_regions.is_some()
-        || cx.cov.show_missing_lines
-    {
-        let format = Format::Json;
-        let json = format
-            .get_json(cx, &object_files, ignore_filename_regex.as_ref())
-            .context("failed to get json")?;
-
-        if let Some(fail_under_lines) = cx.cov.fail_under_lines {
-            // Handle --fail-under-lines.
-            let lines_percent = json.get_lines_percent().context("failed to get line coverage")?;
-            if lines_percent < fail_under_lines {
-                term::error::set(true);
-            }
-        }
-
-        if let Some(fail_uncovered_functions) = cx.cov.fail_uncovered_functions {
-            // Handle --fail-uncovered-functions.
-            let uncovered =
-                json.count_uncovered_functions().context("failed to count uncovered functions")?;
-            if uncovered > fail_uncovered_functions {
-                term::error::set(true);
-            }
-        }
-        if let Some(fail_uncovered_lines) = cx.cov.fail_uncovered_lines {
-            // Handle --fail-uncovered-lines.
-            let uncovered =
-                json.count_uncovered_lines().context("failed to count uncovered lines")?;
-            if uncovered > fail_uncovered_lines {
-                term::error::set(true);
-            }
-        }
-        if let Some(fail_uncovered_regions) = cx.cov.fail_uncovered_regions {
-            // Handle --fail-uncovered-regions.
-            let uncovered =
-                json.count_uncovered_regions().context("failed to count uncovered regions")?;
-            if uncovered > fail_uncovered_regions {
-                term::error::set(true);
-            }
-        }
-
-        if cx.cov.show_missing_lines {
-            // Handle --show-missing-lines.
-            let uncovered_files = json.get_uncovered_lines(&ignore_filename_regex);
-            if !uncovered_files.is_empty() {
-                println!("Uncovered Lines:");
-            }
-            for (file, lines) in &uncovered_files {
-                let lines: Vec<_> = lines.iter().map(ToString::to_string).collect();
-                println!("{}: {}", file, lines.join(", "));
-            }
-        }
+/// Saves the list of object files for subsequent `--no-run` invocations.
+fn save_object_files(cx: &Context) -> Result<()> {
+    if cx.build.dry_run {
+        return Ok(());
     }
-
-    if cx.cov.open {
-        let path = &cx.cov.output_dir.as_ref().unwrap().join("html/index.html");
-        status!("Opening", "{}", path);
-        open_report(cx, path)?;
+    let object_files = cx
+        .timings
+        .time("collect object files", || object_files(cx))
+        .context("failed to collect object files")?;
+    if let Some(dir) = &cx.cov.artifact_dir {
+        artifacts::export_coverage_data(cx, dir, &merge::profraw_files(cx)?, &object_files)
+            .context("failed to export coverage data")?;
+        report::saved("artifacts", dir, false);
     }
     Ok(())
 }
 
-fn open_report(cx: &Context, path: &Utf8Path) -> Result<()> {
-    let browser = cx.ws.config.doc.browser.as_ref().and_then(StringOrArray::path_and_args);
-
-    match browser {
-        Some((browser, initial_args)) => {
-            cmd!(browser).args(initial_args).arg(path).run().with_context(|| {
-                format!("couldn't open report with {}", browser.to_string_lossy())
-            })?;
-        }
-        None => opener::open(path).context("couldn't open report")?,
-    }
-    Ok(())
-}
-
-fn merge_profraw(cx: &Context) -> Result<()> {
-    // Convert raw profile data.
-    let mut cmd = cx.process(&cx.llvm_profdata);
-    cmd.args(["merge", "-sparse"])
-        .args(
-            glob::glob(cx.ws.target_dir.join(format!("{}-*.profraw", cx.ws.name)).as_str())?
-                .filter_map(Result::ok),
-        )
-        .arg("-o")
-        .arg(&cx.ws.profdata_file);
-    if let Some(mode) = &cx.cov.failure_mode {
-        cmd.arg(format!("-failure-mode={}", mode));
-    }
-    if let Some(jobs) = cx.build.jobs {
-        cmd.arg(format!("-num-threads={}", jobs));
-    }
-    if let Some(flags) = &cx.cargo_llvm_profdata_flags {
-        cmd.args(flags.split(' ').filter(|s| !s.trim().is_empty()));
-    }
-    if term::verbose() {
-        status!("Running", "{}", cmd);
-    }
-    cmd.stdout_to_stderr().run()?;
-    Ok(())
-}
-
 fn object_files(cx: &Context) -> Result<Vec<OsString>> {
-    fn walk_target_dir<'a>(
-        cx: &'a Context,
-        target_dir: &Utf8Path,
-    ) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
-        WalkDir::new(target_dir)
-            .into_iter()
-            .filter_entry(move |e| {
-                let p = e.path();
-                if p.is_dir() {
-                    if p.file_name()
-                        .map_or(false, |f| f == "incremental" || f == ".fingerprint" || f == "out")
-                    {
-                        return false;
-                    }
-                } else if let Some(stem) = p.file_stem() {
-                    let stem = stem.to_string_lossy();
-                    if stem == "build-script-build" || stem.starts_with("build_script_build-") {
-                        let p = p.parent().unwrap();
-                        if p.parent().unwrap().file_name().unwrap() == "build" {
-                            if cx.cov.include_build_script {
-                                let dir = p.file_name().unwrap().to_string_lossy();
-                                if !cx.build_script_re.is_match(&dir) {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        }
-                    }
-                }
-                true
-            })
-            .filter_map(Result::ok)
+    if !cx.import_dirs.is_empty() {
+        let (_, mut files) = artifacts::import_coverage_data(cx)?;
+        files.sort_unstable();
+        dedup_object_files(&mut files)?;
+        return Ok(files);
+    }
+    let mut list = ObjectList::new(cx);
+    if cx.no_run {
+        if let Some(files) = list.load(cx) {
+            return Ok(files);
+        }
     }
 
     let mut files = vec![];
@@ -583,15 +1013,22 @@ fn object_files(cx: &Context) -> Result<Vec<OsString>> {
         Some(p) => p,
     };
     target_dir.push(profile);
+    list.add_dir(target_dir.as_std_path());
     for f in walk_target_dir(cx, &target_dir) {
+        if f.file_type().is_dir() {
+            list.add_dir(f.path());
+            continue;
+        }
         let f = f.path();
         if is_executable::is_executable(&f) {
             files.push(make_relative(cx, f).to_owned().into_os_string());
         }
     }
     if cx.doctests {
+        list.add_dir(cx.ws.doctests_dir.as_std_path());
         for f in glob::glob(cx.ws.doctests_dir.join("*/rust_out").as_str())?.filter_map(Result::ok)
         {
+            list.add_dir(f.parent().unwrap());
             if is_executable::is_executable(&f) {
                 files.push(make_relative(cx, &f).to_owned().into_os_string());
             }
@@ -606,9 +1043,11 @@ fn object_files(cx: &Context) -> Result<Vec<OsString>> {
     }
     // Currently, trybuild always use debug build.
     trybuild_target.push("debug");
+    list.add_dir(trybuild_dir.as_std_path());
+    list.add_dir(trybuild_target.as_std_path());
     if trybuild_target.is_dir() {
         let mut trybuild_targets = vec![];
-        for metadata in trybuild_metadata(&cx.ws.metadata.target_directory)? {
+        for metadata in trybuild_metadata(&cx.ws)? {
             for package in metadata.packages {
                 for target in package.targets {
                     trybuild_targets.push(target.name);
@@ -619,6 +1058,10 @@ fn object_files(cx: &Context) -> Result<Vec<OsString>> {
             let re =
                 Regex::new(&format!("^({})(-[0-9a-f]+)?$", trybuild_targets.join("|"))).unwrap();
             for entry in walk_target_dir(cx, &trybuild_target) {
+                if entry.file_type().is_dir() {
+                    list.add_dir(entry.path());
+                    continue;
+                }
                 let path = make_relative(cx, entry.path());
                 if let Some(file_stem) = fs::file_stem_recursive(path).unwrap().to_str() {
                     if re.is_match(file_stem) {
@@ -634,201 +1077,92 @@ fn object_files(cx: &Context) -> Result<Vec<OsString>> {
 
     // This sort is necessary to make the result of `llvm-cov show` match between macos and linux.
     files.sort_unstable();
+    dedup_object_files(&mut files)?;
 
+    list.save(cx, &files)?;
     Ok(files)
 }
 
+fn walk_target_dir<'a>(
+    cx: &'a Context,
+    target_dir: &Utf8Path,
+) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
+    WalkDir::new(target_dir)
+        .into_iter()
+        .filter_entry(move |e| {
+            let p = e.path();
+            if p.is_dir() {
+                if p.file_name()
+                    .map_or(false, |f| f == "incremental" || f == ".fingerprint" || f == "out")
+                {
+                    return false;
+                }
+            } else if let Some(stem) = p.file_stem() {
+                let stem = stem.to_string_lossy();
+                if stem == "build-script-build" || stem.starts_with("build_script_build-") {
+                    let p = p.parent().unwrap();
+                    if p.parent().unwrap().file_name().unwrap() == "build" {
+                        if cx.cov.include_build_script {
+                            let dir = p.file_name().unwrap().to_string_lossy();
+                            if !cx.build_script_re.is_match(&dir) {
+                                return false;
+                            }
+                        } else {
+                            return false;
+                        }
+                    }
+                }
+            }
+            true
+        })
+        .filter_map(Result::ok)
+}
+
+/// Removes duplicate object files, such as hard links created by cargo
+/// (`target/debug/foo` and `target/debug/deps/foo-<hash>`), keeping the first one.
+fn dedup_object_files(files: &mut Vec<OsString>) -> Result<()> {
+    let mut sizes = HashMap::new();
+    for f in files.iter() {
+        *sizes.entry(fs::metadata(f)?.len()).or_insert(0) += 1;
+    }
+    let mut seen = HashSet::new();
+    let mut deduped = Vec::with_capacity(files.len());
+    for f in files.drain(..) {
+        let len = fs::metadata(&f)?.len();
+        // Only files with the same size can be identical.
+        if sizes[&len] > 1 && !seen.insert((len, fs::hash_file(Path::new(&f))?)) {
+            continue;
+        }
+        deduped.push(f);
+    }
+    *files = deduped;
+    Ok(())
+}
+
 /// Collects metadata for packages generated by trybuild. If the trybuild test
 /// directory is not found, it returns an empty vector.
-fn trybuild_metadata(target_dir: &Utf8Path) -> Result<Vec<cargo_metadata::Metadata>> {
-    let trybuild_dir = &target_dir.join("tests");
+fn trybuild_metadata(ws: &Workspace) -> Result<Vec<cargo_metadata::Metadata>> {
+    let trybuild_dir = &ws.metadata.target_directory.join("tests");
     if !trybuild_dir.is_dir() {
         return Ok(vec![]);
     }
     let mut metadata = vec![];
     for entry in fs::read_dir(trybuild_dir)?.filter_map(Result::ok) {
-        let manifest_path = entry.path().join("Cargo.toml");
+        let manifest_path = Utf8PathBuf::try_from(entry.path().join("Cargo.toml"))?;
         if !manifest_path.is_file() {
             continue;
         }
-        metadata.push(
-            cargo_metadata::MetadataCommand::new().manifest_path(manifest_path).no_deps().exec()?,
-        );
+        metadata.push(ws.metadata_no_deps(&manifest_path)?);
     }
     Ok(metadata)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Format {
-    /// `llvm-cov report`
-    None,
-    /// `llvm-cov export -format=text`
-    Json,
-    /// `llvm-cov export -format=lcov`
-    LCov,
-    /// `llvm-cov show -format=text`
-    Text,
-    /// `llvm-cov show -format=html`
-    Html,
-}
-
-impl Format {
-    fn from_args(cx: &Context) -> Vec<Self> {
-        if cx.cov.json {
-            vec![Self::Json]
-        } else if cx.cov.lcov {
-            vec![Self::LCov]
-        } else if cx.cov.text {
-            vec![Self::Text]
-        } else if cx.cov.html {
-            vec![Self::Html]
-        } else {
-            vec![Self::None]
-        }
-    }
-
-    const fn llvm_cov_args(self) -> &'static [&'static str] {
-        match self {
-            Self::None => &["report"],
-            Self::Json => &["export", "-format=text"],
-            Self::LCov => &["export", "-format=lcov"],
-            Self::Text => &["show", "-format=text"],
-            Self::Html => &["show", "-format=html"],
-        }
-    }
-
-    fn use_color(self, cx: &Context) -> Option<&'static str> {
-        if matches!(self, Self::Json | Self::LCov) {
-            // `llvm-cov export` doesn't have `-use-color` flag.
-            // https://llvm.org/docs/CommandGuide/llvm-cov.html#llvm-cov-export
-            return None;
-        }
-        if self == Self::Text && cx.cov.output_dir.is_some() {
-            return Some("-use-color=0");
-        }
-        match cx.build.color {
-            Some(Coloring::Auto) | None => None,
-            Some(Coloring::Always) => Some("-use-color=1"),
-            Some(Coloring::Never) => Some("-use-color=0"),
-        }
-    }
-
-    fn generate_report(
-        self,
-        cx: &Context,
-        object_files: &[OsString],
-        ignore_filename_regex: Option<&String>,
-    ) -> Result<()> {
-        let mut cmd = cx.process(&cx.llvm_cov);
-
-        cmd.args(self.llvm_cov_args());
-        cmd.args(self.use_color(cx));
-        cmd.arg(format!("-instr-profile={}", cx.ws.profdata_file));
-        cmd.args(object_files.iter().flat_map(|f| [OsStr::new("-object"), f]));
-        if let Some(jobs) = cx.build.jobs {
-            cmd.arg(format!("-num-threads={}", jobs));
-        }
-        if let Some(ignore_filename_regex) = ignore_filename_regex {
-            cmd.arg("-ignore-filename-regex");
-            cmd.arg(ignore_filename_regex);
-        }
-
-        match self {
-            Self::Text | Self::Html => {
-                cmd.args([
-                    &format!("-show-instantiations={}", !cx.cov.hide_instantiations),
-                    "-show-line-counts-or-regions",
-                    "-show-expansions",
-                    &format!("-Xdemangler={}", cx.current_exe.display()),
-                    "-Xdemangler=llvm-cov",
-                    "-Xdemangler=demangle",
-                ]);
-                if let Some(output_dir) = &cx.cov.output_dir {
-                    if self == Self::Html {
-                        cmd.arg(&format!("-output-dir={}", output_dir.join("html")));
-                    } else {
-                        cmd.arg(&format!("-output-dir={}", output_dir.join("text")));
-                    }
-                }
-            }
-            Self::Json | Self::LCov => {
-                if cx.cov.summary_only {
-                    cmd.arg("-summary-only");
-                }
-            }
-            Self::None => {}
-        }
-
-        if let Some(flags) = &cx.cargo_llvm_cov_flags {
-            cmd.args(flags.split(' ').filter(|s| !s.trim().is_empty()));
-        }
-
-        if let Some(output_path) = &cx.cov.output_path {
-            if term::verbose() {
-                status!("Running", "{}", cmd);
-            }
-            let out = cmd.read()?;
-            fs::write(output_path, out)?;
-            eprintln!();
-            status!("Finished", "report saved to {}", output_path);
-            return Ok(());
-        }
-
-        if term::verbose() {
-            status!("Running", "{}", cmd);
-        }
-        cmd.run()?;
-        if matches!(self, Self::Html | Self::Text) {
-            if let Some(output_dir) = &cx.cov.output_dir {
-                eprintln!();
-                if self == Self::Html {
-                    status!("Finished", "report saved to {}", output_dir.join("html"));
-                } else {
-                    status!("Finished", "report saved to {}", output_dir.join("text"));
-                }
-            }
-        }
-        Ok(())
-    }
-
-    /// Generates JSON to perform further analysis on it.
-    fn get_json(
-        self,
-        cx: &Context,
-        object_files: &[OsString],
-        ignore_filename_regex: Option<&String>,
-    ) -> Result<LlvmCovJsonExport> {
-        if let Self::Json = self {
-        } else {
-            return Err(anyhow::anyhow!("requested JSON for non-JSON type"));
-        }
-
-        let mut cmd = cx.process(&cx.llvm_cov);
-        cmd.args(self.llvm_cov_args());
-        cmd.arg(format!("-instr-profile={}", cx.ws.profdata_file));
-        cmd.args(object_files.iter().flat_map(|f| [OsStr::new("-object"), f]));
-        if let Some(jobs) = cx.build.jobs {
-            cmd.arg(format!("-num-threads={}", jobs));
-        }
-        if let Some(ignore_filename_regex) = ignore_filename_regex {
-            cmd.arg("-ignore-filename-regex");
-            cmd.arg(ignore_filename_regex);
-        }
-        if term::verbose() {
-            status!("Running", "{}", cmd);
-        }
-        let cmd_out = cmd.read()?;
-        let json = serde_json::from_str::<LlvmCovJsonExport>(&cmd_out)
-            .context("failed to parse json from llvm-cov")?;
-        Ok(json)
-    }
-}
-
 fn ignore_filename_regex(cx: &Context) -> Option<String> {
     #[cfg(not(windows))]
     const SEPARATOR: &str = "/";
+    // On windows, both slashes and backslashes can be used as separators.
     #[cfg(windows)]
-    const SEPARATOR: &str = "\\\\"; // On windows, we should escape the separator.
+    const SEPARATOR: &str = r"[\\/]";
 
     #[derive(Default)]
     struct Out(String);
@@ -842,9 +1176,20 @@ fn ignore_filename_regex(cx: &Context) -> Option<String> {
         }
 
         fn push_abs_path(&mut self, path: impl AsRef<Path>) {
-            let path = regex::escape(path.as_ref().to_string_lossy().as_ref());
-            let path = format!("^{}($|{})", path, SEPARATOR);
-            self.push(path);
+            let path = path.as_ref();
+            let re = fs::path_regex(&path.to_string_lossy(), cfg!(windows));
+            self.push(format!("^{}($|{})", re, SEPARATOR));
+            // The path may be reached through a symlink, and the paths
+            // in the coverage data may use either spelling.
+            #[cfg(not(windows))]
+            if let Some(canonical) =
+                Some(path).filter(|p| p.is_absolute()).and_then(|p| fs::canonicalize(p).ok())
+            {
+                if canonical != path {
+                    let re = fs::path_regex(&canonical.to_string_lossy(), false);
+                    self.push(format!("^{}($|{})", re, SEPARATOR));
+                }
+            }
         }
     }
 
@@ -862,19 +1207,54 @@ fn ignore_filename_regex(cx: &Context) -> Option<String> {
             out.push(format!(
                 r"{0}rustc{0}[0-9a-f]+{0}|^{1}({0}.*)?{0}(tests|examples|benches){0}",
                 SEPARATOR,
-                regex::escape(cx.ws.metadata.workspace_root.as_str())
+                fs::path_regex(cx.ws.metadata.workspace_root.as_str(), cfg!(windows))
             ));
         }
         out.push_abs_path(&cx.ws.target_dir);
+        if !cx.import_dirs.is_empty() {
+            // Other workspaces in the imported coverage data.
+            let (workspace_roots, target_dirs) = artifacts::imported_workspaces(cx);
+            if !cx.build.remap_path_prefix {
+                for root in workspace_roots {
+                    out.push(format!(
+                        r"^{1}({0}.*)?{0}(tests|examples|benches){0}",
+                        SEPARATOR,
+                        fs::path_regex(root.as_str(), cfg!(windows))
+                    ));
+                }
+            }
+            for target_dir in target_dirs {
+                out.push_abs_path(target_dir);
+            }
+        }
+        // Dependencies specified by --include-vendored are carved out of the
+        // directories of dependencies excluded below.
+        let vendored = context::vendored_dirs(&cx.ws.metadata, &cx.cov.include_vendored);
+        let mut dependency_dirs = vec![];
         if cx.build.remap_path_prefix {
             if let Some(path) = home::home_dir() {
-                out.push_abs_path(path);
+                dependency_dirs.push(path);
             }
         }
         if let Ok(path) = home::cargo_home() {
-            let path = regex::escape(path.as_os_str().to_string_lossy().as_ref());
-            let path = format!("^{1}{0}(registry|git){0}", SEPARATOR, path);
-            out.push(path);
+            if vendored.is_empty() {
+                let path = fs::path_regex(&path.as_os_str().to_string_lossy(), cfg!(windows));
+                let path = format!("^{1}{0}(registry|git){0}", SEPARATOR, path);
+                out.push(path);
+            } else {
+                dependency_dirs.push(path.join("registry"));
+                dependency_dirs.push(path.join("git"));
+            }
+        }
+        for dir in dependency_dirs {
+            match Utf8Path::from_path(&dir) {
+                Some(dir) if !vendored.is_empty() => {
+                    for path in context::resolve_excluded_paths(&[dir], &vendored) {
+                        out.push_abs_path(path);
+                    }
+                }
+                _ => out.push_abs_path(dir),
+            }
         }
         if let Ok(path) = home::rustup_home() {
             out.push_abs_path(path.join("toolchains"));
@@ -891,78 +1271,19 @@ fn ignore_filename_regex(cx: &Context) -> Option<String> {
     }
 }
 
+/// Returns the paths of the packages excluded from the report, in the
+/// spelling used in the coverage data.
 fn resolve_excluded_paths(cx: &Context) -> Vec<Utf8PathBuf> {
-    let excluded: Vec<_> = cx
-        .workspace_members
-        .excluded
-        .iter()
-        .map(|id| cx.ws.metadata[id].manifest_path.parent().unwrap())
-        .collect();
-    let included = cx
-        .workspace_members
-        .included
-        .iter()
-        .map(|id| cx.ws.metadata[id].manifest_path.parent().unwrap());
-    let mut excluded_path = vec![];
-    let mut contains: HashMap<&Utf8Path, Vec<_>> = HashMap::new();
-    for included in included {
-        for &excluded in excluded.iter().filter(|e| included.starts_with(e)) {
-            if let Some(v) = contains.get_mut(&excluded) {
-                v.push(included);
-            } else {
-                contains.insert(excluded, vec![included]);
+    let mut excluded_paths = cx.workspace_members.excluded_paths(&cx.ws.metadata);
+    if cx.build.remap_path_prefix {
+        // The paths in the coverage data are relative to the workspace root.
+        for path in &mut excluded_paths {
+            if let Ok(p) = path.strip_prefix(&cx.ws.metadata.workspace_root) {
+                *path = p.to_owned();
             }
         }
     }
-    if contains.is_empty() {
-        for &manifest_dir in &excluded {
-            let package_path =
-                manifest_dir.strip_prefix(&cx.ws.metadata.workspace_root).unwrap_or(manifest_dir);
-            excluded_path.push(package_path.to_owned());
-        }
-        return excluded_path;
-    }
-
-    for &excluded in &excluded {
-        let included = match contains.get(&excluded) {
-            Some(included) => included,
-            None => {
-                let package_path =
-                    excluded.strip_prefix(&cx.ws.metadata.workspace_root).unwrap_or(excluded);
-                excluded_path.push(package_path.to_owned());
-                continue;
-            }
-        };
-
-        for _ in WalkDir::new(excluded).into_iter().filter_entry(|e| {
-            let p = e.path();
-            if !p.is_dir() {
-                if p.extension().map_or(false, |e| e == "rs") {
-                    let p = p.strip_prefix(&cx.ws.metadata.workspace_root).unwrap_or(p);
-                    excluded_path.push(p.to_owned().try_into().unwrap());
-                }
-                return false;
-            }
-
-            let mut contains = false;
-            for included in included {
-                if included.starts_with(p) {
-                    if p.starts_with(included) {
-                        return false;
-                    }
-                    contains = true;
-                }
-            }
-            if contains {
-                // continue to walk
-                return true;
-            }
-            let p = p.strip_prefix(&cx.ws.metadata.workspace_root).unwrap_or(p);
-            excluded_path.push(p.to_owned().try_into().unwrap());
-            false
-        }) {}
-    }
-    excluded_path
+    excluded_paths
 }
 
 /// Make the path relative if it's a descendent of the current working dir, otherwise just return
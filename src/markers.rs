@@ -0,0 +1,762 @@
+// Comment markers and attributes that exclude lines from coverage reports.
+// See "Exclude lines from coverage" in README.md.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+use cargo_llvm_cov_core::json::ExcludedLines;
+
+use crate::{context::Context, fs};
+
+const IGNORE_LINE: &str = "llvm-cov: ignore-line";
+const IGNORE_START: &str = "llvm-cov: ignore-start";
+const IGNORE_END: &str = "llvm-cov: ignore-end";
+
+/// Lines excluded by comment markers, collected on demand. Each source file is
+/// read and scanned only once, however many reports need its excluded lines.
+#[derive(Default)]
+pub(crate) struct Exclusions {
+    scanned: RefCell<BTreeSet<String>>,
+    excluded: RefCell<ExcludedLines>,
+}
+
+impl Exclusions {
+    /// Returns the lines excluded from the given source files.
+    pub(crate) fn get(
+        &self,
+        cx: &Context,
+        files: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> ExcludedLines {
+        let mut scanned = self.scanned.borrow_mut();
+        let mut excluded = self.excluded.borrow_mut();
+        let mut res = ExcludedLines::new();
+        for file in files {
+            let file = file.as_ref();
+            if scanned.insert(file.to_owned()) {
+                if let Some(lines) = excluded_lines(cx, file) {
+                    excluded.insert(file.to_owned(), lines);
+                }
+            }
+            if let Some(lines) = excluded.get(file) {
+                res.insert(file.to_owned(), lines.clone());
+            }
+        }
+        res
+    }
+}
+
+/// Collects lines excluded by comment markers from the given source file.
+fn excluded_lines(cx: &Context, file: &str) -> Option<BTreeSet<u64>> {
+    // If --remap-path-prefix is used, the paths are relative to the workspace root.
+    let path = cx.ws.metadata.workspace_root.join(file);
+    // The source file may be generated or no longer exist.
+    let source = fs::read_to_string(&path).ok()?;
+    let (mut lines, unterminated) = scan(&source);
+    if let Some(line) = unterminated {
+        warn!("`{}` at {}:{} has no matching `{}`", IGNORE_START, file, line, IGNORE_END);
+    }
+    if cx.cov.exclude_derives {
+        lines.extend(derive_lines(&source));
+    }
+    if cx.cov.exclude_unreachable {
+        lines.extend(unreachable_lines(&source));
+    }
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines)
+    }
+}
+
+/// Returns the excluded lines and the line of the unterminated `ignore-start` marker if any.
+fn scan(source: &str) -> (BTreeSet<u64>, Option<u64>) {
+    let mut lines = BTreeSet::new();
+    let mut start = None;
+    for (line_no, comment) in line_comments(source) {
+        let comment = comment.trim_start_matches(&['/', '!'][..]).trim_start();
+        if comment.starts_with(IGNORE_LINE) {
+            lines.insert(line_no);
+        } else if comment.starts_with(IGNORE_START) {
+            start.get_or_insert(line_no);
+        } else if comment.starts_with(IGNORE_END) {
+            if let Some(start) = start.take() {
+                lines.extend(start..=line_no);
+            }
+        }
+    }
+    // Exclude the rest of the file if `ignore-end` is missing.
+    if let Some(start) = start {
+        lines.extend(start..=source.lines().count() as u64);
+    }
+    (lines, start)
+}
+
+/// Returns the line comments (the text after `//`) in the source with their
+/// line numbers. `//` in string literals, character literals, and block
+/// comments does not start a comment.
+// Each byte is counted only once, which is not worth a dependency for faster counting.
+#[allow(clippy::naive_bytecount)]
+fn line_comments(source: &str) -> Vec<(u64, &str)> {
+    let bytes = source.as_bytes();
+    let mut comments = vec![];
+    let mut line_no = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                let end = source[i..].find('\n').map_or(source.len(), |n| i + n);
+                comments.push((line_no, &source[i + 2..end]));
+                i = end;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_block_comment(bytes, i),
+            b'"' => i = skip_str(bytes, i + 1),
+            b'r' | b'b' | b'c' if i == 0 || !is_ident_byte(bytes[i - 1]) => {
+                let mut j = i + 1;
+                if bytes[i] != b'r' && bytes.get(j) == Some(&b'r') {
+                    j += 1;
+                }
+                if j == i + 1 && bytes[i] != b'r' {
+                    // `b"..."` or `c"..."`: handled as a normal string literal.
+                    i += 1;
+                    continue;
+                }
+                let hashes = bytes[j..].iter().take_while(|&&b| b == b'#').count();
+                if bytes.get(j + hashes) == Some(&b'"') {
+                    i = skip_raw_str(bytes, j + hashes + 1, hashes);
+                } else {
+                    // An identifier (including raw identifier `r#ident`).
+                    i += 1;
+                    while i < bytes.len() && is_ident_byte(bytes[i]) {
+                        i += 1;
+                    }
+                }
+            }
+            b'\'' => i = skip_char(source, i),
+            _ if is_ident_byte(bytes[i]) => {
+                while i < bytes.len() && is_ident_byte(bytes[i]) {
+                    i += 1;
+                }
+            }
+            _ => i += 1,
+        }
+        line_no += bytes[start..i].iter().filter(|&&b| b == b'\n').count() as u64;
+    }
+    comments
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii()
+}
+
+/// Returns the position after the block comment starting at `i`. Block
+/// comments can be nested.
+fn skip_block_comment(bytes: &[u8], mut i: usize) -> usize {
+    let mut depth = 0_usize;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'/', Some(b'*')) => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', Some(b'/')) => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    i
+}
+
+/// Returns the position after the closing `"` of the string literal whose
+/// content starts at `i`.
+fn skip_str(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the position after the closing `"#..#` of the raw string literal
+/// whose content starts at `i`.
+fn skip_raw_str(bytes: &[u8], mut i: usize, hashes: usize) -> usize {
+    while i < bytes.len() {
+        if bytes[i] == b'"' && bytes[i + 1..].iter().take_while(|&&b| b == b'#').count() >= hashes {
+            return i + 1 + hashes;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+/// Returns the position after the character literal or lifetime starting at `i`.
+fn skip_char(source: &str, i: usize) -> usize {
+    let rest = &source[i + 1..];
+    if rest.starts_with('\\') {
+        // An escaped character, e.g., `'\''`, `'\u{1F600}'`.
+        return rest.get(2..).and_then(|s| s.find('\'')).map_or(source.len(), |n| i + n + 4);
+    }
+    match rest.chars().next() {
+        Some(c) if rest[c.len_utf8()..].starts_with('\'') => i + 1 + c.len_utf8() + 1,
+        // A lifetime or a label.
+        _ => i + 1,
+    }
+}
+
+/// Returns the lines of attributes that contain `derive(...)`.
+fn derive_lines(source: &str) -> BTreeSet<u64> {
+    let mut lines = BTreeSet::new();
+    // The first line and the text of the attribute being read.
+    let mut attr: Option<(u64, String)> = None;
+    let mut depth = 0_usize;
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i as u64 + 1;
+        let text = match &mut attr {
+            Some((_, text)) => {
+                text.push_str(line);
+                line
+            }
+            None => {
+                let trimmed = line.trim_start();
+                if !trimmed.starts_with("#[") {
+                    continue;
+                }
+                attr = Some((line_no, trimmed.to_owned()));
+                depth = 0;
+                trimmed
+            }
+        };
+        for c in text.chars() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        if depth == 0 {
+            let (start, text) = attr.take().unwrap();
+            let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+            if text.contains("derive(") {
+                lines.extend(start..=line_no);
+            }
+        }
+    }
+    lines
+}
+
+/// Collects lines excluded by --exclude-unreachable from the given source files.
+pub(crate) fn excluded_unreachable_lines(
+    cx: &Context,
+    files: impl IntoIterator<Item = impl AsRef<str>>,
+) -> ExcludedLines {
+    let mut excluded = ExcludedLines::new();
+    for file in files {
+        let file = file.as_ref();
+        let path = cx.ws.metadata.workspace_root.join(file);
+        let lines = match fs::read_to_string(&path) {
+            Ok(source) => unreachable_lines(&source),
+            Err(_) => continue,
+        };
+        if !lines.is_empty() {
+            excluded.insert(file.to_owned(), lines);
+        }
+    }
+    excluded
+}
+
+/// Returns the lines of `unreachable!`/`panic!` calls that are the only code on
+/// their lines, and the lines of `#[cold]` functions.
+fn unreachable_lines(source: &str) -> BTreeSet<u64> {
+    let lines: Vec<_> = source.lines().collect();
+    let mut excluded = BTreeSet::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let code = lines[i].trim();
+        let end = if code.starts_with("#[cold]") {
+            // The body starts at the first `{` after the attribute.
+            (i..lines.len())
+                .find_map(|j| lines[j].find('{').map(|pos| (j, pos)))
+                .and_then(|(j, pos)| closing_line(&lines, j, pos))
+        } else {
+            let call = match code.find("=>") {
+                Some(pos) => code[pos + 2..].trim_start(),
+                None => code,
+            };
+            let call = call.trim_start_matches("std::").trim_start_matches("core::");
+            if call.starts_with("unreachable!") || call.starts_with("panic!") {
+                // `call` is a suffix of the trimmed line.
+                let pos = lines[i].trim_end().len() - call.len();
+                closing_line(&lines, i, pos).filter(|&(end, rest)| {
+                    let rest = lines[end][rest..].trim_start_matches(&[',', ';'][..]).trim();
+                    rest.is_empty() || rest.starts_with("//")
+                })
+            } else {
+                None
+            }
+        };
+        match end {
+            Some((end, _)) => {
+                excluded.extend(i as u64 + 1..=end as u64 + 1);
+                i = end + 1;
+            }
+            None => i += 1,
+        }
+    }
+    excluded
+}
+
+/// Returns the line and the position after the bracket that closes the first
+/// bracket at or after `pos` in `lines[start]`. Brackets in string literals are
+/// ignored.
+fn closing_line(lines: &[&str], start: usize, pos: usize) -> Option<(usize, usize)> {
+    let mut depth = 0_usize;
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let offset = if i == start { pos } else { 0 };
+        for (j, c) in line[offset..].char_indices() {
+            if in_str {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_str = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => in_str = true,
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => {
+                    depth = depth.checked_sub(1)?;
+                    if depth == 0 {
+                        return Some((i, offset + j + 1));
+                    }
+                }
+                // `;` before any bracket: e.g., a function without body.
+                ';' if depth == 0 => return None,
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Files -> list of (line, item) excluded by `#[coverage(off)]` attribute.
+pub(crate) type ExcludedItems = BTreeMap<String, Vec<(u64, String)>>;
+
+/// Collects items excluded by `#[coverage(off)]` or `#[no_coverage]` attribute
+/// from the given source files.
+pub(crate) fn excluded_items(
+    cx: &Context,
+    files: impl IntoIterator<Item = impl AsRef<str>>,
+) -> ExcludedItems {
+    let mut excluded = ExcludedItems::new();
+    for file in files {
+        let file = file.as_ref();
+        if excluded.contains_key(file) {
+            continue;
+        }
+        let path = cx.ws.metadata.workspace_root.join(file);
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => continue,
+        };
+        let items = scan_attrs(&source);
+        if !items.is_empty() {
+            excluded.insert(file.to_owned(), items);
+        }
+    }
+    excluded
+}
+
+fn is_coverage_off(attr: &str) -> bool {
+    let attr: String = attr.chars().filter(|c| !c.is_whitespace()).collect();
+    attr.contains("coverage(off)") || attr.contains("no_coverage")
+}
+
+/// Returns the line and the description of the items to which the
+/// `#[coverage(off)]` attribute is applied.
+fn scan_attrs(source: &str) -> Vec<(u64, String)> {
+    let mut items = vec![];
+    let mut pending = false;
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i as u64 + 1;
+        let line = line.trim();
+        if line.starts_with("#![") {
+            // `#![coverage(off)]` applies to the enclosing module (or crate).
+            if is_coverage_off(line) && !line.contains("feature(") {
+                items.push((line_no, "(entire module)".to_owned()));
+            }
+            continue;
+        }
+        if line.starts_with("#[") {
+            pending |= is_coverage_off(line);
+            continue;
+        }
+        if !pending || line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        pending = false;
+        if let Some(item) = item_name(line) {
+            items.push((line_no, item));
+        }
+    }
+    items
+}
+
+/// Gets a short description of the item (e.g., `fn foo`, `impl Foo`) declared
+/// on the given line.
+fn item_name(line: &str) -> Option<String> {
+    let mut tokens = line.split(char::is_whitespace);
+    while let Some(token) = tokens.next() {
+        match token {
+            "fn" | "mod" | "trait" => {
+                let name = tokens.find(|s| !s.is_empty())?;
+                let name = name.split(|c: char| !c.is_alphanumeric() && c != '_').next().unwrap();
+                return Some(format!("{} {}", token, name));
+            }
+            _ if token == "impl" || token.starts_with("impl<") => {
+                let mut name = line[line.find("impl")? + "impl".len()..].trim_start();
+                if name.starts_with('<') {
+                    name = skip_generics(name).trim_start();
+                }
+                let name = name.split(" where").next().unwrap().trim_end_matches('{').trim();
+                return Some(format!("impl {}", name));
+            }
+            "pub" | "const" | "async" | "unsafe" | "extern" | "\"C\"" | "default" | "" => {}
+            _ if token.starts_with("pub(") => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Skips the leading generic parameters, e.g., `<T: Clone> Foo<T>` -> ` Foo<T>`.
+fn skip_generics(s: &str) -> &str {
+    let mut depth = 0_usize;
+    for (i, c) in s.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return &s[i + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+    ""
+}
+
+/// Gets the list of files in the coverage data in lcov format.
+pub(crate) fn lcov_filenames(lcov: &str) -> impl Iterator<Item = &str> {
+    lcov.lines().filter_map(|line| line.strip_prefix("SF:"))
+}
+
+/// Removes the excluded lines from the coverage data in lcov format and
+/// updates summaries accordingly.
+///
+/// If `summary_only` is `true`, only summary information is emitted, like
+/// `llvm-cov export -format=lcov -summary-only` does.
+pub(crate) fn apply_to_lcov(lcov: &str, excluded: &ExcludedLines, summary_only: bool) -> String {
+    fn is_excluded(lines: Option<&BTreeSet<u64>>, line: &str) -> bool {
+        lines.map_or(false, |lines| line.parse().map_or(false, |line| lines.contains(&line)))
+    }
+
+    let mut out = String::with_capacity(lcov.len());
+    let mut lines = None;
+    let mut excluded_functions = BTreeSet::new();
+    // (FNF, FNH, BRF, BRH, LF, LH)
+    let mut counts = (0, 0, 0, 0, 0, 0);
+    for line in lcov.lines() {
+        let (key, value) = line.split_once(':').unwrap_or((line, ""));
+        let keep = match key {
+            "SF" => {
+                lines = excluded.get(value);
+                excluded_functions.clear();
+                counts = (0, 0, 0, 0, 0, 0);
+                true
+            }
+            // FN:<line number>,<function name>
+            "FN" => {
+                let (line, name) = value.split_once(',').unwrap_or_default();
+                if is_excluded(lines, line) {
+                    excluded_functions.insert(name);
+                    false
+                } else {
+                    counts.0 += 1;
+                    !summary_only
+                }
+            }
+            // FNDA:<execution count>,<function name>
+            "FNDA" => {
+                let (count, name) = value.split_once(',').unwrap_or_default();
+                if excluded_functions.contains(name) {
+                    false
+                } else {
+                    if count != "0" {
+                        counts.1 += 1;
+                    }
+                    !summary_only
+                }
+            }
+            // BRDA:<line number>,<block number>,<branch number>,<taken>
+            "BRDA" => {
+                let mut fields = value.split(',');
+                if is_excluded(lines, fields.next().unwrap_or_default()) {
+                    false
+                } else {
+                    counts.2 += 1;
+                    if !matches!(fields.nth(2), Some("-" | "0") | None) {
+                        counts.3 += 1;
+                    }
+                    !summary_only
+                }
+            }
+            // DA:<line number>,<execution count>
+            "DA" => {
+                let (line, count) = value.split_once(',').unwrap_or_default();
+                if is_excluded(lines, line) {
+                    false
+                } else {
+                    counts.4 += 1;
+                    if count != "0" {
+                        counts.5 += 1;
+                    }
+                    !summary_only
+                }
+            }
+            "FNF" | "FNH" | "BRF" | "BRH" | "LF" | "LH" if lines.is_some() => {
+                let count = match key {
+                    "FNF" => counts.0,
+                    "FNH" => counts.1,
+                    "BRF" => counts.2,
+                    "BRH" => counts.3,
+                    "LF" => counts.4,
+                    _ => counts.5,
+                };
+                let _ = writeln!(out, "{}:{}", key, count);
+                false
+            }
+            _ => true,
+        };
+        if keep {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    while out.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_markers() {
+        let source = "\
+fn a() {
+    b(); // llvm-cov: ignore-line
+    // llvm-cov: ignore-start
+    c();
+    // llvm-cov: ignore-end
+    d(); // llvm-cov: ignore-line (reason)
+}
+// llvm-cov: ignore-start
+fn e() {}
+";
+        let (lines, unterminated) = scan(source);
+        assert_eq!(lines.into_iter().collect::<Vec<_>>(), [2, 3, 4, 5, 6, 8, 9]);
+        assert_eq!(unterminated, Some(8));
+    }
+
+    #[test]
+    fn scan_markers_outside_comments() {
+        let source = r###"
+let a = "// llvm-cov: ignore-line";
+let b = r#"
+// llvm-cov: ignore-start
+"#; /* // llvm-cov: ignore-line
+/* nested */ // llvm-cov: ignore-line */
+let c = ('"', '\'', b'"'); // llvm-cov: ignore-line
+fn d<'a>(x: &'a str) {} /// llvm-cov: ignore-line
+//! llvm-cov: ignore-line
+let r#e = br##"x"##; // llvm-cov: ignore-line
+"###;
+        let (lines, unterminated) = scan(source);
+        assert_eq!(lines.into_iter().collect::<Vec<_>>(), [7, 8, 9, 10]);
+        assert_eq!(unterminated, None);
+    }
+
+    #[test]
+    fn scan_derives() {
+        let source = "\
+#[derive(Debug, Clone)]
+struct A;
+#[cfg_attr(feature = \"serde\", derive(serde::Serialize))]
+#[derive(
+    PartialEq,
+    Eq,
+)]
+#[serde(rename = \"b\")] #[allow(dead_code)]
+enum B { X([u8; 2]) }
+    #[derive (Default)] struct C;
+#[derive_more::Display]
+struct D;
+";
+        assert_eq!(derive_lines(source).into_iter().collect::<Vec<_>>(), [1, 3, 4, 5, 6, 7, 10]);
+    }
+
+    #[test]
+    fn scan_unreachable() {
+        let source = "\
+fn a(x: u8) -> u8 {
+    match x {
+        0 => 1,
+        1 => unreachable!(),
+        2 => panic!(\"x = {} (\\\"two\\\")\", x), // comment
+        _ => {
+            std::panic!(
+                \"{}\",
+                x,
+            );
+        }
+    }
+}
+fn b() { unreachable!() }
+fn c() {
+    unreachable!(); d();
+}
+#[cold]
+#[inline(never)]
+fn e(s: &[u8]) -> ! {
+    panic!(\"}\")
+}
+";
+        assert_eq!(unreachable_lines(source).into_iter().collect::<Vec<_>>(), [
+            4, 5, 7, 8, 9, 10, 18, 19, 20, 21, 22
+        ]);
+    }
+
+    #[test]
+    fn scan_coverage_off() {
+        let source = "\
+#![cfg_attr(coverage_nightly, feature(no_coverage))]
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+fn a() {}
+
+#[test]
+#[coverage(off)]
+pub(crate) async fn b<T>(_: T) {}
+
+fn c() {}
+
+#[cfg_attr(coverage, coverage(off))]
+impl<T: Clone> Foo<T> where T: Copy {
+    #[no_coverage]
+    pub fn d(&self) {}
+}
+
+#[coverage(off)]
+let _ = 1;
+";
+        assert_eq!(scan_attrs(source), [
+            (4, "fn a".to_owned()),
+            (8, "fn b".to_owned()),
+            (13, "impl Foo<T>".to_owned()),
+            (15, "fn d".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn lcov() {
+        let lcov = "\
+SF:src/lib.rs
+FN:1,a
+FN:5,b
+FNDA:1,a
+FNDA:0,b
+FNF:2
+FNH:1
+DA:1,1
+DA:2,0
+DA:3,1
+DA:5,0
+DA:6,0
+BRF:0
+BRH:0
+LF:5
+LH:2
+end_of_record
+SF:src/main.rs
+FNF:1
+FNH:1
+DA:1,1
+LF:1
+LH:1
+end_of_record";
+        let excluded: ExcludedLines =
+            vec![("src/lib.rs".to_string(), [2, 5, 6].iter().copied().collect())]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            apply_to_lcov(lcov, &excluded, false),
+            "\
+SF:src/lib.rs
+FN:1,a
+FNDA:1,a
+FNF:1
+FNH:1
+DA:1,1
+DA:3,1
+BRF:0
+BRH:0
+LF:2
+LH:2
+end_of_record
+SF:src/main.rs
+FNF:1
+FNH:1
+DA:1,1
+LF:1
+LH:1
+end_of_record"
+        );
+        assert_eq!(
+            apply_to_lcov(lcov, &excluded, true),
+            "\
+SF:src/lib.rs
+FNF:1
+FNH:1
+BRF:0
+BRH:0
+LF:2
+LH:2
+end_of_record
+SF:src/main.rs
+FNF:1
+FNH:1
+LF:1
+LH:1
+end_of_record"
+        );
+    }
+}
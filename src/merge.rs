@@ -0,0 +1,310 @@
+// Merging of the raw profile data (*.profraw) of the run.
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    artifacts, attempts,
+    context::Context,
+    fs, make_relative, object_files,
+    process::ProcessBuilder,
+    profraw,
+    progress::{self, Progress},
+    term,
+};
+
+/// Returns the profile data files of this run.
+///
+/// If `--jobs` is passed, the target directory and the directories of the
+/// attempts of tests are scanned concurrently, by that number of threads.
+pub(crate) fn profraw_files(cx: &Context) -> Result<Vec<PathBuf>> {
+    if !cx.import_dirs.is_empty() {
+        let (profraw_files, _) = artifacts::import_coverage_data(cx)?;
+        return Ok(profraw_files.into_iter().map(Utf8PathBuf::into_std_path_buf).collect());
+    }
+    // Without --partition, this also matches the files of all partitions.
+    let mut patterns = vec![cx.ws.profraw_pattern(cx.build.partition).as_str().replace("%m", "*")];
+    patterns.extend(attempts::profraw_patterns(cx)?);
+    let concurrency =
+        cx.build.jobs.map_or(1, |jobs| usize::try_from(jobs).unwrap_or(usize::MAX).max(1));
+
+    let mut profraw_files = vec![];
+    for patterns in patterns.chunks(concurrency) {
+        let handles: Vec<_> = patterns
+            .iter()
+            .map(|pattern| {
+                let pattern = pattern.clone();
+                thread::spawn(move || -> Result<Vec<PathBuf>> {
+                    Ok(glob::glob(&pattern)?.filter_map(Result::ok).collect())
+                })
+            })
+            .collect();
+        for handle in handles {
+            profraw_files.extend(handle.join().unwrap()?);
+        }
+    }
+    Ok(profraw_files)
+}
+
+pub(crate) fn merge_profraw(cx: &Context) -> Result<()> {
+    let profraw_files = profraw_files(cx)?;
+    if profraw_files.is_empty() {
+        if !cx.import_dirs.is_empty() {
+            bail!("no profile data files (*.profraw) were found in the given directories");
+        }
+        zero_coverage_hints(cx);
+        bail!("no profile data files (*.profraw) were found in {}", cx.ws.target_dir);
+    }
+
+    // Skip merging if the profile data is unchanged since the last run.
+    let key = profdata_cache_key(cx, &profraw_files)?;
+    if cx.ws.profdata_file.exists()
+        && fs::read_to_string(&cx.ws.profdata_hash_file).ok().as_ref() == Some(&key)
+    {
+        if term::verbose() {
+            status!("Fresh", "{}", cx.ws.profdata_file);
+        }
+        return Ok(());
+    }
+    fs::remove_file(&cx.ws.profdata_hash_file)?;
+
+    // Convert raw profile data.
+    let progress =
+        Progress::new("Merging", "profile data files".to_owned(), Some(profraw_files.len()));
+    let res = if profraw_files.len() > MERGE_CHUNK_SIZE {
+        merge_profraw_chunks(cx, profraw_files.clone(), &progress)
+    } else {
+        run_profdata_merge(profdata_merge(cx, &profraw_files, &cx.ws.profdata_file, cx.build.jobs))
+    };
+    drop(progress);
+    // llvm-profdata warns about files dropped due to --failure-mode.
+    if res.as_ref().map_or(true, |&warned| warned) {
+        report_bad_profraw(cx, &profraw_files)?;
+    }
+    res?;
+    check_zero_coverage(cx);
+
+    fs::write(&cx.ws.profdata_hash_file, key)?;
+    Ok(())
+}
+
+/// Tells if no code was executed according to the merged profile data, which
+/// results in a report with 0% coverage.
+///
+/// This is not a warning because it is also the case when a package has no tests.
+fn check_zero_coverage(cx: &Context) {
+    let mut cmd = cx.process(&cx.llvm_profdata);
+    cmd.arg("show").arg(&cx.ws.profdata_file);
+    if term::verbose() {
+        status!("Running", "{}", cmd);
+    }
+    // This is only a diagnostic, so ignore errors.
+    let out = match cmd.read() {
+        Ok(out) => out,
+        Err(_) => return,
+    };
+    let max_count = out
+        .lines()
+        .find_map(|l| l.strip_prefix("Maximum function count:"))
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    if max_count == Some(0) {
+        info!(
+            "no code was executed according to the profile data; the report will show 0% coverage"
+        );
+        zero_coverage_hints(cx);
+    }
+}
+
+/// Prints the likely causes of missing or empty profile data.
+fn zero_coverage_hints(cx: &Context) {
+    info!(
+        "the code may not have been instrumented; check that RUSTFLAGS set by cargo-llvm-cov \
+         are not overridden (e.g., by a wrapper or `target.<triple>.rustflags` in the cargo \
+         config), and try `-vv` to see the flags passed to rustc"
+    );
+    info!(
+        "the profile data may not have been written; tests run in a sandbox, a container, or \
+         with a cleared environment need write access to {} and LLVM_PROFILE_FILE environment \
+         variable, and processes that are killed or abort do not write it",
+        cx.ws.target_dir
+    );
+    if cx.build.target.is_some() {
+        info!(
+            "with --target, only the code built for that target is instrumented; binaries \
+             run through a runner or on another machine must write profile data to the target \
+             directory"
+        );
+    }
+    info!("no tests may have been run; check the test filters and the `test = false` settings");
+}
+
+/// Maximum number of files merged by one `llvm-profdata merge` invocation.
+const MERGE_CHUNK_SIZE: usize = 1000;
+
+/// Merges profile data in chunks, and then merges the merged chunks.
+///
+/// If `--jobs` is passed, the chunks are merged concurrently, by that number
+/// of single-threaded processes. Otherwise, the chunks are merged one by one,
+/// each using all threads.
+///
+/// Returns `true` if llvm-profdata emitted warnings.
+fn merge_profraw_chunks(
+    cx: &Context,
+    mut inputs: Vec<PathBuf>,
+    progress: &Progress,
+) -> Result<bool> {
+    let chunk_dir = cx.ws.profdata_chunk_dir(cx.build.partition);
+    fs::remove_dir_all(&chunk_dir)?;
+    fs::create_dir_all(&chunk_dir)?;
+    let (concurrency, num_threads) = match cx.build.jobs {
+        Some(jobs) => (usize::try_from(jobs).unwrap_or(usize::MAX).max(1), Some(1)),
+        None => (1, None),
+    };
+
+    let mut warned = false;
+    let mut level = 0;
+    while inputs.len() > MERGE_CHUNK_SIZE {
+        let mut outputs = vec![];
+        let mut cmds = vec![];
+        for (i, chunk) in inputs.chunks(MERGE_CHUNK_SIZE).enumerate() {
+            let output = chunk_dir.join(format!("{}-{}.profdata", level, i));
+            // Only count profraw files, not intermediate files.
+            let count = if level == 0 { chunk.len() } else { 0 };
+            cmds.push((profdata_merge(cx, chunk, &output, num_threads), count));
+            outputs.push(output.into_std_path_buf());
+        }
+        for cmds in cmds.chunks(concurrency) {
+            let handles: Vec<_> = cmds
+                .iter()
+                .map(|(cmd, count)| {
+                    let cmd = cmd.clone();
+                    (thread::spawn(move || run_profdata_merge(cmd)), *count)
+                })
+                .collect();
+            for (handle, count) in handles {
+                warned |= handle.join().unwrap()?;
+                progress.inc(count);
+            }
+        }
+        inputs = outputs;
+        level += 1;
+    }
+
+    warned |= run_profdata_merge(profdata_merge(cx, &inputs, &cx.ws.profdata_file, cx.build.jobs))?;
+    fs::remove_dir_all(&chunk_dir)?;
+    Ok(warned)
+}
+
+pub(crate) fn profdata_merge(
+    cx: &Context,
+    inputs: &[PathBuf],
+    output: &Utf8Path,
+    num_threads: Option<u32>,
+) -> ProcessBuilder {
+    let mut cmd = cx.process(&cx.llvm_profdata);
+    cmd.args(["merge", &format!("-sparse={}", !cx.cov.no_sparse)])
+        .args(inputs)
+        .arg("-o")
+        .arg(output);
+    if let Some(mode) = &cx.cov.failure_mode {
+        cmd.arg(format!("-failure-mode={}", mode));
+    }
+    if let Some(num_threads) = num_threads {
+        cmd.arg(format!("-num-threads={}", num_threads));
+    }
+    if let Some(flags) = &cx.cargo_llvm_profdata_flags {
+        cmd.args(flags.split(' ').filter(|s| !s.trim().is_empty()));
+    }
+    cmd.stdout_to_stderr();
+    cmd
+}
+
+/// Runs `llvm-profdata merge`, returning `true` if it emitted warnings.
+pub(crate) fn run_profdata_merge(mut cmd: ProcessBuilder) -> Result<bool> {
+    if term::verbose() {
+        status!("Running", "{}", cmd);
+    }
+    let output = cmd.run_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    progress::clear();
+    eprint!("{}", stderr);
+    Ok(stderr.lines().any(|l| l.starts_with("warning:")))
+}
+
+/// Prints which profraw files llvm-profdata cannot read, and which test binary
+/// produced them if known.
+fn report_bad_profraw(cx: &Context, profraw_files: &[PathBuf]) -> Result<()> {
+    let mut bad = vec![];
+    for path in profraw_files {
+        let mut cmd = cx.process(&cx.llvm_profdata);
+        cmd.arg("show").arg(path);
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        let output = cmd.output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let prefix = format!("{}: ", path.display());
+            let msg = stderr.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or_default();
+            let msg = msg.trim_start_matches("error: ").trim_start_matches(&*prefix);
+            bad.push((path, msg.to_owned()));
+        }
+    }
+    if bad.is_empty() {
+        return Ok(());
+    }
+
+    let object_files = object_files(cx).unwrap_or_default();
+    for (path, msg) in &bad {
+        let binary =
+            profraw::binary_ids(path).and_then(|ids| profraw::find_binary(&ids, &object_files));
+        match binary {
+            Some(binary) => warn!(
+                "failed to read profile data file {} produced by {}: {}",
+                make_relative(cx, path).display(),
+                make_relative(cx, Path::new(binary)).display(),
+                msg
+            ),
+            None => warn!(
+                "failed to read profile data file {}: {}",
+                make_relative(cx, path).display(),
+                msg
+            ),
+        }
+    }
+    info!(
+        "{} of {} profile data files could not be read; this is usually caused by a test \
+         process that was killed or crashed while writing them",
+        bad.len(),
+        profraw_files.len()
+    );
+    Ok(())
+}
+
+/// Computes the key that identifies the input of `llvm-profdata merge`.
+///
+/// File names of profraw files contain the hash of the binary, but not the
+/// contents, so the contents of each file are hashed.
+fn profdata_cache_key(cx: &Context, profraw_files: &[PathBuf]) -> Result<String> {
+    let mut file_hashes = Vec::with_capacity(profraw_files.len());
+    for path in profraw_files {
+        file_hashes.push(fs::hash_file(path)?);
+    }
+    file_hashes.sort_unstable();
+
+    let mut key = String::new();
+    let _ = writeln!(key, "{}", cx.llvm_profdata.display());
+    let _ = writeln!(key, "{:?}", cx.cov.failure_mode);
+    let _ = writeln!(key, "{}", cx.cov.no_sparse);
+    let _ = writeln!(key, "{:?}", cx.cargo_llvm_profdata_flags);
+    for hash in file_hashes {
+        let _ = writeln!(key, "{:016x}", hash);
+    }
+    Ok(key)
+}
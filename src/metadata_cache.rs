@@ -0,0 +1,143 @@
+// Caching of `cargo metadata` output across runs (CARGO_LLVM_COV_METADATA_CACHE).
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::Hasher,
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{cargo, env, fs, process::ProcessBuilder};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Files -> hash of the content (`None` if the file does not exist).
+    files: BTreeMap<Utf8PathBuf, Option<u64>>,
+    /// Output of `cargo metadata`.
+    metadata: String,
+}
+
+/// Returns `true` if `CARGO_LLVM_COV_METADATA_CACHE` is set to `1` or `true`.
+///
+/// This is opt-in because the targets of workspace members are auto-discovered
+/// from their source files, so they may be stale in the cached metadata.
+fn enabled() -> bool {
+    env::var_os("CARGO_LLVM_COV_METADATA_CACHE").map_or(false, |v| v == "1" || v == "true")
+}
+
+/// Runs `cmd` (`cargo metadata` for `manifest_path`), or returns the cached
+/// output of the previous run if caching is enabled and the inputs are unchanged.
+pub(crate) fn metadata(
+    mut cmd: ProcessBuilder,
+    cargo_version: &str,
+    manifest_path: &Utf8Path,
+) -> Result<cargo_metadata::Metadata> {
+    if !enabled() {
+        let output = cmd.read()?;
+        return parse(&cmd, &output);
+    }
+
+    let file_name = format!("metadata-{:016x}.json", key(&cmd, cargo_version));
+    for dir in candidate_dirs(manifest_path)? {
+        if let Some(metadata) = load(&dir.join(&file_name)) {
+            // The target directory may have changed.
+            if cache_dir(&metadata)? == dir {
+                return Ok(metadata);
+            }
+        }
+    }
+
+    let output = cmd.read()?;
+    let metadata = parse(&cmd, &output)?;
+    let entry =
+        Entry { files: hash_files(&input_files(&metadata, manifest_path)), metadata: output };
+    let dir = cache_dir(&metadata)?;
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(file_name), serde_json::to_string(&entry)?)?;
+    Ok(metadata)
+}
+
+fn parse(cmd: &ProcessBuilder, output: &str) -> Result<cargo_metadata::Metadata> {
+    serde_json::from_str(output).with_context(|| format!("failed to parse output from {}", cmd))
+}
+
+fn load(path: &Utf8Path) -> Option<cargo_metadata::Metadata> {
+    let entry: Entry = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let files: Vec<_> = entry.files.keys().cloned().collect();
+    if hash_files(&files) != entry.files {
+        return None;
+    }
+    serde_json::from_str(&entry.metadata).ok()
+}
+
+/// Hashes the inputs that are known before running `cargo metadata`.
+fn key(cmd: &ProcessBuilder, cargo_version: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(cmd.to_string().as_bytes());
+    hasher.write(cargo_version.as_bytes());
+    // Cargo can be configured by environment variables.
+    let mut vars: Vec<_> = std::env::vars_os()
+        .filter(|(k, _)| {
+            k.to_str().map_or(false, |k| k.starts_with("CARGO_") || k == "RUSTUP_TOOLCHAIN")
+        })
+        .collect();
+    vars.sort();
+    for (k, v) in vars {
+        hasher.write(k.to_string_lossy().as_bytes());
+        hasher.write(v.to_string_lossy().as_bytes());
+    }
+    hasher.finish()
+}
+
+/// Directory where the cache for the given metadata is stored.
+fn cache_dir(metadata: &cargo_metadata::Metadata) -> Result<Utf8PathBuf> {
+    Ok(match cargo::target_dir_from_env()? {
+        Some(path) => path,
+        None => metadata.target_directory.join("llvm-cov-target"),
+    })
+}
+
+/// Directories where the cache for the given manifest may be stored.
+fn candidate_dirs(manifest_path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    if let Some(path) = cargo::target_dir_from_env()? {
+        return Ok(vec![path]);
+    }
+    if let Some(path) = env::var("CARGO_TARGET_DIR")?.or(env::var("CARGO_BUILD_TARGET_DIR")?) {
+        return Ok(vec![Utf8PathBuf::from(path).join("llvm-cov-target")]);
+    }
+    Ok(manifest_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("target").join("llvm-cov-target"))
+        .collect())
+}
+
+/// Files that affect the output of `cargo metadata`.
+fn input_files(metadata: &cargo_metadata::Metadata, manifest_path: &Utf8Path) -> Vec<Utf8PathBuf> {
+    let mut files = vec![manifest_path.to_owned(), metadata.workspace_root.join("Cargo.lock")];
+    // Local packages (workspace members and path dependencies).
+    for package in &metadata.packages {
+        if package.source.is_none() {
+            files.push(package.manifest_path.clone());
+        }
+    }
+    // https://doc.rust-lang.org/nightly/cargo/reference/config.html#hierarchical-structure
+    let mut config_dirs: Vec<_> =
+        manifest_path.ancestors().skip(1).map(|dir| dir.join(".cargo")).collect();
+    if let Some(cargo_home) =
+        home::cargo_home().ok().and_then(|p| Utf8PathBuf::from_path_buf(p).ok())
+    {
+        config_dirs.push(cargo_home);
+    }
+    for dir in config_dirs {
+        files.push(dir.join("config"));
+        files.push(dir.join("config.toml"));
+    }
+    files
+}
+
+fn hash_files(files: &[Utf8PathBuf]) -> BTreeMap<Utf8PathBuf, Option<u64>> {
+    files.iter().map(|f| (f.clone(), fs::hash_file(f).ok())).collect()
+}
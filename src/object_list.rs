@@ -0,0 +1,126 @@
+// Cache of the object files passed to llvm-cov, reused by `--no-run`.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{cargo::Workspace, cli::Partition, context::Context, fs};
+
+/// Directories modified within this duration before the walk may be modified
+/// again without changing their modification time on file systems with coarse
+/// timestamps.
+const RACY_DURATION: Duration = Duration::from_secs(2);
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct ObjectList {
+    /// Options that affect which files are collected.
+    options: String,
+    /// Walked directories -> modification time (`None` if it does not exist).
+    dirs: BTreeMap<PathBuf, Option<SystemTime>>,
+    files: Vec<PathBuf>,
+    /// The partition (`M/N`) this list was collected for, to identify the
+    /// lists of partitions combined from different jobs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partition: Option<String>,
+    /// The nextest filter expressions (--filter-expr) of the run that produced
+    /// the profile data, to label reports of partial coverage.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filter_exprs: Vec<String>,
+    /// Whether some directories were modified too recently to be trusted. Such
+    /// a list is saved only to keep the metadata above, and is never reused.
+    #[serde(default, skip_serializing_if = "is_false")]
+    racy: bool,
+}
+
+/// Returns the path of the list. With --partition, each partition has its own
+/// list, so that it is not overwritten when the target directories of
+/// partitions are combined.
+pub(crate) fn path(ws: &Workspace, partition: Option<Partition>) -> Utf8PathBuf {
+    match partition {
+        Some(partition) => {
+            ws.target_dir.join(format!("{}.objects-{}.json", ws.name, partition.file_suffix()))
+        }
+        None => ws.target_dir.join(format!("{}.objects.json", ws.name)),
+    }
+}
+
+/// Returns the nextest filter expressions the profile data was produced with.
+/// With --no-run, these are the ones recorded by the previous run.
+pub(crate) fn filter_exprs(cx: &Context) -> Vec<String> {
+    if cx.no_run {
+        return read(cx).map(|list| list.filter_exprs).unwrap_or_default();
+    }
+    cx.build.filter_expr.clone()
+}
+
+fn read(cx: &Context) -> Option<ObjectList> {
+    serde_json::from_str(&fs::read_to_string(path(&cx.ws, cx.build.partition)).ok()?).ok()
+}
+
+impl ObjectList {
+    pub(crate) fn new(cx: &Context) -> Self {
+        let mut options = String::new();
+        let _ = writeln!(options, "{}", cx.current_exe.display());
+        let _ = writeln!(options, "{}", cx.current_dir.display());
+        let _ = writeln!(options, "{}", cx.ws.target_dir);
+        let _ = writeln!(options, "{:?}", cx.build.target);
+        let _ = writeln!(options, "{:?}", cx.build.profile);
+        let _ = writeln!(options, "{}", cx.build.release);
+        let _ = writeln!(options, "{}", cx.doctests);
+        let _ = writeln!(options, "{}", cx.cov.include_build_script);
+        let _ = writeln!(options, "{}", cx.build_script_re);
+        let partition = cx.build.partition.map(Partition::id);
+        Self { options, partition, filter_exprs: filter_exprs(cx), ..Self::default() }
+    }
+
+    /// Records the modification time of a walked directory.
+    pub(crate) fn add_dir(&mut self, path: &Path) {
+        let mtime = mtime(path);
+        if let Some(mtime) = mtime {
+            self.racy |=
+                SystemTime::now().duration_since(mtime).map_or(true, |d| d < RACY_DURATION);
+        }
+        self.dirs.insert(path.to_owned(), mtime);
+    }
+
+    /// Returns the object files of the previous run if the walked directories
+    /// are unchanged.
+    pub(crate) fn load(&self, cx: &Context) -> Option<Vec<OsString>> {
+        let prev = read(cx)?;
+        if prev.racy || prev.options != self.options {
+            return None;
+        }
+        if prev.dirs.iter().any(|(dir, &prev_mtime)| mtime(dir) != prev_mtime) {
+            return None;
+        }
+        Some(prev.files.into_iter().map(PathBuf::into_os_string).collect())
+    }
+
+    pub(crate) fn save(mut self, cx: &Context, files: &[OsString]) -> Result<()> {
+        let path = path(&cx.ws, cx.build.partition);
+        self.files = files.iter().map(PathBuf::from).collect();
+        match serde_json::to_string(&self) {
+            Ok(s) => fs::write(path, s)?,
+            // Paths that are not valid UTF-8 cannot be serialized to JSON.
+            _ => fs::remove_file(path)?,
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // serde requires this signature
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,209 @@
+// Mapping of tests to the lines they cover (--per-test).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use crate::{
+    cargo,
+    cli::Args,
+    context::Context,
+    fs, html,
+    progress::{self, Progress},
+    term,
+};
+
+/// The name of the output of `cargo nextest list` in the directory of the
+/// profile data of attempts.
+const LIST_FILE: &str = "nextest-list.json";
+
+// https://nexte.st/book/machine-readable
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TestList {
+    rust_suites: BTreeMap<String, Suite>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Suite {
+    binary_path: Utf8PathBuf,
+    testcases: BTreeMap<String, serde_json::Value>,
+}
+
+/// Saves the list of the tests run by `cargo llvm-cov nextest`.
+pub(crate) fn save_test_list(cx: &Context, args: &Args) -> Result<()> {
+    let mut cargo = cx.cargo();
+    crate::set_env(cx, &mut cargo);
+    cargo.arg("nextest").arg("list").arg("--message-format").arg("json");
+    cargo::nextest_list_args(cx, args, &mut cargo);
+    if term::verbose() {
+        status!("Running", "{}", cargo);
+    }
+    let list = cargo.read().context("failed to list tests")?;
+    let dir = cx.ws.attempts_dir(cx.build.partition);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(LIST_FILE), list)?;
+    Ok(())
+}
+
+/// Handles --per-test. The output maps the IDs of the tests
+/// (`<binary-id> <test name>`) to the files (relative to the workspace root)
+/// and the lines they covered.
+pub(crate) fn write(
+    cx: &Context,
+    path: &Utf8Path,
+    object_files: &[OsString],
+    ignore_filename_regex: Option<&String>,
+) -> Result<()> {
+    let dir = cx.ws.attempts_dir(cx.build.partition);
+    let list = fs::read_to_string(dir.join(LIST_FILE))
+        .context("the list of tests was not found; --per-test requires `cargo llvm-cov nextest`")?;
+    let list: TestList =
+        serde_json::from_str(&list).context("failed to parse the list of tests")?;
+    let tests = profraw_files_by_test(&dir, &list)?;
+
+    let progress =
+        Progress::new("Exporting", "coverage of each test".to_owned(), Some(tests.len()));
+    let mut out = BTreeMap::new();
+    for (test, profraw_files) in &tests {
+        let profdata = dir.join("per-test.profdata");
+        let merge = crate::merge::profdata_merge(cx, profraw_files, &profdata, cx.build.jobs);
+        crate::merge::run_profdata_merge(merge)?;
+        let lcov = export_lcov(cx, &profdata, object_files, ignore_filename_regex)?;
+        out.insert(test, covered_lines(&lcov, &cx.ws.metadata.workspace_root));
+        progress.inc(1);
+    }
+    drop(progress);
+    progress::clear();
+    fs::write(path, serde_json::to_string(&serde_json::json!({ "tests": out }))?)?;
+    if cx.cov.html {
+        if let Some(output_dir) = &cx.cov.output_dir {
+            let html_dir = output_dir.join("html");
+            html::annotate_tests(&html_dir, &cx.ws.metadata.workspace_root, &out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the profile data files of each test.
+fn profraw_files_by_test(
+    dir: &Utf8Path,
+    list: &TestList,
+) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut tests: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    let pattern = dir.join("*.args.json");
+    for path in glob::glob(pattern.as_str())?.filter_map(Result::ok) {
+        let test: Vec<String> = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        let (program, args) = match test.split_first() {
+            Some(test) => test,
+            None => continue,
+        };
+        // One of the arguments is the name of the test.
+        let test = list.rust_suites.iter().find_map(|(binary_id, suite)| {
+            if suite.binary_path != *program {
+                return None;
+            }
+            let name = args.iter().find(|arg| suite.testcases.contains_key(*arg))?;
+            Some(format!("{} {}", binary_id, name))
+        });
+        let test = match test {
+            Some(test) => test,
+            None => continue,
+        };
+        let id =
+            path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".args.json"));
+        let id = match id {
+            Some(id) => id,
+            None => continue,
+        };
+        let files = dir.join(format!("{}-*.profraw", id));
+        let files: Vec<_> = glob::glob(files.as_str())?.filter_map(Result::ok).collect();
+        // The files of failed attempts may have been removed by --merge-attempts.
+        if !files.is_empty() {
+            tests.entry(test).or_default().extend(files);
+        }
+    }
+    Ok(tests)
+}
+
+fn export_lcov(
+    cx: &Context,
+    profdata: &Utf8Path,
+    object_files: &[OsString],
+    ignore_filename_regex: Option<&String>,
+) -> Result<String> {
+    let mut cmd = cx.process(&cx.llvm_cov);
+    cmd.args(["export", "-format=lcov", "-skip-functions"]);
+    cmd.arg(format!("-instr-profile={}", profdata));
+    cmd.args(object_files.iter().flat_map(|f| [OsStr::new("-object"), f]));
+    if let Some(jobs) = cx.build.jobs {
+        cmd.arg(format!("-num-threads={}", jobs));
+    }
+    if let Some(ignore_filename_regex) = ignore_filename_regex {
+        cmd.arg("-ignore-filename-regex");
+        cmd.arg(ignore_filename_regex);
+    }
+    if term::verbose() {
+        status!("Running", "{}", cmd);
+    }
+    cmd.read()
+}
+
+/// Returns the lines executed at least once according to the lcov report.
+fn covered_lines(lcov: &str, workspace_root: &Utf8Path) -> BTreeMap<String, BTreeSet<u64>> {
+    let mut files: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    let mut current = None;
+    for line in lcov.lines() {
+        if let Some(filename) = line.strip_prefix("SF:") {
+            let path = Utf8Path::new(filename);
+            let path = path.strip_prefix(workspace_root).unwrap_or(path);
+            current = Some(path.as_str().replace('\\', "/"));
+        } else if line == "end_of_record" {
+            current = None;
+        } else if let (Some(filename), Some(da)) = (&current, line.strip_prefix("DA:")) {
+            // DA:<line number>,<execution count>[,<checksum>]
+            let mut da = da.split(',');
+            if let (Some(Ok(number)), Some(Ok(hits))) =
+                (da.next().map(str::parse), da.next().map(str::parse::<u64>))
+            {
+                if hits > 0 {
+                    files.entry(filename.clone()).or_default().insert(number);
+                }
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lcov_covered_lines() {
+        let lcov = "\
+SF:/ws/src/lib.rs
+DA:1,2
+DA:2,0
+DA:3,1
+end_of_record
+SF:/ws/src/main.rs
+DA:1,0
+end_of_record
+SF:/other/lib.rs
+DA:5,1
+end_of_record
+";
+        let lines = covered_lines(lcov, Utf8Path::new("/ws"));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines["src/lib.rs"].iter().copied().collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(lines["/other/lib.rs"].iter().copied().collect::<Vec<_>>(), [5]);
+    }
+}
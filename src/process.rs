@@ -1,16 +1,19 @@
 use std::{
+    borrow::Cow,
     cell::Cell,
     collections::BTreeMap,
     ffi::OsString,
-    fmt,
-    path::PathBuf,
+    fmt, io,
+    path::{Path, PathBuf},
     process::{ExitStatus, Output},
     str,
 };
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use shell_escape::escape;
 
+use crate::fs;
+
 macro_rules! cmd {
     ($program:expr $(, $arg:expr)* $(,)?) => {{
         let mut _cmd = $crate::process::ProcessBuilder::new($program);
@@ -34,8 +37,12 @@ pub(crate) struct ProcessBuilder {
     /// The working directory where the process will execute.
     dir: Option<PathBuf>,
     stdout_to_stderr: bool,
+    /// The bytes written to the process's standard input.
+    stdin: Option<Vec<u8>>,
     /// `true` to include environment variables in display.
     display_env_vars: Cell<bool>,
+    /// The path to the response file used if the command line is too long.
+    response_file: Option<PathBuf>,
 }
 
 impl ProcessBuilder {
@@ -47,7 +54,9 @@ impl ProcessBuilder {
             env: BTreeMap::new(),
             dir: None,
             stdout_to_stderr: false,
+            stdin: None,
             display_env_vars: Cell::new(false),
+            response_file: None,
         };
         this.env("CARGO_INCREMENTAL", "0");
         this
@@ -74,11 +83,11 @@ impl ProcessBuilder {
         self
     }
 
-    // /// Remove a variable from the process's environment.
-    // pub(crate) fn env_remove(&mut self, key: impl Into<String>) -> &mut Self {
-    //     self.env.insert(key.into(), None);
-    //     self
-    // }
+    /// Remove a variable from the process's environment.
+    pub(crate) fn env_remove(&mut self, key: impl Into<String>) -> &mut Self {
+        self.env.insert(key.into(), None);
+        self
+    }
 
     /// Set the working directory where the process will execute.
     pub(crate) fn dir(&mut self, path: impl Into<PathBuf>) -> &mut Self {
@@ -92,6 +101,21 @@ impl ProcessBuilder {
         self
     }
 
+    /// Writes the given bytes to the standard input of the process.
+    pub(crate) fn stdin(&mut self, bytes: impl Into<Vec<u8>>) -> &mut Self {
+        self.stdin = Some(bytes.into());
+        self
+    }
+
+    /// Passes the arguments via the given response file (`@file`) if the
+    /// command line is too long.
+    ///
+    /// This is only supported by LLVM tools.
+    pub(crate) fn response_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.response_file = Some(path.into());
+        self
+    }
+
     /// Enables environment variables display.
     pub(crate) fn display_env_vars(&mut self) -> &mut Self {
         self.display_env_vars.set(true);
@@ -101,7 +125,7 @@ impl ProcessBuilder {
     /// Executes a process, waiting for completion, and mapping non-zero exit
     /// status to an error.
     pub(crate) fn run(&mut self) -> Result<Output> {
-        let output = self.build().unchecked().run().with_context(|| {
+        let output = self.build()?.unchecked().run().with_context(|| {
             ProcessError::new(&format!("could not execute process {}", self), None, None)
         })?;
         if output.status.success() {
@@ -119,10 +143,7 @@ impl ProcessBuilder {
     /// Executes a process, captures its stdio output, returning the captured
     /// output, or an error if non-zero exit status.
     pub(crate) fn run_with_output(&mut self) -> Result<Output> {
-        let output =
-            self.build().stdout_capture().stderr_capture().unchecked().run().with_context(
-                || ProcessError::new(&format!("could not execute process {}", self), None, None),
-            )?;
+        let output = self.output()?;
         if output.status.success() {
             Ok(output)
         } else {
@@ -135,11 +156,36 @@ impl ProcessBuilder {
         }
     }
 
+    /// Executes a process, captures its stdio output, returning the captured
+    /// output regardless of its exit status.
+    pub(crate) fn output(&mut self) -> Result<Output> {
+        self.build()?.stdout_capture().stderr_capture().unchecked().run().with_context(|| {
+            ProcessError::new(&format!("could not execute process {}", self), None, None)
+        })
+    }
+
     /// Executes a process, captures its stdio output, returning the captured
     /// standard output as a `String`.
     pub(crate) fn read(&mut self) -> Result<String> {
         assert!(!self.stdout_to_stderr);
-        let mut output = String::from_utf8(self.run_with_output()?.stdout)
+        let output = self.run_with_output()?;
+        self.output_to_string(output)
+    }
+
+    /// Starts a process, returning a reader of its standard output.
+    ///
+    /// Standard error is captured and included in the error returned by
+    /// [`ProcessReader::finish`] if the process exits with non-zero status.
+    pub(crate) fn reader(&self) -> Result<ProcessReader> {
+        assert!(!self.stdout_to_stderr);
+        let handle = self.build()?.stderr_capture().unchecked().reader().with_context(|| {
+            ProcessError::new(&format!("could not execute process {}", self), None, None)
+        })?;
+        Ok(ProcessReader { cmd: self.clone(), handle })
+    }
+
+    fn output_to_string(&self, output: Output) -> Result<String> {
+        let mut output = String::from_utf8(output.stdout)
             .with_context(|| format!("failed to parse output from {}", self))?;
         while output.ends_with('\n') || output.ends_with('\r') {
             output.pop();
@@ -147,8 +193,15 @@ impl ProcessBuilder {
         Ok(output)
     }
 
-    fn build(&self) -> duct::Expression {
-        let mut cmd = duct::cmd(&*self.program, &self.args);
+    fn build(&self) -> Result<duct::Expression> {
+        let mut cmd = match self.write_response_file()? {
+            Some(path) => {
+                let mut arg = OsString::from("@");
+                arg.push(path);
+                duct::cmd(&*self.program, [arg])
+            }
+            None => duct::cmd(&*self.program, &self.args),
+        };
 
         for (k, v) in &self.env {
             match v {
@@ -167,8 +220,116 @@ impl ProcessBuilder {
         if self.stdout_to_stderr {
             cmd = cmd.stdout_to_stderr();
         }
+        if let Some(bytes) = &self.stdin {
+            cmd = cmd.stdin_bytes(bytes.clone());
+        }
 
-        cmd
+        Ok(cmd)
+    }
+
+    /// Writes the arguments to the response file if the command line is too long.
+    fn write_response_file(&self) -> Result<Option<&Path>> {
+        let path = match &self.response_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let len = self.program.len() + self.args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+        if len <= MAX_COMMAND_LINE_LEN {
+            return Ok(None);
+        }
+        let mut contents = String::new();
+        for arg in &self.args {
+            match arg.to_str() {
+                Some(arg) => {
+                    contents.push_str(&quote_response_file_arg(arg, cfg!(windows)));
+                    contents.push('\n');
+                }
+                // Response files must be valid UTF-8.
+                None => return Ok(None),
+            }
+        }
+        fs::write(path, contents)?;
+        Ok(Some(path))
+    }
+}
+
+// Windows' limit is 32767 characters: https://docs.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-createprocessw
+const MAX_COMMAND_LINE_LEN: usize = 30 * 1024;
+
+/// Quotes an argument in the response file.
+///
+/// LLVM tools tokenize response files using the same rules as the command line
+/// of the host platform.
+// https://github.com/llvm/llvm-project/blob/llvmorg-14.0.0/llvm/lib/Support/CommandLine.cpp#L859-L1069
+fn quote_response_file_arg(arg: &str, windows: bool) -> String {
+    let mut out = String::with_capacity(arg.len() + 2);
+    out.push('"');
+    if windows {
+        // Backslashes are literal unless followed by a double quote.
+        let mut backslashes = 0;
+        for c in arg.chars() {
+            if c == '\\' {
+                backslashes += 1;
+                continue;
+            }
+            if c == '"' {
+                // 2n+1 backslashes followed by a double quote are interpreted
+                // as n backslashes and a literal double quote.
+                backslashes = backslashes * 2 + 1;
+            }
+            out.extend((0..backslashes).map(|_| '\\'));
+            out.push(c);
+            backslashes = 0;
+        }
+        // Backslashes followed by the closing quote need to be escaped.
+        out.extend((0..backslashes * 2).map(|_| '\\'));
+    } else {
+        for c in arg.chars() {
+            if matches!(c, '\\' | '"') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A reader of the standard output of a process started by [`ProcessBuilder::reader`].
+pub(crate) struct ProcessReader {
+    cmd: ProcessBuilder,
+    handle: duct::ReaderHandle,
+}
+
+impl ProcessReader {
+    /// Reads the rest of the output and waits for the process to exit,
+    /// mapping non-zero exit status to an error.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        // The reader waits for the process when it reaches EOF.
+        io::copy(&mut self.handle, &mut io::sink())?;
+        let output = match self.handle.try_wait()? {
+            Some(output) => output,
+            None => {
+                self.handle.kill()?;
+                bail!("process didn't exit after closing its stdout: {}", self.cmd);
+            }
+        };
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ProcessError::new(
+                &format!("process didn't exit successfully: {}", self.cmd),
+                Some(output.status),
+                Some(output),
+            )
+            .into())
+        }
+    }
+}
+
+impl io::Read for ProcessReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.read(buf)
     }
 }
 
@@ -178,13 +339,24 @@ impl fmt::Display for ProcessBuilder {
         write!(f, "`")?;
 
         if self.display_env_vars.get() {
+            // Display in a form that can be copied and pasted into a shell.
             for (key, val) in &self.env {
-                if let Some(val) = val {
-                    let val = escape(val.to_string_lossy());
-                    if cfg!(windows) {
-                        write!(f, "set {}={}&& ", key, val)?;
-                    } else {
-                        write!(f, "{}={} ", key, val)?;
+                match val {
+                    Some(val) => {
+                        let val = val.to_string_lossy();
+                        let val = escape_env_value(&val);
+                        if cfg!(windows) {
+                            write!(f, "set {}={}&& ", key, val)?;
+                        } else {
+                            write!(f, "{}={} ", key, val)?;
+                        }
+                    }
+                    None => {
+                        if cfg!(windows) {
+                            write!(f, "set {}=&& ", key)?;
+                        } else {
+                            write!(f, "env -u {} ", key)?;
+                        }
                     }
                 }
             }
@@ -202,6 +374,29 @@ impl fmt::Display for ProcessBuilder {
     }
 }
 
+/// Escapes the value of an environment variable for display.
+///
+/// The separator of `CARGO_ENCODED_RUSTFLAGS` (`\x1f`) is not visible, so it is
+/// displayed using ANSI-C quoting on non-Windows.
+pub(crate) fn escape_env_value(val: &str) -> Cow<'_, str> {
+    if cfg!(windows) || !val.contains('\x1f') {
+        return escape(val.into());
+    }
+    let mut out = String::from("$'");
+    for c in val.chars() {
+        match c {
+            '\x1f' => out.push_str("\\x1f"),
+            '\'' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push('\'');
+    out.into()
+}
+
 // Based on https://github.com/rust-lang/cargo/blob/0.47.0/src/cargo/util/errors.rs
 #[derive(Debug)]
 struct ProcessError {
@@ -249,3 +444,46 @@ impl fmt::Display for ProcessError {
 }
 
 impl std::error::Error for ProcessError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::{escape_env_value, quote_response_file_arg};
+
+    #[test]
+    fn quote() {
+        assert_eq!(quote_response_file_arg("a b", false), r#""a b""#);
+        assert_eq!(quote_response_file_arg(r#"a\"b\"#, false), r#""a\\\"b\\""#);
+        assert_eq!(quote_response_file_arg("a b", true), r#""a b""#);
+        assert_eq!(quote_response_file_arg(r"C:\a b\", true), r#""C:\a b\\""#);
+        assert_eq!(quote_response_file_arg(r#"a\"b"#, true), r#""a\\\"b""#);
+    }
+
+    #[test]
+    fn escape_env() {
+        if !cfg!(windows) {
+            assert_eq!(escape_env_value("-C opt-level=1"), "'-C opt-level=1'");
+            assert_eq!(escape_env_value("-C\x1fopt-level=1"), r"$'-C\x1fopt-level=1'");
+            assert_eq!(escape_env_value("--cfg\x1fa='b'"), r"$'--cfg\x1fa=\'b\''");
+        }
+    }
+
+    #[test]
+    fn reader_finish() {
+        if cfg!(windows) {
+            return;
+        }
+        let mut reader = cmd!("sh", "-c", "echo a").reader().unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "a\n");
+        reader.finish().unwrap();
+        // The exit status is checked even if not all output has been read.
+        let mut reader = cmd!("sh", "-c", "echo a; echo b; exit 1").reader().unwrap();
+        reader.read_exact(&mut [0; 1]).unwrap();
+        assert!(reader.finish().is_err());
+        let reader = cmd!("sh", "-c", "exit 1").reader().unwrap();
+        assert!(reader.finish().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+// Resolution of the `strip` setting of the profiles used for coverage builds.
+// https://doc.rust-lang.org/nightly/cargo/reference/profiles.html
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+use crate::{cargo::Workspace, cli::BuildOptions, env, fs};
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Profile {
+    inherits: Option<String>,
+    strip: Option<Strip>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Strip {
+    Bool(bool),
+    String(String),
+}
+
+impl Strip {
+    /// Returns `true` if this removes symbols or debuginfo.
+    fn strips(&self) -> bool {
+        match self {
+            Self::Bool(strip) => *strip,
+            // Environment variables are always strings.
+            Self::String(strip) => strip != "none" && strip != "false",
+        }
+    }
+
+    fn to_toml(&self) -> String {
+        match self {
+            Self::Bool(strip) => strip.to_string(),
+            Self::String(strip) => format!("{:?}", strip),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+}
+
+/// Returns the profiles used to build the instrumented code.
+pub(crate) fn build_profiles(build: &BuildOptions) -> Vec<&str> {
+    match &build.profile {
+        Some(profile) => vec![profile],
+        // `test` and `bench` profiles are used for test targets.
+        None if build.release => vec!["release", "bench"],
+        None => vec!["dev", "test"],
+    }
+}
+
+/// Returns the environment variable that sets `key` of the profile.
+pub(crate) fn env_key(profile: &str, key: &str) -> String {
+    format!("CARGO_PROFILE_{}_{}", profile.to_uppercase().replace('-', "_"), key)
+}
+
+/// Prints the profiles used for the coverage build whose `strip` setting
+/// removes symbols or debuginfo, which cargo-llvm-cov overrides because
+/// llvm-cov can silently miss files or functions in stripped binaries.
+pub(crate) fn check_strip(ws: &Workspace, build: &BuildOptions) -> Result<()> {
+    // Only the manifest of the workspace root can define profiles.
+    let manifest_path = ws.metadata.workspace_root.join("Cargo.toml");
+    let manifest: Manifest = toml::from_str(&fs::read_to_string(&manifest_path)?)
+        .with_context(|| format!("failed to parse {}", manifest_path))?;
+    for profile in build_profiles(build) {
+        if let Some((strip, source)) = strip(&ws.config.profile, &manifest.profile, profile)? {
+            if strip.strips() {
+                info!(
+                    "overriding `strip = {}` of profile `{}` (set by {}) with `strip = \"none\"`, \
+                     because llvm-cov can miss files or functions in stripped binaries; pass \
+                     --allow-stripped to keep it",
+                    strip.to_toml(),
+                    profile,
+                    source
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `strip` setting of the profile and where it is set, following
+/// `inherits`. Returns `None` if it is not set (cargo's default is used).
+fn strip(
+    config: &BTreeMap<String, Profile>,
+    manifest: &BTreeMap<String, Profile>,
+    profile: &str,
+) -> Result<Option<(Strip, String)>> {
+    let mut profile = profile.to_owned();
+    let mut visited = vec![];
+    loop {
+        let key = env_key(&profile, "STRIP");
+        if let Some(strip) = env::var(&key)? {
+            return Ok(Some((Strip::String(strip), key)));
+        }
+        let sources =
+            [(config.get(&profile), "cargo config"), (manifest.get(&profile), "Cargo.toml")];
+        for (p, source) in &sources {
+            if let Some(strip) = p.and_then(|p| p.strip.as_ref()) {
+                let source = format!("`profile.{}.strip` in {}", profile, source);
+                return Ok(Some((strip.clone(), source)));
+            }
+        }
+        let inherits = match env::var(&env_key(&profile, "INHERITS"))? {
+            Some(inherits) => Some(inherits),
+            None => sources.iter().find_map(|(p, _)| p.and_then(|p| p.inherits.clone())),
+        };
+        let inherits = inherits.or_else(|| match profile.as_str() {
+            "test" => Some("dev".to_owned()),
+            "bench" => Some("release".to_owned()),
+            _ => None,
+        });
+        visited.push(profile);
+        match inherits {
+            // cargo rejects cycles, so stop at them instead of reporting an error here.
+            Some(inherits) if !visited.contains(&inherits) => profile = inherits,
+            _ => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(s: &str) -> BTreeMap<String, Profile> {
+        toml::from_str::<Manifest>(s).unwrap().profile
+    }
+
+    #[test]
+    fn inherits() {
+        let manifest = profiles(
+            "[profile.release]\nstrip = true\n\
+             [profile.coverage]\ninherits = \"release\"\n\
+             [profile.dist]\ninherits = \"release\"\nstrip = \"none\"\n",
+        );
+        let config = profiles("[profile.ci]\ninherits = \"coverage\"\n");
+        let get = |profile| {
+            strip(&config, &manifest, profile)
+                .unwrap()
+                .map(|(strip, source)| (strip.strips(), source))
+        };
+        let release = Some((true, "`profile.release.strip` in Cargo.toml".to_owned()));
+        assert_eq!(get("release"), release);
+        assert_eq!(get("bench"), release);
+        assert_eq!(get("coverage"), release);
+        assert_eq!(get("ci"), release);
+        assert_eq!(get("dist"), Some((false, "`profile.dist.strip` in Cargo.toml".to_owned())));
+        assert_eq!(get("dev"), None);
+        assert_eq!(get("test"), None);
+    }
+}
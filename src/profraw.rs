@@ -0,0 +1,96 @@
+// Helpers for diagnosing profraw files that llvm-profdata failed to read.
+// https://github.com/llvm/llvm-project/blob/llvmorg-15.0.0/compiler-rt/include/profile/InstrProfData.inc
+
+use std::{ffi::OsString, path::Path};
+
+use crate::fs;
+
+const MAGIC_64: u64 = 0xFF6C_7072_6F66_7281;
+const MAGIC_32: u64 = 0xFF6C_7072_6F66_5281;
+
+/// Returns the binary IDs recorded in the profraw file.
+pub(crate) fn binary_ids(path: &Path) -> Option<Vec<Vec<u8>>> {
+    let buf = fs::read(path).ok()?;
+    parse_binary_ids(&buf)
+}
+
+fn parse_binary_ids(buf: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let read_u64 = |offset: usize, big_endian: bool| -> Option<u64> {
+        let bytes: [u8; 8] = buf.get(offset..offset + 8)?.try_into().ok()?;
+        Some(if big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) })
+    };
+    let big_endian = match read_u64(0, false)? {
+        MAGIC_64 | MAGIC_32 => false,
+        magic if magic.swap_bytes() == MAGIC_64 || magic.swap_bytes() == MAGIC_32 => true,
+        _ => return None,
+    };
+    // The upper bits of the version are used for flags.
+    let version = read_u64(8, big_endian)? & 0xFFFF_FFFF;
+    let header_len: usize = match version {
+        6..=8 => 11,
+        9 => 14,
+        10 => 16,
+        _ => return None,
+    } * 8;
+    let binary_ids_len = usize::try_from(read_u64(16, big_endian)?).ok()?;
+    let end = header_len.checked_add(binary_ids_len)?;
+    let mut offset = header_len;
+    let mut ids = vec![];
+    while offset < end {
+        let len = usize::try_from(read_u64(offset, big_endian)?).ok()?;
+        offset += 8;
+        ids.push(buf.get(offset..offset.checked_add(len)?)?.to_vec());
+        // Each ID is padded to 8 bytes.
+        offset += (len + 7) / 8 * 8;
+    }
+    Some(ids)
+}
+
+/// Returns the object file that contains one of the given binary IDs.
+pub(crate) fn find_binary<'a>(
+    ids: &[Vec<u8>],
+    object_files: &'a [OsString],
+) -> Option<&'a OsString> {
+    let ids: Vec<_> = ids.iter().filter(|id| !id.is_empty()).collect();
+    if ids.is_empty() {
+        return None;
+    }
+    object_files.iter().find(|f| match fs::read(f) {
+        Ok(buf) => ids.iter().any(|id| buf.windows(id.len()).any(|w| w == id.as_slice())),
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u64, fields: usize, ids: &[&[u8]]) -> Vec<u8> {
+        let mut ids_buf = vec![];
+        for id in ids {
+            ids_buf.extend_from_slice(&(id.len() as u64).to_le_bytes());
+            ids_buf.extend_from_slice(id);
+            ids_buf.resize((ids_buf.len() + 7) / 8 * 8, 0);
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&MAGIC_64.to_le_bytes());
+        buf.extend_from_slice(&(version | 1 << 56).to_le_bytes());
+        buf.extend_from_slice(&(ids_buf.len() as u64).to_le_bytes());
+        buf.resize(fields * 8, 0);
+        buf.extend_from_slice(&ids_buf);
+        // Truncated data.
+        buf.extend_from_slice(&[1, 2, 3]);
+        buf
+    }
+
+    #[test]
+    fn parse() {
+        let id: &[u8] = &[0x6e, 0xd8, 0x81, 0x5b, 0xd6, 0x44, 0x3d, 0x7e, 0xca, 0xda];
+        assert_eq!(parse_binary_ids(&header(8, 11, &[id])), Some(vec![id.to_vec()]));
+        assert_eq!(parse_binary_ids(&header(9, 14, &[id, id])), Some(vec![id.to_vec(); 2]));
+        assert_eq!(parse_binary_ids(&header(10, 16, &[])), Some(vec![]));
+        assert_eq!(parse_binary_ids(&header(5, 10, &[])), None);
+        assert_eq!(parse_binary_ids(&header(10, 16, &[id])[..130]), None);
+        assert_eq!(parse_binary_ids(b"not a profraw file"), None);
+    }
+}
@@ -0,0 +1,147 @@
+// Progress indicator for merging profile data and generating reports.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use camino::Utf8PathBuf;
+use walkdir::WalkDir;
+
+use crate::term;
+
+const TICK: Duration = Duration::from_millis(100);
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Whether a progress indicator is currently displayed.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Clears the line of the progress indicator, if displayed, so that other
+/// messages are not mixed into it. The indicator is redrawn on the next tick.
+pub(crate) fn clear() {
+    if ACTIVE.load(Ordering::Relaxed) {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = write!(stderr, "\r\x1b[2K");
+        let _ = stderr.flush();
+    }
+}
+
+enum Count {
+    /// Incremented by `Progress::inc`.
+    Counter(AtomicUsize),
+    /// Number of files in the directory (e.g., the output directory of the
+    /// report being rendered).
+    Files(Utf8PathBuf),
+}
+
+impl Count {
+    fn get(&self) -> usize {
+        match self {
+            Self::Counter(count) => count.load(Ordering::Relaxed),
+            Self::Files(dir) => WalkDir::new(dir)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().is_file())
+                .count(),
+        }
+    }
+}
+
+struct State {
+    status: &'static str,
+    message: String,
+    count: Count,
+    total: Option<usize>,
+    done: AtomicBool,
+}
+
+impl State {
+    fn draw(&self, frame: usize) {
+        let count = self.count.get();
+        let mut line = format!("{:>12} {}", self.status, self.message);
+        match (&self.count, self.total) {
+            (_, Some(total)) => {
+                let _ = write!(line, " ({}/{})", count, total);
+            }
+            (Count::Files(_), None) => {
+                let _ = write!(line, " ({} files)", count);
+            }
+            (Count::Counter(_), None) => {}
+        }
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = write!(stderr, "\r\x1b[2K{} {}", line, SPINNER[frame % SPINNER.len()]);
+        let _ = stderr.flush();
+    }
+}
+
+/// A spinner with a count, drawn on stderr until dropped.
+///
+/// This is no-op if stderr is not a terminal, running on CI, or `--verbose`
+/// or `--log-format json` is passed.
+pub(crate) struct Progress {
+    inner: Option<(Arc<State>, thread::JoinHandle<()>)>,
+}
+
+impl Progress {
+    /// Starts a progress indicator that counts up to `total` by `inc`.
+    pub(crate) fn new(status: &'static str, message: String, total: Option<usize>) -> Self {
+        Self::start(status, message, Count::Counter(AtomicUsize::new(0)), total)
+    }
+
+    /// Starts a progress indicator that counts the files written to `dir`.
+    pub(crate) fn files(status: &'static str, message: String, dir: Utf8PathBuf) -> Self {
+        Self::start(status, message, Count::Files(dir), None)
+    }
+
+    fn start(status: &'static str, message: String, count: Count, total: Option<usize>) -> Self {
+        if term::verbose()
+            || term::log_json()
+            || term::ci()
+            || !atty::is(atty::Stream::Stderr)
+            || ACTIVE.swap(true, Ordering::Relaxed)
+        {
+            return Self { inner: None };
+        }
+        let state = Arc::new(State { status, message, count, total, done: AtomicBool::new(false) });
+        let handle = thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                let mut frame = 0;
+                while !state.done.load(Ordering::Relaxed) {
+                    state.draw(frame);
+                    frame += 1;
+                    thread::park_timeout(TICK);
+                }
+            }
+        });
+        Self { inner: Some((state, handle)) }
+    }
+
+    pub(crate) fn inc(&self, n: usize) {
+        if let Some((state, _)) = &self.inner {
+            if let Count::Counter(count) = &state.count {
+                count.fetch_add(n, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some((state, handle)) = self.inner.take() {
+            state.done.store(true, Ordering::Relaxed);
+            handle.thread().unpark();
+            let _ = handle.join();
+            clear();
+            ACTIVE.store(false, Ordering::Relaxed);
+        }
+    }
+}
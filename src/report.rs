@@ -0,0 +1,1366 @@
+// Generation of the reports from the merged profile data.
+
+use std::{
+    ffi::{OsStr, OsString},
+    io::{self, Write as _},
+    thread,
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_llvm_cov_core::{
+    policy::{self, Policy},
+    thresholds::{self, Thresholds},
+};
+use termcolor::{Color, ColorSpec, WriteColor};
+
+use crate::{
+    annotations, artifacts, changed, ci, cobertura, codecov, comment,
+    config::StringOrArray,
+    context::Context,
+    csv, dry_run, env, fs, hook, html, ignore_filename_regex,
+    json::{ExcludedLines, LlvmCovJsonExport, UncoveredLines},
+    lcov, markers,
+    merge::{merge_profraw, profdata_merge},
+    notify, object_files, object_list, per_test,
+    process::ProcessBuilder,
+    progress::Progress,
+    summary, template, term,
+    term::Coloring,
+    treemap, uncovered, uncovered_spans, unsafe_coverage, vscoverage,
+};
+
+/// Merges the profile data of the run and generates the reports from it.
+pub(crate) fn generate(cx: &Context) -> Result<()> {
+    cx.check_llvm_version()?;
+    if cx.build.dry_run {
+        print_report_commands(cx);
+        return Ok(());
+    }
+
+    cx.timings
+        .time("merge profile data", || merge_profraw(cx))
+        .context("failed to merge profile data")?;
+
+    let object_files = cx
+        .timings
+        .time("collect object files", || object_files(cx))
+        .context("failed to collect object files")?;
+    let ignore_filename_regex = ignore_filename_regex(cx);
+    let filter_exprs = object_list::filter_exprs(cx);
+    if !filter_exprs.is_empty() {
+        info!("coverage is partial: only tests matching {} were run", filter_exprs.join(", "));
+    }
+
+    let needs_json = needs_json(cx)?;
+    let exclusions = markers::Exclusions::default();
+    let mut exports =
+        Exports::spawn(cx, needs_json, &object_files, ignore_filename_regex.as_ref())?;
+    let formats = Format::from_args(cx);
+    // Reports generated by `llvm-cov report` and `llvm-cov show` need to know
+    // whether lines are excluded from coverage, and the html report uses the
+    // coverage data to determine which pages need to be regenerated, so wait
+    // for the export before generating them.
+    let mut coverage = None;
+    if formats.iter().any(|f| matches!(f, Format::None | Format::Text | Format::Html)) {
+        coverage = Some(coverage_data(
+            cx,
+            &exclusions,
+            exports.json.take(),
+            needs_json,
+            &object_files,
+            ignore_filename_regex.as_ref(),
+        )?);
+    }
+
+    for format in formats {
+        cx.timings
+            .time(&format!("report ({})", format.name()), || {
+                format.generate_report(
+                    cx,
+                    &exclusions,
+                    &object_files,
+                    ignore_filename_regex.as_ref(),
+                    coverage.as_ref().map(|(json, excluded)| (json, excluded)),
+                )
+            })
+            .context("failed to generate report")?;
+    }
+
+    write_lcov_outputs(
+        cx,
+        &exclusions,
+        exports.lcov,
+        &object_files,
+        ignore_filename_regex.as_ref(),
+    )?;
+    if let Some(path) = &cx.cov.per_test {
+        per_test::write(cx, path, &object_files, ignore_filename_regex.as_ref())
+            .context("failed to generate per-test coverage")?;
+        saved("per_test", path, false);
+    }
+
+    let (json, excluded) = match coverage {
+        Some(coverage) => coverage,
+        None => coverage_data(
+            cx,
+            &exclusions,
+            exports.json,
+            needs_json,
+            &object_files,
+            ignore_filename_regex.as_ref(),
+        )?,
+    };
+
+    handle_coverage_data(cx, &json, &excluded, ignore_filename_regex.as_ref())?;
+
+    if let Some(file) = &cx.cov.open {
+        let html_dir = cx.cov.output_dir.as_ref().unwrap().join("html");
+        let path = match file {
+            Some(file) => html::page(&html_dir, html::find_file(json.filenames(), file)?),
+            None => html_dir.join("index.html"),
+        };
+        status!("Opening", "{}", path);
+        open_report(cx, &path)?;
+    }
+    Ok(())
+}
+
+/// Handles the outputs and checks based on the coverage data.
+fn handle_coverage_data(
+    cx: &Context,
+    json: &LlvmCovJsonExport,
+    excluded: &ExcludedLines,
+    ignore_filename_regex: Option<&String>,
+) -> Result<()> {
+    write_json_reports(cx, json)?;
+    if term::log_json() {
+        print_summary_event(json)?;
+    }
+    if term::message_json() {
+        print_summary_message(json)?;
+    }
+    if cx.cov.summary_bars {
+        summary::print(cx, json);
+    }
+    if cx.cov.show_missing_lines {
+        show_missing_lines(cx, json.get_uncovered_lines(&ignore_filename_regex.cloned()), excluded);
+    }
+    if let Some(n) = cx.cov.show_functions {
+        show_functions(cx, json, n.unwrap_or(10));
+    }
+    if cx.cov.show_excluded_functions || cx.cov.exclude_unreachable {
+        show_excluded(cx, json);
+    }
+    summary::print_delta(cx, json)?;
+    if cx.cov.github_output {
+        ci::write_github_output(json)?;
+    }
+    if cx.cov.gitlab {
+        ci::print_gitlab_coverage(json)?;
+    }
+    write_review_outputs(cx, json)?;
+    check_thresholds(cx, json)?;
+    if let Some(dir) = &cx.cov.artifact_dir {
+        artifacts::write(cx, dir, &saved_reports(cx), json)
+            .context("failed to collect artifacts")?;
+        saved("artifacts", dir, false);
+    }
+    if let Some(command) = &cx.cov.post_process {
+        hook::run(cx, command, &saved_reports(cx), json)?;
+    }
+    if let Some(url) = &cx.cov.notify_url {
+        notify::send(cx, url, json)?;
+    }
+    Ok(())
+}
+
+/// The exports of the coverage data that run concurrently with generating reports.
+struct Exports {
+    json: Option<thread::JoinHandle<Result<LlvmCovJsonExport>>>,
+    lcov: Option<thread::JoinHandle<Result<String>>>,
+}
+
+impl Exports {
+    /// Starts the exports needed after generating reports, as long as the
+    /// number of processes running at the same time, including the one
+    /// generating reports, does not exceed `--jobs`.
+    fn spawn(
+        cx: &Context,
+        needs_json: bool,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<Self> {
+        let mut jobs = cx.build.jobs.map_or(usize::MAX, |jobs| jobs as usize).saturating_sub(1);
+        let mut take_job = |needed: bool| {
+            let available = needed && jobs > 0;
+            if available {
+                jobs -= 1;
+            }
+            available
+        };
+        let mut exports = Self { json: None, lcov: None };
+        if take_job(needs_json) {
+            exports.json = Some(
+                Format::Json
+                    .spawn_json(cx, object_files, ignore_filename_regex)
+                    .context("failed to get json")?,
+            );
+        }
+        if take_job(needs_lcov(cx)) {
+            exports.lcov = Some(
+                Format::LCov
+                    .spawn_export(cx, object_files, ignore_filename_regex)
+                    .context("failed to export lcov")?,
+            );
+        }
+        Ok(exports)
+    }
+}
+
+/// Returns the coverage data used after generating reports, with lines excluded
+/// by comment markers removed, and the excluded lines.
+fn coverage_data(
+    cx: &Context,
+    exclusions: &markers::Exclusions,
+    json_export: Option<thread::JoinHandle<Result<LlvmCovJsonExport>>>,
+    needs_json: bool,
+    object_files: &[OsString],
+    ignore_filename_regex: Option<&String>,
+) -> Result<(LlvmCovJsonExport, ExcludedLines)> {
+    // With concurrent export, this only measures the time waiting for the export.
+    let mut json = cx
+        .timings
+        .time("export coverage data", || match json_export {
+            Some(handle) => handle.join().unwrap(),
+            None if needs_json => Format::Json.get_json(cx, object_files, ignore_filename_regex),
+            // Only the totals are needed for the comparison with the previous run.
+            None => Format::Json.get_summary_json(cx, object_files, ignore_filename_regex),
+        })
+        .context("failed to get json")?;
+    let excluded = exclusions.get(cx, json.filenames());
+    if !needs_json && !excluded.is_empty() {
+        // The summary-only data doesn't have line information.
+        json = Format::Json
+            .get_json(cx, object_files, ignore_filename_regex)
+            .context("failed to get json")?;
+    }
+    json.exclude_lines(&excluded);
+    Ok((json, excluded))
+}
+
+/// Returns `true` if the full coverage data is used after generating reports.
+/// Otherwise, only the totals are needed for the comparison with the previous run.
+fn needs_json(cx: &Context) -> Result<bool> {
+    Ok(cx.cov.fail_under_lines.is_some()
+        || cx.cov.fail_uncovered_functions.is_some()
+        || cx.cov.fail_uncovered_lines.is_some()
+        || cx.cov.fail_uncovered_regions.is_some()
+        || cx.cov.policy.is_some()
+        || env::var("CARGO_LLVM_COV_POLICY")?.is_some()
+        || cx.cov.show_missing_lines
+        || cx.cov.show_functions.is_some()
+        || cx.cov.show_excluded_functions
+        || cx.cov.exclude_unreachable
+        || cx.cov.summary_bars
+        || cx.cov.template.is_some()
+        || cx.cov.uncovered_only
+        || cx.cov.html && cx.cov.output_dir.is_some()
+        || cx.cov.unsafe_coverage
+        || cx.cov.csv
+        || cx.cov.uncovered_spans.is_some()
+        || cx.cov.github_output
+        || cx.cov.gitlab
+        || cx.cov.pr_comment.is_some()
+        || cx.cov.buildkite_annotation.is_some()
+        || cx.cov.annotations.is_some()
+        || cx.cov.artifact_dir.is_some()
+        || cx.cov.post_process.is_some()
+        || cx.cov.notify_url.is_some()
+        || matches!(cx.cov.open, Some(Some(_)))
+        || term::log_json()
+        || term::message_json())
+}
+
+/// Handles --pr-comment, --buildkite-annotation, and --annotations.
+fn write_review_outputs(cx: &Context, json: &LlvmCovJsonExport) -> Result<()> {
+    if let Some(path) = &cx.cov.pr_comment {
+        let out = comment::render(cx, json).context("failed to generate pull request comment")?;
+        fs::write(path, out)?;
+        saved("pr_comment", path, false);
+    }
+    if let Some(path) = &cx.cov.buildkite_annotation {
+        let out = comment::render_buildkite_annotation(cx, json)
+            .context("failed to generate Buildkite annotation")?;
+        fs::write(path, out)?;
+        saved("buildkite_annotation", path, false);
+    }
+    if let Some(path) = &cx.cov.annotations {
+        let annotations =
+            annotations::generate(cx, json).context("failed to generate annotations")?;
+        fs::write(path, serde_json::to_string_pretty(&annotations)?)?;
+        saved("annotations", path, false);
+    }
+    Ok(())
+}
+
+/// Handles the reports rendered from the exported coverage data (--template,
+/// --text --uncovered-only, --unsafe-coverage, --csv, --uncovered-spans, and
+/// the treemap page of --html).
+fn write_json_reports(cx: &Context, json: &LlvmCovJsonExport) -> Result<()> {
+    if let Some(template) = &cx.cov.template {
+        write_template_report(cx, template, json)?;
+    }
+    if cx.cov.uncovered_only {
+        write_uncovered_report(cx, json)?;
+    }
+    if let (true, Some(output_dir)) = (cx.cov.html, &cx.cov.output_dir) {
+        treemap::write(cx, &output_dir.join("html"), json)
+            .context("failed to generate treemap page")?;
+    }
+    if cx.cov.unsafe_coverage {
+        show_unsafe_coverage(cx, json);
+    }
+    if cx.cov.csv {
+        let out = csv::regions(json);
+        if let Some(output_path) = &cx.cov.output_path {
+            fs::write(output_path, out)?;
+            saved("csv", output_path, false);
+        } else {
+            print!("{}", out);
+        }
+    }
+    if let Some(path) = &cx.cov.uncovered_spans {
+        fs::write(path, serde_json::to_string_pretty(&uncovered_spans::generate(cx, json))?)?;
+        saved("uncovered_spans", path, false);
+    }
+    Ok(())
+}
+
+fn write_template_report(
+    cx: &Context,
+    template: &Utf8Path,
+    json: &LlvmCovJsonExport,
+) -> Result<()> {
+    let out = template::render_report(cx, &fs::read_to_string(template)?, json)
+        .with_context(|| format!("failed to render template {}", template))?;
+    if let Some(output_path) = &cx.cov.output_path {
+        fs::write(output_path, out)?;
+        saved("template", output_path, false);
+    } else {
+        print!("{}", out);
+    }
+    Ok(())
+}
+
+/// Handles --json --functions.
+fn function_records(json: &LlvmCovJsonExport) -> Result<String> {
+    let mut functions = json.get_functions();
+    functions.sort_by(|a, b| (&a.filename, a.line).cmp(&(&b.filename, b.line)));
+    let functions: Vec<_> = functions
+        .iter()
+        .map(|f| {
+            serde_json::json!({
+                "name": f.name,
+                "filename": f.filename,
+                "start_line": f.line,
+                "end_line": f.end_line,
+                "count": f.execution_count,
+                "regions": {
+                    "count": f.count,
+                    "covered": f.covered,
+                    "percent": summary::percent(f.count, f.covered),
+                },
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string(&serde_json::json!({ "functions": functions }))?)
+}
+
+/// Handles --text --uncovered-only.
+fn write_uncovered_report(cx: &Context, json: &LlvmCovJsonExport) -> Result<()> {
+    let out = uncovered::render(cx, json);
+    if let Some(output_path) = &cx.cov.output_path {
+        fs::write(output_path, out)?;
+        saved("text", output_path, false);
+    } else {
+        print!("{}", out);
+    }
+    Ok(())
+}
+
+/// Handles the outputs based on the lcov report (--gutters, --gitlab, --azure,
+/// and --codecov), which is exported only once.
+fn write_lcov_outputs(
+    cx: &Context,
+    exclusions: &markers::Exclusions,
+    lcov_export: Option<thread::JoinHandle<Result<String>>>,
+    object_files: &[OsString],
+    ignore_filename_regex: Option<&String>,
+) -> Result<()> {
+    if !needs_lcov(cx) {
+        return Ok(());
+    }
+    // With concurrent export, this only measures the time waiting for the export.
+    let lcov = cx
+        .timings
+        .time("export lcov", || match lcov_export {
+            Some(handle) => handle.join().unwrap(),
+            // Export without -summary-only, because editors need the line information.
+            None => Format::LCov.export(cx, object_files, ignore_filename_regex),
+        })
+        .context("failed to export lcov")?;
+    let lcov = post_process_lcov(cx, exclusions, &lcov);
+    if cx.cov.gutters {
+        cx.timings
+            .time("report (gutters)", || write_gutters(cx, &lcov))
+            .context("failed to write lcov.info")?;
+    }
+    if cx.cov.gitlab {
+        let path = cx.ws.metadata.workspace_root.join("cobertura.xml");
+        cx.timings
+            .time("report (cobertura)", || write_cobertura(cx, &lcov, &path))
+            .with_context(|| format!("failed to write {}", path))?;
+    }
+    if cx.cov.azure {
+        let output_dir = cx.cov.output_dir.as_ref().unwrap();
+        let path = output_dir.join("cobertura.xml");
+        cx.timings
+            .time("report (cobertura)", || write_cobertura(cx, &lcov, &path))
+            .with_context(|| format!("failed to write {}", path))?;
+        let path = output_dir.join("summary.coverage.xml");
+        cx.timings
+            .time("report (vscoverage)", || write_vscoverage(cx, &lcov, &path))
+            .with_context(|| format!("failed to write {}", path))?;
+    }
+    if cx.cov.codecov {
+        cx.timings.time("upload (codecov)", || codecov::upload(cx, &lcov))?;
+    }
+    Ok(())
+}
+
+/// Handles --gutters.
+fn write_gutters(cx: &Context, lcov: &str) -> Result<()> {
+    let path = cx.ws.metadata.workspace_root.join("lcov.info");
+    fs::write_atomic(&path, lcov)?;
+    saved(Format::LCov.name(), &path, false);
+    Ok(())
+}
+
+/// Handles --gitlab and --azure.
+fn write_cobertura(cx: &Context, lcov: &str, path: &Utf8Path) -> Result<()> {
+    let out = cobertura::from_lcov(lcov, &cx.ws.metadata.workspace_root, &package_dirs(cx));
+    fs::write(path, out)?;
+    saved("cobertura", path, false);
+    Ok(())
+}
+
+/// Handles --azure.
+fn write_vscoverage(cx: &Context, lcov: &str, path: &Utf8Path) -> Result<()> {
+    let out = vscoverage::from_lcov(lcov, &cx.ws.metadata.workspace_root, &package_dirs(cx));
+    fs::write(path, out)?;
+    saved("vscoverage", path, false);
+    Ok(())
+}
+
+/// Returns the directories and names of the included workspace members.
+fn package_dirs(cx: &Context) -> Vec<(Utf8PathBuf, String)> {
+    cx.workspace_members
+        .included
+        .iter()
+        .map(|id| {
+            let pkg = &cx.ws.metadata[id];
+            (pkg.manifest_path.parent().unwrap().to_owned(), pkg.name.clone())
+        })
+        .collect()
+}
+
+/// Removes lines excluded by comment markers from the lcov report, and sorts it.
+fn post_process_lcov(cx: &Context, exclusions: &markers::Exclusions, out: &str) -> String {
+    let excluded = exclusions.get(cx, markers::lcov_filenames(out));
+    let out = markers::apply_to_lcov(out, &excluded, false);
+    let out = if cfg!(windows) { lcov::normalize_windows_paths(&out) } else { out };
+    lcov::sort(&out).unwrap_or(out)
+}
+
+/// Returns `true` if the outputs based on the lcov report are requested.
+fn needs_lcov(cx: &Context) -> bool {
+    cx.cov.gutters || cx.cov.gitlab || cx.cov.azure || cx.cov.codecov
+}
+
+/// Handles --show-missing-lines.
+fn show_missing_lines(cx: &Context, mut uncovered_files: UncoveredLines, excluded: &ExcludedLines) {
+    for (file, lines) in &mut uncovered_files {
+        if let Some(excluded) = excluded.get(file) {
+            lines.retain(|line| !excluded.contains(line));
+        }
+    }
+    uncovered_files.retain(|_, lines| !lines.is_empty());
+    if !uncovered_files.is_empty() {
+        println!("Uncovered Lines:");
+    }
+    for (file, lines) in &uncovered_files {
+        let link = hyperlink(cx, file, file, lines.first().copied());
+        let lines: Vec<_> = lines.iter().map(ToString::to_string).collect();
+        println!("{}: {}", link, lines.join(", "));
+    }
+}
+
+/// Handles --unsafe-coverage.
+fn show_unsafe_coverage(cx: &Context, json: &LlvmCovJsonExport) {
+    let files = unsafe_coverage::collect(cx, json);
+    println!("Unsafe Code Coverage:");
+    if files.is_empty() {
+        println!("no unsafe code with coverage data");
+        return;
+    }
+    let (mut count, mut covered) = (0, 0);
+    for (file, coverage) in &files {
+        count += coverage.count;
+        covered += coverage.covered;
+        let link = hyperlink(cx, file, file, coverage.uncovered_lines.first().copied());
+        print!(
+            "{}: {:.2}% ({}/{} lines)",
+            link,
+            summary::percent(coverage.count, coverage.covered),
+            coverage.covered,
+            coverage.count
+        );
+        if coverage.uncovered_lines.is_empty() {
+            println!();
+        } else {
+            let lines: Vec<_> = coverage.uncovered_lines.iter().map(ToString::to_string).collect();
+            println!(", uncovered: {}", lines.join(", "));
+        }
+    }
+    println!("TOTAL: {:.2}% ({}/{} lines)", summary::percent(count, covered), covered, count);
+}
+
+/// Handles --show-functions.
+fn show_functions(cx: &Context, json: &LlvmCovJsonExport, n: usize) {
+    let functions: Vec<_> =
+        json.get_functions().into_iter().filter(|f| f.covered < f.count).take(n).collect();
+    if functions.is_empty() {
+        return;
+    }
+    let locations: Vec<_> =
+        functions.iter().map(|f| format!("{}:{}", f.filename, f.line)).collect();
+    let width = locations.iter().map(String::len).max().unwrap_or(0);
+    println!("Least Covered Functions:");
+    println!("{:>9}  {:>9}  {:3$}  Function", "Uncovered", "Regions", "Location", width);
+    for (f, location) in functions.iter().zip(&locations) {
+        let link = hyperlink(cx, location, &f.filename, Some(f.line));
+        // Pad based on the visible width of the location.
+        let pad = " ".repeat(width - location.len());
+        let regions = format!("{}/{}", f.covered, f.count);
+        println!("{:>9}  {:>9}  {}{}  {}", f.count - f.covered, regions, link, pad, f.name);
+    }
+}
+
+/// Handles --show-excluded-functions and --exclude-unreachable.
+fn show_excluded(cx: &Context, json: &LlvmCovJsonExport) {
+    if cx.cov.show_excluded_functions {
+        show_excluded_functions(cx, json);
+    }
+    if cx.cov.exclude_unreachable {
+        show_excluded_unreachable_lines(cx, json);
+    }
+}
+
+/// Handles --show-excluded-functions.
+fn show_excluded_functions(cx: &Context, json: &LlvmCovJsonExport) {
+    let excluded_items = markers::excluded_items(cx, json.filenames());
+    if !excluded_items.is_empty() {
+        println!("Excluded Functions:");
+    }
+    for (file, items) in &excluded_items {
+        for (line, item) in items {
+            let link = hyperlink(cx, &format!("{}:{}", file, line), file, Some(*line));
+            println!("{}: {}", link, item);
+        }
+    }
+}
+
+/// Lists the lines excluded by --exclude-unreachable for audit.
+fn show_excluded_unreachable_lines(cx: &Context, json: &LlvmCovJsonExport) {
+    let excluded = markers::excluded_unreachable_lines(cx, json.filenames());
+    if !excluded.is_empty() {
+        println!("Excluded Unreachable Lines:");
+    }
+    for (file, lines) in &excluded {
+        let link = hyperlink(cx, file, file, lines.iter().next().copied());
+        let lines: Vec<_> = lines.iter().map(ToString::to_string).collect();
+        println!("{}: {}", link, lines.join(", "));
+    }
+}
+
+/// Prints the commands that generate the report for `--dry-run`.
+fn print_report_commands(cx: &Context) {
+    // Profile data and test binaries do not exist yet.
+    let profraw = cx.ws.target_dir.join(format!("{}-*.profraw", cx.ws.name));
+    let mut cmd =
+        profdata_merge(cx, &[profraw.into_std_path_buf()], &cx.ws.profdata_file, cx.build.jobs);
+    dry_run(cx, &mut cmd);
+    let object_files = [OsString::from("<OBJECT-FILES>")];
+    let ignore_filename_regex = ignore_filename_regex(cx);
+    for format in Format::from_args(cx) {
+        let mut cmd = format.llvm_cov_cmd(cx, &object_files, ignore_filename_regex.as_ref());
+        if format == Format::Html {
+            if let Some(output_dir) = &cx.cov.output_dir {
+                cmd.arg(format!("-output-dir={}", output_dir.join("html")));
+            }
+        }
+        dry_run(cx, &mut cmd);
+    }
+}
+
+/// Makes `text` a hyperlink to the file in the coverage data, if enabled.
+fn hyperlink(cx: &Context, text: &str, file: &str, line: Option<u64>) -> String {
+    let format = match &cx.hyperlink_format {
+        Some(format) => format,
+        None => return text.to_owned(),
+    };
+    // --remap-path-prefix strips the workspace root.
+    let path = cx.ws.metadata.workspace_root.join(file);
+    term::hyperlink(Some(format), text, path.as_str(), line)
+}
+
+/// Handles --fail-under-lines, --fail-uncovered-*, and --policy.
+fn check_thresholds(cx: &Context, json: &LlvmCovJsonExport) -> Result<()> {
+    let thresholds = Thresholds {
+        fail_under_lines: cx.cov.fail_under_lines,
+        fail_uncovered_functions: cx.cov.fail_uncovered_functions,
+        fail_uncovered_lines: cx.cov.fail_uncovered_lines,
+        fail_uncovered_regions: cx.cov.fail_uncovered_regions,
+    };
+    let mut rows: Vec<_> = thresholds
+        .evaluate(json)?
+        .iter()
+        .map(|gate| {
+            let percent = gate.kind == thresholds::Kind::LinesPercent;
+            threshold_row(gate.kind.flag().to_owned(), percent, gate.measured, gate.threshold)
+        })
+        .collect();
+    let policy = match &cx.cov.policy {
+        Some(path) => Some(path.clone()),
+        None => env::var("CARGO_LLVM_COV_POLICY")?.map(Utf8PathBuf::from),
+    };
+    if let Some(path) = &policy {
+        let s = fs::read_to_string(path)?;
+        let policy = if path.extension() == Some("toml") {
+            Policy::from_toml(&s)
+        } else {
+            Policy::from_json(&s)
+        }
+        .with_context(|| format!("failed to parse policy {}", path))?;
+        for outcome in evaluate_policy(cx, &policy, json)? {
+            let label = format!(
+                "policy #{} {}{} of {}",
+                outcome.rule + 1,
+                if outcome.percent { "" } else { "uncovered " },
+                outcome.kind.as_str(),
+                outcome.subject
+            );
+            rows.push(threshold_row(label, outcome.percent, outcome.measured, outcome.threshold));
+        }
+    }
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    print_thresholds(&rows);
+    let failed: Vec<_> = rows.iter().filter(|row| !row.4).map(|row| row.0.as_str()).collect();
+    if !failed.is_empty() {
+        term::set_failure(term::Failure::Threshold);
+        error!("coverage did not meet the threshold of {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Evaluates the rules of --policy, with the workspace members as packages and
+/// the lines changed since --policy-base as the diff.
+fn evaluate_policy(
+    cx: &Context,
+    policy: &Policy,
+    json: &LlvmCovJsonExport,
+) -> Result<Vec<policy::Outcome>> {
+    let mut input = policy::Input::new(json);
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    for id in &cx.ws.metadata.workspace_members {
+        let pkg = &cx.ws.metadata[id];
+        let mut dir = pkg.manifest_path.parent().unwrap();
+        if cx.build.remap_path_prefix {
+            dir = dir.strip_prefix(workspace_root).unwrap_or(dir);
+        }
+        input.packages.push((pkg.name.clone(), dir.into()));
+    }
+    if policy.rules.iter().any(|rule| rule.scope == policy::Scope::Diff) {
+        let rev = cx.cov.policy_base.as_deref().unwrap_or("HEAD");
+        input.changed_lines = Some(changed::changed_lines(cx, rev)?);
+    }
+    policy.evaluate(&input)
+}
+
+/// Label, measured, threshold, margin, and whether the threshold is met.
+type ThresholdRow = (String, String, String, String, bool);
+
+fn threshold_row(label: String, percent: bool, measured: f64, threshold: f64) -> ThresholdRow {
+    let margin = if percent { measured - threshold } else { threshold - measured };
+    if percent {
+        (
+            label,
+            format!("{:.2}%", measured),
+            format!("{:.2}%", threshold),
+            format!("{:+.2}%", margin),
+            margin >= 0.0,
+        )
+    } else {
+        (
+            label,
+            format!("{}", measured),
+            format!("{}", threshold),
+            format!("{:+}", margin),
+            margin >= 0.0,
+        )
+    }
+}
+
+/// Prints a table of the results of `--fail-*` flags and `--policy` to stderr.
+fn print_thresholds(rows: &[ThresholdRow]) {
+    if term::log_json() {
+        for (flag, measured, threshold, margin, passed) in rows {
+            term::print_event(&serde_json::json!({
+                "reason": "threshold",
+                "flag": flag,
+                "measured": measured,
+                "threshold": threshold,
+                "margin": margin,
+                "passed": passed,
+            }));
+        }
+        return;
+    }
+    let width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let mut stderr = term::stderr();
+    let _ = writeln!(stderr, "\nCoverage Thresholds:");
+    let _ = writeln!(
+        stderr,
+        "  {:4$}  {:>9}  {:>9}  {:>9}  Result",
+        "Check", "Measured", "Threshold", "Margin", width
+    );
+    for (flag, measured, threshold, margin, passed) in rows {
+        let _ = write!(
+            stderr,
+            "  {:4$}  {:>9}  {:>9}  {:>9}  ",
+            flag, measured, threshold, margin, width
+        );
+        let (result, color) = if *passed { ("pass", Color::Green) } else { ("FAIL", Color::Red) };
+        let _ = stderr.set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)));
+        let _ = write!(stderr, "{}", result);
+        let _ = stderr.reset();
+        let _ = writeln!(stderr);
+    }
+    let passed = rows.iter().filter(|row| row.4).count();
+    let _ = writeln!(stderr, "  {} of {} checks passed", passed, rows.len());
+}
+
+/// Prints the coverage summary for `--log-format json`.
+fn print_summary_event(json: &LlvmCovJsonExport) -> Result<()> {
+    term::print_event(&serde_json::json!({
+        "reason": "summary",
+        "lines_percent": json.get_lines_percent()?,
+        "uncovered_functions": json.count_uncovered_functions()?,
+        "uncovered_lines": json.count_uncovered_lines()?,
+        "uncovered_regions": json.count_uncovered_regions()?,
+    }));
+    Ok(())
+}
+
+fn print_summary_message(json: &LlvmCovJsonExport) -> Result<()> {
+    term::print_message(&serde_json::json!({
+        "reason": "llvm-cov-summary",
+        "totals": summary::totals(json)?,
+    }));
+    Ok(())
+}
+
+fn open_report(cx: &Context, path: &Utf8Path) -> Result<()> {
+    let browser = cx.ws.config.doc.browser.as_ref().and_then(StringOrArray::path_and_args);
+
+    match browser {
+        Some((browser, initial_args)) => {
+            cmd!(browser).args(initial_args).arg(path).run().with_context(|| {
+                format!("couldn't open report with {}", browser.to_string_lossy())
+            })?;
+        }
+        None => opener::open(path).context("couldn't open report")?,
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// `llvm-cov report`
+    None,
+    /// `llvm-cov export -format=text`
+    Json,
+    /// `llvm-cov export -format=lcov`
+    LCov,
+    /// `llvm-cov show -format=text`
+    Text,
+    /// `llvm-cov show -format=html`
+    Html,
+}
+
+impl Format {
+    fn from_args(cx: &Context) -> Vec<Self> {
+        if cx.cov.json {
+            vec![Self::Json]
+        } else if cx.cov.lcov {
+            vec![Self::LCov]
+        } else if cx.cov.text && !cx.cov.uncovered_only {
+            vec![Self::Text]
+        } else if cx.cov.html {
+            vec![Self::Html]
+        } else if cx.cov.csv || cx.cov.text || cx.cov.summary_bars || cx.cov.template.is_some() {
+            // --csv, --text --uncovered-only, --summary-bars, and --template replace the table of llvm-cov.
+            vec![]
+        } else {
+            vec![Self::None]
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::None => "summary",
+            Self::Json => "json",
+            Self::LCov => "lcov",
+            Self::Text => "text",
+            Self::Html => "html",
+        }
+    }
+
+    const fn llvm_cov_args(self) -> &'static [&'static str] {
+        match self {
+            Self::None => &["report"],
+            Self::Json => &["export", "-format=text"],
+            Self::LCov => &["export", "-format=lcov"],
+            Self::Text => &["show", "-format=text"],
+            Self::Html => &["show", "-format=html"],
+        }
+    }
+
+    fn use_color(self, cx: &Context) -> Option<&'static str> {
+        if matches!(self, Self::Json | Self::LCov) {
+            // `llvm-cov export` doesn't have `-use-color` flag.
+            // https://llvm.org/docs/CommandGuide/llvm-cov.html#llvm-cov-export
+            return None;
+        }
+        if self == Self::Text && cx.cov.output_dir.is_some() {
+            return Some("-use-color=0");
+        }
+        match cx.build.color {
+            Some(Coloring::Auto) | None => None,
+            Some(Coloring::Always) => Some("-use-color=1"),
+            Some(Coloring::Never) => Some("-use-color=0"),
+        }
+    }
+
+    fn llvm_cov_cmd(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> ProcessBuilder {
+        let mut cmd = cx.process(&cx.llvm_cov);
+
+        cmd.args(self.llvm_cov_args());
+        cmd.args(self.use_color(cx));
+        cmd.arg(format!("-instr-profile={}", cx.ws.profdata_file));
+        cmd.args(object_files.iter().flat_map(|f| [OsStr::new("-object"), f]));
+        if let Some(jobs) = cx.build.jobs {
+            cmd.arg(format!("-num-threads={}", jobs));
+        }
+        if let Some(ignore_filename_regex) = ignore_filename_regex {
+            cmd.arg("-ignore-filename-regex");
+            cmd.arg(ignore_filename_regex);
+        }
+
+        match self {
+            Self::Text | Self::Html => {
+                cmd.args([
+                    &format!("-show-instantiations={}", !cx.cov.hide_instantiations),
+                    "-show-line-counts-or-regions",
+                    "-show-expansions",
+                    &format!("-Xdemangler={}", cx.current_exe.display()),
+                    "-Xdemangler=llvm-cov",
+                    "-Xdemangler=demangle",
+                ]);
+                if let Some(function_regex) = &cx.cov.function_regex {
+                    cmd.arg(format!("-name-regex={}", function_regex));
+                }
+                if let Some(output_dir) = &cx.cov.output_dir {
+                    // The output directory of html report is set in generate_html_report.
+                    if self == Self::Text {
+                        cmd.arg(&format!("-output-dir={}", output_dir.join("text")));
+                    }
+                }
+            }
+            Self::Json | Self::LCov => {
+                if cx.cov.summary_only {
+                    cmd.arg("-summary-only");
+                }
+            }
+            Self::None => {}
+        }
+
+        if let Some(flags) = &cx.cargo_llvm_cov_flags {
+            cmd.args(flags.split(' ').filter(|s| !s.trim().is_empty()));
+        }
+        cmd
+    }
+
+    /// `coverage` is the coverage data and the lines excluded from it, which
+    /// are required for the reports generated by `llvm-cov report` and
+    /// `llvm-cov show`.
+    fn generate_report(
+        self,
+        cx: &Context,
+        exclusions: &markers::Exclusions,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+        coverage: Option<(&LlvmCovJsonExport, &ExcludedLines)>,
+    ) -> Result<()> {
+        let mut cmd = self.llvm_cov_cmd(cx, object_files, ignore_filename_regex);
+        if let Some((json, excluded)) = coverage {
+            if let Some((file, lines)) = excluded.iter().next() {
+                match self {
+                    // llvm-cov doesn't know about the excluded lines, so render
+                    // the table from the coverage data instead.
+                    Self::None => {
+                        let out = summary::table(json)?;
+                        match &cx.cov.output_path {
+                            Some(output_path) => {
+                                fs::write(output_path, out)?;
+                                saved(self.name(), output_path, false);
+                            }
+                            None => print!("{}", out),
+                        }
+                        return Ok(());
+                    }
+                    // llvm-cov show has no way to exclude lines.
+                    Self::Text | Self::Html => warn!(
+                        "the {} report does not reflect the lines excluded by comment markers, \
+                         --exclude-derives, or --exclude-unreachable (e.g., {}:{}); the summary, \
+                         --json, --lcov, and threshold checks do",
+                        self.name(),
+                        file,
+                        lines.iter().next().unwrap()
+                    ),
+                    Self::Json | Self::LCov => {}
+                }
+            }
+        }
+
+        if matches!(self, Self::Json | Self::LCov) {
+            if term::verbose() {
+                status!("Running", "{}", cmd);
+            }
+            let progress = Progress::new("Generating", format!("{} report", self.name()), None);
+            if let Some(output_path) = &cx.cov.output_path {
+                let mut out = io::BufWriter::new(fs::File::create(output_path)?);
+                self.write_export(
+                    cx,
+                    exclusions,
+                    &cmd,
+                    &mut out,
+                    object_files,
+                    ignore_filename_regex,
+                )?;
+                out.flush()?;
+                drop(progress);
+                saved(self.name(), output_path, false);
+            } else {
+                let stdout = io::stdout();
+                let mut out = io::BufWriter::new(stdout.lock());
+                self.write_export(
+                    cx,
+                    exclusions,
+                    &cmd,
+                    &mut out,
+                    object_files,
+                    ignore_filename_regex,
+                )?;
+                out.flush()?;
+            }
+            return Ok(());
+        }
+
+        if self == Self::Html {
+            if let Some(output_dir) = &cx.cov.output_dir {
+                let (json, _) = coverage.unwrap();
+                return generate_html_report(cx, cmd, &output_dir.join("html"), json);
+            }
+        }
+
+        if let Some(output_path) = &cx.cov.output_path {
+            if term::verbose() {
+                status!("Running", "{}", cmd);
+            }
+            let progress = Progress::new("Generating", format!("{} report", self.name()), None);
+            let out = cmd.read()?;
+            drop(progress);
+            fs::write(output_path, out)?;
+            saved(self.name(), output_path, false);
+            return Ok(());
+        }
+
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        // The text report is written to stdout unless --output-dir is passed.
+        let progress =
+            cx.cov.output_dir.as_ref().filter(|_| self == Self::Text).map(|dir| {
+                Progress::files("Generating", "text report".to_owned(), dir.join("text"))
+            });
+        cmd.run()?;
+        drop(progress);
+        if self == Self::Text {
+            if let Some(output_dir) = &cx.cov.output_dir {
+                saved(self.name(), &output_dir.join("text"), false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the output of `llvm-cov export` while reading it, removing lines
+    /// excluded by comment markers, and sorting it so that it does not depend
+    /// on the order of the object files.
+    ///
+    /// The output ends with a newline only if it is written to stdout.
+    fn write_export(
+        self,
+        cx: &Context,
+        exclusions: &markers::Exclusions,
+        cmd: &ProcessBuilder,
+        out: &mut impl io::Write,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<()> {
+        let newline = cx.cov.output_path.is_none();
+        match self {
+            Self::Json => {
+                let mut json = read_json(cmd, None)?;
+                let excluded = exclusions.get(cx, json.filenames());
+                if cx.cov.functions {
+                    json.exclude_lines(&excluded);
+                    out.write_all(function_records(&json)?.as_bytes())?;
+                } else {
+                    if !excluded.is_empty() {
+                        if cx.cov.summary_only {
+                            // The summary-only data doesn't have line information.
+                            json = self.get_json(cx, object_files, ignore_filename_regex)?;
+                        }
+                        json.exclude_lines(&excluded);
+                        if cx.cov.summary_only {
+                            json.summary_only();
+                        }
+                    }
+                    json.sort();
+                    serde_json::to_writer(&mut *out, &json)?;
+                }
+                if newline {
+                    out.write_all(b"\n")?;
+                }
+            }
+            Self::LCov => {
+                let mut records = read_lcov(cmd)?;
+                let excluded = exclusions.get(cx, records.iter().filter_map(|r| lcov::filename(r)));
+                let summary_only = cx.cov.summary_only && !excluded.is_empty();
+                if summary_only {
+                    // The summary-only data doesn't have line information.
+                    let cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+                    if term::verbose() {
+                        status!("Running", "{}", cmd);
+                    }
+                    records = read_lcov(&cmd)?;
+                }
+                let len = records.len();
+                for (i, record) in records.into_iter().enumerate() {
+                    let record = if excluded.is_empty() {
+                        record
+                    } else {
+                        markers::apply_to_lcov(&record, &excluded, summary_only)
+                    };
+                    let record = match record.strip_suffix('\n') {
+                        Some(trimmed) if !newline && i + 1 == len => trimmed,
+                        _ => &record,
+                    };
+                    out.write_all(record.as_bytes())?;
+                }
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Exports the full coverage data (not summary-only) in JSON or lcov format.
+    fn export(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<String> {
+        let mut cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        cmd.read()
+    }
+
+    /// Starts exporting the full coverage data in JSON or lcov format in a
+    /// separate thread.
+    fn spawn_export(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<thread::JoinHandle<Result<String>>> {
+        let mut cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        Ok(thread::spawn(move || cmd.read()))
+    }
+
+    /// Starts exporting the full coverage data in JSON format in a separate thread.
+    fn spawn_json(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<thread::JoinHandle<Result<LlvmCovJsonExport>>> {
+        let cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        let ignore_filename_regex = ignore_filename_regex.cloned();
+        Ok(thread::spawn(move || read_json(&cmd, ignore_filename_regex.as_deref())))
+    }
+
+    fn export_cmd(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<ProcessBuilder> {
+        if !matches!(self, Self::Json | Self::LCov) {
+            return Err(anyhow::anyhow!("requested export for non-export type"));
+        }
+
+        let mut cmd = cx.process(&cx.llvm_cov);
+        cmd.args(self.llvm_cov_args());
+        cmd.arg(format!("-instr-profile={}", cx.ws.profdata_file));
+        cmd.args(object_files.iter().flat_map(|f| [OsStr::new("-object"), f]));
+        if let Some(jobs) = cx.build.jobs {
+            cmd.arg(format!("-num-threads={}", jobs));
+        }
+        if let Some(ignore_filename_regex) = ignore_filename_regex {
+            cmd.arg("-ignore-filename-regex");
+            cmd.arg(ignore_filename_regex);
+        }
+        Ok(cmd)
+    }
+
+    /// Generates JSON with only the summary of each file and the totals.
+    fn get_summary_json(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<LlvmCovJsonExport> {
+        let mut cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+        cmd.arg("-summary-only");
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        read_json(&cmd, ignore_filename_regex.map(String::as_str))
+    }
+
+    /// Generates JSON to perform further analysis on it.
+    fn get_json(
+        self,
+        cx: &Context,
+        object_files: &[OsString],
+        ignore_filename_regex: Option<&String>,
+    ) -> Result<LlvmCovJsonExport> {
+        if let Self::Json = self {
+        } else {
+            return Err(anyhow::anyhow!("requested JSON for non-JSON type"));
+        }
+
+        let cmd = self.export_cmd(cx, object_files, ignore_filename_regex)?;
+        if term::verbose() {
+            status!("Running", "{}", cmd);
+        }
+        read_json(&cmd, ignore_filename_regex.map(String::as_str))
+    }
+}
+
+/// Parses the output of `llvm-cov export -format=text` while reading it, to
+/// avoid buffering the whole output, which can be very large.
+fn read_json(
+    cmd: &ProcessBuilder,
+    ignore_filename_regex: Option<&str>,
+) -> Result<LlvmCovJsonExport> {
+    let mut reader = cmd.reader()?;
+    let json =
+        LlvmCovJsonExport::from_reader(io::BufReader::new(&mut reader), ignore_filename_regex);
+    // If the process failed, its error is more useful than the parse error.
+    reader.finish()?;
+    json.context("failed to parse json from llvm-cov")
+}
+
+/// Reads the records of files in the output of `llvm-cov export -format=lcov`,
+/// sorted by filename.
+fn read_lcov(cmd: &ProcessBuilder) -> Result<Vec<String>> {
+    let mut reader = cmd.reader()?;
+    let records = lcov::read_sorted(io::BufReader::new(&mut reader));
+    // If the process failed, its error is more useful than the read error.
+    reader.finish()?;
+    let mut records = records.context("failed to read lcov from llvm-cov")?;
+    // The same file can be spelled differently on Windows.
+    if cfg!(windows) {
+        for record in &mut records {
+            *record = lcov::normalize_windows_paths(record);
+        }
+        records.sort_by(|a, b| lcov::filename(a).cmp(&lcov::filename(b)));
+    }
+    Ok(records)
+}
+
+/// Generates html report, regenerating only the pages of changed files if possible.
+fn generate_html_report(
+    cx: &Context,
+    mut cmd: ProcessBuilder,
+    html_dir: &Utf8Path,
+    json: &LlvmCovJsonExport,
+) -> Result<()> {
+    cmd.args(html::detail_args(json));
+    let state = html::State::new(cx, json);
+    match state.plan(cx, html_dir) {
+        html::Plan::Fresh => {
+            html::enhance_index(html_dir)?;
+            saved(Format::Html.name(), html_dir, true);
+            return Ok(());
+        }
+        html::Plan::Partial(files) => {
+            let partial_dir = html_dir.with_file_name("html.partial");
+            fs::remove_dir_all(&partial_dir)?;
+            let mut partial_cmd = cmd.clone();
+            partial_cmd.arg(format!("-output-dir={}", partial_dir));
+            partial_cmd.args(files);
+            if term::verbose() {
+                status!("Running", "{}", partial_cmd);
+            }
+            let progress =
+                Progress::files("Generating", "html report".to_owned(), partial_dir.clone());
+            partial_cmd.run()?;
+            drop(progress);
+            if let Err(e) = html::merge(html_dir, &partial_dir) {
+                warn!("failed to update html report ({:#}); regenerating all pages", e);
+                fs::remove_dir_all(&partial_dir)?;
+                generate_full_html_report(cmd, html_dir)?;
+            }
+        }
+        html::Plan::Full => generate_full_html_report(cmd, html_dir)?,
+    }
+    html::enhance_index(html_dir)?;
+    state.save(html_dir)?;
+    saved(Format::Html.name(), html_dir, false);
+    Ok(())
+}
+
+fn generate_full_html_report(mut cmd: ProcessBuilder, html_dir: &Utf8Path) -> Result<()> {
+    // Remove pages of files that no longer exist in the report.
+    fs::remove_dir_all(html_dir)?;
+    cmd.arg(format!("-output-dir={}", html_dir));
+    if term::verbose() {
+        status!("Running", "{}", cmd);
+    }
+    let progress = Progress::files("Generating", "html report".to_owned(), html_dir.to_owned());
+    cmd.run()?;
+    drop(progress);
+    Ok(())
+}
+
+/// Prints where the report was saved. `fresh` means the existing report was
+/// up to date.
+pub(crate) fn saved(format: &str, path: &Utf8Path, fresh: bool) {
+    if term::message_json() {
+        term::print_message(&serde_json::json!({
+            "reason": "llvm-cov-artifact",
+            "format": format,
+            "path": path,
+            "fresh": fresh,
+        }));
+    }
+    if term::log_json() {
+        term::print_event(&serde_json::json!({
+            "reason": "artifact",
+            "kind": format,
+            "path": path,
+            "fresh": fresh,
+        }));
+        return;
+    }
+    eprintln!();
+    if fresh {
+        status!("Fresh", "report is up to date at {}", path);
+    } else {
+        status!("Finished", "report saved to {}", path);
+    }
+}
+
+/// Returns the formats and paths of the reports saved to files by this run.
+fn saved_reports(cx: &Context) -> Vec<(&'static str, Utf8PathBuf)> {
+    let mut reports = vec![];
+    for format in Format::from_args(cx) {
+        let path = match (&cx.cov.output_path, &cx.cov.output_dir) {
+            (Some(output_path), _) => output_path.clone(),
+            (None, Some(output_dir)) if format == Format::Html => output_dir.join("html"),
+            (None, Some(output_dir)) if format == Format::Text => output_dir.join("text"),
+            _ => continue,
+        };
+        reports.push((format.name(), path));
+    }
+    if let (Some(_), Some(output_path)) = (&cx.cov.template, &cx.cov.output_path) {
+        reports.push(("template", output_path.clone()));
+    }
+    if let Some(path) = &cx.cov.pr_comment {
+        reports.push(("pr_comment", path.clone()));
+    }
+    if let Some(path) = &cx.cov.buildkite_annotation {
+        reports.push(("buildkite_annotation", path.clone()));
+    }
+    if let Some(path) = &cx.cov.annotations {
+        reports.push(("annotations", path.clone()));
+    }
+    if let Some(path) = &cx.cov.per_test {
+        reports.push(("per_test", path.clone()));
+    }
+    if let Some(path) = &cx.cov.uncovered_spans {
+        reports.push(("uncovered_spans", path.clone()));
+    }
+    if cx.cov.gitlab {
+        reports.push(("cobertura", cx.ws.metadata.workspace_root.join("cobertura.xml")));
+    }
+    if let (true, Some(output_dir)) = (cx.cov.azure, &cx.cov.output_dir) {
+        reports.push(("cobertura", output_dir.join("cobertura.xml")));
+        reports.push(("vscoverage", output_dir.join("summary.coverage.xml")));
+    }
+    if cx.cov.gutters {
+        reports.push(("gutters", cx.ws.metadata.workspace_root.join("lcov.info")));
+    }
+    reports
+}
@@ -0,0 +1,100 @@
+// Syntaxes of the shells supported by `show-env --shell`.
+
+use std::fmt::Write as _;
+
+use crate::cli::ShowEnvShell;
+
+/// Returns the command to set the environment variable.
+pub(crate) fn set(shell: ShowEnvShell, key: &str, value: &str) -> String {
+    match shell {
+        ShowEnvShell::Bash => format!("export {}={}", key, single_quote(value, "'\\''")),
+        // https://fishshell.com/docs/current/language.html#quotes
+        ShowEnvShell::Fish => {
+            format!("set -gx {} {}", key, single_quote(&value.replace('\\', "\\\\"), "\\'"))
+        }
+        // https://learn.microsoft.com/en-us/powershell/module/microsoft.powershell.core/about/about_quoting_rules
+        ShowEnvShell::Pwsh => format!("$env:{} = {}", key, single_quote(value, "''")),
+        // Single-quoted strings of nushell cannot contain single quotes.
+        // https://www.nushell.sh/book/working_with_strings.html
+        ShowEnvShell::Nu => format!("$env.{} = {}", key, nu_double_quote(value)),
+    }
+}
+
+/// Returns the command to append `flags` to the current value of the
+/// environment variable.
+pub(crate) fn append(shell: ShowEnvShell, key: &str, flags: &str) -> String {
+    match shell {
+        ShowEnvShell::Bash => {
+            format!("export {}=\"${{{}}}\"{}", key, key, single_quote(flags, "'\\''"))
+        }
+        ShowEnvShell::Fish => format!(
+            "set -gx {} \"${}\"{}",
+            key,
+            key,
+            single_quote(&flags.replace('\\', "\\\\"), "\\'")
+        ),
+        // `$null + 'flags'` is 'flags'.
+        ShowEnvShell::Pwsh => {
+            format!("$env:{} = $env:{} + {}", key, key, single_quote(flags, "''"))
+        }
+        ShowEnvShell::Nu => {
+            format!("$env.{} = ($env.{}? | default \"\") + {}", key, key, nu_double_quote(flags))
+        }
+    }
+}
+
+/// Returns the command to unset the environment variable.
+pub(crate) fn unset(shell: ShowEnvShell, key: &str) -> String {
+    match shell {
+        ShowEnvShell::Bash => format!("unset {}", key),
+        ShowEnvShell::Fish => format!("set -e {}", key),
+        ShowEnvShell::Pwsh => format!("Remove-Item Env:{} -ErrorAction SilentlyContinue", key),
+        ShowEnvShell::Nu => format!("hide-env -i {}", key),
+    }
+}
+
+fn single_quote(value: &str, escaped_quote: &str) -> String {
+    format!("'{}'", value.replace('\'', escaped_quote))
+}
+
+fn nu_double_quote(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // e.g., the separator of CARGO_ENCODED_RUSTFLAGS (\x1f)
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, set, unset};
+    use crate::cli::ShowEnvShell::{Bash, Fish, Nu, Pwsh};
+
+    #[test]
+    fn quote() {
+        let value = r#"-C a'b "c\d"#;
+        assert_eq!(set(Bash, "K", value), r#"export K='-C a'\''b "c\d'"#);
+        assert_eq!(set(Fish, "K", value), r#"set -gx K '-C a\'b "c\\d'"#);
+        assert_eq!(set(Pwsh, "K", value), r#"$env:K = '-C a''b "c\d'"#);
+        assert_eq!(set(Nu, "K", value), r#"$env.K = "-C a'b \"c\\d""#);
+        assert_eq!(set(Nu, "K", "a\x1fb"), r#"$env.K = "a\u{1f}b""#);
+        assert_eq!(unset(Fish, "K"), "set -e K");
+        assert_eq!(append(Bash, "K", " -C x"), r#"export K="${K}"' -C x'"#);
+        assert_eq!(append(Fish, "K", " -C x"), r#"set -gx K "$K"' -C x'"#);
+        assert_eq!(append(Pwsh, "K", " -C x"), "$env:K = $env:K + ' -C x'");
+        assert_eq!(append(Nu, "K", " -C x"), r#"$env.K = ($env.K? | default "") + " -C x""#);
+    }
+}
@@ -0,0 +1,421 @@
+// Summaries of the coverage per package, module, or file, and the comparison of
+// the totals with the previous run.
+
+use std::{cmp::Reverse, collections::BTreeMap, fmt::Write as _, io::Write};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use termcolor::{Color, ColorSpec, WriteColor};
+
+use crate::{cli::GroupBy, context::Context, fs, term};
+
+const BAR_WIDTH: usize = 20;
+const DEFAULT_THRESHOLDS: (f64, f64) = (50.0, 80.0);
+/// The file in the output directory that the totals of the last run are saved to.
+const TOTALS_FILE: &str = "last-run-totals.json";
+const KINDS: &[&str] = &["lines", "functions", "regions"];
+/// The kinds of coverage and the headers of the columns in the table of `llvm-cov report`.
+const TABLE_COLUMNS: &[(&str, [&str; 3])] = &[
+    ("regions", ["Regions", "Missed Regions", "Cover"]),
+    ("functions", ["Functions", "Missed Functions", "Executed"]),
+    ("lines", ["Lines", "Missed Lines", "Cover"]),
+    ("branches", ["Branches", "Missed Branches", "Cover"]),
+];
+const TABLE_WIDTHS: [usize; 3] = [12, 18, 10];
+
+pub(crate) struct Row {
+    pub(crate) name: String,
+    /// The package directory.
+    pub(crate) dir: Option<Utf8PathBuf>,
+    pub(crate) count: u64,
+    pub(crate) covered: u64,
+}
+
+impl Row {
+    pub(crate) fn percent(&self) -> f64 {
+        percent(self.count, self.covered)
+    }
+}
+
+/// Returns the line coverage of each workspace member in the report, sorted
+/// by name, followed by the files that do not belong to any member.
+/// Packages with no lines are omitted.
+pub(crate) fn package_rows(cx: &Context, json: &LlvmCovJsonExport) -> Vec<Row> {
+    let mut packages: Vec<_> = cx
+        .workspace_members
+        .included
+        .iter()
+        .map(|id| {
+            let pkg = &cx.ws.metadata[id];
+            let dir = pkg.manifest_path.parent().unwrap().to_owned();
+            (dir.clone(), Row { name: pkg.name.clone(), dir: Some(dir), count: 0, covered: 0 })
+        })
+        .collect();
+    // Match nested packages before their parents.
+    packages.sort_by_key(|(dir, _)| Reverse(dir.as_str().len()));
+    let mut other = Row { name: "(other)".to_owned(), dir: None, count: 0, covered: 0 };
+    for (filename, count, covered) in json.get_lines_by_file() {
+        let mut path = Utf8PathBuf::from(filename);
+        if path.is_relative() {
+            // --remap-path-prefix strips the workspace root.
+            path = cx.ws.metadata.workspace_root.join(path);
+        }
+        let row = match packages.iter_mut().find(|(dir, _)| path.starts_with(dir)) {
+            Some((_, row)) => row,
+            None => &mut other,
+        };
+        row.count += count;
+        row.covered += covered;
+    }
+
+    let mut rows: Vec<_> = packages.into_iter().map(|(_, row)| row).collect();
+    rows.sort_by(|a, b| a.name.cmp(&b.name));
+    rows.push(other);
+    rows.retain(|row| row.count != 0);
+    rows
+}
+
+/// Returns the line coverage of each module in the report, sorted by module
+/// path, followed by the files that do not belong to any target of the
+/// workspace members. With --module-depth, submodules are counted in their
+/// ancestor at that depth.
+pub(crate) fn module_rows(cx: &Context, json: &LlvmCovJsonExport) -> Vec<Row> {
+    let mut targets: Vec<_> = cx
+        .workspace_members
+        .included
+        .iter()
+        .flat_map(|id| &cx.ws.metadata[id].targets)
+        .map(|target| (target.name.replace('-', "_"), target))
+        .collect();
+    // Match nested directories (e.g., `src/bin`) before their parents, and
+    // prefer the library if multiple targets share a directory.
+    targets.sort_by_key(|(_, target)| {
+        let dir = target.src_path.parent().map_or(0, |dir| dir.as_str().len());
+        (Reverse(dir), !target.kind.iter().any(|kind| kind == "lib"))
+    });
+    let mut modules: BTreeMap<String, Row> = BTreeMap::new();
+    let mut other = Row { name: "(other)".to_owned(), dir: None, count: 0, covered: 0 };
+    for (filename, count, covered) in json.get_lines_by_file() {
+        let mut path = Utf8PathBuf::from(filename);
+        if path.is_relative() {
+            // --remap-path-prefix strips the workspace root.
+            path = cx.ws.metadata.workspace_root.join(path);
+        }
+        let module = targets
+            .iter()
+            .find(|(_, target)| target.src_path == path)
+            .or_else(|| {
+                targets.iter().find(|(_, target)| {
+                    target.src_path.parent().map_or(false, |dir| path.starts_with(dir))
+                })
+            })
+            .and_then(|(name, target)| module_path(name, &target.src_path, &path));
+        let row = match module {
+            Some(mut module) => {
+                if let Some(depth) = cx.cov.module_depth {
+                    module.truncate(depth.max(1));
+                }
+                let name = module.join("::");
+                modules.entry(name.clone()).or_insert(Row { name, dir: None, count: 0, covered: 0 })
+            }
+            None => &mut other,
+        };
+        row.count += count;
+        row.covered += covered;
+    }
+
+    let mut rows: Vec<_> = modules.into_values().collect();
+    rows.push(other);
+    rows.retain(|row| row.count != 0);
+    rows
+}
+
+/// Returns the module path of `file` in the crate whose root is `root`, or
+/// `None` if the file is not in the directory of the crate root.
+fn module_path(crate_name: &str, root: &Utf8Path, file: &Utf8Path) -> Option<Vec<String>> {
+    let mut module = vec![crate_name.to_owned()];
+    if file == root {
+        return Some(module);
+    }
+    let relative = file.strip_prefix(root.parent()?).ok()?;
+    module.extend(relative.components().map(|c| c.as_str().to_owned()));
+    let last = module.last_mut().unwrap();
+    if let Some(name) = last.strip_suffix(".rs") {
+        *last = name.to_owned();
+    }
+    if module.len() > 2 && module.last().map_or(false, |name| name == "mod") {
+        module.pop();
+    }
+    Some(module)
+}
+
+/// Prints a bar of line coverage for each workspace member (or each module
+/// with `--group-by module`) in the report, followed by the total.
+pub(crate) fn print(cx: &Context, json: &LlvmCovJsonExport) {
+    let rows = match cx.cov.group_by {
+        Some(GroupBy::Module) => module_rows(cx, json),
+        Some(GroupBy::Package) | None => package_rows(cx, json),
+    };
+    let total = Row {
+        name: "TOTAL".to_owned(),
+        dir: None,
+        count: rows.iter().map(|row| row.count).sum(),
+        covered: rows.iter().map(|row| row.covered).sum(),
+    };
+    let thresholds = cx.cov.bar_thresholds.unwrap_or(DEFAULT_THRESHOLDS);
+    let width = rows.iter().map(|row| row.name.len()).max().unwrap_or(0).max(total.name.len());
+
+    let mut stdout = term::stdout();
+    for row in rows.iter().chain(Some(&total)) {
+        let percent = row.percent();
+        let color = match percent {
+            p if p < thresholds.0 => Color::Red,
+            p if p < thresholds.1 => Color::Yellow,
+            _ => Color::Green,
+        };
+        let name = match &row.dir {
+            Some(dir) => {
+                term::hyperlink(cx.hyperlink_format.as_deref(), &row.name, dir.as_str(), None)
+            }
+            None => row.name.clone(),
+        };
+        // Pad based on the visible width of the name.
+        let _ = write!(stdout, "{}{:2$} ", name, "", width - row.name.len());
+        let _ = stdout.set_color(ColorSpec::new().set_fg(Some(color)));
+        let _ = write!(stdout, "{}", bar(percent));
+        let _ = stdout.reset();
+        let _ = writeln!(stdout, " {:>6.2}% ({}/{} lines)", percent, row.covered, row.count);
+    }
+}
+
+/// Prints the totals and their change since the previous run, and saves the
+/// totals for the next run.
+///
+/// The totals are printed in a stable format (`lines: 83.4% (+0.6), ...`) so
+/// that CI scripts can extract them from the logs.
+pub(crate) fn print_delta(cx: &Context, json: &LlvmCovJsonExport) -> Result<()> {
+    let mut totals = BTreeMap::new();
+    for &kind in KINDS {
+        totals.insert(kind.to_owned(), json.get_totals(kind)?);
+    }
+    let path = cx.ws.output_dir.join(TOTALS_FILE);
+    let prev: Option<BTreeMap<String, (u64, u64)>> =
+        fs::read_to_string(&path).ok().and_then(|s| serde_json::from_str(&s).ok());
+    fs::create_dir_all(&cx.ws.output_dir)?;
+    fs::write(&path, serde_json::to_string(&totals)?)?;
+
+    // Nothing to compare with on the first run, but always print the totals
+    // on CI, where the previous run is usually not available.
+    if prev.is_none() && !term::ci() {
+        return Ok(());
+    }
+    let mut msg = String::new();
+    for &kind in KINDS {
+        let (count, covered) = totals[kind];
+        let percent = percent(count, covered);
+        if !msg.is_empty() {
+            msg.push_str(", ");
+        }
+        let _ = write!(msg, "{}: {:.1}%", kind, percent);
+        if let Some(&(count, covered)) = prev.as_ref().and_then(|prev| prev.get(kind)) {
+            let _ = write!(msg, " ({})", delta(percent - self::percent(count, covered)));
+        }
+    }
+    status!("Coverage", "{}", msg);
+    Ok(())
+}
+
+/// Returns the count, covered count, and percentage of each kind of coverage,
+/// in the format used by the JSON outputs (e.g., `--message-format json`).
+pub(crate) fn totals(json: &LlvmCovJsonExport) -> Result<serde_json::Value> {
+    let mut totals = serde_json::Map::new();
+    for &kind in KINDS {
+        let (count, covered) = json.get_totals(kind)?;
+        totals.insert(
+            kind.to_owned(),
+            serde_json::json!({
+                "count": count,
+                "covered": covered,
+                "percent": percent(count, covered),
+            }),
+        );
+    }
+    Ok(totals.into())
+}
+
+/// Renders the coverage of each file and the totals in the layout of
+/// `llvm-cov report`. This is used instead of `llvm-cov report` when lines are
+/// excluded from coverage, because llvm-cov does not know about them.
+pub(crate) fn table(json: &LlvmCovJsonExport) -> Result<String> {
+    // Branch coverage is not exported by LLVM 11 and earlier.
+    let columns: Vec<_> =
+        TABLE_COLUMNS.iter().filter(|(kind, _)| json.get_totals(kind).is_ok()).collect();
+    let mut files: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
+    let mut totals = vec![];
+    for (kind, _) in &columns {
+        for (file, count, covered) in json.get_totals_by_file(kind)? {
+            files.entry(file).or_default().push((count, covered));
+        }
+        totals.push(json.get_totals(kind)?);
+    }
+
+    // Like llvm-cov, strip the directory prefix common to all files.
+    let mut prefix = files.keys().next().map_or("", |first| first);
+    for file in files.keys() {
+        let len = prefix.bytes().zip(file.bytes()).take_while(|(a, b)| a == b).count();
+        prefix = &prefix[..len];
+    }
+    let prefix = prefix.rfind(std::path::is_separator).map_or(0, |i| i + 1);
+    let width = files.keys().map(|file| file[prefix..].len()).max().unwrap_or(0).max(25);
+
+    let mut out = String::new();
+    let _ = write!(out, "{:1$}", "Filename", width);
+    for (_, headers) in &columns {
+        for (header, width) in headers.iter().zip(TABLE_WIDTHS) {
+            let _ = write!(out, "{:>1$}", header, width);
+        }
+    }
+    out.push('\n');
+    let divider = "-".repeat(width + columns.len() * TABLE_WIDTHS.iter().sum::<usize>());
+    let _ = writeln!(out, "{}", divider);
+    // Files without functions are listed separately, as llvm-cov does.
+    let functions = columns.iter().position(|(kind, _)| *kind == "functions").unwrap();
+    let (with_functions, without_functions): (Vec<_>, Vec<_>) =
+        files.iter().partition(|(_, counts)| counts[functions].0 != 0);
+    for (file, counts) in &with_functions {
+        table_row(&mut out, &file[prefix..], width, counts);
+    }
+    if !without_functions.is_empty() {
+        out.push_str("\nFiles which contain no functions:\n");
+        for (file, counts) in &without_functions {
+            table_row(&mut out, &file[prefix..], width, counts);
+        }
+    }
+    let _ = writeln!(out, "{}", divider);
+    table_row(&mut out, "TOTAL", width, &totals);
+    Ok(out)
+}
+
+fn table_row(out: &mut String, name: &str, width: usize, counts: &[(u64, u64)]) {
+    let _ = write!(out, "{:1$}", name, width);
+    for &(count, covered) in counts {
+        let percent =
+            if count == 0 { "-".to_owned() } else { format!("{:.2}%", percent(count, covered)) };
+        let _ = write!(
+            out,
+            "{:>w0$}{:>w1$}{:>w2$}",
+            count,
+            count - covered,
+            percent,
+            w0 = TABLE_WIDTHS[0],
+            w1 = TABLE_WIDTHS[1],
+            w2 = TABLE_WIDTHS[2]
+        );
+    }
+    out.push('\n');
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn percent(count: u64, covered: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        covered as f64 * 100.0 / count as f64
+    }
+}
+
+pub(crate) fn delta(delta: f64) -> String {
+    // Avoid printing changes that round to zero as "-0.0".
+    if format!("{:.1}", delta.abs()) == "0.0" {
+        "+0.0".to_owned()
+    } else {
+        format!("{:+.1}", delta)
+    }
+}
+
+fn bar(percent: f64) -> String {
+    // Round down so that the bar is full only if all lines are covered.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss, clippy::cast_sign_loss)]
+    let filled = ((percent / 100.0 * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    let mut s = "█".repeat(filled);
+    s.push_str(&"░".repeat(BAR_WIDTH - filled));
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use cargo_llvm_cov_core::json::ExcludedLines;
+
+    use super::*;
+
+    #[test]
+    fn bar() {
+        assert_eq!(super::bar(0.0), "░".repeat(BAR_WIDTH));
+        assert_eq!(super::bar(50.0), format!("{}{}", "█".repeat(10), "░".repeat(10)));
+        assert_eq!(super::bar(99.9), format!("{}{}", "█".repeat(19), "░"));
+        assert_eq!(super::bar(100.0), "█".repeat(BAR_WIDTH));
+    }
+
+    #[test]
+    fn module_path() {
+        let module_path = |root: &str, file: &str| {
+            super::module_path("foo", Utf8Path::new(root), Utf8Path::new(file))
+                .map(|module| module.join("::"))
+        };
+        assert_eq!(module_path("/a/src/lib.rs", "/a/src/lib.rs").unwrap(), "foo");
+        assert_eq!(module_path("/a/src/lib.rs", "/a/src/parser.rs").unwrap(), "foo::parser");
+        assert_eq!(module_path("/a/src/lib.rs", "/a/src/parser/mod.rs").unwrap(), "foo::parser");
+        assert_eq!(
+            module_path("/a/src/lib.rs", "/a/src/parser/lexer.rs").unwrap(),
+            "foo::parser::lexer"
+        );
+        assert_eq!(module_path("/a/src/lib.rs", "/a/src/mod.rs").unwrap(), "foo::mod");
+        assert_eq!(module_path("/a/tests/t.rs", "/a/tests/common/mod.rs").unwrap(), "foo::common");
+        assert_eq!(module_path("/a/src/lib.rs", "/a/build.rs"), None);
+    }
+
+    #[test]
+    fn table() {
+        let s = fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/show-missing-lines.json"
+        ))
+        .unwrap();
+        let rows = |json: &LlvmCovJsonExport| {
+            let table = super::table(json).unwrap();
+            let lines: Vec<_> = table.lines().collect();
+            assert!(lines.iter().all(|line| line.len() == lines[0].len()));
+            lines
+                .iter()
+                .map(|line| line.split_whitespace().map(str::to_owned).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let mut json = serde_json::from_str::<LlvmCovJsonExport>(&s).unwrap();
+        let row = ["7", "3", "57.14%", "5", "1", "80.00%", "11", "3", "72.73%", "0", "0", "-"];
+        let rows1 = rows(&json);
+        assert_eq!(rows1.len(), 5);
+        assert_eq!(rows1[0][..2], ["Filename", "Regions"]);
+        assert_eq!(rows1[2][0], "lib.rs");
+        assert_eq!(rows1[2][1..], row);
+        assert_eq!(rows1[4][0], "TOTAL");
+        assert_eq!(rows1[4][1..], row);
+
+        let excluded: ExcludedLines =
+            vec![("src/lib.rs".to_owned(), (7..=9).collect())].into_iter().collect();
+        json.exclude_lines(&excluded);
+        let rows2 = rows(&json);
+        assert_eq!(rows2[4][1..], rows2[2][1..]);
+        assert_eq!(rows2[4][1..], [
+            "4", "0", "100.00%", "4", "0", "100.00%", "8", "0", "100.00%", "0", "0", "-"
+        ]);
+    }
+
+    #[test]
+    fn delta() {
+        assert_eq!(super::delta(0.6), "+0.6");
+        assert_eq!(super::delta(-1.25), "-1.2");
+        assert_eq!(super::delta(0.0), "+0.0");
+        assert_eq!(super::delta(-0.01), "+0.0");
+    }
+}
@@ -0,0 +1,104 @@
+// User-defined report templates (--template).
+// See "Report templates" in README.md for the data passed to templates.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use handlebars::Handlebars;
+use serde_json::Value;
+
+use crate::{context::Context, summary};
+
+const KINDS: &[&str] = &["lines", "functions", "regions"];
+
+/// Handles --template.
+pub(crate) fn render_report(
+    cx: &Context,
+    template: &str,
+    json: &LlvmCovJsonExport,
+) -> Result<String> {
+    let mut totals = serde_json::Map::new();
+    let mut files = BTreeMap::<&str, serde_json::Map<String, Value>>::new();
+    for &kind in KINDS {
+        let (count, covered) = json.get_totals(kind)?;
+        totals.insert(kind.to_owned(), counts(count, covered));
+        for (filename, count, covered) in json.get_totals_by_file(kind)? {
+            files.entry(filename).or_default().insert(kind.to_owned(), counts(count, covered));
+        }
+    }
+    let files = files
+        .into_iter()
+        .map(|(filename, mut file)| {
+            file.insert("filename".to_owned(), filename.into());
+            Value::Object(file)
+        })
+        .collect::<Vec<_>>();
+    let data = serde_json::json!({
+        "workspace_root": cx.ws.metadata.workspace_root,
+        "totals": totals,
+        "files": files,
+    });
+    render(template, &data)
+}
+
+fn counts(count: u64, covered: u64) -> Value {
+    serde_json::json!({
+        "count": count,
+        "covered": covered,
+        "uncovered": count - covered,
+        "percent": format!("{:.2}", summary::percent(count, covered)),
+    })
+}
+
+/// Renders the template with the given data.
+fn render(template: &str, data: &Value) -> Result<String> {
+    Ok(Handlebars::new().render_template(template, data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn render_template() {
+        let data = serde_json::json!({
+            "totals": { "lines": { "count": 3, "covered": 2, "percent": "66.67" } },
+            "files": [
+                { "filename": "a.rs", "lines": { "covered": 2 } },
+                { "filename": "b.rs", "lines": { "covered": 0 } },
+            ],
+        });
+        let template = "{{! comment }}lines: {{totals.lines.percent}}% ({{ totals.lines.covered }}/{{totals.lines.count}})\n\
+                        {{#each files}}{{@index}}. {{filename}}: \
+                        {{#if lines.covered}}{{this.lines.covered}}{{else}}none{{/if}} of {{@root.totals.lines.count}}\n{{/each}}";
+        assert_eq!(
+            render(template, &data).unwrap(),
+            "lines: 66.67% (2/3)\n0. a.rs: 2 of 3\n1. b.rs: none of 3\n"
+        );
+    }
+
+    #[test]
+    fn render_template_standalone_lines() {
+        let data = serde_json::json!({ "files": ["a.rs", "b.rs"] });
+        let template = "files:\n  {{#each files}}\n  - {{this}}\n  {{/each}}\n{{! end }}\ndone";
+        assert_eq!(render(template, &data).unwrap(), "files:\n  - a.rs\n  - b.rs\ndone");
+    }
+
+    #[test]
+    fn render_template_escape() {
+        let data = serde_json::json!({ "filename": "src/<a>&b.rs" });
+        assert_eq!(
+            render("{{filename}} {{{filename}}}", &data).unwrap(),
+            "src/&lt;a&gt;&amp;b.rs src/<a>&b.rs"
+        );
+    }
+
+    #[test]
+    fn render_template_error() {
+        let data = serde_json::json!({ "files": [] });
+        for template in ["{{#each files}}", "{{/each}}", "{{#each files}}{{/if}}", "{{files"] {
+            assert!(render(template, &data).is_err(), "{}", template);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
-    io::Write,
-    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    io::{self, Write},
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering},
 };
 
 use serde::Deserialize;
@@ -38,6 +38,19 @@ pub(crate) fn set_coloring(coloring: &mut Option<Coloring>) {
     }
     COLORING.store(color as _, Ordering::Relaxed);
 }
+/// Returns a stream to stdout, colored according to `--color`.
+pub(crate) fn stdout() -> StandardStream {
+    let choice = match coloring() {
+        ColorChoice::Auto if !atty::is(atty::Stream::Stdout) => ColorChoice::Never,
+        choice => choice,
+    };
+    StandardStream::stdout(choice)
+}
+/// Returns a stream to stderr, colored according to `--color`.
+pub(crate) fn stderr() -> StandardStream {
+    crate::progress::clear();
+    StandardStream::stderr(coloring())
+}
 fn coloring() -> ColorChoice {
     match COLORING.load(Ordering::Relaxed) {
         Coloring::AUTO => ColorChoice::Auto,
@@ -64,9 +77,96 @@ macro_rules! global_flag {
 global_flag!(verbose: bool = AtomicBool::new(false));
 global_flag!(error: bool = AtomicBool::new(false));
 global_flag!(warn: bool = AtomicBool::new(false));
+global_flag!(log_json: bool = AtomicBool::new(false));
+global_flag!(ci: bool = AtomicBool::new(false));
+global_flag!(message_json: bool = AtomicBool::new(false));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub(crate) enum LogFormat {
+    Human,
+    Json,
+}
+
+/// Prints an event of `--log-format json` as a line of JSON to stderr.
+pub(crate) fn print_event(event: &serde_json::Value) {
+    crate::progress::clear();
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = writeln!(stderr, "{}", event);
+}
+
+/// Prints a message of `--message-format json` as a line of JSON to stdout,
+/// in the same stream as cargo's messages.
+pub(crate) fn print_message(message: &serde_json::Value) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{}", message);
+}
+
+/// Kind of failure, which determines the exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Failure {
+    /// Tests failed.
+    Test = 1,
+    /// A `--fail-*` threshold was not met.
+    Threshold,
+    /// Other errors, e.g., report generation failed.
+    Report,
+}
+
+static FAILURE: AtomicU8 = AtomicU8::new(0);
+static EXIT_CODES: [AtomicI32; 3] = [AtomicI32::new(1), AtomicI32::new(1), AtomicI32::new(1)];
+
+/// Records the kind of failure. Only the first one is recorded.
+pub(crate) fn set_failure(kind: Failure) {
+    let _ = FAILURE.compare_exchange(0, kind as _, Ordering::Relaxed, Ordering::Relaxed);
+}
+pub(crate) fn set_exit_code(kind: Failure, code: u8) {
+    EXIT_CODES[kind as usize - 1].store(code.into(), Ordering::Relaxed);
+}
+/// Returns the exit status for the recorded kind of failure.
+pub(crate) fn exit_code() -> i32 {
+    let kind = match FAILURE.load(Ordering::Relaxed) {
+        0 => Failure::Report as u8,
+        kind => kind,
+    };
+    EXIT_CODES[kind as usize - 1].load(Ordering::Relaxed)
+}
+
+/// Wraps `text` in an OSC 8 hyperlink to the given file (and line), if
+/// `format` (the format of the URL) is given.
+pub(crate) fn hyperlink(format: Option<&str>, text: &str, path: &str, line: Option<u64>) -> String {
+    let format = match format {
+        Some(format) => format,
+        None => return text.to_owned(),
+    };
+    let mut path = path.replace('\\', "/").replace(' ', "%20");
+    if !path.starts_with('/') {
+        // Windows path (C:/...)
+        path.insert(0, '/');
+    }
+    let url = format.replace("{path}", &path).replace("{line}", &line.unwrap_or(1).to_string());
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
+
+/// Asks the user a yes/no question. Returns `false` if stdin or stderr is not
+/// a terminal.
+pub(crate) fn confirm(question: &str) -> bool {
+    if !atty::is(atty::Stream::Stdin) || !atty::is(atty::Stream::Stderr) {
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
 
 #[allow(clippy::let_underscore_drop)]
 pub(crate) fn print_status(status: &str, color: Option<Color>, justified: bool) -> StandardStream {
+    crate::progress::clear();
     let mut stream = StandardStream::stderr(coloring());
     let _ = stream.set_color(ColorSpec::new().set_bold(true).set_fg(color));
     if justified {
@@ -85,9 +185,15 @@ macro_rules! error {
     ($($msg:expr),* $(,)?) => {{
         use std::io::Write;
         crate::term::error::set(true);
-        let mut stream = crate::term::print_status("error", Some(termcolor::Color::Red), false);
-        #[allow(clippy::let_underscore_drop)]
-        let _ = writeln!(stream, $($msg),*);
+        if crate::term::log_json() {
+            crate::term::print_event(
+                &serde_json::json!({ "reason": "error", "message": format!($($msg),*) }),
+            );
+        } else {
+            let mut stream = crate::term::print_status("error", Some(termcolor::Color::Red), false);
+            #[allow(clippy::let_underscore_drop)]
+            let _ = writeln!(stream, $($msg),*);
+        }
     }};
 }
 
@@ -95,26 +201,48 @@ macro_rules! warn {
     ($($msg:expr),* $(,)?) => {{
         use std::io::Write;
         crate::term::warn::set(true);
-        let mut stream = crate::term::print_status("warning", Some(termcolor::Color::Yellow), false);
-        #[allow(clippy::let_underscore_drop)]
-        let _ = writeln!(stream, $($msg),*);
+        if crate::term::log_json() {
+            crate::term::print_event(
+                &serde_json::json!({ "reason": "warning", "message": format!($($msg),*) }),
+            );
+        } else {
+            let mut stream =
+                crate::term::print_status("warning", Some(termcolor::Color::Yellow), false);
+            #[allow(clippy::let_underscore_drop)]
+            let _ = writeln!(stream, $($msg),*);
+        }
     }};
 }
 
 macro_rules! info {
     ($($msg:expr),* $(,)?) => {{
         use std::io::Write;
-        let mut stream = crate::term::print_status("info", None, false);
-        #[allow(clippy::let_underscore_drop)]
-        let _ = writeln!(stream, $($msg),*);
+        if crate::term::log_json() {
+            crate::term::print_event(
+                &serde_json::json!({ "reason": "info", "message": format!($($msg),*) }),
+            );
+        } else {
+            let mut stream = crate::term::print_status("info", None, false);
+            #[allow(clippy::let_underscore_drop)]
+            let _ = writeln!(stream, $($msg),*);
+        }
     }};
 }
 
 macro_rules! status {
     ($status:expr, $($msg:expr),* $(,)?) => {{
         use std::io::Write;
-        let mut stream = crate::term::print_status($status, Some(termcolor::Color::Cyan), true);
-        #[allow(clippy::let_underscore_drop)]
-        let _ = writeln!(stream, $($msg),*);
+        if crate::term::log_json() {
+            crate::term::print_event(&serde_json::json!({
+                "reason": "status",
+                "status": $status,
+                "message": format!($($msg),*),
+            }));
+        } else {
+            let mut stream =
+                crate::term::print_status($status, Some(termcolor::Color::Cyan), true);
+            #[allow(clippy::let_underscore_drop)]
+            let _ = writeln!(stream, $($msg),*);
+        }
     }};
 }
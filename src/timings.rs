@@ -0,0 +1,97 @@
+// Wall time of each step of coverage collection, reported with --timings.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{cargo::Workspace, fs, term};
+
+pub(crate) struct Timings {
+    enabled: bool,
+    start: Instant,
+    steps: Mutex<Vec<(String, Duration)>>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    /// Seconds since the start of cargo-llvm-cov.
+    total: f64,
+    steps: Vec<Step<'a>>,
+}
+
+#[derive(Serialize)]
+struct Step<'a> {
+    name: &'a str,
+    /// Seconds spent on this step.
+    duration: f64,
+}
+
+impl Timings {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self { enabled, start: Instant::now(), steps: Mutex::default() }
+    }
+
+    /// Runs `f` and records its wall time as `step`.
+    ///
+    /// With `--log-format json`, the start and end of the step are also printed.
+    pub(crate) fn time<T>(&self, step: &str, f: impl FnOnce() -> T) -> T {
+        let log_json = term::log_json();
+        if !self.enabled && !log_json {
+            return f();
+        }
+        if log_json {
+            term::print_event(&json!({ "reason": "phase-started", "phase": step }));
+        }
+        let start = Instant::now();
+        let res = f();
+        let duration = start.elapsed();
+        if log_json {
+            term::print_event(&json!({
+                "reason": "phase-finished",
+                "phase": step,
+                "duration": duration.as_secs_f64(),
+            }));
+        }
+        if self.enabled {
+            self.steps.lock().unwrap().push((step.to_owned(), duration));
+        }
+        res
+    }
+
+    /// Prints the recorded timings and writes them to `timings.json` in the
+    /// output directory.
+    pub(crate) fn finish(&self, ws: &Workspace) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let steps = self.steps.lock().unwrap();
+        let report = Report {
+            total: self.start.elapsed().as_secs_f64(),
+            steps: steps
+                .iter()
+                .map(|(name, duration)| Step { name, duration: duration.as_secs_f64() })
+                .collect(),
+        };
+
+        let width = steps.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for step in &report.steps {
+            status!("Timing", "{0:1$} {2:>9.2}s", step.name, width, step.duration);
+        }
+        status!("Timing", "{0:1$} {2:>9.2}s", "total", width, report.total);
+
+        fs::create_dir_all(&ws.output_dir)?;
+        let path = ws.output_dir.join("timings.json");
+        fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+        if term::log_json() {
+            term::print_event(&json!({ "reason": "artifact", "kind": "timings", "path": path }));
+        } else {
+            status!("Finished", "timing report saved to {}", path);
+        }
+        Ok(())
+    }
+}
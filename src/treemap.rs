@@ -0,0 +1,316 @@
+// Treemap of the workspace in html report (`treemap.html` next to `index.html`).
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use anyhow::Result;
+use camino::{Utf8Component, Utf8Path};
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+use crate::{context::Context, fs, html, summary};
+
+const WIDTH: f64 = 1200.0;
+const HEIGHT: f64 = 720.0;
+/// The height of the label of a directory.
+const LABEL_HEIGHT: f64 = 16.0;
+const PADDING: f64 = 2.0;
+const LINK_MARKER: &str = "<!-- cargo-llvm-cov: treemap -->";
+
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    /// The page of the file in html report, relative to the html directory.
+    page: Option<String>,
+    count: u64,
+    covered: u64,
+    children: BTreeMap<String, Node>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rect {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+}
+
+/// Writes `treemap.html` to the html directory and links it from the index.
+pub(crate) fn write(cx: &Context, html_dir: &Utf8Path, json: &LlvmCovJsonExport) -> Result<()> {
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let mut root = Node::default();
+    for (filename, count, covered) in json.get_lines_by_file() {
+        if count == 0 {
+            continue;
+        }
+        let path = Utf8Path::new(filename);
+        let page = html::page(html_dir, filename);
+        let page = page.strip_prefix(html_dir).unwrap_or(&page).as_str().replace('\\', "/");
+        let components: Vec<_> = path
+            .strip_prefix(workspace_root)
+            .unwrap_or(path)
+            .components()
+            .filter_map(|c| match c {
+                Utf8Component::Normal(c) => Some(c),
+                _ => None,
+            })
+            .collect();
+        root.insert(&components, page, count, covered);
+    }
+    root.collapse();
+
+    let mut out = String::new();
+    out.push_str(HEADER);
+    let _ = writeln!(
+        out,
+        "<p>Line coverage: {:.2}% ({}/{} lines). The area of each file is the number of lines, \
+         and the color is the line coverage. <a href='index.html'>Back to index</a></p>",
+        summary::percent(root.count, root.covered),
+        root.covered,
+        root.count
+    );
+    let _ = writeln!(out, "<div class='treemap' style='width:{}px;height:{}px'>", WIDTH, HEIGHT);
+    root.render_children(&mut out, Rect { x: 0.0, y: 0.0, w: WIDTH, h: HEIGHT }, "");
+    out.push_str("</div>\n</body>\n</html>\n");
+    fs::write(html_dir.join("treemap.html"), out)?;
+
+    let index_path = html_dir.join("index.html");
+    let index = fs::read_to_string(&index_path)?;
+    if let Some(index) = insert_link(&index) {
+        fs::write(index_path, index)?;
+    }
+    Ok(())
+}
+
+impl Node {
+    fn insert(&mut self, path: &[&str], page: String, count: u64, covered: u64) {
+        self.count += count;
+        self.covered += covered;
+        match path.split_first() {
+            Some((name, rest)) => {
+                let child = self
+                    .children
+                    .entry((*name).to_owned())
+                    .or_insert_with(|| Node { name: (*name).to_owned(), ..Node::default() });
+                child.insert(rest, page, count, covered);
+            }
+            None => self.page = Some(page),
+        }
+    }
+
+    /// Merges directories that contain only one directory (e.g., `crates/foo/src`).
+    fn collapse(&mut self) {
+        for child in self.children.values_mut() {
+            while child.page.is_none() && child.children.len() == 1 {
+                let name = child.children.keys().next().unwrap().clone();
+                let grandchild = child.children.remove(&name).unwrap();
+                if grandchild.page.is_some() {
+                    child.children.insert(grandchild.name.clone(), grandchild);
+                    break;
+                }
+                child.name = format!("{}/{}", child.name, grandchild.name);
+                child.children = grandchild.children;
+            }
+            child.collapse();
+        }
+    }
+
+    fn render_children(&self, out: &mut String, rect: Rect, path: &str) {
+        let mut children: Vec<_> = self.children.values().collect();
+        children.sort_by_key(|child| std::cmp::Reverse(child.count));
+        #[allow(clippy::cast_precision_loss)]
+        let weights: Vec<_> = children.iter().map(|child| child.count as f64).collect();
+        for (child, rect) in children.iter().zip(squarify(&weights, rect)) {
+            let path = if path.is_empty() {
+                child.name.clone()
+            } else {
+                format!("{}/{}", path, child.name)
+            };
+            child.render(out, rect, &path);
+        }
+    }
+
+    fn render(&self, out: &mut String, rect: Rect, path: &str) {
+        let percent = summary::percent(self.count, self.covered);
+        let title =
+            escape(&format!("{}: {:.2}% ({}/{} lines)", path, percent, self.covered, self.count));
+        let style = format!(
+            "left:{:.1}px;top:{:.1}px;width:{:.1}px;height:{:.1}px",
+            rect.x, rect.y, rect.w, rect.h
+        );
+        let inner = Rect {
+            x: rect.x + PADDING,
+            y: rect.y + LABEL_HEIGHT,
+            w: rect.w - PADDING * 2.0,
+            h: rect.h - LABEL_HEIGHT - PADDING,
+        };
+        if self.page.is_none() && inner.w > 1.0 && inner.h > 1.0 {
+            let _ = writeln!(
+                out,
+                "<div class='dir' style='{}' title='{}'>{}</div>",
+                style,
+                title,
+                escape(&self.name)
+            );
+            self.render_children(out, inner, path);
+            return;
+        }
+        let _ = writeln!(
+            out,
+            "<a class='file' href='{}' style='{};background:{}' title='{}'>{}</a>",
+            escape(self.page.as_deref().unwrap_or("index.html")),
+            style,
+            color(percent),
+            title,
+            escape(&self.name)
+        );
+    }
+}
+
+/// Lays out rectangles with the given areas (sorted in descending order) in
+/// `rect`, keeping their aspect ratios close to 1 (the squarified algorithm
+/// of Bruls et al., 2000).
+fn squarify(weights: &[f64], mut rect: Rect) -> Vec<Rect> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return vec![];
+    }
+    let scale = rect.w * rect.h / total;
+    let areas: Vec<_> = weights.iter().map(|w| w * scale).collect();
+    let mut out = Vec::with_capacity(areas.len());
+    let mut start = 0;
+    while start < areas.len() {
+        let side = rect.w.min(rect.h);
+        let mut end = start + 1;
+        while end < areas.len()
+            && worst(&areas[start..=end], side) <= worst(&areas[start..end], side)
+        {
+            end += 1;
+        }
+        let row = &areas[start..end];
+        let sum: f64 = row.iter().sum();
+        if rect.w >= rect.h {
+            // Lay out the row as a column on the left.
+            let w = sum / rect.h;
+            let mut y = rect.y;
+            for area in row {
+                let h = area / w;
+                out.push(Rect { x: rect.x, y, w, h });
+                y += h;
+            }
+            rect.x += w;
+            rect.w -= w;
+        } else {
+            // Lay out the row on the top.
+            let h = sum / rect.w;
+            let mut x = rect.x;
+            for area in row {
+                let w = area / h;
+                out.push(Rect { x, y: rect.y, w, h });
+                x += w;
+            }
+            rect.y += h;
+            rect.h -= h;
+        }
+        start = end;
+    }
+    out
+}
+
+/// Returns the worst aspect ratio in the row laid out along a side of the
+/// given length.
+fn worst(row: &[f64], side: f64) -> f64 {
+    let sum: f64 = row.iter().sum();
+    let max = row.iter().copied().fold(f64::MIN, f64::max);
+    let min = row.iter().copied().fold(f64::MAX, f64::min);
+    let side = side * side;
+    let sum = sum * sum;
+    (side * max / sum).max(sum / (side * min))
+}
+
+/// Returns the color of the given percentage, from red (0%) to green (100%).
+fn color(percent: f64) -> String {
+    format!("hsl({:.0},65%,55%)", percent * 1.2)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&#39;")
+}
+
+fn insert_link(index: &str) -> Option<String> {
+    if index.contains(LINK_MARKER) {
+        return None;
+    }
+    let pos = index
+        .find("<div class='centered'>")
+        .or_else(|| index.rfind("</body>"))
+        .unwrap_or(index.len());
+    Some(format!(
+        "{}{}\n<p><a href='treemap.html'>Treemap</a></p>\n{}",
+        &index[..pos],
+        LINK_MARKER,
+        &index[pos..]
+    ))
+}
+
+const HEADER: &str = "<!doctype html>
+<html>
+<head>
+<meta charset='UTF-8'>
+<title>Coverage Treemap</title>
+<style>
+body { font-family: sans-serif; }
+.treemap { position: relative; }
+.treemap > * { position: absolute; box-sizing: border-box; overflow: hidden; font-size: 11px; }
+.dir { border: 1px solid #888; background: #eee; padding: 1px 3px; white-space: nowrap; }
+.file { border: 1px solid #fff; color: #000; padding: 1px 3px; text-decoration: none; }
+.file:hover { border-color: #000; }
+</style>
+</head>
+<body>
+<h2>Coverage Treemap</h2>
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let rects =
+            squarify(&[6.0, 6.0, 4.0, 3.0, 2.0, 2.0, 1.0], Rect { x: 0.0, y: 0.0, w: 6.0, h: 4.0 });
+        assert_eq!(rects.len(), 7);
+        // The rectangles fill the area without overlapping.
+        let area: f64 = rects.iter().map(|r| r.w * r.h).sum();
+        assert!((area - 24.0).abs() < 1e-9);
+        assert_eq!(rects[0], Rect { x: 0.0, y: 0.0, w: 3.0, h: 2.0 });
+        assert_eq!(rects[1], Rect { x: 0.0, y: 2.0, w: 3.0, h: 2.0 });
+        for (i, a) in rects.iter().enumerate() {
+            for b in &rects[i + 1..] {
+                let overlap_x = a.x.max(b.x) < (a.x + a.w).min(b.x + b.w) - 1e-9;
+                let overlap_y = a.y.max(b.y) < (a.y + a.h).min(b.y + b.h) - 1e-9;
+                assert!(!(overlap_x && overlap_y), "{:?} {:?}", a, b);
+            }
+        }
+        assert!(squarify(&[], Rect { x: 0.0, y: 0.0, w: 1.0, h: 1.0 }).is_empty());
+    }
+
+    #[test]
+    fn tree() {
+        let mut root = Node::default();
+        root.insert(&["crates", "foo", "src", "lib.rs"], "a".to_owned(), 10, 5);
+        root.insert(&["crates", "foo", "src", "a", "b.rs"], "b".to_owned(), 4, 4);
+        root.insert(&["build.rs"], "c".to_owned(), 2, 0);
+        root.collapse();
+        assert_eq!((root.count, root.covered), (16, 9));
+        let names: Vec<_> = root.children.values().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["build.rs", "crates/foo/src"]);
+        let src = &root.children["crates"];
+        assert_eq!(src.children.len(), 2);
+        assert_eq!(src.children["a"].children["b.rs"].page.as_deref(), Some("b"));
+
+        let index =
+            "<body><h2>Coverage Report</h2><div class='centered'><table></table></div></body>";
+        let index = insert_link(index).unwrap();
+        assert!(index.contains("</h2><!-- cargo-llvm-cov: treemap -->"));
+        assert_eq!(insert_link(&index), None);
+    }
+}
@@ -0,0 +1,114 @@
+// Text report that shows only the uncovered lines (--text --uncovered-only).
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use camino::Utf8Path;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+
+use crate::{context::Context, fs};
+
+const DEFAULT_CONTEXT: usize = 3;
+
+/// Renders the hunks of uncovered lines with a few lines of context, in a
+/// format similar to `llvm-cov show -format=text`.
+pub(crate) fn render(cx: &Context, json: &LlvmCovJsonExport) -> String {
+    let context = cx.cov.context.unwrap_or(DEFAULT_CONTEXT);
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let mut out = String::new();
+    for (filename, lines) in json.get_line_coverage() {
+        if lines.values().all(|&covered| covered) {
+            continue;
+        }
+        let source = match fs::read_to_string(&filename) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("{:#}", e);
+                continue;
+            }
+        };
+        let name = Utf8Path::new(&filename);
+        let name = name.strip_prefix(workspace_root).unwrap_or(name);
+        render_file(&mut out, name.as_str(), &source, &lines, context);
+    }
+    out
+}
+
+fn render_file(
+    out: &mut String,
+    name: &str,
+    source: &str,
+    lines: &BTreeMap<u64, bool>,
+    context: usize,
+) {
+    let source: Vec<_> = source.lines().collect();
+    let hunks = hunks(lines, context as u64, source.len() as u64);
+    if hunks.is_empty() {
+        return;
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    let _ = writeln!(out, "{}:", name);
+    let width = hunks.last().map_or(0, |&(_, end)| end.to_string().len()).max(5);
+    for (i, &(start, end)) in hunks.iter().enumerate() {
+        if i != 0 {
+            let _ = writeln!(out, "{:>1$}", "...", width);
+        }
+        for line in start..=end {
+            let count = if lines.get(&line) == Some(&false) { "0" } else { "" };
+            #[allow(clippy::cast_possible_truncation)] // line <= source.len()
+            let text = source[line as usize - 1];
+            let _ = writeln!(out, "{:>3$}|{:>7}|{}", line, count, text, width);
+        }
+    }
+}
+
+/// Returns the ranges of lines (1-based, inclusive) to show: the uncovered
+/// lines and `context` lines around them. Overlapping or adjacent ranges are
+/// merged.
+fn hunks(lines: &BTreeMap<u64, bool>, context: u64, last_line: u64) -> Vec<(u64, u64)> {
+    let mut hunks: Vec<(u64, u64)> = vec![];
+    for (&line, _) in lines.iter().filter(|(_, &covered)| !covered) {
+        if line == 0 || line > last_line {
+            // The source file has changed since the coverage was collected.
+            continue;
+        }
+        let start = line.saturating_sub(context).max(1);
+        let end = (line + context).min(last_line);
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncovered_hunks() {
+        let lines: BTreeMap<u64, bool> =
+            [(1, true), (2, false), (5, false), (6, true), (12, false), (20, false)]
+                .into_iter()
+                .collect();
+        assert_eq!(hunks(&lines, 0, 15), [(2, 2), (5, 5), (12, 12)]);
+        assert_eq!(hunks(&lines, 1, 15), [(1, 6), (11, 13)]);
+        assert_eq!(hunks(&lines, 3, 15), [(1, 15)]);
+
+        let source = "fn a() {\n    b();\n}\n\nfn c() {}\n";
+        let lines: BTreeMap<u64, bool> =
+            [(1, true), (2, false), (3, true), (5, false)].into_iter().collect();
+        let mut out = String::new();
+        render_file(&mut out, "src/lib.rs", source, &lines, 0);
+        assert_eq!(out, "src/lib.rs:\n    2|      0|    b();\n  ...\n    5|      0|fn c() {}\n");
+        let mut out = String::new();
+        render_file(&mut out, "src/lib.rs", source, &lines, 1);
+        assert_eq!(
+            out,
+            "src/lib.rs:\n    1|       |fn a() {\n    2|      0|    b();\n    3|       |}\n    \
+             4|       |\n    5|      0|fn c() {}\n"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Uncovered spans of source code for mutation testing tools (--uncovered-spans).
+// See "Export uncovered spans" in README.md for the format.
+
+use std::collections::BTreeMap;
+
+use camino::Utf8Path;
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use serde_json::Value;
+
+use crate::context::Context;
+
+const FORMAT: &str = "cargo-llvm-cov-uncovered-spans";
+const VERSION: u32 = 1;
+
+/// Line and column.
+type Position = (u64, u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Span {
+    function: String,
+    start: Position,
+    end: Position,
+}
+
+struct Region {
+    count: u64,
+    function: String,
+}
+
+/// Handles --uncovered-spans.
+pub(crate) fn generate(cx: &Context, json: &LlvmCovJsonExport) -> Value {
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    // Merge the regions of instantiations of generic functions: a region is
+    // uncovered only if it is not executed in any instantiation.
+    let mut regions: BTreeMap<String, BTreeMap<(Position, Position), Region>> = BTreeMap::new();
+    for r in json.get_regions() {
+        if r.kind != "code" {
+            continue;
+        }
+        let region = regions
+            .entry(r.filename)
+            .or_default()
+            .entry(((r.line_start, r.column_start), (r.line_end, r.column_end)))
+            .or_insert_with(|| Region { count: 0, function: r.function.clone() });
+        region.count += r.execution_count;
+        if r.function < region.function {
+            region.function = r.function;
+        }
+    }
+
+    let mut files: Vec<_> = regions
+        .into_iter()
+        .map(|(filename, regions)| {
+            let path = Utf8Path::new(&filename);
+            let path =
+                path.strip_prefix(workspace_root).unwrap_or(path).as_str().replace('\\', "/");
+            let uncovered = regions
+                .into_iter()
+                .filter(|(_, region)| region.count == 0)
+                .map(|((start, end), region)| Span { function: region.function, start, end });
+            (path, outermost(uncovered))
+        })
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let files: Vec<_> = files
+        .into_iter()
+        .map(|(path, spans)| {
+            let spans: Vec<_> = spans
+                .into_iter()
+                .map(|span| {
+                    serde_json::json!({
+                        "function": span.function,
+                        "start_line": span.start.0,
+                        "start_column": span.start.1,
+                        "end_line": span.end.0,
+                        "end_column": span.end.1,
+                    })
+                })
+                .collect();
+            serde_json::json!({ "path": path, "spans": spans })
+        })
+        .collect();
+    serde_json::json!({ "format": FORMAT, "version": VERSION, "files": files })
+}
+
+/// Removes spans contained in other spans.
+fn outermost(spans: impl IntoIterator<Item = Span>) -> Vec<Span> {
+    let mut spans: Vec<_> = spans.into_iter().collect();
+    // Sort by start, and longer spans first among spans with the same start,
+    // so that a span containing others precedes them.
+    spans.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| b.end.cmp(&a.end)));
+    let mut out: Vec<Span> = vec![];
+    for span in spans {
+        // If any kept span contains this span, the last one does.
+        if out.last().map_or(false, |last| span.end <= last.end) {
+            continue;
+        }
+        out.push(span);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_spans() {
+        fn span(start: Position, end: Position) -> Span {
+            Span { function: "f".to_owned(), start, end }
+        }
+        let spans = outermost(vec![
+            span((5, 1), (9, 2)),
+            span((1, 1), (3, 2)),
+            span((6, 5), (7, 6)),
+            span((1, 1), (2, 8)),
+            span((9, 2), (10, 2)),
+        ]);
+        assert_eq!(spans, [span((1, 1), (3, 2)), span((5, 1), (9, 2)), span((9, 2), (10, 2))]);
+    }
+}
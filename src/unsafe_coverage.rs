@@ -0,0 +1,156 @@
+// Coverage of unsafe code (--unsafe-coverage).
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use cargo_llvm_cov_core::json::LlvmCovJsonExport;
+use syn::visit::{self, Visit};
+
+use crate::{context::Context, fs};
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct UnsafeCoverage {
+    /// The number of unsafe lines with coverage data.
+    pub(crate) count: u64,
+    pub(crate) covered: u64,
+    pub(crate) uncovered_lines: Vec<u64>,
+}
+
+/// Returns the coverage of unsafe lines of each file that contains unsafe code.
+pub(crate) fn collect(cx: &Context, json: &LlvmCovJsonExport) -> BTreeMap<String, UnsafeCoverage> {
+    let workspace_root = &cx.ws.metadata.workspace_root;
+    let mut files = BTreeMap::new();
+    for (filename, lines) in json.get_line_coverage() {
+        let source = match fs::read_to_string(workspace_root.join(&filename)) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("{:#}", e);
+                continue;
+            }
+        };
+        let unsafe_lines = match unsafe_lines(&source) {
+            Ok(unsafe_lines) => unsafe_lines,
+            Err(e) => {
+                warn!("failed to parse {}: {}", filename, e);
+                continue;
+            }
+        };
+        let coverage = file_coverage(&unsafe_lines, &lines);
+        if coverage.count != 0 {
+            files.insert(filename, coverage);
+        }
+    }
+    files
+}
+
+fn file_coverage(unsafe_lines: &BTreeSet<u64>, lines: &BTreeMap<u64, bool>) -> UnsafeCoverage {
+    let mut coverage = UnsafeCoverage::default();
+    for line in unsafe_lines {
+        match lines.get(line) {
+            Some(true) => coverage.covered += 1,
+            Some(false) => coverage.uncovered_lines.push(*line),
+            None => continue,
+        }
+        coverage.count += 1;
+    }
+    coverage
+}
+
+/// Returns the lines of `unsafe` blocks and `unsafe fn`s, from the line of the
+/// `unsafe` keyword to the line of the closing brace. Unsafe code in the
+/// arguments of macro invocations is not found, because syn does not parse them.
+fn unsafe_lines(source: &str) -> syn::Result<BTreeSet<u64>> {
+    let file = syn::parse_file(source)?;
+    let mut visitor = UnsafeVisitor::default();
+    visitor.visit_file(&file);
+    Ok(visitor.lines)
+}
+
+#[derive(Default)]
+struct UnsafeVisitor {
+    lines: BTreeSet<u64>,
+}
+
+impl UnsafeVisitor {
+    fn insert(&mut self, unsafety: &syn::token::Unsafe, block: &syn::Block) {
+        let start = unsafety.span.start().line as u64;
+        let end = block.brace_token.span.end().line as u64;
+        self.lines.extend(start..=end);
+    }
+}
+
+impl<'ast> Visit<'ast> for UnsafeVisitor {
+    fn visit_expr_unsafe(&mut self, i: &'ast syn::ExprUnsafe) {
+        self.insert(&i.unsafe_token, &i.block);
+        visit::visit_expr_unsafe(self, i);
+    }
+
+    fn visit_item_fn(&mut self, i: &'ast syn::ItemFn) {
+        if let Some(unsafety) = &i.sig.unsafety {
+            self.insert(unsafety, &i.block);
+        }
+        visit::visit_item_fn(self, i);
+    }
+
+    fn visit_impl_item_method(&mut self, i: &'ast syn::ImplItemMethod) {
+        if let Some(unsafety) = &i.sig.unsafety {
+            self.insert(unsafety, &i.block);
+        }
+        visit::visit_impl_item_method(self, i);
+    }
+
+    fn visit_trait_item_method(&mut self, i: &'ast syn::TraitItemMethod) {
+        if let (Some(unsafety), Some(block)) = (&i.sig.unsafety, &i.default) {
+            self.insert(unsafety, block);
+        }
+        visit::visit_trait_item_method(self, i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_unsafe() {
+        let source = "\
+fn a(p: *const u8) -> u8 {
+    let x = 1;
+    let y = unsafe { *p };
+    unsafe {
+        x + y
+    }
+}
+pub unsafe fn b() {
+    c();
+}
+struct S;
+impl S {
+    unsafe fn d(&self) {}
+    fn e(&self) {
+        let _ = || unsafe { b() };
+    }
+}
+trait T {
+    unsafe fn f() {
+        unsafe { b() }
+    }
+    unsafe fn g();
+}
+unsafe impl Send for S {}
+";
+        let lines: Vec<_> = unsafe_lines(source).unwrap().into_iter().collect();
+        assert_eq!(lines, [3, 4, 5, 6, 8, 9, 10, 13, 15, 19, 20, 21]);
+        assert!(unsafe_lines("fn a() {").is_err());
+
+        let lines: BTreeMap<u64, bool> =
+            [(1, true), (3, true), (4, true), (5, false), (6, true), (9, false)]
+                .into_iter()
+                .collect();
+        let unsafe_lines: BTreeSet<u64> = [3, 4, 5, 6, 8, 9, 10].into_iter().collect();
+        assert_eq!(file_coverage(&unsafe_lines, &lines), UnsafeCoverage {
+            count: 5,
+            covered: 3,
+            uncovered_lines: vec![5, 9],
+        });
+    }
+}
@@ -0,0 +1,289 @@
+// Conversion of coverage data in lcov format to Visual Studio coverage XML (--azure).
+// https://learn.microsoft.com/en-us/dotnet/core/additional-tools/dotnet-coverage
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::cobertura::{absolute_path, escape, package_of};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Covered {
+    Yes,
+    Partial,
+    No,
+}
+
+impl Covered {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Yes => "yes",
+            Self::Partial => "partial",
+            Self::No => "no",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Line {
+    hits: u64,
+    /// Whether some branch on the line is not taken.
+    branch_not_taken: bool,
+}
+
+impl Line {
+    fn covered(&self) -> Covered {
+        match (self.hits, self.branch_not_taken) {
+            (0, _) => Covered::No,
+            (_, true) => Covered::Partial,
+            (_, false) => Covered::Yes,
+        }
+    }
+}
+
+#[derive(Default)]
+struct File {
+    /// Start line -> function name.
+    functions: BTreeMap<u64, String>,
+    lines: BTreeMap<u64, Line>,
+}
+
+#[derive(Default)]
+struct Counts {
+    covered: u64,
+    partially_covered: u64,
+    not_covered: u64,
+}
+
+impl Counts {
+    fn add(&mut self, covered: Covered) {
+        match covered {
+            Covered::Yes => self.covered += 1,
+            Covered::Partial => self.partially_covered += 1,
+            Covered::No => self.not_covered += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.covered += other.covered;
+        self.partially_covered += other.partially_covered;
+        self.not_covered += other.not_covered;
+    }
+
+    fn attrs(&self) -> String {
+        let blocks_covered = self.covered + self.partially_covered;
+        let total = blocks_covered + self.not_covered;
+        format!(
+            "block_coverage=\"{0}\" line_coverage=\"{0}\" blocks_covered=\"{1}\" \
+             blocks_not_covered=\"{2}\" lines_covered=\"{3}\" lines_partially_covered=\"{4}\" \
+             lines_not_covered=\"{2}\"",
+            percent(total, blocks_covered),
+            blocks_covered,
+            self.not_covered,
+            self.covered,
+            self.partially_covered
+        )
+    }
+}
+
+/// Package name -> absolute filename -> file.
+type Files<'a> = BTreeMap<&'a str, BTreeMap<Utf8PathBuf, File>>;
+
+/// Converts the coverage data in lcov format to Visual Studio coverage XML, the
+/// form of `.coverage` files that `dotnet-coverage merge --output-format xml`
+/// generates (the binary form is not documented).
+///
+/// Each package is a module, and each line belongs to the function that starts
+/// last before it. lcov has no blocks, so the block counts are the line counts.
+///
+/// `packages` is the list of the directories and names of the packages, as in
+/// [`cobertura::from_lcov`](crate::cobertura::from_lcov).
+pub(crate) fn from_lcov(
+    lcov: &str,
+    workspace_root: &Utf8Path,
+    packages: &[(Utf8PathBuf, String)],
+) -> String {
+    render(&parse(lcov, workspace_root, packages))
+}
+
+fn parse<'a>(
+    lcov: &str,
+    workspace_root: &Utf8Path,
+    packages: &'a [(Utf8PathBuf, String)],
+) -> Files<'a> {
+    let mut files = Files::new();
+    let mut current = None;
+    for line in lcov.lines() {
+        if let Some(filename) = line.strip_prefix("SF:") {
+            let path = absolute_path(filename, workspace_root);
+            let package = package_of(&path, packages);
+            current = Some(files.entry(package).or_default().entry(path).or_default());
+        } else if line == "end_of_record" {
+            current = None;
+        } else if let (Some(file), Some(fn_)) = (&mut current, line.strip_prefix("FN:")) {
+            // FN:<line number of function start>,<function name>
+            if let Some((number, name)) = fn_.split_once(',') {
+                if let Ok(number) = number.parse() {
+                    // Closures and instantiations may start on the same line.
+                    file.functions.entry(number).or_insert_with(|| name.to_owned());
+                }
+            }
+        } else if let (Some(file), Some(da)) = (&mut current, line.strip_prefix("DA:")) {
+            // DA:<line number>,<execution count>[,<checksum>]
+            let mut da = da.split(',');
+            if let (Some(Ok(number)), Some(Ok(hits))) =
+                (da.next().map(str::parse), da.next().map(str::parse::<u64>))
+            {
+                file.lines.entry(number).or_default().hits += hits;
+            }
+        } else if let (Some(file), Some(brda)) = (&mut current, line.strip_prefix("BRDA:")) {
+            // BRDA:<line number>,<block number>,<branch number>,<taken>
+            let brda: Vec<_> = brda.split(',').collect();
+            if let (Some(Ok(number)), Some(taken)) = (brda.first().map(|n| n.parse()), brda.get(3))
+            {
+                let line = file.lines.entry(number).or_default();
+                line.branch_not_taken |= *taken == "-" || *taken == "0";
+            }
+        }
+    }
+    files
+}
+
+fn render(files: &Files<'_>) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<results>\n  <modules>\n");
+    let mut function_id = 0;
+    for (package, files) in files {
+        let mut module = String::new();
+        let mut module_counts = Counts::default();
+        for (source_id, file) in files.values().enumerate() {
+            // Lines before the first function are grouped into a function named `(other)`.
+            let mut functions: BTreeMap<u64, (&str, Counts, String)> = BTreeMap::new();
+            for (&number, line) in &file.lines {
+                let (&start, name) = file
+                    .functions
+                    .range(..=number)
+                    .next_back()
+                    .map_or((&0, "(other)"), |(start, name)| (start, name));
+                let (_, counts, ranges) = functions
+                    .entry(start)
+                    .or_insert_with(|| (name, Counts::default(), String::new()));
+                counts.add(line.covered());
+                let _ = writeln!(
+                    ranges,
+                    "            <range source_id=\"{}\" covered=\"{}\" start_line=\"{2}\" \
+                     end_line=\"{2}\"/>",
+                    source_id,
+                    line.covered().as_str(),
+                    number
+                );
+            }
+            for (name, counts, ranges) in functions.values() {
+                module_counts.merge(counts);
+                let _ = writeln!(
+                    module,
+                    "        <function id=\"{}\" name=\"{}\" namespace=\"\" type_name=\"\" {}>\n          \
+                     <ranges>\n{}          </ranges>\n        </function>",
+                    function_id,
+                    escape(name),
+                    counts.attrs(),
+                    ranges
+                );
+                function_id += 1;
+            }
+        }
+        let package = escape(package);
+        let _ = writeln!(
+            out,
+            "    <module id=\"{0}\" name=\"{0}\" path=\"{0}\" {1}>\n      <functions>\n{2}      \
+             </functions>\n      <source_files>",
+            package,
+            module_counts.attrs(),
+            module
+        );
+        for (source_id, path) in files.keys().enumerate() {
+            let _ = writeln!(
+                out,
+                "        <source_file id=\"{}\" path=\"{}\"/>",
+                source_id,
+                escape(path.as_str())
+            );
+        }
+        out.push_str("      </source_files>\n    </module>\n");
+    }
+    out.push_str("  </modules>\n</results>\n");
+    out
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn percent(count: u64, covered: u64) -> String {
+    if count == 0 {
+        "100.00".to_owned()
+    } else {
+        format!("{:.2}", covered as f64 * 100.0 / count as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert() {
+        let lcov = "\
+SF:/ws/a/src/lib.rs
+FN:1,f
+FN:4,g<T>
+FN:4,g<T>::{closure#0}
+DA:1,2
+DA:2,0
+DA:4,1
+BRDA:4,0,0,1
+BRDA:4,0,1,-
+end_of_record
+SF:src/main.rs
+DA:3,1
+end_of_record
+";
+        let packages = [(Utf8PathBuf::from("/ws/a"), "a".to_owned())];
+        let out = from_lcov(lcov, Utf8Path::new("/ws"), &packages);
+        let out: Vec<_> = out.lines().collect();
+        assert_eq!(out, [
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>",
+            "<results>",
+            "  <modules>",
+            "    <module id=\"(other)\" name=\"(other)\" path=\"(other)\" block_coverage=\"100.00\" line_coverage=\"100.00\" blocks_covered=\"1\" blocks_not_covered=\"0\" lines_covered=\"1\" lines_partially_covered=\"0\" lines_not_covered=\"0\">",
+            "      <functions>",
+            "        <function id=\"0\" name=\"(other)\" namespace=\"\" type_name=\"\" block_coverage=\"100.00\" line_coverage=\"100.00\" blocks_covered=\"1\" blocks_not_covered=\"0\" lines_covered=\"1\" lines_partially_covered=\"0\" lines_not_covered=\"0\">",
+            "          <ranges>",
+            "            <range source_id=\"0\" covered=\"yes\" start_line=\"3\" end_line=\"3\"/>",
+            "          </ranges>",
+            "        </function>",
+            "      </functions>",
+            "      <source_files>",
+            "        <source_file id=\"0\" path=\"/ws/src/main.rs\"/>",
+            "      </source_files>",
+            "    </module>",
+            "    <module id=\"a\" name=\"a\" path=\"a\" block_coverage=\"66.67\" line_coverage=\"66.67\" blocks_covered=\"2\" blocks_not_covered=\"1\" lines_covered=\"1\" lines_partially_covered=\"1\" lines_not_covered=\"1\">",
+            "      <functions>",
+            "        <function id=\"1\" name=\"f\" namespace=\"\" type_name=\"\" block_coverage=\"50.00\" line_coverage=\"50.00\" blocks_covered=\"1\" blocks_not_covered=\"1\" lines_covered=\"1\" lines_partially_covered=\"0\" lines_not_covered=\"1\">",
+            "          <ranges>",
+            "            <range source_id=\"0\" covered=\"yes\" start_line=\"1\" end_line=\"1\"/>",
+            "            <range source_id=\"0\" covered=\"no\" start_line=\"2\" end_line=\"2\"/>",
+            "          </ranges>",
+            "        </function>",
+            "        <function id=\"2\" name=\"g&lt;T&gt;\" namespace=\"\" type_name=\"\" block_coverage=\"100.00\" line_coverage=\"100.00\" blocks_covered=\"1\" blocks_not_covered=\"0\" lines_covered=\"0\" lines_partially_covered=\"1\" lines_not_covered=\"0\">",
+            "          <ranges>",
+            "            <range source_id=\"0\" covered=\"partial\" start_line=\"4\" end_line=\"4\"/>",
+            "          </ranges>",
+            "        </function>",
+            "      </functions>",
+            "      <source_files>",
+            "        <source_file id=\"0\" path=\"/ws/a/src/lib.rs\"/>",
+            "      </source_files>",
+            "    </module>",
+            "  </modules>",
+            "</results>",
+        ]);
+    }
+}
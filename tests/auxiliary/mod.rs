@@ -28,9 +28,17 @@ pub fn cargo_llvm_cov() -> Command {
         .env_remove("CARGO_BUILD_RUSTDOCFLAGS")
         .env_remove("CARGO_TERM_VERBOSE")
         .env_remove("CARGO_TERM_COLOR")
+        .env_remove("NO_COLOR")
+        .env_remove("CLICOLOR")
+        .env_remove("CLICOLOR_FORCE")
         .env_remove("BROWSER")
+        .env_remove("CARGO_LLVM_COV")
+        .env_remove("CARGO_LLVM_COV_SHOW_ENV")
         .env_remove("RUST_LOG")
-        .env_remove("CI");
+        .env_remove("CI")
+        .env_remove("GITHUB_ACTIONS")
+        .env_remove("GITLAB_CI")
+        .env_remove("BUILDKITE");
     cmd
 }
 
@@ -206,6 +214,13 @@ fn line_separated(lines: &str, f: impl FnMut(&str)) {
 }
 
 impl AssertOutput {
+    /// Asserts whether the process exited with the given code.
+    #[track_caller]
+    pub fn exit_code(&self, code: i32) -> &Self {
+        assert_eq!(self.status.code(), Some(code), "STDERR:\n{}", self.stderr);
+        self
+    }
+
     /// Receives a line(`\n`)-separated list of patterns and asserts whether stderr contains each pattern.
     #[track_caller]
     pub fn stderr_contains(&self, pats: &str) -> &Self {
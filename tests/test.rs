@@ -244,6 +244,62 @@ fn clean_ws() {
     }
 }
 
+#[cfg(unix)]
+#[test]
+fn symlinked_workspace_root() {
+    let model = "real1";
+    let name = "workspace_root";
+    let output_dir = FIXTURES_PATH.join("coverage-reports").join(model);
+    for (extension, args) in test_set() {
+        let workspace_root = test_project(model).unwrap();
+        let tmpdir = tempdir().unwrap();
+        let link = tmpdir.path().join("link");
+        std::os::unix::fs::symlink(workspace_root.path(), &link).unwrap();
+        let expected =
+            &fs::read_to_string(output_dir.join(name).with_extension(extension)).unwrap();
+        let output_path = &Utf8Path::from_path(tmpdir.path()).unwrap().join(name);
+        cargo_llvm_cov()
+            .args(["--color", "never", "--output-path"])
+            .arg(output_path)
+            .arg("--remap-path-prefix")
+            .arg("--manifest-path")
+            .arg(link.join("Cargo.toml"))
+            .args(args)
+            .current_dir(tmpdir.path())
+            .assert_success();
+
+        normalize_output(output_path, args).unwrap();
+        assert_output(output_path, expected).unwrap();
+    }
+}
+
+#[test]
+fn ignore_run_fail_exit_code() {
+    let tmpdir = tempdir().unwrap();
+    let workspace_root = tmpdir.path();
+    fs::create_dir_all(workspace_root.join("src")).unwrap();
+    fs::write(
+        workspace_root.join("Cargo.toml"),
+        "[package]\nname = \"run_fail\"\nversion = \"0.0.0\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    fs::write(
+        workspace_root.join("src/lib.rs"),
+        "pub fn f(x: u32) -> u32 {\n    if x == 0 { 0 } else { 1 }\n}\n\n\
+         #[test]\nfn fail() {\n    assert_eq!(f(0), 1);\n}\n",
+    )
+    .unwrap();
+    // The test failure is only warned about, so the exit code is the one of
+    // the threshold failure.
+    cargo_llvm_cov()
+        .args(["--color", "never", "--ignore-run-fail", "--fail-under-lines", "100"])
+        .args(["--failure-exit-code", "test=2", "--failure-exit-code", "threshold=3"])
+        .current_dir(workspace_root)
+        .assert_failure()
+        .exit_code(3)
+        .stderr_contains("coverage did not meet the threshold of lines");
+}
+
 #[cfg_attr(windows, ignore)] // `echo` may not be available
 #[test]
 fn open_report() {